@@ -0,0 +1,13 @@
+pub mod apu;
+mod blip;
+mod dmc;
+mod envelope;
+mod filter;
+mod frame_counter;
+mod length;
+mod mixer;
+mod noise;
+pub mod pulse;
+pub mod ring;
+pub mod sink;
+mod triangle;
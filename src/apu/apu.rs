@@ -0,0 +1,368 @@
+use std::collections::VecDeque;
+
+use super::blip::BlipBuffer;
+use super::dmc::Dmc;
+use super::filter::Filter;
+use super::frame_counter::FrameCounter;
+use super::mixer::Mixer;
+use super::noise::Noise;
+use super::pulse::{Pulse, PulseChannel};
+use super::sink::AudioSink;
+use super::triangle::Triangle;
+use crate::error::Result;
+use crate::state::{StateReader, StateWriter};
+
+// NTSC CPU clock, the rate the APU's output is synthesized at
+pub const CPU_CLOCK_RATE: f64 = 1_789_773.0;
+pub const DEFAULT_SAMPLE_RATE: f64 = 44_100.0;
+
+// How often (in CPU cycles) synthesized samples are moved to the output queue
+const CHUNK_CYCLES: u32 = 2048;
+
+#[derive(Debug)]
+pub struct Apu {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    frame_counter: FrameCounter,
+    // The pulse timers are clocked every other CPU cycle
+    odd_cycle: bool,
+    // Output
+    mixer: Mixer,
+    // Level of the cartridge's expansion audio
+    expansion: f32,
+    blip: BlipBuffer,
+    filters: [Filter; 3],
+    sample_rate: f64,
+    // Rate change requested through set_sample_rate(), applied at the next chunk boundary
+    next_sample_rate: Option<f64>,
+    chunk_cycle: u32,
+    amplitude: f32,
+    samples: VecDeque<f32>,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new(DEFAULT_SAMPLE_RATE)
+    }
+}
+
+impl Apu {
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            pulse1: Pulse::new(PulseChannel::One),
+            pulse2: Pulse::new(PulseChannel::Two),
+            triangle: Triangle::default(),
+            noise: Noise::default(),
+            dmc: Dmc::default(),
+            frame_counter: FrameCounter::default(),
+            odd_cycle: false,
+            mixer: Mixer::default(),
+            expansion: 0.0,
+            blip: BlipBuffer::new(CPU_CLOCK_RATE, sample_rate),
+            filters: Self::make_filters(sample_rate),
+            sample_rate,
+            next_sample_rate: None,
+            chunk_cycle: 0,
+            amplitude: 0.0,
+            samples: VecDeque::new(),
+        }
+    }
+
+    // The filters of the console's analog output stage
+    // https://www.nesdev.org/wiki/APU_Mixer
+    fn make_filters(sample_rate: f64) -> [Filter; 3] {
+        let rate = sample_rate as f32;
+        [
+            Filter::high_pass(90.0, rate),
+            Filter::high_pass(440.0, rate),
+            Filter::low_pass(14_000.0, rate),
+        ]
+    }
+
+    // $4015 status: IF-D NT21
+    pub fn read_status(&mut self) -> u8 {
+        let status = self.peek_status();
+        // Reading the status acknowledges the frame interrupt
+        self.frame_counter.clear_irq();
+        status
+    }
+
+    // $4015 without acknowledging the frame interrupt
+    pub fn peek_status(&self) -> u8 {
+        let mut status = 0;
+        status |= self.pulse1.length.active() as u8;
+        status |= (self.pulse2.length.active() as u8) << 1;
+        status |= (self.triangle.length.active() as u8) << 2;
+        status |= (self.noise.length.active() as u8) << 3;
+        status |= (self.dmc.active() as u8) << 4;
+        status |= (self.frame_counter.irq() as u8) << 6;
+        status |= (self.dmc.irq() as u8) << 7;
+        status
+    }
+
+    pub fn write(&mut self, addr: u16, byte: u8) {
+        match addr {
+            0x4000..=0x4003 => self.pulse1.write(addr - 0x4000, byte),
+            0x4004..=0x4007 => self.pulse2.write(addr - 0x4004, byte),
+            0x4008..=0x400B => self.triangle.write(addr - 0x4008, byte),
+            0x400C..=0x400F => self.noise.write(addr - 0x400C, byte),
+            0x4010..=0x4013 => self.dmc.write(addr - 0x4010, byte),
+            0x4015 => {
+                self.pulse1.length.set_enabled(byte & 0x1 != 0);
+                self.pulse2.length.set_enabled(byte & 0x2 != 0);
+                self.triangle.length.set_enabled(byte & 0x4 != 0);
+                self.noise.length.set_enabled(byte & 0x8 != 0);
+                self.dmc.set_enabled(byte & 0x10 != 0);
+            }
+            0x4017 => self.frame_counter.write(byte, self.odd_cycle),
+            _ => (),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.write(0x4015, 0);
+        self.frame_counter = FrameCounter::default();
+        self.odd_cycle = false;
+    }
+
+    // Whether the APU is holding the CPU's IRQ line
+    pub fn irq(&self) -> bool {
+        self.frame_counter.irq() || self.dmc.irq()
+    }
+
+    // Address the DMC needs fetched into its sample buffer, if any
+    pub fn dmc_dma_addr(&self) -> Option<u16> {
+        self.dmc.dma_addr()
+    }
+
+    pub fn dmc_dma_fill(&mut self, byte: u8) {
+        self.dmc.dma_fill(byte)
+    }
+
+    // Cartridges with their own sound channels mix them in on the cartridge connector
+    pub fn set_expansion_output(&mut self, level: f32) {
+        self.expansion = level;
+    }
+
+    // Advance the APU by a single CPU cycle
+    pub fn tick(&mut self) {
+        if self.odd_cycle {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        self.odd_cycle = !self.odd_cycle;
+
+        let clocks = self.frame_counter.tick();
+        if clocks.quarter {
+            self.pulse1.clock_quarter_frame();
+            self.pulse2.clock_quarter_frame();
+            self.triangle.clock_quarter_frame();
+            self.noise.clock_quarter_frame();
+        }
+        if clocks.half {
+            self.pulse1.clock_half_frame();
+            self.pulse2.clock_half_frame();
+            self.triangle.clock_half_frame();
+            self.noise.clock_half_frame();
+        }
+
+        let amplitude = self.mix();
+        if amplitude != self.amplitude {
+            self.blip.add_delta(self.chunk_cycle, amplitude - self.amplitude);
+            self.amplitude = amplitude;
+        }
+        self.chunk_cycle += 1;
+        if self.chunk_cycle >= CHUNK_CYCLES {
+            self.end_chunk();
+        }
+    }
+
+    fn mix(&self) -> f32 {
+        self.mixer.mix(
+            self.pulse1.output(),
+            self.pulse2.output(),
+            self.triangle.output(),
+            self.noise.output(),
+            self.dmc.output(),
+        ) + self.expansion
+    }
+
+    // Resamples and filters everything synthesized so far into the output queue
+    fn end_chunk(&mut self) {
+        self.blip.end_frame(self.chunk_cycle);
+        self.chunk_cycle = 0;
+
+        let mut chunk = vec![];
+        self.blip.read_samples(&mut chunk);
+        if let Some(rate) = self.next_sample_rate.take() {
+            self.blip.set_rates(CPU_CLOCK_RATE, rate);
+        }
+        for sample in chunk {
+            let filtered = self.filters.iter_mut().fold(sample, |x, f| f.process(x));
+            self.samples.push_back(filtered);
+        }
+
+        // Nobody is draining the samples, only keep the most recent second around
+        let max_samples = self.sample_rate as usize;
+        if self.samples.len() > max_samples {
+            self.samples.drain(..self.samples.len() - max_samples);
+        }
+    }
+
+    // Small adjustments to the output rate, for keeping audio in sync with video.
+    // The filters keep using the original rate, the difference is inaudible.
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.next_sample_rate = Some(sample_rate);
+    }
+
+    // Hands every sample produced since the last call over to `sink`
+    pub fn flush(&mut self, sink: &mut dyn AudioSink) {
+        if !self.samples.is_empty() {
+            sink.push_samples(self.samples.make_contiguous());
+            self.samples.clear();
+        }
+    }
+
+    // Only the channels and sequencer, samples already synthesized aren't part of the state
+    pub fn save_state(&self, w: &mut StateWriter) {
+        self.pulse1.save_state(w);
+        self.pulse2.save_state(w);
+        self.triangle.save_state(w);
+        self.noise.save_state(w);
+        self.dmc.save_state(w);
+        self.frame_counter.save_state(w);
+        w.bool(self.odd_cycle);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.pulse1.load_state(r)?;
+        self.pulse2.load_state(r)?;
+        self.triangle.load_state(r)?;
+        self.noise.load_state(r)?;
+        self.dmc.load_state(r)?;
+        self.frame_counter.load_state(r)?;
+        self.odd_cycle = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod apu_tests {
+    use super::{Apu, CPU_CLOCK_RATE};
+
+    #[test]
+    fn pulse_output() {
+        let mut apu = Apu::default();
+        // Constant volume 15, 50% duty, halted length counter
+        apu.write(0x4000, 0xBF);
+        apu.write(0x4002, 0x40);
+        apu.write(0x4003, 0x00);
+        // Channel wasn't enabled when the length was loaded
+        // (the idle triangle still contributes a constant offset)
+        let idle = apu.mix();
+        let mut max = idle;
+        for _ in 0..1000 {
+            apu.tick();
+            max = max.max(apu.mix());
+        }
+        assert_eq!(max, idle);
+
+        apu.write(0x4015, 0x01);
+        apu.write(0x4003, 0x00);
+        for _ in 0..1000 {
+            apu.tick();
+            max = max.max(apu.mix());
+        }
+        assert!(max - idle > 0.1);
+    }
+
+    #[test]
+    fn status() {
+        let mut apu = Apu::default();
+        apu.write(0x4017, 0x40);
+        apu.write(0x4015, 0x0F);
+        apu.write(0x4003, 0x08);
+        apu.write(0x400B, 0x08);
+        assert_eq!(apu.read_status(), 0b0101);
+        apu.write(0x4007, 0x08);
+        apu.write(0x400F, 0x08);
+        assert_eq!(apu.read_status(), 0b1111);
+
+        apu.write(0x4015, 0x00);
+        assert_eq!(apu.read_status(), 0);
+    }
+
+    #[test]
+    fn length_counter_silences() {
+        let mut apu = Apu::default();
+        apu.write(0x4015, 0x02);
+        // Length index 1 -> 254 half frames, so use index 3 -> 2 half frames
+        apu.write(0x4004, 0xBF & !0x20);
+        apu.write(0x4006, 0x40);
+        apu.write(0x4007, 0x03 << 3);
+        assert!(apu.pulse2.length.active());
+        // A whole 4-step frame has both half frame clocks
+        for _ in 0..30000 {
+            apu.tick();
+        }
+        assert!(!apu.pulse2.length.active());
+    }
+
+    #[test]
+    fn frame_irq() {
+        let mut apu = Apu::default();
+        apu.write(0x4017, 0x00);
+        for _ in 0..30000 {
+            apu.tick();
+        }
+        assert!(apu.irq());
+        assert_eq!(apu.read_status() & 0x40, 0x40);
+        // Reading the status cleared the flag
+        assert!(!apu.irq());
+        assert_eq!(apu.read_status() & 0x40, 0);
+    }
+
+    #[test]
+    fn output_rate() {
+        let mut apu = Apu::new(48_000.0);
+        // 440Hz square wave
+        apu.write(0x4015, 0x01);
+        apu.write(0x4000, 0xBF);
+        apu.write(0x4002, 0xFD);
+        apu.write(0x4003, 0x00);
+
+        let mut out: Vec<f32> = vec![];
+        for _ in 0..CPU_CLOCK_RATE as usize {
+            apu.tick();
+        }
+        apu.flush(&mut out);
+        // One second of audio (minus whatever is still waiting in the last chunk)
+        assert!(out.len() <= 48_000 && out.len() > 48_000 - 100);
+        // The high-pass filters center the output around zero
+        let mean = out.iter().sum::<f32>() / out.len() as f32;
+        assert!(mean.abs() < 0.01);
+        assert!(out.iter().any(|s| *s > 0.05) && out.iter().any(|s| *s < -0.05));
+
+        apu.flush(&mut out);
+        assert!(out.len() <= 48_000);
+    }
+
+    #[test]
+    fn sample_rate_change() {
+        let mut apu = Apu::new(48_000.0);
+        apu.set_sample_rate(24_000.0);
+        let mut out: Vec<f32> = vec![];
+        for _ in 0..CPU_CLOCK_RATE as usize {
+            apu.tick();
+        }
+        apu.flush(&mut out);
+        // The first chunk was still produced at the old rate
+        assert!(out.len() > 24_000 - 100 && out.len() < 24_000 + 100);
+    }
+}
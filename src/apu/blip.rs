@@ -0,0 +1,144 @@
+use std::f64::consts::PI;
+
+// Band-limited step synthesis: every change in the APU's output level is added to
+// the output as a windowed-sinc step instead of a hard edge, which resamples
+// the 1.79 MHz signal down to the output rate without aliasing.
+// (Same idea as blargg's blip_buf, minus the fixed-point tricks)
+
+// Kernel resolution between two output samples
+const PHASES: usize = 32;
+// Kernel taps on each side of a step, this is also the latency in output samples
+const HALF_WIDTH: usize = 8;
+const WIDTH: usize = 2 * HALF_WIDTH;
+// Cutoff relative to the output Nyquist frequency
+const CUTOFF: f64 = 0.9;
+
+#[derive(Debug)]
+pub struct BlipBuffer {
+    // Output samples per input clock
+    factor: f64,
+    // Position (in output samples) of clock 0 of the current frame
+    frame_start: f64,
+    // Per-sample deltas, integrated when read
+    deltas: Vec<f32>,
+    integrator: f32,
+    kernel: Box<[[f32; WIDTH]; PHASES]>,
+}
+
+impl BlipBuffer {
+    pub fn new(clock_rate: f64, sample_rate: f64) -> Self {
+        let mut kernel = Box::new([[0.0f32; WIDTH]; PHASES]);
+        for (phase, taps) in kernel.iter_mut().enumerate() {
+            let frac = phase as f64 / PHASES as f64;
+            let mut sum = 0.0;
+            let mut raw = [0.0f64; WIDTH];
+            for (k, tap) in raw.iter_mut().enumerate() {
+                // Distance from the step to the middle of this sample's interval
+                let x = k as f64 + 0.5 - frac - HALF_WIDTH as f64;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (PI * x * CUTOFF).sin() / (PI * x * CUTOFF)
+                };
+                // Blackman window over the kernel's width
+                let w = (x + HALF_WIDTH as f64) / WIDTH as f64;
+                let window =
+                    0.42 - 0.5 * (2.0 * PI * w).cos() + 0.08 * (4.0 * PI * w).cos();
+                *tap = sinc * window;
+                sum += *tap;
+            }
+            // Normalize so every step settles at exactly its delta
+            for (tap, r) in taps.iter_mut().zip(raw.iter()) {
+                *tap = (r / sum) as f32;
+            }
+        }
+
+        Self {
+            factor: sample_rate / clock_rate,
+            frame_start: 0.0,
+            deltas: vec![0.0; WIDTH],
+            integrator: 0.0,
+            kernel,
+        }
+    }
+
+    // Only call this between frames, deltas already added keep their old positions
+    pub fn set_rates(&mut self, clock_rate: f64, sample_rate: f64) {
+        self.factor = sample_rate / clock_rate;
+    }
+
+    // Adds a change in amplitude at `clock` clocks into the current frame
+    pub fn add_delta(&mut self, clock: u32, delta: f32) {
+        let pos = self.frame_start + clock as f64 * self.factor;
+        let index = pos as usize;
+        let phase = ((pos - index as f64) * PHASES as f64) as usize;
+
+        if self.deltas.len() < index + WIDTH {
+            self.deltas.resize(index + WIDTH, 0.0);
+        }
+        for (out, tap) in self.deltas[index..index + WIDTH]
+            .iter_mut()
+            .zip(self.kernel[phase].iter())
+        {
+            *out += delta * tap;
+        }
+    }
+
+    // Ends the current frame after `clocks` clocks, making its samples available
+    pub fn end_frame(&mut self, clocks: u32) {
+        self.frame_start += clocks as f64 * self.factor;
+        let needed = self.frame_start as usize + WIDTH;
+        if self.deltas.len() < needed {
+            self.deltas.resize(needed, 0.0);
+        }
+    }
+
+    // Number of samples which can no longer be affected by future deltas
+    pub fn samples_avail(&self) -> usize {
+        self.frame_start as usize
+    }
+
+    // Moves all available samples into `out`
+    pub fn read_samples(&mut self, out: &mut Vec<f32>) {
+        let avail = self.samples_avail();
+        for delta in self.deltas.drain(..avail) {
+            self.integrator += delta;
+            out.push(self.integrator);
+        }
+        self.frame_start -= avail as f64;
+    }
+}
+
+#[cfg(test)]
+mod blip_tests {
+    use super::{BlipBuffer, HALF_WIDTH};
+
+    #[test]
+    fn sample_count() {
+        let mut blip = BlipBuffer::new(1_000_000.0, 1000.0);
+        let mut out = vec![];
+        for _ in 0..10 {
+            blip.end_frame(100_000);
+            blip.read_samples(&mut out);
+        }
+        assert_eq!(out.len(), 1000);
+    }
+
+    #[test]
+    fn step_settles() {
+        let mut blip = BlipBuffer::new(100_000.0, 1000.0);
+        blip.add_delta(1234, 0.5);
+        blip.add_delta(5678, -0.25);
+        blip.end_frame(100_000);
+        let mut out = vec![];
+        blip.read_samples(&mut out);
+
+        // The kernel only reaches forward, steps are delayed by HALF_WIDTH samples instead
+        assert!(out[..12].iter().all(|s| *s == 0.0));
+        assert!((out[40] - 0.5).abs() < 1e-4);
+        assert!((out[99] - 0.25).abs() < 1e-4);
+        // Band-limited, so there's ringing around the step instead of a hard edge
+        let edge = &out[12..12 + 2 * HALF_WIDTH];
+        assert!(edge.iter().any(|s| *s > 0.0 && *s < 0.5));
+    }
+}
@@ -0,0 +1,266 @@
+use crate::error::Result;
+use crate::state::{StateReader, StateWriter};
+
+// Timer periods in CPU cycles
+const RATE_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+// Delta modulation channel, plays 1-bit delta encoded samples fetched from cartridge space
+#[derive(Debug)]
+pub struct Dmc {
+    irq_enabled: bool,
+    irq_flag: bool,
+    looping: bool,
+    timer_period: u16,
+    timer: u16,
+    // 7-bit output level
+    level: u8,
+    sample_addr: u16,
+    sample_length: u16,
+    // Memory reader
+    current_addr: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    // Output unit
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+}
+
+impl Default for Dmc {
+    fn default() -> Self {
+        Self {
+            irq_enabled: false,
+            irq_flag: false,
+            looping: false,
+            timer_period: RATE_TABLE[0],
+            timer: 0,
+            level: 0,
+            sample_addr: 0xC000,
+            sample_length: 1,
+            current_addr: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+        }
+    }
+}
+
+impl Dmc {
+    // reg is the register offset from $4010
+    pub fn write(&mut self, reg: u16, byte: u8) {
+        match reg & 0x3 {
+            0 => {
+                // IL-- RRRR
+                self.irq_enabled = byte & 0x80 != 0;
+                if !self.irq_enabled {
+                    self.irq_flag = false;
+                }
+                self.looping = byte & 0x40 != 0;
+                self.timer_period = RATE_TABLE[(byte & 0xF) as usize];
+            }
+            1 => self.level = byte & 0x7F,
+            2 => self.sample_addr = 0xC000 | ((byte as u16) << 6),
+            3 => self.sample_length = ((byte as u16) << 4) | 1,
+            _ => panic!("impossible"),
+        }
+    }
+
+    // Bit 4 of $4015
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq_flag = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_addr = self.sample_addr;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    pub fn active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    pub fn irq(&self) -> bool {
+        self.irq_flag
+    }
+
+    // Address of the next sample byte when the reader needs the CPU to fetch one
+    pub fn dma_addr(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_addr)
+        } else {
+            None
+        }
+    }
+
+    // Hands the byte fetched from dma_addr() to the memory reader
+    pub fn dma_fill(&mut self, byte: u8) {
+        self.sample_buffer = Some(byte);
+        // The address wraps around to $8000, not $0000
+        self.current_addr = if self.current_addr == 0xFFFF {
+            0x8000
+        } else {
+            self.current_addr + 1
+        };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq_flag = true;
+            }
+        }
+    }
+
+    // Clocked every CPU cycle, the rate table is in CPU cycles
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period - 1;
+
+        if !self.silence {
+            if self.shift & 1 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(byte) => {
+                    self.silence = false;
+                    self.shift = byte;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        self.level
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.irq_enabled);
+        w.bool(self.irq_flag);
+        w.bool(self.looping);
+        w.u16(self.timer_period);
+        w.u16(self.timer);
+        w.u8(self.level);
+        w.u16(self.sample_addr);
+        w.u16(self.sample_length);
+        w.u16(self.current_addr);
+        w.u16(self.bytes_remaining);
+        w.bool(self.sample_buffer.is_some());
+        w.u8(self.sample_buffer.unwrap_or(0));
+        w.u8(self.shift);
+        w.u8(self.bits_remaining);
+        w.bool(self.silence);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.irq_enabled = r.bool()?;
+        self.irq_flag = r.bool()?;
+        self.looping = r.bool()?;
+        self.timer_period = r.u16()?;
+        self.timer = r.u16()?;
+        self.level = r.u8()? & 0x7F;
+        self.sample_addr = r.u16()?;
+        self.sample_length = r.u16()?;
+        self.current_addr = r.u16()?;
+        self.bytes_remaining = r.u16()?;
+        let has_sample = r.bool()?;
+        let sample = r.u8()?;
+        self.sample_buffer = has_sample.then_some(sample);
+        self.shift = r.u8()?;
+        self.bits_remaining = r.u8()?.clamp(1, 8);
+        self.silence = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod dmc_tests {
+    use super::Dmc;
+
+    // Runs the channel with every fetch returning `byte`, returns the number of fetches
+    fn play(dmc: &mut Dmc, byte: u8, cycles: usize) -> usize {
+        let mut fetches = 0;
+        for _ in 0..cycles {
+            if dmc.dma_addr().is_some() {
+                dmc.dma_fill(byte);
+                fetches += 1;
+            }
+            dmc.clock_timer();
+        }
+        fetches
+    }
+
+    #[test]
+    fn sample_playback() {
+        let mut dmc = Dmc::default();
+        // Fastest rate, IRQ enabled, sample at $C040 of 17 bytes
+        dmc.write(0, 0x8F);
+        dmc.write(1, 0x40);
+        dmc.write(2, 0x01);
+        dmc.write(3, 0x01);
+        assert_eq!(dmc.dma_addr(), None);
+
+        dmc.set_enabled(true);
+        assert_eq!(dmc.dma_addr(), Some(0xC040));
+        assert!(dmc.active());
+
+        // All ones -> output ramps up until it saturates
+        let fetches = play(&mut dmc, 0xFF, 54 * 8 * 20);
+        assert_eq!(fetches, 17);
+        assert!(!dmc.active());
+        assert!(dmc.irq());
+        assert_eq!(dmc.output(), 126);
+
+        dmc.set_enabled(false);
+        assert!(!dmc.irq());
+    }
+
+    #[test]
+    fn looping_sample() {
+        let mut dmc = Dmc::default();
+        dmc.write(0, 0xCF);
+        dmc.write(3, 0x00);
+        dmc.set_enabled(true);
+        play(&mut dmc, 0x00, 54 * 8 * 20);
+        // Looping samples restart instead of raising an IRQ
+        assert!(dmc.active());
+        assert!(!dmc.irq());
+        assert_eq!(dmc.output(), 0);
+    }
+
+    #[test]
+    fn address_wraps() {
+        let mut dmc = Dmc::default();
+        dmc.write(2, 0xFF);
+        dmc.write(3, 0xFF);
+        dmc.set_enabled(true);
+        assert_eq!(dmc.dma_addr(), Some(0xFFC0));
+        for _ in 0..0x40 {
+            dmc.dma_fill(0);
+            dmc.sample_buffer = None;
+        }
+        assert_eq!(dmc.dma_addr(), Some(0x8000));
+    }
+}
@@ -0,0 +1,111 @@
+use crate::error::Result;
+use crate::state::{StateReader, StateWriter};
+
+// Volume envelope shared by the pulse and noise channels
+#[derive(Debug, Default)]
+pub struct Envelope {
+    start: bool,
+    looping: bool,
+    constant: bool,
+    // Constant volume, or the reload value of the divider
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    // Handles the lower 6 bits of $4000/$4004/$400C (--LC VVVV)
+    pub fn write_control(&mut self, byte: u8) {
+        self.looping = byte & 0x20 != 0;
+        self.constant = byte & 0x10 != 0;
+        self.volume = byte & 0xF;
+    }
+
+    // Writing a channel's length register restarts its envelope
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+
+    // Clocked by the frame counter on quarter frames
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.constant {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.start);
+        w.bool(self.looping);
+        w.bool(self.constant);
+        w.u8(self.volume);
+        w.u8(self.divider);
+        w.u8(self.decay);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.start = r.bool()?;
+        self.looping = r.bool()?;
+        self.constant = r.bool()?;
+        self.volume = r.u8()?;
+        self.divider = r.u8()?;
+        self.decay = r.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod envelope_tests {
+    use super::Envelope;
+
+    #[test]
+    fn constant_volume() {
+        let mut e = Envelope::default();
+        e.write_control(0x1A);
+        e.restart();
+        e.clock();
+        e.clock();
+        assert_eq!(e.output(), 0xA);
+    }
+
+    #[test]
+    fn decay() {
+        let mut e = Envelope::default();
+        // Divider period of 1 -> decay level drops every 2 clocks
+        e.write_control(0x01);
+        e.restart();
+        e.clock();
+        assert_eq!(e.output(), 15);
+        e.clock();
+        e.clock();
+        assert_eq!(e.output(), 14);
+        for _ in 0..40 {
+            e.clock();
+        }
+        assert_eq!(e.output(), 0);
+
+        // Looping envelopes wrap back around to 15
+        e.write_control(0x21);
+        e.clock();
+        e.clock();
+        assert_eq!(e.output(), 15);
+    }
+}
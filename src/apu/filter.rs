@@ -0,0 +1,71 @@
+use std::f32::consts::PI;
+
+// First-order IIR filters run at the output sample rate, modelling the analog
+// filtering the console applies to the DAC output.
+#[derive(Debug)]
+pub enum Filter {
+    HighPass { alpha: f32, prev_in: f32, prev_out: f32 },
+    LowPass { alpha: f32, prev_out: f32 },
+}
+
+impl Filter {
+    fn rc_dt(cutoff: f32, sample_rate: f32) -> (f32, f32) {
+        (1.0 / (2.0 * PI * cutoff), 1.0 / sample_rate)
+    }
+
+    pub fn high_pass(cutoff: f32, sample_rate: f32) -> Self {
+        let (rc, dt) = Self::rc_dt(cutoff, sample_rate);
+        Filter::HighPass {
+            alpha: rc / (rc + dt),
+            prev_in: 0.0,
+            prev_out: 0.0,
+        }
+    }
+
+    pub fn low_pass(cutoff: f32, sample_rate: f32) -> Self {
+        let (rc, dt) = Self::rc_dt(cutoff, sample_rate);
+        Filter::LowPass {
+            alpha: dt / (rc + dt),
+            prev_out: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, x: f32) -> f32 {
+        match self {
+            Filter::HighPass { alpha, prev_in, prev_out } => {
+                *prev_out = *alpha * (*prev_out + x - *prev_in);
+                *prev_in = x;
+                *prev_out
+            }
+            Filter::LowPass { alpha, prev_out } => {
+                *prev_out += *alpha * (x - *prev_out);
+                *prev_out
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod filter_tests {
+    use super::Filter;
+
+    #[test]
+    fn high_pass_removes_dc() {
+        let mut f = Filter::high_pass(90.0, 44100.0);
+        let mut y = 0.0;
+        for _ in 0..44100 {
+            y = f.process(0.5);
+        }
+        assert!(y.abs() < 1e-3);
+    }
+
+    #[test]
+    fn low_pass_passes_dc() {
+        let mut f = Filter::low_pass(14000.0, 44100.0);
+        let mut y = 0.0;
+        for _ in 0..100 {
+            y = f.process(0.5);
+        }
+        assert!((y - 0.5).abs() < 1e-3);
+    }
+}
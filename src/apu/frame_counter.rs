@@ -0,0 +1,185 @@
+use crate::error::Result;
+use crate::state::{StateReader, StateWriter};
+
+// CPU cycles (after the sequencer was last reset) at which each step happens
+// https://www.nesdev.org/wiki/APU_Frame_Counter
+const STEP_1: u32 = 7457;
+const STEP_2: u32 = 14913;
+const STEP_3: u32 = 22371;
+const STEP_4: u32 = 29829;
+const STEP_5: u32 = 37281;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SequenceMode {
+    FourStep,
+    FiveStep,
+}
+
+// Which units of the channels should be clocked this cycle
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FrameClocks {
+    pub quarter: bool,
+    pub half: bool,
+}
+
+impl FrameClocks {
+    const NONE: Self = Self { quarter: false, half: false };
+    const QUARTER: Self = Self { quarter: true, half: false };
+    const HALF: Self = Self { quarter: true, half: true };
+}
+
+#[derive(Debug)]
+pub struct FrameCounter {
+    mode: SequenceMode,
+    irq_inhibit: bool,
+    irq_flag: bool,
+    cycle: u32,
+    // A write to $4017 takes effect 3 or 4 CPU cycles later
+    pending_write: Option<(u8, u8)>,
+}
+
+impl Default for FrameCounter {
+    fn default() -> Self {
+        Self {
+            mode: SequenceMode::FourStep,
+            irq_inhibit: false,
+            irq_flag: false,
+            cycle: 0,
+            pending_write: None,
+        }
+    }
+}
+
+impl FrameCounter {
+    // $4017: MI-- ----
+    // odd_cycle is whether the write lands between two APU cycles
+    pub fn write(&mut self, byte: u8, odd_cycle: bool) {
+        self.irq_inhibit = byte & 0x40 != 0;
+        if self.irq_inhibit {
+            self.irq_flag = false;
+        }
+        let delay = if odd_cycle { 4 } else { 3 };
+        self.pending_write = Some((byte, delay));
+    }
+
+    pub fn irq(&self) -> bool {
+        self.irq_flag
+    }
+
+    pub fn clear_irq(&mut self) {
+        self.irq_flag = false;
+    }
+
+    // Advance the sequencer by a single CPU cycle
+    pub fn tick(&mut self) -> FrameClocks {
+        if let Some((byte, delay)) = self.pending_write {
+            if delay <= 1 {
+                self.pending_write = None;
+                self.cycle = 0;
+                if byte & 0x80 != 0 {
+                    self.mode = SequenceMode::FiveStep;
+                    // Entering 5-step mode immediately clocks all units
+                    return FrameClocks::HALF;
+                } else {
+                    self.mode = SequenceMode::FourStep;
+                    return FrameClocks::NONE;
+                }
+            }
+            self.pending_write = Some((byte, delay - 1));
+        }
+
+        self.cycle += 1;
+        match self.mode {
+            SequenceMode::FourStep => {
+                // The IRQ flag gets set on the last three cycles of the sequence
+                if (STEP_4 - 1..=STEP_4 + 1).contains(&self.cycle) {
+                    self.irq_flag |= !self.irq_inhibit;
+                }
+                match self.cycle {
+                    STEP_1 | STEP_3 => FrameClocks::QUARTER,
+                    STEP_2 | STEP_4 => FrameClocks::HALF,
+                    c if c > STEP_4 => {
+                        self.cycle = 0;
+                        FrameClocks::NONE
+                    }
+                    _ => FrameClocks::NONE,
+                }
+            }
+            SequenceMode::FiveStep => match self.cycle {
+                STEP_1 | STEP_3 => FrameClocks::QUARTER,
+                STEP_2 => FrameClocks::HALF,
+                STEP_5 => FrameClocks::HALF,
+                c if c > STEP_5 => {
+                    self.cycle = 0;
+                    FrameClocks::NONE
+                }
+                _ => FrameClocks::NONE,
+            },
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.mode == SequenceMode::FiveStep);
+        w.bool(self.irq_inhibit);
+        w.bool(self.irq_flag);
+        w.u32(self.cycle);
+        let (byte, delay) = self.pending_write.unwrap_or((0, 0));
+        w.bool(self.pending_write.is_some());
+        w.u8(byte);
+        w.u8(delay);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.mode = if r.bool()? {
+            SequenceMode::FiveStep
+        } else {
+            SequenceMode::FourStep
+        };
+        self.irq_inhibit = r.bool()?;
+        self.irq_flag = r.bool()?;
+        self.cycle = r.u32()?;
+        let pending = r.bool()?;
+        let (byte, delay) = (r.u8()?, r.u8()?);
+        self.pending_write = pending.then_some((byte, delay));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod frame_counter_tests {
+    use super::{FrameCounter, STEP_4, STEP_5};
+
+    fn run(fc: &mut FrameCounter, cycles: u32) -> (u32, u32) {
+        let (mut quarters, mut halves) = (0, 0);
+        for _ in 0..cycles {
+            let clocks = fc.tick();
+            quarters += clocks.quarter as u32;
+            halves += clocks.half as u32;
+        }
+        (quarters, halves)
+    }
+
+    #[test]
+    fn four_step() {
+        let mut fc = FrameCounter::default();
+        assert_eq!(run(&mut fc, STEP_4 + 1), (4, 2));
+        assert!(fc.irq());
+        fc.clear_irq();
+
+        // Setting the inhibit flag stops (and acknowledges) the IRQ
+        fc.write(0x40, false);
+        assert_eq!(run(&mut fc, 3), (0, 0));
+        assert_eq!(run(&mut fc, STEP_4 + 1), (4, 2));
+        assert!(!fc.irq());
+    }
+
+    #[test]
+    fn five_step() {
+        let mut fc = FrameCounter::default();
+        fc.write(0x80, true);
+        // The write itself clocks every unit once it takes effect
+        assert_eq!(run(&mut fc, 4), (1, 1));
+        assert_eq!(run(&mut fc, STEP_5 + 1), (4, 2));
+        assert!(!fc.irq());
+    }
+}
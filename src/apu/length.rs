@@ -0,0 +1,89 @@
+use crate::error::Result;
+use crate::state::{StateReader, StateWriter};
+
+// Lengths (in half frames) loaded by the upper 5 bits of a channel's length register
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+#[derive(Debug, Default)]
+pub struct LengthCounter {
+    enabled: bool,
+    halt: bool,
+    counter: u8,
+}
+
+impl LengthCounter {
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    pub fn set_halt(&mut self, halt: bool) {
+        self.halt = halt;
+    }
+
+    // Loading only takes effect while the channel is enabled through $4015
+    pub fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[(index & 0x1F) as usize];
+        }
+    }
+
+    // Clocked by the frame counter on half frames
+    pub fn clock(&mut self) {
+        if self.counter > 0 && !self.halt {
+            self.counter -= 1;
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.counter > 0
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.bool(self.halt);
+        w.u8(self.counter);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.enabled = r.bool()?;
+        self.halt = r.bool()?;
+        self.counter = r.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod length_tests {
+    use super::LengthCounter;
+
+    #[test]
+    fn load_and_clock() {
+        let mut l = LengthCounter::default();
+        // Disabled channels ignore loads
+        l.load(1);
+        assert!(!l.active());
+
+        l.set_enabled(true);
+        l.load(3);
+        assert!(l.active());
+        l.clock();
+        l.clock();
+        assert!(!l.active());
+
+        l.load(1);
+        l.set_halt(true);
+        for _ in 0..300 {
+            l.clock();
+        }
+        assert!(l.active());
+
+        l.set_enabled(false);
+        assert!(!l.active());
+    }
+}
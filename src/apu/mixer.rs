@@ -0,0 +1,50 @@
+// Nonlinear DAC mixing of the five channels, using the lookup table approximation
+// https://www.nesdev.org/wiki/APU_Mixer
+#[derive(Debug)]
+pub struct Mixer {
+    // Indexed by pulse1 + pulse2
+    pulse_table: [f32; 31],
+    // Indexed by 3 * triangle + 2 * noise + dmc
+    tnd_table: [f32; 203],
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        let mut pulse_table = [0.0; 31];
+        for (n, out) in pulse_table.iter_mut().enumerate().skip(1) {
+            *out = (95.52 / (8128.0 / n as f64 + 100.0)) as f32;
+        }
+        let mut tnd_table = [0.0; 203];
+        for (n, out) in tnd_table.iter_mut().enumerate().skip(1) {
+            *out = (163.67 / (24329.0 / n as f64 + 100.0)) as f32;
+        }
+        Self {
+            pulse_table,
+            tnd_table,
+        }
+    }
+}
+
+impl Mixer {
+    // Output level in [0.0, 1.0)
+    pub fn mix(&self, pulse1: u8, pulse2: u8, triangle: u8, noise: u8, dmc: u8) -> f32 {
+        let pulse = self.pulse_table[(pulse1 + pulse2) as usize];
+        let tnd = self.tnd_table[3 * triangle as usize + 2 * noise as usize + dmc as usize];
+        pulse + tnd
+    }
+}
+
+#[cfg(test)]
+mod mixer_tests {
+    use super::Mixer;
+
+    #[test]
+    fn mix_range() {
+        let m = Mixer::default();
+        assert_eq!(m.mix(0, 0, 0, 0, 0), 0.0);
+        let max = m.mix(15, 15, 15, 15, 127);
+        assert!(max > 0.99 && max < 1.0);
+        // Mixing is nonlinear, two channels are quieter than twice one channel
+        assert!(m.mix(15, 15, 0, 0, 0) < 2.0 * m.mix(15, 0, 0, 0, 0));
+    }
+}
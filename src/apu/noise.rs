@@ -0,0 +1,133 @@
+use super::envelope::Envelope;
+use super::length::LengthCounter;
+use crate::error::Result;
+use crate::state::{StateReader, StateWriter};
+
+// Timer periods in CPU cycles
+const PERIOD_TABLE: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+#[derive(Debug)]
+pub struct Noise {
+    // "Mode" flag, taps bit 6 instead of bit 1 for a short, metallic sequence
+    short_mode: bool,
+    timer_period: u16,
+    timer: u16,
+    shift: u16,
+    envelope: Envelope,
+    pub length: LengthCounter,
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Self {
+            short_mode: false,
+            timer_period: PERIOD_TABLE[0],
+            timer: 0,
+            // The shift register is 1 on power-up
+            shift: 1,
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+        }
+    }
+}
+
+impl Noise {
+    // reg is the register offset from $400C
+    pub fn write(&mut self, reg: u16, byte: u8) {
+        match reg & 0x3 {
+            0 => {
+                // --LC VVVV
+                self.length.set_halt(byte & 0x20 != 0);
+                self.envelope.write_control(byte);
+            }
+            1 => (),
+            2 => {
+                // M--- PPPP
+                self.short_mode = byte & 0x80 != 0;
+                self.timer_period = PERIOD_TABLE[(byte & 0xF) as usize];
+            }
+            3 => {
+                // LLLL L---
+                self.length.load(byte >> 3);
+                self.envelope.restart();
+            }
+            _ => panic!("impossible"),
+        }
+    }
+
+    // Clocked every CPU cycle, the period table is in CPU cycles
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> tap)) & 1;
+            self.shift = (self.shift >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.shift & 1 != 0 || !self.length.active() {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.short_mode);
+        w.u16(self.timer_period);
+        w.u16(self.timer);
+        w.u16(self.shift);
+        self.envelope.save_state(w);
+        self.length.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.short_mode = r.bool()?;
+        self.timer_period = r.u16()?;
+        self.timer = r.u16()?;
+        self.shift = r.u16()?;
+        self.envelope.load_state(r)?;
+        self.length.load_state(r)
+    }
+}
+
+#[cfg(test)]
+mod noise_tests {
+    use super::Noise;
+
+    // Number of steps before the shift register returns to its initial state
+    fn sequence_length(short_mode: bool) -> usize {
+        let mut n = Noise::default();
+        n.write(2, if short_mode { 0x80 } else { 0x00 });
+        let start = n.shift;
+        let mut steps = 0;
+        loop {
+            for _ in 0..4 {
+                n.clock_timer();
+            }
+            steps += 1;
+            if n.shift == start {
+                return steps;
+            }
+        }
+    }
+
+    #[test]
+    fn lfsr_period() {
+        assert_eq!(sequence_length(false), 32767);
+        assert_eq!(sequence_length(true), 93);
+    }
+}
@@ -0,0 +1,248 @@
+use super::envelope::Envelope;
+use super::length::LengthCounter;
+use crate::error::Result;
+use crate::state::{StateReader, StateWriter};
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+// The two pulse channels are identical except for how the sweep unit negates:
+// pulse 1 uses ones' complement (subtracts an extra 1), pulse 2 uses two's complement.
+// MMC5's pulse channels are copies of these without a sweep unit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PulseChannel {
+    One,
+    Two,
+    Mmc5,
+}
+
+#[derive(Debug, Default)]
+struct Sweep {
+    enabled: bool,
+    period: u8,
+    negate: bool,
+    shift: u8,
+    reload: bool,
+    divider: u8,
+}
+
+#[derive(Debug)]
+pub struct Pulse {
+    channel: PulseChannel,
+    duty: u8,
+    duty_pos: u8,
+    timer_period: u16,
+    timer: u16,
+    envelope: Envelope,
+    sweep: Sweep,
+    pub length: LengthCounter,
+}
+
+impl Pulse {
+    pub fn new(channel: PulseChannel) -> Self {
+        Self {
+            channel,
+            duty: 0,
+            duty_pos: 0,
+            timer_period: 0,
+            timer: 0,
+            envelope: Envelope::default(),
+            sweep: Sweep::default(),
+            length: LengthCounter::default(),
+        }
+    }
+
+    // reg is the register offset from the channel's base address ($4000 or $4004)
+    pub fn write(&mut self, reg: u16, byte: u8) {
+        match reg & 0x3 {
+            0 => {
+                // DDLC VVVV
+                self.duty = byte >> 6;
+                self.length.set_halt(byte & 0x20 != 0);
+                self.envelope.write_control(byte);
+            }
+            1 => {
+                // EPPP NSSS
+                self.sweep.enabled = byte & 0x80 != 0;
+                self.sweep.period = (byte >> 4) & 0x7;
+                self.sweep.negate = byte & 0x8 != 0;
+                self.sweep.shift = byte & 0x7;
+                self.sweep.reload = true;
+            }
+            2 => {
+                self.timer_period = (self.timer_period & 0x700) | (byte as u16);
+            }
+            3 => {
+                // LLLL LTTT
+                self.timer_period = (self.timer_period & 0xFF) | (((byte & 0x7) as u16) << 8);
+                self.length.load(byte >> 3);
+                self.envelope.restart();
+                self.duty_pos = 0;
+            }
+            _ => panic!("impossible"),
+        }
+    }
+
+    // Clocked every APU cycle (every other CPU cycle)
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.duty_pos = (self.duty_pos + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+
+        if self.sweep.divider == 0 && self.sweep.enabled && self.sweep.shift > 0 && !self.muted() {
+            self.timer_period = self.sweep_target();
+        }
+        if self.sweep.divider == 0 || self.sweep.reload {
+            self.sweep.divider = self.sweep.period;
+            self.sweep.reload = false;
+        } else {
+            self.sweep.divider -= 1;
+        }
+    }
+
+    // The period the sweep unit is continuously computing, whether or not it's enabled
+    fn sweep_target(&self) -> u16 {
+        let change = self.timer_period >> self.sweep.shift;
+        if self.sweep.negate {
+            match self.channel {
+                PulseChannel::One => self.timer_period.wrapping_sub(change + 1),
+                PulseChannel::Two | PulseChannel::Mmc5 => self.timer_period.wrapping_sub(change),
+            }
+        } else {
+            self.timer_period + change
+        }
+    }
+
+    // The sweep unit silences the channel when the period is too small or the target overflows
+    fn muted(&self) -> bool {
+        if self.channel == PulseChannel::Mmc5 {
+            return false;
+        }
+        self.timer_period < 8 || self.sweep_target() > 0x7FF
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.muted()
+            || !self.length.active()
+            || DUTY_TABLE[self.duty as usize][self.duty_pos as usize] == 0
+        {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.duty);
+        w.u8(self.duty_pos);
+        w.u16(self.timer_period);
+        w.u16(self.timer);
+        self.envelope.save_state(w);
+        w.bool(self.sweep.enabled);
+        w.u8(self.sweep.period);
+        w.bool(self.sweep.negate);
+        w.u8(self.sweep.shift);
+        w.bool(self.sweep.reload);
+        w.u8(self.sweep.divider);
+        self.length.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.duty = r.u8()? & 0x3;
+        self.duty_pos = r.u8()? & 0x7;
+        self.timer_period = r.u16()?;
+        self.timer = r.u16()?;
+        self.envelope.load_state(r)?;
+        self.sweep.enabled = r.bool()?;
+        self.sweep.period = r.u8()?;
+        self.sweep.negate = r.bool()?;
+        self.sweep.shift = r.u8()? & 0x7;
+        self.sweep.reload = r.bool()?;
+        self.sweep.divider = r.u8()?;
+        self.length.load_state(r)
+    }
+}
+
+#[cfg(test)]
+mod pulse_tests {
+    use super::{Pulse, PulseChannel};
+
+    fn enabled_pulse(channel: PulseChannel) -> Pulse {
+        let mut p = Pulse::new(channel);
+        p.length.set_enabled(true);
+        p
+    }
+
+    #[test]
+    fn sweep_negate_complement() {
+        let mut p1 = enabled_pulse(PulseChannel::One);
+        let mut p2 = enabled_pulse(PulseChannel::Two);
+        for p in [&mut p1, &mut p2] {
+            // Enabled, period 0, negate, shift 1
+            p.write(1, 0x89);
+            p.write(2, 0x00);
+            p.write(3, 0x01);
+        }
+        assert_eq!(p1.sweep_target(), 0x100 - 0x80 - 1);
+        assert_eq!(p2.sweep_target(), 0x100 - 0x80);
+
+        p1.clock_half_frame();
+        p2.clock_half_frame();
+        assert_eq!(p1.timer_period, 0x7F);
+        assert_eq!(p2.timer_period, 0x80);
+    }
+
+    #[test]
+    fn sweep_mutes() {
+        let mut p = enabled_pulse(PulseChannel::Two);
+        // Constant volume 15, 50% duty
+        p.write(0, 0xBF);
+        p.write(2, 0xFF);
+        p.write(3, 0x07);
+        // Sweep disabled but target would overflow with shift 0
+        p.write(1, 0x00);
+        assert!(p.muted());
+        assert_eq!(p.output(), 0);
+
+        p.write(3, 0x01);
+        assert!(!p.muted());
+        // Periods below 8 are always muted
+        p.write(2, 0x07);
+        p.write(3, 0x00);
+        assert!(p.muted());
+    }
+
+    #[test]
+    fn duty_output() {
+        let mut p = enabled_pulse(PulseChannel::One);
+        // 25% duty, constant volume 9
+        p.write(0, 0x79);
+        p.write(2, 0x10);
+        p.write(3, 0x08);
+        let mut high = 0;
+        for _ in 0..8 {
+            if p.output() == 9 {
+                high += 1;
+            }
+            for _ in 0..=0x10 {
+                p.clock_timer();
+            }
+        }
+        assert_eq!(high, 2);
+    }
+}
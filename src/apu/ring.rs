@@ -0,0 +1,77 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use super::sink::AudioSink;
+
+// Bounded sample queue shared between the emulator (producer) and the audio device (consumer).
+// Clones share the same buffer.
+#[derive(Debug, Clone)]
+pub struct AudioRing {
+    buf: Arc<Mutex<VecDeque<f32>>>,
+    capacity: usize,
+}
+
+impl AudioRing {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buf: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.buf.lock().unwrap().len()
+    }
+
+    // How full the buffer is, from 0.0 (empty) to 1.0 (full)
+    pub fn fill(&self) -> f64 {
+        self.len() as f64 / self.capacity as f64
+    }
+
+    // Fills `out` with queued samples, returns how many there were.
+    // Never blocks on the producer: the rest of `out` is filled with silence.
+    pub fn pop_into(&self, out: &mut [f32]) -> usize {
+        let mut buf = self.buf.lock().unwrap();
+        let n = out.len().min(buf.len());
+        for (o, s) in out.iter_mut().zip(buf.drain(..n)) {
+            *o = s;
+        }
+        out[n..].fill(0.0);
+        n
+    }
+}
+
+impl AudioSink for AudioRing {
+    fn push_samples(&mut self, samples: &[f32]) {
+        let mut buf = self.buf.lock().unwrap();
+        buf.extend(samples);
+        // Overflowing means the consumer fell behind, drop the oldest samples
+        if buf.len() > self.capacity {
+            let excess = buf.len() - self.capacity;
+            buf.drain(..excess);
+        }
+    }
+}
+
+#[cfg(test)]
+mod ring_tests {
+    use super::AudioRing;
+    use crate::apu::sink::AudioSink;
+
+    #[test]
+    fn push_and_pop() {
+        let mut ring = AudioRing::new(8);
+        let mut producer = ring.clone();
+        producer.push_samples(&[1.0, 2.0, 3.0]);
+        assert_eq!(ring.len(), 3);
+
+        let mut out = [9.0; 5];
+        assert_eq!(ring.pop_into(&mut out), 3);
+        // Underrun is padded with silence
+        assert_eq!(out, [1.0, 2.0, 3.0, 0.0, 0.0]);
+
+        ring.push_samples(&[0.5; 10]);
+        assert_eq!(ring.len(), 8);
+        assert_eq!(ring.fill(), 1.0);
+    }
+}
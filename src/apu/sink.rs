@@ -0,0 +1,18 @@
+use std::collections::VecDeque;
+
+// Destination for the APU's output samples, mono f32 at the rate the APU was created with
+pub trait AudioSink {
+    fn push_samples(&mut self, samples: &[f32]);
+}
+
+impl AudioSink for Vec<f32> {
+    fn push_samples(&mut self, samples: &[f32]) {
+        self.extend_from_slice(samples);
+    }
+}
+
+impl AudioSink for VecDeque<f32> {
+    fn push_samples(&mut self, samples: &[f32]) {
+        self.extend(samples);
+    }
+}
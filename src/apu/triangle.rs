@@ -0,0 +1,141 @@
+use super::length::LengthCounter;
+use crate::error::Result;
+use crate::state::{StateReader, StateWriter};
+
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+#[derive(Debug, Default)]
+pub struct Triangle {
+    // Doubles as the length counter halt flag
+    control: bool,
+    linear_reload_value: u8,
+    linear_reload: bool,
+    linear_counter: u8,
+    timer_period: u16,
+    timer: u16,
+    seq_pos: u8,
+    pub length: LengthCounter,
+}
+
+impl Triangle {
+    // reg is the register offset from $4008
+    pub fn write(&mut self, reg: u16, byte: u8) {
+        match reg & 0x3 {
+            0 => {
+                // CRRR RRRR
+                self.control = byte & 0x80 != 0;
+                self.length.set_halt(self.control);
+                self.linear_reload_value = byte & 0x7F;
+            }
+            1 => (),
+            2 => {
+                self.timer_period = (self.timer_period & 0x700) | (byte as u16);
+            }
+            3 => {
+                // LLLL LTTT
+                self.timer_period = (self.timer_period & 0xFF) | (((byte & 0x7) as u16) << 8);
+                self.length.load(byte >> 3);
+                self.linear_reload = true;
+            }
+            _ => panic!("impossible"),
+        }
+    }
+
+    // Unlike the other channels, the triangle's timer is clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            // Ultrasonic periods are used by games to silence the channel, not advancing
+            // the sequencer avoids the pop the resulting DC offset would cause.
+            if self.linear_counter > 0 && self.length.active() && self.timer_period >= 2 {
+                self.seq_pos = (self.seq_pos + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+    }
+
+    // The triangle keeps outputting its current step when silenced
+    pub fn output(&self) -> u8 {
+        SEQUENCE[self.seq_pos as usize]
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.control);
+        w.u8(self.linear_reload_value);
+        w.bool(self.linear_reload);
+        w.u8(self.linear_counter);
+        w.u16(self.timer_period);
+        w.u16(self.timer);
+        w.u8(self.seq_pos);
+        self.length.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.control = r.bool()?;
+        self.linear_reload_value = r.u8()?;
+        self.linear_reload = r.bool()?;
+        self.linear_counter = r.u8()?;
+        self.timer_period = r.u16()?;
+        self.timer = r.u16()?;
+        self.seq_pos = r.u8()? % 32;
+        self.length.load_state(r)
+    }
+}
+
+#[cfg(test)]
+mod triangle_tests {
+    use super::Triangle;
+
+    #[test]
+    fn linear_counter_gates_sequencer() {
+        let mut t = Triangle::default();
+        t.length.set_enabled(true);
+        // Linear counter of 1, control clear
+        t.write(0, 0x01);
+        t.write(2, 0x10);
+        t.write(3, 0x08);
+        assert_eq!(t.output(), 15);
+
+        // Linear counter hasn't been reloaded yet
+        for _ in 0..0x100 {
+            t.clock_timer();
+        }
+        assert_eq!(t.output(), 15);
+
+        t.clock_quarter_frame();
+        for _ in 0..=0x10 {
+            t.clock_timer();
+        }
+        assert_eq!(t.output(), 14);
+
+        // Counter runs out
+        t.clock_quarter_frame();
+        for _ in 0..0x100 {
+            t.clock_timer();
+        }
+        let out = t.output();
+        for _ in 0..0x100 {
+            t.clock_timer();
+        }
+        assert_eq!(t.output(), out);
+    }
+}
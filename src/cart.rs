@@ -3,4 +3,16 @@ pub mod mock;
 pub mod builder;
 mod mapper0;
 mod mapper1;
-mod mapper2;
\ No newline at end of file
+mod mapper2;
+mod mapper3;
+mod mapper4;
+mod mapper5;
+mod mapper7;
+mod mapper9;
+mod mapper19;
+mod mapper21;
+mod mapper24;
+mod mapper34;
+mod mapper66;
+mod mapper69;
+mod vrc_irq;
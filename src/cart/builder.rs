@@ -6,6 +6,17 @@ use super::cart::Cartridge;
 use super::mapper0::build_nrom_cart;
 use super::mapper1::build_mmc1_cart;
 use super::mapper2::build_uxrom;
+use super::mapper3::build_cnrom;
+use super::mapper4::build_mmc3_cart;
+use super::mapper5::build_mmc5_cart;
+use super::mapper7::build_axrom;
+use super::mapper9::{build_mmc2_cart, build_mmc4_cart};
+use super::mapper19::build_n163_cart;
+use super::mapper21::build_vrc4_cart;
+use super::mapper24::build_vrc6_cart;
+use super::mapper34::build_mapper34;
+use super::mapper66::build_gxrom;
+use super::mapper69::build_fme7_cart;
 
 
 pub fn build_cartridge(rom: &INesFile) -> Result<Cartridge> {
@@ -14,8 +25,58 @@ pub fn build_cartridge(rom: &INesFile) -> Result<Cartridge> {
     } else {
         match rom.header.mapper {
             0 => build_nrom_cart(&rom.prg_rom, &rom.chr_rom, rom.header.mirror_type),
-            1 => build_mmc1_cart(&rom.prg_rom, &rom.chr_rom),
+            1 => build_mmc1_cart(&rom.prg_rom, &rom.chr_rom, rom.header.battery_present),
             2 => build_uxrom(&rom.prg_rom, &rom.chr_rom, rom.header.mirror_type),
+            3 => build_cnrom(
+                &rom.prg_rom,
+                &rom.chr_rom,
+                rom.header.mirror_type,
+                rom.header.submapper,
+            ),
+            4 => build_mmc3_cart(
+                &rom.prg_rom,
+                &rom.chr_rom,
+                rom.header.mirror_type,
+                rom.header.four_screen,
+                rom.header.battery_present,
+            ),
+            5 => build_mmc5_cart(
+                &rom.prg_rom,
+                &rom.chr_rom,
+                rom.header.prg_ram_size as usize,
+                rom.header.prg_nvram_size as usize,
+                rom.header.battery_present,
+            ),
+            7 => build_axrom(&rom.prg_rom, &rom.chr_rom, rom.header.submapper),
+            9 => build_mmc2_cart(&rom.prg_rom, &rom.chr_rom, rom.header.mirror_type),
+            10 => build_mmc4_cart(
+                &rom.prg_rom,
+                &rom.chr_rom,
+                rom.header.mirror_type,
+                rom.header.battery_present,
+            ),
+            19 => build_n163_cart(&rom.prg_rom, &rom.chr_rom, rom.header.battery_present),
+            21 | 22 | 23 | 25 => build_vrc4_cart(
+                &rom.prg_rom,
+                &rom.chr_rom,
+                rom.header.mapper,
+                rom.header.submapper,
+                rom.header.battery_present,
+            ),
+            24 | 26 => build_vrc6_cart(
+                &rom.prg_rom,
+                &rom.chr_rom,
+                rom.header.mapper,
+                rom.header.battery_present,
+            ),
+            34 => build_mapper34(
+                &rom.prg_rom,
+                &rom.chr_rom,
+                rom.header.mirror_type,
+                rom.header.submapper,
+            ),
+            66 => build_gxrom(&rom.prg_rom, &rom.chr_rom, rom.header.mirror_type),
+            69 => build_fme7_cart(&rom.prg_rom, &rom.chr_rom, rom.header.battery_present),
             _ => Err("ROM uses an unsupported mapper".into())
         }
     }
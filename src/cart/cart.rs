@@ -4,6 +4,7 @@ use std::fmt;
 
 use crate::error::Result;
 use crate::ines::parse::MirrorType;
+use crate::state::{StateReader, StateWriter};
 
 pub enum PpuMemoryError {
     PpuReadOnly(u16),
@@ -35,12 +36,73 @@ pub fn ppu_inv_addr(addr: u16) -> Box<dyn Error> {
     Box::new(PpuMemoryError::PpuInvalidAddress(addr))
 }
 
+// What the PPU's rendering pipeline is fetching
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PpuFetch {
+    Nametable,
+    Attribute,
+    Background,
+    Sprite,
+}
+
 pub trait Cart {
     fn name(&self) -> String;
-    fn read(&mut self, addr: u16) -> Result<u8>;
+    // What a read would return, without any of its side effects (for debuggers)
+    fn peek(&self, addr: u16) -> Result<u8>;
+    fn ppu_peek(&self, addr: u16, vram: &[u8]) -> Result<u8>;
+    // Mappers that react to reads override these (e.g. MMC2 switching CHR banks when the PPU
+    // fetches certain tiles)
+    fn read(&mut self, addr: u16) -> Result<u8> {
+        self.peek(addr)
+    }
+    fn ppu_read(&mut self, addr: u16, vram: &[u8]) -> Result<u8> {
+        self.ppu_peek(addr, vram)
+    }
+    // Reads done while rendering. Mappers that bank sprites and the background separately or
+    // substitute their own nametable data (MMC5) override this
+    fn ppu_fetch(&mut self, addr: u16, _kind: PpuFetch, vram: &[u8]) -> Result<u8> {
+        self.ppu_read(addr, vram)
+    }
     fn write(&mut self, addr: u16, byte: u8) -> Result<()>;
-    fn ppu_read(&self, addr: u16, vram: &[u8]) -> Result<u8>;
     fn ppu_write(&mut self, addr: u16, byte: u8, vram: &mut [u8]) -> Result<()>; 
+
+    // Offset into PRG ROM that a CPU address is currently mapped to (None for RAM and
+    // registers), so debuggers can follow bank switching
+    fn prg_rom_offset(&self, _addr: u16) -> Option<usize> {
+        None
+    }
+    // Same for PPU addresses and CHR ROM (None for CHR RAM and nametables)
+    fn chr_rom_offset(&self, _addr: u16) -> Option<usize> {
+        None
+    }
+
+    // Called with every address the PPU puts on its bus while rendering (and for $2007 accesses),
+    // lets mappers watch for e.g. A12 edges
+    fn ppu_bus(&mut self, _addr: u16) {}
+    // CPU writes to the PPU's registers ($2000-$2007), the cartridge sees them on the bus too
+    fn ppu_register_write(&mut self, _addr: u16, _byte: u8) {}
+    // Called once per CPU cycle
+    fn cpu_cycle(&mut self) {}
+    // Whether the cartridge is holding the CPU's IRQ line
+    fn irq(&self) -> bool {
+        false
+    }
+
+    // Expansion audio, in the same units as the APU's output and added to it
+    fn audio_output(&self) -> f32 {
+        0.0
+    }
+
+    // Battery-backed PRG-RAM, None if the cartridge has no battery
+    fn nvram(&self) -> Option<&[u8]> {
+        None
+    }
+    // Restores what nvram() returned in an earlier session
+    fn load_nvram(&mut self, _data: &[u8]) {}
+
+    // Mapper registers and cartridge RAM for save states (ROM is not included)
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()>;
 }
 
 impl Debug for dyn Cart + Send {
@@ -3,6 +3,7 @@ use super::cart::{nametable_addr, ppu_inv_addr, ppu_rd_only, Cart, Cartridge};
 use crate::error::Result;
 use crate::ines::parse::MirrorType;
 use crate::mem::error::{inv_addr, rd_only};
+use crate::state::{StateReader, StateWriter};
 
 #[derive(Debug)]
 pub struct Nrom {
@@ -19,7 +20,7 @@ impl Cart for Nrom {
         "NROM".into()
     }
 
-    fn read(&mut self, addr: u16) -> Result<u8> {
+    fn peek(&self, addr: u16) -> Result<u8> {
         match addr {
             0x6000..=0x7FFF => Ok(self.prg_ram[((addr - 0x6000) as usize)]),
             0x8000..=0xFFFF => {
@@ -34,6 +35,15 @@ impl Cart for Nrom {
         }
     }
 
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        let mask = if self.num_prg_banks == 1 { 0x3FFF } else { 0x7FFF };
+        (addr >= 0x8000).then_some((addr & mask) as usize)
+    }
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr < 0x2000 && !self.chr_rom.is_empty()).then_some(addr as usize)
+    }
+
     fn write(&mut self, addr: u16, byte: u8) -> Result<()> {
         match addr {
             0x6000..=0x7FFF => Ok(self.prg_ram[((addr - 0x6000) as usize)] = byte),
@@ -42,7 +52,7 @@ impl Cart for Nrom {
         }
     }
 
-    fn ppu_read(&self, addr: u16, vram: &[u8]) -> Result<u8> {
+    fn ppu_peek(&self, addr: u16, vram: &[u8]) -> Result<u8> {
         match addr {
             0x0000..=0x1FFF => {
                 if self.chr_rom.len() == 0 {
@@ -69,6 +79,16 @@ impl Cart for Nrom {
             _ => Err(ppu_inv_addr(addr)),
         }
     }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.prg_ram);
+        w.bytes(&self.chr_ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        r.bytes_into(&mut self.prg_ram)?;
+        r.bytes_into(&mut self.chr_ram)
+    }
 }
 
 pub fn build_nrom_cart(prg_rom: &[u8], chr_rom: &[u8], mirroring: MirrorType) -> Result<Cartridge> {
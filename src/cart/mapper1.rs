@@ -3,6 +3,8 @@ use bitfield::bitfield;
 use crate::error::Result;
 use crate::{ines::parse::MirrorType, mem::error::inv_addr};
 
+use crate::state::{StateReader, StateWriter};
+
 use super::cart::{nametable_addr, ppu_inv_addr, ppu_rd_only, Cart, Cartridge};
 
 bitfield! {
@@ -32,6 +34,7 @@ pub struct Mmc1 {
     chr_rom_bank_0_base: usize,
     chr_rom_bank_1_base: usize,
     mirror_type: MirrorType,
+    battery: bool,
 }
 
 impl Mmc1 {
@@ -104,7 +107,7 @@ impl Cart for Mmc1 {
         "MMC1".into()
     }
 
-    fn read(&mut self, addr: u16) -> crate::error::Result<u8> {
+    fn peek(&self, addr: u16) -> crate::error::Result<u8> {
         match addr {
             0x6000..=0x7FFF => Ok(self.prg_ram[((addr - 0x6000) as usize)]),
             0x8000..=0xFFFF => Ok(self.prg_rom[self.map_cpu_addr(addr)]),
@@ -112,6 +115,14 @@ impl Cart for Mmc1 {
         }
     }
 
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr >= 0x8000).then(|| self.map_cpu_addr(addr))
+    }
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr < 0x2000 && !self.chr_rom.is_empty()).then(|| self.map_chr_addr(addr))
+    }
+
     fn write(&mut self, addr: u16, byte: u8) -> crate::error::Result<()> {
         match addr {
             0x6000..=0x7FFF => Ok(self.prg_ram[((addr - 0x6000) as usize)] = byte),
@@ -144,7 +155,7 @@ impl Cart for Mmc1 {
         }
     }
 
-    fn ppu_read(&self, addr: u16, vram: &[u8]) -> Result<u8> {
+    fn ppu_peek(&self, addr: u16, vram: &[u8]) -> Result<u8> {
         match addr {
             0x0000..=0x1FFF => {
                 if self.chr_rom.len() == 0 {
@@ -174,9 +185,43 @@ impl Cart for Mmc1 {
             _ => Err(ppu_inv_addr(addr)),
         }
     }
+
+    fn nvram(&self) -> Option<&[u8]> {
+        self.battery.then_some(&self.prg_ram[..])
+    }
+
+    fn load_nvram(&mut self, data: &[u8]) {
+        let len = data.len().min(self.prg_ram.len());
+        self.prg_ram[..len].copy_from_slice(&data[..len]);
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.prg_ram);
+        w.bytes(&self.chr_ram);
+        w.u8(self.shift_reg);
+        w.u8(self.control.0);
+        w.u8(self.chr_bank0);
+        w.u8(self.chr_bank1);
+        w.u8(self.prg_bank);
+        w.u8(self.write_count);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        r.bytes_into(&mut self.prg_ram)?;
+        r.bytes_into(&mut self.chr_ram)?;
+        self.shift_reg = r.u8()?;
+        self.control = ControlReg(r.u8()?);
+        self.chr_bank0 = r.u8()?;
+        self.chr_bank1 = r.u8()?;
+        self.prg_bank = r.u8()?;
+        self.write_count = r.u8()?;
+        // The bases are derived from the registers
+        self.update_base_addr();
+        Ok(())
+    }
 }
 
-pub fn build_mmc1_cart(prg_rom: &[u8], chr_rom: &[u8]) -> Result<Cartridge> {
+pub fn build_mmc1_cart(prg_rom: &[u8], chr_rom: &[u8], battery: bool) -> Result<Cartridge> {
     let mut cart = Mmc1 {
         prg_ram: [0; 8 * 1024],
         prg_rom: prg_rom.to_vec(),
@@ -193,6 +238,7 @@ pub fn build_mmc1_cart(prg_rom: &[u8], chr_rom: &[u8]) -> Result<Cartridge> {
         chr_rom_bank_0_base: 0,
         chr_rom_bank_1_base: 0,
         mirror_type: MirrorType::OneScreenLow,
+        battery,
     };
     cart.update_base_addr();
     Ok(Box::new(cart))
@@ -0,0 +1,432 @@
+use crate::error::Result;
+use crate::mem::error::inv_addr;
+use crate::state::{StateReader, StateWriter};
+
+use super::cart::{ppu_inv_addr, ppu_rd_only, Cart, Cartridge};
+
+const PRG_BANK_SIZE: usize = 8 * 1024;
+const CHR_BANK_SIZE: usize = 1024;
+
+// One wavetable channel is updated every 15 CPU cycles
+const CHANNEL_CYCLES: u8 = 15;
+
+// Namco 163, mapper 19. 1K CHR banks that can also point at the console's nametable RAM, a
+// 15-bit CPU cycle IRQ counter, and up to 8 wavetable channels that play 4-bit samples out of
+// 128 bytes of internal RAM (which also holds the channel registers).
+// https://www.nesdev.org/wiki/Namco_163
+// https://www.nesdev.org/wiki/Namco_163_audio
+#[derive(Debug)]
+pub struct Namco163 {
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+    prg_ram: [u8; 8 * 1024],
+    internal_ram: [u8; 128],
+    // $F800: bit 7 auto increment, bits 0-6 internal RAM address
+    ram_addr: u8,
+    prg_banks: [u8; 3],
+    // 8 pattern table banks then the 4 nametables, $E0 and up picks a nametable RAM page
+    chr_banks: [u8; 12],
+    // $E800 bits 6 and 7, set when the pattern table halves can't use nametable RAM
+    ciram_disabled: [bool; 2],
+    sound_disabled: bool,
+    counter: u16,
+    irq_enabled: bool,
+    irq_pending: bool,
+    // Channel being updated and the last output of each
+    channel: u8,
+    channel_cycles: u8,
+    outputs: [i16; 8],
+    battery: bool,
+}
+
+// Where a 1K CHR bank points
+enum ChrBank {
+    Rom(usize),
+    Ciram(usize),
+}
+
+impl Namco163 {
+    fn map_cpu_addr(&self, addr: u16) -> usize {
+        let banks = self.prg_rom.len() / PRG_BANK_SIZE;
+        let bank = match addr {
+            0x8000..=0xDFFF => self.prg_banks[(addr - 0x8000) as usize / PRG_BANK_SIZE] as usize,
+            _ => banks - 1,
+        };
+        (bank % banks) * PRG_BANK_SIZE + addr as usize % PRG_BANK_SIZE
+    }
+
+    fn map_ppu_addr(&self, addr: u16) -> ChrBank {
+        // $3000-$3EFF mirrors the nametables
+        let addr = if addr >= 0x3000 { addr - 0x1000 } else { addr };
+        let slot = addr as usize / CHR_BANK_SIZE;
+        let bank = self.chr_banks[slot];
+        let offset = addr as usize % CHR_BANK_SIZE;
+        let ciram_allowed = slot >= 8 || !self.ciram_disabled[slot / 4];
+        if bank >= 0xE0 && ciram_allowed {
+            ChrBank::Ciram((bank as usize & 1) * CHR_BANK_SIZE + offset)
+        } else {
+            ChrBank::Rom((bank as usize * CHR_BANK_SIZE + offset) % self.chr_rom.len())
+        }
+    }
+
+    fn channel_count(&self) -> u8 {
+        ((self.internal_ram[0x7F] >> 4) & 0x7) + 1
+    }
+
+    // Channels are updated from 7 down, as many as $7F enables
+    fn update_channel(&mut self) {
+        let count = self.channel_count();
+        self.channel = if self.channel <= 8 - count {
+            7
+        } else {
+            self.channel - 1
+        };
+
+        let base = 0x40 + self.channel as usize * 8;
+        let regs = &self.internal_ram[base..base + 8];
+        let freq = regs[0] as u32 | ((regs[2] as u32) << 8) | ((regs[4] as u32 & 0x3) << 16);
+        let phase = regs[1] as u32 | ((regs[3] as u32) << 8) | ((regs[5] as u32) << 16);
+        let length = 256 - (regs[4] & 0xFC) as u32;
+        let wave_addr = regs[6] as u32;
+        let volume = (regs[7] & 0xF) as i16;
+
+        let phase = (phase + freq) % (length << 16);
+        let sample_addr = ((wave_addr + (phase >> 16)) & 0xFF) as usize;
+        let byte = self.internal_ram[sample_addr / 2];
+        let sample = if sample_addr.is_multiple_of(2) {
+            byte & 0xF
+        } else {
+            byte >> 4
+        };
+        self.outputs[self.channel as usize] = (sample as i16 - 8) * volume;
+
+        self.internal_ram[base + 1] = phase as u8;
+        self.internal_ram[base + 3] = (phase >> 8) as u8;
+        self.internal_ram[base + 5] = (phase >> 16) as u8;
+    }
+
+    fn read_internal_ram(&mut self) -> u8 {
+        let byte = self.internal_ram[(self.ram_addr & 0x7F) as usize];
+        self.increment_ram_addr();
+        byte
+    }
+
+    fn increment_ram_addr(&mut self) {
+        if self.ram_addr & 0x80 != 0 {
+            self.ram_addr = 0x80 | (self.ram_addr.wrapping_add(1) & 0x7F);
+        }
+    }
+}
+
+impl Cart for Namco163 {
+    fn name(&self) -> String {
+        "Namco 163".into()
+    }
+
+    fn peek(&self, addr: u16) -> Result<u8> {
+        match addr {
+            0x4800..=0x4FFF => Ok(self.internal_ram[(self.ram_addr & 0x7F) as usize]),
+            0x5000..=0x57FF => Ok(self.counter as u8),
+            0x5800..=0x5FFF => Ok(((self.counter >> 8) as u8) | ((self.irq_enabled as u8) << 7)),
+            0x6000..=0x7FFF => Ok(self.prg_ram[(addr - 0x6000) as usize]),
+            0x8000..=0xFFFF => Ok(self.prg_rom[self.map_cpu_addr(addr)]),
+            _ => Err(inv_addr(addr)),
+        }
+    }
+
+    fn read(&mut self, addr: u16) -> Result<u8> {
+        match addr {
+            0x4800..=0x4FFF => Ok(self.read_internal_ram()),
+            _ => self.peek(addr),
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr >= 0x8000).then(|| self.map_cpu_addr(addr))
+    }
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        match self.map_ppu_addr(addr) {
+            ChrBank::Rom(offset) if addr < 0x2000 => Some(offset),
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) -> Result<()> {
+        match addr {
+            0x4800..=0x4FFF => {
+                self.internal_ram[(self.ram_addr & 0x7F) as usize] = byte;
+                self.increment_ram_addr();
+            }
+            0x5000..=0x57FF => {
+                self.counter = (self.counter & 0x7F00) | byte as u16;
+                self.irq_pending = false;
+            }
+            0x5800..=0x5FFF => {
+                self.counter = (self.counter & 0x00FF) | (((byte & 0x7F) as u16) << 8);
+                self.irq_enabled = byte & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            // Write protection through $F800 isn't emulated
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize] = byte,
+            0x8000..=0xDFFF => self.chr_banks[(addr - 0x8000) as usize / 0x800] = byte,
+            0xE000..=0xE7FF => {
+                self.prg_banks[0] = byte & 0x3F;
+                self.sound_disabled = byte & 0x40 != 0;
+            }
+            0xE800..=0xEFFF => {
+                self.prg_banks[1] = byte & 0x3F;
+                self.ciram_disabled = [byte & 0x40 != 0, byte & 0x80 != 0];
+            }
+            0xF000..=0xF7FF => self.prg_banks[2] = byte & 0x3F,
+            0xF800..=0xFFFF => self.ram_addr = byte,
+            _ => return Err(inv_addr(addr)),
+        }
+        Ok(())
+    }
+
+    fn ppu_peek(&self, addr: u16, vram: &[u8]) -> Result<u8> {
+        match addr {
+            0x0000..=0x3EFF => match self.map_ppu_addr(addr) {
+                ChrBank::Rom(offset) => Ok(self.chr_rom[offset]),
+                ChrBank::Ciram(offset) => Ok(vram[offset]),
+            },
+            _ => Err(ppu_inv_addr(addr)),
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, byte: u8, vram: &mut [u8]) -> Result<()> {
+        match addr {
+            0x0000..=0x3EFF => match self.map_ppu_addr(addr) {
+                ChrBank::Rom(_) => Err(ppu_rd_only(addr)),
+                ChrBank::Ciram(offset) => {
+                    vram[offset] = byte;
+                    Ok(())
+                }
+            },
+            _ => Err(ppu_inv_addr(addr)),
+        }
+    }
+
+    fn cpu_cycle(&mut self) {
+        if self.irq_enabled && self.counter < 0x7FFF {
+            self.counter += 1;
+            if self.counter == 0x7FFF {
+                self.irq_pending = true;
+            }
+        }
+        if !self.sound_disabled {
+            self.channel_cycles += 1;
+            if self.channel_cycles >= CHANNEL_CYCLES {
+                self.channel_cycles = 0;
+                self.update_channel();
+            }
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    // The chip plays the channels one after another, averaging them out comes close to what
+    // the filtering on the cartridge does. A full volume wave is about as loud as an APU pulse.
+    fn audio_output(&self) -> f32 {
+        if self.sound_disabled {
+            return 0.0;
+        }
+        let count = self.channel_count();
+        let sum: i16 = self.outputs[(8 - count) as usize..].iter().sum();
+        sum as f32 / count as f32 * 0.00125
+    }
+
+    fn nvram(&self) -> Option<&[u8]> {
+        self.battery.then_some(&self.prg_ram[..])
+    }
+
+    fn load_nvram(&mut self, data: &[u8]) {
+        let len = data.len().min(self.prg_ram.len());
+        self.prg_ram[..len].copy_from_slice(&data[..len]);
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.prg_ram);
+        w.bytes(&self.internal_ram);
+        w.u8(self.ram_addr);
+        w.bytes(&self.prg_banks);
+        w.bytes(&self.chr_banks);
+        w.bool(self.ciram_disabled[0]);
+        w.bool(self.ciram_disabled[1]);
+        w.bool(self.sound_disabled);
+        w.u16(self.counter);
+        w.bool(self.irq_enabled);
+        w.bool(self.irq_pending);
+        w.u8(self.channel);
+        w.u8(self.channel_cycles);
+        for output in self.outputs {
+            w.u16(output as u16);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        r.bytes_into(&mut self.prg_ram)?;
+        r.bytes_into(&mut self.internal_ram)?;
+        self.ram_addr = r.u8()?;
+        r.bytes_into(&mut self.prg_banks)?;
+        r.bytes_into(&mut self.chr_banks)?;
+        self.ciram_disabled = [r.bool()?, r.bool()?];
+        self.sound_disabled = r.bool()?;
+        self.counter = r.u16()? & 0x7FFF;
+        self.irq_enabled = r.bool()?;
+        self.irq_pending = r.bool()?;
+        self.channel = r.u8()? & 0x7;
+        self.channel_cycles = r.u8()?;
+        for output in self.outputs.iter_mut() {
+            *output = r.u16()? as i16;
+        }
+        Ok(())
+    }
+}
+
+pub fn build_n163_cart(prg_rom: &[u8], chr_rom: &[u8], battery: bool) -> Result<Cartridge> {
+    if prg_rom.len() < PRG_BANK_SIZE || !prg_rom.len().is_multiple_of(PRG_BANK_SIZE) {
+        return Err("Namco 163 PRG ROM size must be a multiple of 8K".into());
+    }
+    if chr_rom.is_empty() {
+        return Err("Namco 163 boards need CHR ROM".into());
+    }
+    Ok(Box::new(Namco163 {
+        prg_rom: prg_rom.to_vec(),
+        chr_rom: chr_rom.to_vec(),
+        prg_ram: [0; 8 * 1024],
+        internal_ram: [0; 128],
+        ram_addr: 0,
+        prg_banks: [0, 1, 2],
+        chr_banks: [0, 1, 2, 3, 4, 5, 6, 7, 0xE0, 0xE1, 0xE0, 0xE1],
+        ciram_disabled: [false; 2],
+        sound_disabled: false,
+        counter: 0,
+        irq_enabled: false,
+        irq_pending: false,
+        channel: 7,
+        channel_cycles: 0,
+        outputs: [0; 8],
+        battery,
+    }))
+}
+
+#[cfg(test)]
+mod n163_tests {
+    use super::build_n163_cart;
+    use crate::cart::mock::numbered_roms;
+
+    #[test]
+    fn banking() {
+        let (prg, chr) = numbered_roms(16, 64);
+        let mut vram = [0; 2048];
+        let mut cart = build_n163_cart(&prg, &chr, true).unwrap();
+        cart.write(0xE000, 3).unwrap();
+        cart.write(0xE800, 4).unwrap();
+        cart.write(0xF000, 5).unwrap();
+        assert_eq!(cart.read(0x8000).unwrap(), 3);
+        assert_eq!(cart.read(0xA000).unwrap(), 4);
+        assert_eq!(cart.read(0xC000).unwrap(), 5);
+        assert_eq!(cart.read(0xE000).unwrap(), 15);
+        assert_eq!(cart.prg_rom_offset(0xC001), Some(5 * 8 * 1024 + 1));
+
+        cart.write(0xB800, 0x2A).unwrap();
+        assert_eq!(cart.ppu_read(0x1C00, &vram).unwrap(), 0x2A);
+        assert_eq!(cart.chr_rom_offset(0x1C01), Some(0x2A * 1024 + 1));
+
+        // Pattern table bank pointing at nametable RAM, unless $E800 bit 7 turns that off
+        cart.write(0xB800, 0xE1).unwrap();
+        cart.ppu_write(0x1C10, 0x42, &mut vram).unwrap();
+        assert_eq!(vram[0x410], 0x42);
+        assert_eq!(cart.chr_rom_offset(0x1C10), None);
+        cart.write(0xE800, 0x84).unwrap();
+        assert_eq!(cart.ppu_read(0x1C10, &vram).unwrap(), 0xE1 % 64);
+
+        // Nametables: horizontal mirroring out of nametable RAM, then one from CHR ROM
+        for (addr, bank) in [
+            (0xC000, 0xE0),
+            (0xC800, 0xE0),
+            (0xD000, 0xE1),
+            (0xD800, 0xE1),
+        ] {
+            cart.write(addr, bank).unwrap();
+        }
+        cart.ppu_write(0x2400, 0x11, &mut vram).unwrap();
+        assert_eq!(vram[0], 0x11);
+        assert_eq!(cart.ppu_read(0x2C10, &vram).unwrap(), 0x42);
+        cart.write(0xD800, 0x07).unwrap();
+        assert_eq!(cart.ppu_read(0x2C00, &vram).unwrap(), 0x07);
+        assert!(cart.ppu_write(0x2C00, 0, &mut vram).is_err());
+
+        cart.write(0x6000, 0x12).unwrap();
+        assert_eq!(cart.nvram().unwrap()[0], 0x12);
+    }
+
+    #[test]
+    fn internal_ram() {
+        let (prg, chr) = numbered_roms(16, 64);
+        let mut cart = build_n163_cart(&prg, &chr, false).unwrap();
+        // Auto increment, wrapping at $7F
+        cart.write(0xF800, 0xFE).unwrap();
+        for byte in [1, 2, 3] {
+            cart.write(0x4800, byte).unwrap();
+        }
+        cart.write(0xF800, 0xFE).unwrap();
+        assert_eq!(cart.read(0x4800).unwrap(), 1);
+        assert_eq!(cart.read(0x4800).unwrap(), 2);
+        assert_eq!(cart.read(0x4800).unwrap(), 3);
+        // Without it the address stays put
+        cart.write(0xF800, 0x7E).unwrap();
+        assert_eq!(cart.read(0x4800).unwrap(), 1);
+        assert_eq!(cart.read(0x4800).unwrap(), 1);
+    }
+
+    #[test]
+    fn irq() {
+        let (prg, chr) = numbered_roms(16, 64);
+        let mut cart = build_n163_cart(&prg, &chr, false).unwrap();
+        cart.write(0x5000, 0xFD).unwrap();
+        cart.write(0x5800, 0xFF).unwrap();
+        cart.cpu_cycle();
+        assert!(!cart.irq());
+        cart.cpu_cycle();
+        assert!(cart.irq());
+        // Stops counting at $7FFF
+        cart.cpu_cycle();
+        assert_eq!(cart.read(0x5000).unwrap(), 0xFF);
+        assert_eq!(cart.read(0x5800).unwrap(), 0xFF);
+        cart.write(0x5800, 0x00).unwrap();
+        assert!(!cart.irq());
+    }
+
+    #[test]
+    fn audio() {
+        let (prg, chr) = numbered_roms(16, 64);
+        let mut cart = build_n163_cart(&prg, &chr, false).unwrap();
+        // A 4 sample wave at the start of RAM: 15, 15, 0, 0
+        cart.write(0xF800, 0x80).unwrap();
+        cart.write(0x4800, 0xFF).unwrap();
+        cart.write(0x4800, 0x00).unwrap();
+        // Channel 7 (the only one enabled), stepping one sample per update, volume 15
+        cart.write(0xF800, 0xF8).unwrap();
+        for byte in [0x00, 0x00, 0x00, 0x00, 0xFD, 0x00, 0x00, 0x0F] {
+            cart.write(0x4800, byte).unwrap();
+        }
+
+        let mut levels = vec![];
+        for _ in 0..4 {
+            for _ in 0..15 {
+                cart.cpu_cycle();
+            }
+            levels.push((cart.audio_output() / 0.00125).round() as i16);
+        }
+        assert_eq!(levels, [15 * 7, -15 * 8, -15 * 8, 15 * 7]);
+
+        // Sound disabled
+        cart.write(0xE000, 0x40).unwrap();
+        assert_eq!(cart.audio_output(), 0.0);
+    }
+}
@@ -3,6 +3,7 @@ use super::cart::{Cartridge, ppu_inv_addr, nametable_addr, ppu_rd_only};
 use crate::ines::parse::MirrorType;
 use crate::error::Result;
 use crate::mem::error::{rd_only, inv_addr};
+use crate::state::{StateReader, StateWriter};
 
 use super::cart::Cart;
 
@@ -21,24 +22,27 @@ impl Cart for Uxrom {
         "UxROM".into()
     }
 
-    fn read(&mut self, addr: u16) -> Result<u8> {
-        match addr {
-            0x6000..=0x7FFF => Err(inv_addr(addr)),
-            0x8000..=0xBFFF => {
-                let base = (self.bank_select as usize) * (16*1024);
-                let offset =  (addr & 0x3FFF) as usize;
-                Ok(self.prg_rom[base + offset]) 
-            },
-            0xC000..=0xFFFF => {
-                // Hardwired to last bank
-                let base = self.prg_rom.len() - (16 * 1024);
-                let offset = (addr & 0x3FFF) as usize;
-                Ok(self.prg_rom[base + offset])
-            }
-            _ => Err(inv_addr(addr)),
+    fn peek(&self, addr: u16) -> Result<u8> {
+        match self.prg_rom_offset(addr) {
+            Some(offset) => Ok(self.prg_rom[offset]),
+            None => Err(inv_addr(addr)),
         }
     }
 
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        let base = match addr {
+            0x8000..=0xBFFF => (self.bank_select as usize) * (16*1024),
+            // Hardwired to last bank
+            0xC000..=0xFFFF => self.prg_rom.len() - (16 * 1024),
+            _ => return None,
+        };
+        Some(base + (addr & 0x3FFF) as usize)
+    }
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr < 0x2000 && !self.chr_rom.is_empty()).then_some(addr as usize)
+    }
+
     fn write(&mut self, addr: u16, byte: u8) -> Result<()> {
         match addr {
             0x6000..=0x7FFF => Err(inv_addr(addr)),
@@ -50,7 +54,7 @@ impl Cart for Uxrom {
         }
     }
 
-    fn ppu_read(&self, addr: u16, vram: &[u8]) -> Result<u8> {
+    fn ppu_peek(&self, addr: u16, vram: &[u8]) -> Result<u8> {
         match addr {
             0x0000..=0x1FFF => {
                 if self.chr_rom.len() == 0 {
@@ -77,6 +81,17 @@ impl Cart for Uxrom {
             _ => Err(ppu_inv_addr(addr)),
         }
     }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.chr_ram);
+        w.u8(self.bank_select);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        r.bytes_into(&mut self.chr_ram)?;
+        self.bank_select = r.u8()?;
+        Ok(())
+    }
 }
 
 pub fn build_uxrom(prg_rom: &[u8], chr_rom: &[u8], mirror_type: MirrorType) -> Result<Cartridge> {
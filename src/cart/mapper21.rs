@@ -0,0 +1,358 @@
+use crate::error::Result;
+use crate::state::{StateReader, StateWriter};
+use crate::{ines::parse::MirrorType, mem::error::inv_addr};
+
+use super::cart::{nametable_addr, ppu_inv_addr, ppu_rd_only, Cart, Cartridge};
+use super::vrc_irq::VrcIrq;
+
+const PRG_BANK_SIZE: usize = 8 * 1024;
+const CHR_BANK_SIZE: usize = 1024;
+
+// Konami VRC2 and VRC4, mappers 21, 22, 23 and 25. Every board connects the chips' two register
+// select pins to different CPU address lines, the submapper says which.
+// https://www.nesdev.org/wiki/VRC2_and_VRC4
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Variant {
+    Vrc2,
+    Vrc4,
+}
+
+#[derive(Debug)]
+pub struct Vrc4 {
+    variant: Variant,
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+    prg_ram: [u8; 8 * 1024],
+    // CPU address lines wired to register select bit 0 and bit 1. Boards without a submapper
+    // get both of the possible lines, games only ever set one of them.
+    pins: (u16, u16),
+    // VRC2a drops the lowest bit of the CHR banks
+    chr_shift: u8,
+    prg_banks: [u8; 2],
+    prg_swap: bool,
+    chr_banks: [u16; 8],
+    mirror_type: MirrorType,
+    // VRC2 boards without PRG RAM have a single bit at $6000-$6FFF
+    latch: u8,
+    irq: VrcIrq,
+    battery: bool,
+}
+
+impl Vrc4 {
+    // $x000-$x003 with the register select pins sorted out
+    fn register(&self, addr: u16) -> u16 {
+        let bit0 = (addr & self.pins.0 != 0) as u16;
+        let bit1 = (addr & self.pins.1 != 0) as u16;
+        (addr & 0xF000) | (bit1 << 1) | bit0
+    }
+
+    fn map_cpu_addr(&self, addr: u16) -> usize {
+        let banks = self.prg_rom.len() / PRG_BANK_SIZE;
+        let bank = match (addr - 0x8000) as usize / PRG_BANK_SIZE {
+            0 if self.prg_swap => banks - 2,
+            0 => self.prg_banks[0] as usize,
+            1 => self.prg_banks[1] as usize,
+            2 if self.prg_swap => self.prg_banks[0] as usize,
+            2 => banks - 2,
+            _ => banks - 1,
+        };
+        (bank % banks) * PRG_BANK_SIZE + addr as usize % PRG_BANK_SIZE
+    }
+
+    fn map_chr_addr(&self, addr: u16) -> usize {
+        let bank = (self.chr_banks[addr as usize / CHR_BANK_SIZE] >> self.chr_shift) as usize;
+        (bank * CHR_BANK_SIZE + addr as usize % CHR_BANK_SIZE) % self.chr_rom.len()
+    }
+}
+
+impl Cart for Vrc4 {
+    fn name(&self) -> String {
+        match self.variant {
+            Variant::Vrc2 => "VRC2".into(),
+            Variant::Vrc4 => "VRC4".into(),
+        }
+    }
+
+    fn peek(&self, addr: u16) -> Result<u8> {
+        match addr {
+            0x6000..=0x6FFF if self.variant == Variant::Vrc2 => Ok(self.latch),
+            0x6000..=0x7FFF if self.variant == Variant::Vrc4 => {
+                Ok(self.prg_ram[(addr - 0x6000) as usize])
+            }
+            // Open bus
+            0x6000..=0x7FFF => Ok(0),
+            0x8000..=0xFFFF => Ok(self.prg_rom[self.map_cpu_addr(addr)]),
+            _ => Err(inv_addr(addr)),
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr >= 0x8000).then(|| self.map_cpu_addr(addr))
+    }
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr < 0x2000).then(|| self.map_chr_addr(addr))
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) -> Result<()> {
+        if addr < 0x8000 {
+            match (addr, self.variant) {
+                (0x6000..=0x6FFF, Variant::Vrc2) => self.latch = byte & 1,
+                (0x6000..=0x7FFF, Variant::Vrc4) => self.prg_ram[(addr - 0x6000) as usize] = byte,
+                (0x6000..=0x7FFF, _) => {}
+                _ => return Err(inv_addr(addr)),
+            }
+            return Ok(());
+        }
+        let vrc4 = self.variant == Variant::Vrc4;
+        match self.register(addr) {
+            0x8000..=0x8003 => self.prg_banks[0] = byte & 0x1F,
+            0x9000..=0x9003 if !vrc4 => {
+                self.mirror_type = match byte & 1 {
+                    0 => MirrorType::Vertical,
+                    _ => MirrorType::Horizontal,
+                }
+            }
+            0x9000 => {
+                self.mirror_type = match byte & 3 {
+                    0 => MirrorType::Vertical,
+                    1 => MirrorType::Horizontal,
+                    2 => MirrorType::OneScreenLow,
+                    _ => MirrorType::OneScreenHigh,
+                }
+            }
+            // Bit 0 is the PRG RAM enable, the RAM is left on
+            0x9002 => self.prg_swap = byte & 0x2 != 0,
+            0xA000..=0xA003 => self.prg_banks[1] = byte & 0x1F,
+            reg @ 0xB000..=0xE003 => {
+                // Two registers per 1K bank, the low 4 bits and the high 5 bits
+                let bank = ((reg - 0xB000) >> 12) as usize * 2 + (reg & 0x2 != 0) as usize;
+                let value = &mut self.chr_banks[bank];
+                *value = if reg & 1 == 0 {
+                    (*value & 0x1F0) | (byte & 0x0F) as u16
+                } else {
+                    (*value & 0x0F) | (((byte & 0x1F) as u16) << 4)
+                };
+            }
+            0xF000 if vrc4 => self.irq.write_latch_low(byte),
+            0xF001 if vrc4 => self.irq.write_latch_high(byte),
+            0xF002 if vrc4 => self.irq.write_control(byte),
+            0xF003 if vrc4 => self.irq.acknowledge(),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn ppu_peek(&self, addr: u16, vram: &[u8]) -> Result<u8> {
+        match addr {
+            0x0000..=0x1FFF => Ok(self.chr_rom[self.map_chr_addr(addr)]),
+            0x2000..=0x3EFF => Ok(vram[nametable_addr(addr, self.mirror_type) as usize]),
+            _ => Err(ppu_inv_addr(addr)),
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, byte: u8, vram: &mut [u8]) -> Result<()> {
+        match addr {
+            0x0000..=0x1FFF => Err(ppu_rd_only(addr)),
+            0x2000..=0x3EFF => {
+                vram[nametable_addr(addr, self.mirror_type) as usize] = byte;
+                Ok(())
+            }
+            _ => Err(ppu_inv_addr(addr)),
+        }
+    }
+
+    fn cpu_cycle(&mut self) {
+        self.irq.clock();
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn nvram(&self) -> Option<&[u8]> {
+        self.battery.then_some(&self.prg_ram[..])
+    }
+
+    fn load_nvram(&mut self, data: &[u8]) {
+        let len = data.len().min(self.prg_ram.len());
+        self.prg_ram[..len].copy_from_slice(&data[..len]);
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.prg_ram);
+        w.bytes(&self.prg_banks);
+        w.bool(self.prg_swap);
+        for bank in self.chr_banks {
+            w.u16(bank);
+        }
+        w.u8(self.mirror_type as u8);
+        w.u8(self.latch);
+        self.irq.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        r.bytes_into(&mut self.prg_ram)?;
+        r.bytes_into(&mut self.prg_banks)?;
+        self.prg_swap = r.bool()?;
+        for bank in self.chr_banks.iter_mut() {
+            *bank = r.u16()?;
+        }
+        self.mirror_type =
+            MirrorType::try_from(r.u8()?).map_err(|_| "Invalid mirroring in save state")?;
+        self.latch = r.u8()?;
+        self.irq.load_state(r)
+    }
+}
+
+// Address lines for register select bits 0 and 1, from the board list on the wiki
+fn pins(mapper: u16, submapper: u8) -> Result<(u16, u16)> {
+    const A: [u16; 8] = [1 << 0, 1 << 1, 1 << 2, 1 << 3, 1 << 4, 1 << 5, 1 << 6, 1 << 7];
+    Ok(match (mapper, submapper) {
+        (21, 1) => (A[1], A[2]),
+        (21, 2) => (A[6], A[7]),
+        (21, _) => (A[1] | A[6], A[2] | A[7]),
+        (22, _) => (A[1], A[0]),
+        (23, 1 | 3) => (A[0], A[1]),
+        (23, 2) => (A[2], A[3]),
+        (23, _) => (A[0] | A[2], A[1] | A[3]),
+        (25, 1 | 3) => (A[1], A[0]),
+        (25, 2) => (A[3], A[2]),
+        (25, _) => (A[1] | A[3], A[0] | A[2]),
+        _ => return Err(format!("Mapper {mapper} isn't a VRC2/VRC4 board").into()),
+    })
+}
+
+pub fn build_vrc4_cart(
+    prg_rom: &[u8],
+    chr_rom: &[u8],
+    mapper: u16,
+    submapper: u8,
+    battery: bool,
+) -> Result<Cartridge> {
+    if prg_rom.len() < 2 * PRG_BANK_SIZE || !prg_rom.len().is_multiple_of(PRG_BANK_SIZE) {
+        return Err("VRC2/VRC4 PRG ROM size must be a multiple of 8K".into());
+    }
+    if chr_rom.is_empty() {
+        return Err("VRC2/VRC4 boards need CHR ROM".into());
+    }
+    // Submapper 3 is VRC2 on mappers 23 and 25, without one they're treated as VRC4 (which
+    // VRC2 games run fine on)
+    let variant = match (mapper, submapper) {
+        (22, _) | (23 | 25, 3) => Variant::Vrc2,
+        _ => Variant::Vrc4,
+    };
+    Ok(Box::new(Vrc4 {
+        variant,
+        prg_rom: prg_rom.to_vec(),
+        chr_rom: chr_rom.to_vec(),
+        prg_ram: [0; 8 * 1024],
+        pins: pins(mapper, submapper)?,
+        chr_shift: (mapper == 22) as u8,
+        prg_banks: [0, 1],
+        prg_swap: false,
+        chr_banks: [0; 8],
+        mirror_type: MirrorType::Vertical,
+        latch: 0,
+        irq: VrcIrq::default(),
+        battery: battery && variant == Variant::Vrc4,
+    }))
+}
+
+#[cfg(test)]
+mod vrc4_tests {
+    use super::build_vrc4_cart;
+    use crate::cart::mock::numbered_roms;
+
+    #[test]
+    fn wiring() {
+        let (prg, chr) = numbered_roms(16, 128);
+        let vram = [0; 2048];
+        // The register for the high bits of CHR bank 1 at $B003 on every board
+        for (mapper, submapper, addr) in [
+            (21, 1, 0xB006),
+            (21, 2, 0xB0C0),
+            (21, 0, 0xB0C0),
+            (22, 0, 0xB003),
+            (23, 1, 0xB003),
+            (23, 2, 0xB00C),
+            (23, 0, 0xB00C),
+            (25, 1, 0xB003),
+            (25, 2, 0xB00C),
+            (25, 3, 0xB003),
+        ] {
+            let mut cart = build_vrc4_cart(&prg, &chr, mapper, submapper, false).unwrap();
+            cart.write(addr, 0x02).unwrap();
+            let expected = if mapper == 22 { 0x10 } else { 0x20 };
+            assert_eq!(cart.ppu_read(0x0400, &vram).unwrap(), expected, "mapper {mapper}.{submapper}");
+            assert_eq!(cart.ppu_read(0x0000, &vram).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn banking() {
+        let (prg, chr) = numbered_roms(16, 128);
+        let mut vram = [0; 2048];
+        let mut cart = build_vrc4_cart(&prg, &chr, 21, 1, true).unwrap();
+        assert_eq!(cart.name(), "VRC4");
+        cart.write(0x8000, 3).unwrap();
+        cart.write(0xA000, 4).unwrap();
+        assert_eq!(cart.read(0x8000).unwrap(), 3);
+        assert_eq!(cart.read(0xA000).unwrap(), 4);
+        assert_eq!(cart.read(0xC000).unwrap(), 14);
+        assert_eq!(cart.read(0xE000).unwrap(), 15);
+        // Swapped $8000 and $C000
+        cart.write(0x9004, 0x02).unwrap();
+        assert_eq!(cart.read(0x8000).unwrap(), 14);
+        assert_eq!(cart.read(0xC000).unwrap(), 3);
+        assert_eq!(cart.prg_rom_offset(0xC000), Some(3 * 8 * 1024));
+
+        cart.write(0xE000, 0x05).unwrap();
+        cart.write(0xE002, 0x01).unwrap();
+        assert_eq!(cart.ppu_read(0x1800, &vram).unwrap(), 0x15);
+        assert_eq!(cart.chr_rom_offset(0x1810), Some(0x15 * 1024 + 0x10));
+
+        // One-screen mirroring
+        cart.write(0x9000, 0x03).unwrap();
+        cart.ppu_write(0x2000, 0x42, &mut vram).unwrap();
+        assert_eq!(vram[0x400], 0x42);
+
+        cart.write(0x6000, 0x11).unwrap();
+        assert_eq!(cart.read(0x6000).unwrap(), 0x11);
+        assert_eq!(cart.nvram().unwrap()[0], 0x11);
+    }
+
+    #[test]
+    fn vrc2() {
+        let (prg, chr) = numbered_roms(16, 128);
+        let mut cart = build_vrc4_cart(&prg, &chr, 23, 3, true).unwrap();
+        assert_eq!(cart.name(), "VRC2");
+        assert!(cart.nvram().is_none());
+        cart.write(0x6000, 0xFF).unwrap();
+        assert_eq!(cart.read(0x6000).unwrap(), 0x01);
+        // No IRQ
+        cart.write(0xF002, 0x07).unwrap();
+        for _ in 0..1000 {
+            cart.cpu_cycle();
+        }
+        assert!(!cart.irq());
+    }
+
+    #[test]
+    fn irq() {
+        let (prg, chr) = numbered_roms(16, 128);
+        let mut cart = build_vrc4_cart(&prg, &chr, 25, 1, false).unwrap();
+        // Latch $F0, CPU cycle mode, 16 cycles
+        cart.write(0xF000, 0x00).unwrap();
+        cart.write(0xF002, 0x0F).unwrap();
+        cart.write(0xF001, 0x07).unwrap();
+        for _ in 0..15 {
+            cart.cpu_cycle();
+        }
+        assert!(!cart.irq());
+        cart.cpu_cycle();
+        assert!(cart.irq());
+        cart.write(0xF003, 0).unwrap();
+        assert!(!cart.irq());
+    }
+}
@@ -0,0 +1,471 @@
+use crate::error::Result;
+use crate::state::{StateReader, StateWriter};
+use crate::{ines::parse::MirrorType, mem::error::inv_addr};
+
+use super::cart::{nametable_addr, ppu_inv_addr, ppu_rd_only, Cart, Cartridge};
+use super::vrc_irq::VrcIrq;
+
+const PRG_BANK_SIZE: usize = 8 * 1024;
+const CHR_BANK_SIZE: usize = 1024;
+
+// VRC6 pulse: 16 step duty cycle, or a constant volume in digitized mode
+#[derive(Debug, Default)]
+struct Vrc6Pulse {
+    volume: u8,
+    duty: u8,
+    digitized: bool,
+    period: u16,
+    enabled: bool,
+    divider: u16,
+    step: u8,
+}
+
+impl Vrc6Pulse {
+    fn write(&mut self, reg: u16, byte: u8) {
+        match reg {
+            0 => {
+                self.digitized = byte & 0x80 != 0;
+                self.duty = (byte >> 4) & 0x7;
+                self.volume = byte & 0xF;
+            }
+            1 => self.period = (self.period & 0xF00) | byte as u16,
+            _ => {
+                self.period = (self.period & 0xFF) | (((byte & 0xF) as u16) << 8);
+                self.enabled = byte & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 15;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.divider == 0 {
+            self.divider = self.period >> shift;
+            self.step = self.step.wrapping_sub(1) & 0xF;
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.digitized || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.volume);
+        w.u8(self.duty);
+        w.bool(self.digitized);
+        w.u16(self.period);
+        w.bool(self.enabled);
+        w.u16(self.divider);
+        w.u8(self.step);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.volume = r.u8()?;
+        self.duty = r.u8()?;
+        self.digitized = r.bool()?;
+        self.period = r.u16()?;
+        self.enabled = r.bool()?;
+        self.divider = r.u16()?;
+        self.step = r.u8()?;
+        Ok(())
+    }
+}
+
+// VRC6 sawtooth: adds the rate to an accumulator every other step, resets after 14 steps
+#[derive(Debug, Default)]
+struct Vrc6Saw {
+    rate: u8,
+    period: u16,
+    enabled: bool,
+    divider: u16,
+    step: u8,
+    accumulator: u8,
+}
+
+impl Vrc6Saw {
+    fn write(&mut self, reg: u16, byte: u8) {
+        match reg {
+            0 => self.rate = byte & 0x3F,
+            1 => self.period = (self.period & 0xF00) | byte as u16,
+            _ => {
+                self.period = (self.period & 0xFF) | (((byte & 0xF) as u16) << 8);
+                self.enabled = byte & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.divider > 0 {
+            self.divider -= 1;
+            return;
+        }
+        self.divider = self.period >> shift;
+        self.step += 1;
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step.is_multiple_of(2) {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.rate);
+        w.u16(self.period);
+        w.bool(self.enabled);
+        w.u16(self.divider);
+        w.u8(self.step);
+        w.u8(self.accumulator);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.rate = r.u8()?;
+        self.period = r.u16()?;
+        self.enabled = r.bool()?;
+        self.divider = r.u16()?;
+        self.step = r.u8()?;
+        self.accumulator = r.u8()?;
+        Ok(())
+    }
+}
+
+// Konami VRC6, mapper 24 and mapper 26 with the register select lines swapped.
+// https://www.nesdev.org/wiki/VRC6
+#[derive(Debug)]
+pub struct Vrc6 {
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+    prg_ram: [u8; 8 * 1024],
+    prg_ram_enabled: bool,
+    swapped_pins: bool,
+    prg_16k: u8,
+    prg_8k: u8,
+    chr_banks: [u8; 8],
+    mirror_type: MirrorType,
+    irq: VrcIrq,
+    pulse1: Vrc6Pulse,
+    pulse2: Vrc6Pulse,
+    saw: Vrc6Saw,
+    // $9003: halt, and frequency x16 / x256 for all three channels
+    audio_halt: bool,
+    audio_shift: u8,
+    battery: bool,
+}
+
+impl Vrc6 {
+    fn map_cpu_addr(&self, addr: u16) -> usize {
+        let banks = self.prg_rom.len() / PRG_BANK_SIZE;
+        let bank = match addr {
+            0x8000..=0xBFFF => self.prg_16k as usize * 2 + (addr >= 0xA000) as usize,
+            0xC000..=0xDFFF => self.prg_8k as usize,
+            _ => banks - 1,
+        };
+        (bank % banks) * PRG_BANK_SIZE + addr as usize % PRG_BANK_SIZE
+    }
+
+    fn map_chr_addr(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[addr as usize / CHR_BANK_SIZE] as usize;
+        (bank * CHR_BANK_SIZE + addr as usize % CHR_BANK_SIZE) % self.chr_rom.len()
+    }
+
+    fn clock_audio(&mut self) {
+        if self.audio_halt {
+            return;
+        }
+        self.pulse1.clock(self.audio_shift);
+        self.pulse2.clock(self.audio_shift);
+        self.saw.clock(self.audio_shift);
+    }
+}
+
+impl Cart for Vrc6 {
+    fn name(&self) -> String {
+        "VRC6".into()
+    }
+
+    fn peek(&self, addr: u16) -> Result<u8> {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled => Ok(self.prg_ram[(addr - 0x6000) as usize]),
+            // Open bus
+            0x6000..=0x7FFF => Ok(0),
+            0x8000..=0xFFFF => Ok(self.prg_rom[self.map_cpu_addr(addr)]),
+            _ => Err(inv_addr(addr)),
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr >= 0x8000).then(|| self.map_cpu_addr(addr))
+    }
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr < 0x2000).then(|| self.map_chr_addr(addr))
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) -> Result<()> {
+        let reg = if self.swapped_pins {
+            (addr & 0xF000) | ((addr & 1) << 1) | ((addr >> 1) & 1)
+        } else {
+            addr & 0xF003
+        };
+        match reg {
+            0x0000..=0x5FFF => return Err(inv_addr(addr)),
+            0x6000..=0x7FFF if self.prg_ram_enabled => {
+                self.prg_ram[(addr - 0x6000) as usize] = byte
+            }
+            0x8000..=0x8003 => self.prg_16k = byte & 0x0F,
+            0x9000..=0x9002 => self.pulse1.write(reg & 3, byte),
+            0x9003 => {
+                self.audio_halt = byte & 0x1 != 0;
+                self.audio_shift = match byte & 0x6 {
+                    0 => 0,
+                    2 => 4,
+                    _ => 8,
+                };
+            }
+            0xA000..=0xA002 => self.pulse2.write(reg & 3, byte),
+            0xB000..=0xB002 => self.saw.write(reg & 3, byte),
+            // Only the CHR mode every game uses (0, eight 1K banks) is supported
+            0xB003 => {
+                self.mirror_type = match (byte >> 2) & 3 {
+                    0 => MirrorType::Vertical,
+                    1 => MirrorType::Horizontal,
+                    2 => MirrorType::OneScreenLow,
+                    _ => MirrorType::OneScreenHigh,
+                };
+                self.prg_ram_enabled = byte & 0x80 != 0;
+            }
+            0xC000..=0xC003 => self.prg_8k = byte & 0x1F,
+            0xD000..=0xE003 => {
+                self.chr_banks[((reg - 0xD000) >> 10) as usize | (reg & 3) as usize] = byte
+            }
+            0xF000 => self.irq.write_latch(byte),
+            0xF001 => self.irq.write_control(byte),
+            0xF002 => self.irq.acknowledge(),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn ppu_peek(&self, addr: u16, vram: &[u8]) -> Result<u8> {
+        match addr {
+            0x0000..=0x1FFF => Ok(self.chr_rom[self.map_chr_addr(addr)]),
+            0x2000..=0x3EFF => Ok(vram[nametable_addr(addr, self.mirror_type) as usize]),
+            _ => Err(ppu_inv_addr(addr)),
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, byte: u8, vram: &mut [u8]) -> Result<()> {
+        match addr {
+            0x0000..=0x1FFF => Err(ppu_rd_only(addr)),
+            0x2000..=0x3EFF => {
+                vram[nametable_addr(addr, self.mirror_type) as usize] = byte;
+                Ok(())
+            }
+            _ => Err(ppu_inv_addr(addr)),
+        }
+    }
+
+    fn cpu_cycle(&mut self) {
+        self.irq.clock();
+        self.clock_audio();
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending()
+    }
+
+    // Linear, a full volume pulse comes out about as loud as one of the APU's
+    fn audio_output(&self) -> f32 {
+        let sum = self.pulse1.output() + self.pulse2.output() + self.saw.output();
+        sum as f32 * 0.01
+    }
+
+    fn nvram(&self) -> Option<&[u8]> {
+        self.battery.then_some(&self.prg_ram[..])
+    }
+
+    fn load_nvram(&mut self, data: &[u8]) {
+        let len = data.len().min(self.prg_ram.len());
+        self.prg_ram[..len].copy_from_slice(&data[..len]);
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.prg_ram);
+        w.bool(self.prg_ram_enabled);
+        w.u8(self.prg_16k);
+        w.u8(self.prg_8k);
+        w.bytes(&self.chr_banks);
+        w.u8(self.mirror_type as u8);
+        self.irq.save_state(w);
+        self.pulse1.save_state(w);
+        self.pulse2.save_state(w);
+        self.saw.save_state(w);
+        w.bool(self.audio_halt);
+        w.u8(self.audio_shift);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        r.bytes_into(&mut self.prg_ram)?;
+        self.prg_ram_enabled = r.bool()?;
+        self.prg_16k = r.u8()?;
+        self.prg_8k = r.u8()?;
+        r.bytes_into(&mut self.chr_banks)?;
+        self.mirror_type =
+            MirrorType::try_from(r.u8()?).map_err(|_| "Invalid mirroring in save state")?;
+        self.irq.load_state(r)?;
+        self.pulse1.load_state(r)?;
+        self.pulse2.load_state(r)?;
+        self.saw.load_state(r)?;
+        self.audio_halt = r.bool()?;
+        self.audio_shift = r.u8()?;
+        Ok(())
+    }
+}
+
+pub fn build_vrc6_cart(
+    prg_rom: &[u8],
+    chr_rom: &[u8],
+    mapper: u16,
+    battery: bool,
+) -> Result<Cartridge> {
+    if prg_rom.len() < 2 * PRG_BANK_SIZE || !prg_rom.len().is_multiple_of(PRG_BANK_SIZE) {
+        return Err("VRC6 PRG ROM size must be a multiple of 8K".into());
+    }
+    if chr_rom.is_empty() {
+        return Err("VRC6 boards need CHR ROM".into());
+    }
+    Ok(Box::new(Vrc6 {
+        prg_rom: prg_rom.to_vec(),
+        chr_rom: chr_rom.to_vec(),
+        prg_ram: [0; 8 * 1024],
+        prg_ram_enabled: false,
+        swapped_pins: mapper == 26,
+        prg_16k: 0,
+        prg_8k: 0,
+        chr_banks: [0; 8],
+        mirror_type: MirrorType::Vertical,
+        irq: VrcIrq::default(),
+        pulse1: Vrc6Pulse { step: 15, ..Default::default() },
+        pulse2: Vrc6Pulse { step: 15, ..Default::default() },
+        saw: Vrc6Saw::default(),
+        audio_halt: false,
+        audio_shift: 0,
+        battery,
+    }))
+}
+
+#[cfg(test)]
+mod vrc6_tests {
+    use super::build_vrc6_cart;
+    use crate::cart::mock::numbered_roms;
+
+    #[test]
+    fn banking() {
+        let (prg, chr) = numbered_roms(16, 64);
+        let mut vram = [0; 2048];
+        for (mapper, chr_reg) in [(24, 0xE001), (26, 0xE002)] {
+            let mut cart = build_vrc6_cart(&prg, &chr, mapper, true).unwrap();
+            cart.write(0x8000, 2).unwrap();
+            cart.write(0xC000, 9).unwrap();
+            assert_eq!(cart.read(0x8000).unwrap(), 4);
+            assert_eq!(cart.read(0xA000).unwrap(), 5);
+            assert_eq!(cart.read(0xC000).unwrap(), 9);
+            assert_eq!(cart.read(0xE000).unwrap(), 15);
+            assert_eq!(cart.prg_rom_offset(0xA001), Some(5 * 8 * 1024 + 1));
+
+            // Second CHR bank of the upper pattern table
+            cart.write(chr_reg, 0x33).unwrap();
+            assert_eq!(cart.ppu_read(0x1400, &vram).unwrap(), 0x33, "mapper {mapper}");
+            assert_eq!(cart.chr_rom_offset(0x1401), Some(0x33 * 1024 + 1));
+
+            // PRG RAM disabled until $B003 bit 7, horizontal mirroring
+            cart.write(0x6000, 0x42).unwrap();
+            assert_eq!(cart.read(0x6000).unwrap(), 0);
+            cart.write(0xB003, 0x84).unwrap();
+            cart.write(0x6000, 0x42).unwrap();
+            assert_eq!(cart.read(0x6000).unwrap(), 0x42);
+            assert_eq!(cart.nvram().unwrap()[0], 0x42);
+            cart.ppu_write(0x2400, 0x11, &mut vram).unwrap();
+            assert_eq!(vram[0], 0x11);
+        }
+    }
+
+    #[test]
+    fn irq() {
+        let (prg, chr) = numbered_roms(16, 64);
+        let mut cart = build_vrc6_cart(&prg, &chr, 24, false).unwrap();
+        // CPU cycle mode, 2 cycles
+        cart.write(0xF000, 0xFE).unwrap();
+        cart.write(0xF001, 0x06).unwrap();
+        cart.cpu_cycle();
+        assert!(!cart.irq());
+        cart.cpu_cycle();
+        assert!(cart.irq());
+        cart.write(0xF002, 0).unwrap();
+        assert!(!cart.irq());
+    }
+
+    #[test]
+    fn audio() {
+        let (prg, chr) = numbered_roms(16, 64);
+        let mut cart = build_vrc6_cart(&prg, &chr, 24, false).unwrap();
+        assert_eq!(cart.audio_output(), 0.0);
+
+        // Pulse 1 at volume 15, 50% duty: on for 8 steps, off for 8
+        cart.write(0x9000, 0x7F).unwrap();
+        cart.write(0x9001, 0x00).unwrap();
+        cart.write(0x9002, 0x80).unwrap();
+        let mut on = 0;
+        for _ in 0..16 {
+            cart.cpu_cycle();
+            if cart.audio_output() > 0.0 {
+                on += 1;
+            }
+        }
+        assert_eq!(on, 8);
+        // Digitized mode is a constant level
+        cart.write(0x9000, 0x8F).unwrap();
+        for _ in 0..16 {
+            cart.cpu_cycle();
+            assert!((cart.audio_output() - 0.15).abs() < 1e-6);
+        }
+        cart.write(0x9002, 0x00).unwrap();
+
+        // Saw with rate 16: 0, 16, 32 .. 96, then back to 0
+        cart.write(0xB000, 0x10).unwrap();
+        cart.write(0xB002, 0x80).unwrap();
+        let mut levels = vec![];
+        for _ in 0..14 {
+            cart.cpu_cycle();
+            levels.push((cart.audio_output() * 100.0).round() as u8);
+        }
+        assert_eq!(levels, [0, 2, 2, 4, 4, 6, 6, 8, 8, 10, 10, 12, 12, 0]);
+
+        // Halted
+        cart.write(0x9003, 0x01).unwrap();
+        let level = cart.audio_output();
+        cart.cpu_cycle();
+        assert_eq!(cart.audio_output(), level);
+    }
+}
@@ -0,0 +1,141 @@
+use crate::error::Result;
+use crate::state::{StateReader, StateWriter};
+use crate::{ines::parse::MirrorType, mem::error::inv_addr};
+
+use super::cart::{nametable_addr, ppu_inv_addr, ppu_rd_only, Cart, Cartridge};
+
+const CHR_BANK_SIZE: usize = 8 * 1024;
+
+// CNROM: fixed 16K/32K PRG, any write to ROM selects an 8K CHR bank
+// https://www.nesdev.org/wiki/CNROM
+#[derive(Debug)]
+pub struct Cnrom {
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+    chr_bank: u8,
+    mirror_type: MirrorType,
+    // The ROM drives the bus during writes too, so only bits that are 1 in both get through
+    bus_conflicts: bool,
+}
+
+impl Cnrom {
+    fn map_chr_addr(&self, addr: u16) -> usize {
+        let banks = self.chr_rom.len() / CHR_BANK_SIZE;
+        (self.chr_bank as usize % banks) * CHR_BANK_SIZE + addr as usize
+    }
+}
+
+impl Cart for Cnrom {
+    fn name(&self) -> String {
+        "CNROM".into()
+    }
+
+    fn peek(&self, addr: u16) -> Result<u8> {
+        match self.prg_rom_offset(addr) {
+            Some(offset) => Ok(self.prg_rom[offset]),
+            None => Err(inv_addr(addr)),
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        // 16K boards mirror it at $C000
+        (addr >= 0x8000).then(|| (addr - 0x8000) as usize % self.prg_rom.len())
+    }
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr < 0x2000).then(|| self.map_chr_addr(addr))
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) -> Result<()> {
+        match addr {
+            0x8000..=0xFFFF => {
+                self.chr_bank = if self.bus_conflicts {
+                    byte & self.peek(addr)?
+                } else {
+                    byte
+                };
+                Ok(())
+            }
+            _ => Err(inv_addr(addr)),
+        }
+    }
+
+    fn ppu_peek(&self, addr: u16, vram: &[u8]) -> Result<u8> {
+        match addr {
+            0x0000..=0x1FFF => Ok(self.chr_rom[self.map_chr_addr(addr)]),
+            0x2000..=0x3EFF => Ok(vram[nametable_addr(addr, self.mirror_type) as usize]),
+            _ => Err(ppu_inv_addr(addr)),
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, byte: u8, vram: &mut [u8]) -> Result<()> {
+        match addr {
+            0x0000..=0x1FFF => Err(ppu_rd_only(addr)),
+            0x2000..=0x3EFF => {
+                vram[nametable_addr(addr, self.mirror_type) as usize] = byte;
+                Ok(())
+            }
+            _ => Err(ppu_inv_addr(addr)),
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.chr_bank);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.chr_bank = r.u8()?;
+        Ok(())
+    }
+}
+
+// Submapper 1 is the boards without bus conflicts
+pub fn build_cnrom(
+    prg_rom: &[u8],
+    chr_rom: &[u8],
+    mirror_type: MirrorType,
+    submapper: u8,
+) -> Result<Cartridge> {
+    if prg_rom.len() != 16 * 1024 && prg_rom.len() != 32 * 1024 {
+        return Err("Unsupported PRG ROM size for CNROM".into());
+    }
+    if chr_rom.is_empty() || !chr_rom.len().is_multiple_of(CHR_BANK_SIZE) {
+        return Err("CNROM CHR ROM size must be a multiple of 8K".into());
+    }
+    Ok(Box::new(Cnrom {
+        prg_rom: prg_rom.to_vec(),
+        chr_rom: chr_rom.to_vec(),
+        chr_bank: 0,
+        mirror_type,
+        bus_conflicts: submapper != 1,
+    }))
+}
+
+#[cfg(test)]
+mod cnrom_tests {
+    use super::build_cnrom;
+    use crate::cart::mock::numbered_banks;
+    use crate::ines::parse::MirrorType;
+
+    #[test]
+    fn chr_banking() {
+        // 4 CHR banks, each filled with its own bank number
+        let mut prg = vec![0xFF; 16 * 1024];
+        prg[0x10] = 0x01;
+        let chr = numbered_banks(8 * 1024, 4);
+        let vram = [0; 2048];
+        let mut cart = build_cnrom(&prg, &chr, MirrorType::Vertical, 0).unwrap();
+        assert_eq!(cart.read(0xC010).unwrap(), 0x01);
+
+        cart.write(0x8000, 2).unwrap();
+        assert_eq!(cart.ppu_read(0x1FFF, &vram).unwrap(), 2);
+        assert_eq!(cart.chr_rom_offset(0x0000), Some(2 * 8 * 1024));
+        // Bus conflict with the $01 in ROM
+        cart.write(0x8010, 3).unwrap();
+        assert_eq!(cart.ppu_read(0x0000, &vram).unwrap(), 1);
+
+        let mut cart = build_cnrom(&prg, &chr, MirrorType::Vertical, 1).unwrap();
+        cart.write(0x8010, 3).unwrap();
+        assert_eq!(cart.ppu_read(0x0000, &vram).unwrap(), 3);
+    }
+}
@@ -0,0 +1,284 @@
+use crate::error::Result;
+use crate::state::{StateReader, StateWriter};
+use crate::{ines::parse::MirrorType, mem::error::inv_addr};
+
+use super::cart::{nametable_addr, ppu_inv_addr, ppu_rd_only, Cart, Cartridge};
+
+const PRG_BANK_SIZE: usize = 32 * 1024;
+
+// Mapper 34 is two unrelated boards that ended up sharing a number
+// https://www.nesdev.org/wiki/INES_Mapper_034
+
+// BNROM: any write to ROM picks a 32K PRG bank, 8K of CHR RAM
+// https://www.nesdev.org/wiki/BNROM
+#[derive(Debug)]
+pub struct Bnrom {
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+    chr_ram: [u8; 8 * 1024],
+    prg_bank: u8,
+    mirror_type: MirrorType,
+}
+
+impl Cart for Bnrom {
+    fn name(&self) -> String {
+        "BNROM".into()
+    }
+
+    fn peek(&self, addr: u16) -> Result<u8> {
+        match self.prg_rom_offset(addr) {
+            Some(offset) => Ok(self.prg_rom[offset]),
+            None => Err(inv_addr(addr)),
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        let banks = self.prg_rom.len() / PRG_BANK_SIZE;
+        let base = (self.prg_bank as usize % banks) * PRG_BANK_SIZE;
+        (addr >= 0x8000).then(|| base + (addr - 0x8000) as usize)
+    }
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr < 0x2000 && !self.chr_rom.is_empty()).then_some(addr as usize)
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) -> Result<()> {
+        match addr {
+            // Always has bus conflicts
+            0x8000..=0xFFFF => {
+                self.prg_bank = byte & self.peek(addr)?;
+                Ok(())
+            }
+            _ => Err(inv_addr(addr)),
+        }
+    }
+
+    fn ppu_peek(&self, addr: u16, vram: &[u8]) -> Result<u8> {
+        match addr {
+            0x0000..=0x1FFF => {
+                if self.chr_rom.is_empty() {
+                    Ok(self.chr_ram[addr as usize])
+                } else {
+                    Ok(self.chr_rom[addr as usize])
+                }
+            }
+            0x2000..=0x3EFF => Ok(vram[nametable_addr(addr, self.mirror_type) as usize]),
+            _ => Err(ppu_inv_addr(addr)),
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, byte: u8, vram: &mut [u8]) -> Result<()> {
+        match addr {
+            0x0000..=0x1FFF => {
+                if self.chr_rom.is_empty() {
+                    self.chr_ram[addr as usize] = byte;
+                    Ok(())
+                } else {
+                    Err(ppu_rd_only(addr))
+                }
+            }
+            0x2000..=0x3EFF => {
+                vram[nametable_addr(addr, self.mirror_type) as usize] = byte;
+                Ok(())
+            }
+            _ => Err(ppu_inv_addr(addr)),
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.chr_ram);
+        w.u8(self.prg_bank);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        r.bytes_into(&mut self.chr_ram)?;
+        self.prg_bank = r.u8()?;
+        Ok(())
+    }
+}
+
+// NINA-001: 8K of PRG RAM with the bank registers at the top of it, 32K PRG and two 4K CHR
+// banks. No bus conflicts since the registers aren't in ROM.
+// https://www.nesdev.org/wiki/NINA-001
+#[derive(Debug)]
+pub struct Nina001 {
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+    prg_ram: [u8; 8 * 1024],
+    prg_bank: u8,
+    chr_banks: [u8; 2],
+    mirror_type: MirrorType,
+}
+
+impl Nina001 {
+    fn map_chr_addr(&self, addr: u16) -> usize {
+        let banks = self.chr_rom.len() / 0x1000;
+        let bank = self.chr_banks[(addr >> 12) as usize & 1] as usize % banks;
+        bank * 0x1000 + (addr & 0x0FFF) as usize
+    }
+}
+
+impl Cart for Nina001 {
+    fn name(&self) -> String {
+        "NINA-001".into()
+    }
+
+    fn peek(&self, addr: u16) -> Result<u8> {
+        match addr {
+            0x6000..=0x7FFF => Ok(self.prg_ram[(addr - 0x6000) as usize]),
+            _ => match self.prg_rom_offset(addr) {
+                Some(offset) => Ok(self.prg_rom[offset]),
+                None => Err(inv_addr(addr)),
+            },
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        let banks = self.prg_rom.len() / PRG_BANK_SIZE;
+        let base = (self.prg_bank as usize % banks) * PRG_BANK_SIZE;
+        (addr >= 0x8000).then(|| base + (addr - 0x8000) as usize)
+    }
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr < 0x2000).then(|| self.map_chr_addr(addr))
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) -> Result<()> {
+        match addr {
+            0x6000..=0x7FFF => {
+                // The registers are write-only, the RAM underneath still gets the byte
+                match addr {
+                    0x7FFD => self.prg_bank = byte & 0x01,
+                    0x7FFE => self.chr_banks[0] = byte & 0x0F,
+                    0x7FFF => self.chr_banks[1] = byte & 0x0F,
+                    _ => {}
+                }
+                self.prg_ram[(addr - 0x6000) as usize] = byte;
+                Ok(())
+            }
+            _ => Err(inv_addr(addr)),
+        }
+    }
+
+    fn ppu_peek(&self, addr: u16, vram: &[u8]) -> Result<u8> {
+        match addr {
+            0x0000..=0x1FFF => Ok(self.chr_rom[self.map_chr_addr(addr)]),
+            0x2000..=0x3EFF => Ok(vram[nametable_addr(addr, self.mirror_type) as usize]),
+            _ => Err(ppu_inv_addr(addr)),
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, byte: u8, vram: &mut [u8]) -> Result<()> {
+        match addr {
+            0x0000..=0x1FFF => Err(ppu_rd_only(addr)),
+            0x2000..=0x3EFF => {
+                vram[nametable_addr(addr, self.mirror_type) as usize] = byte;
+                Ok(())
+            }
+            _ => Err(ppu_inv_addr(addr)),
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.prg_ram);
+        w.u8(self.prg_bank);
+        w.bytes(&self.chr_banks);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        r.bytes_into(&mut self.prg_ram)?;
+        self.prg_bank = r.u8()?;
+        r.bytes_into(&mut self.chr_banks)?;
+        Ok(())
+    }
+}
+
+// Submapper 1 is NINA-001 and 2 is BNROM, old headers without one only have CHR ROM bigger
+// than 8K on NINA-001
+pub fn build_mapper34(
+    prg_rom: &[u8],
+    chr_rom: &[u8],
+    mirror_type: MirrorType,
+    submapper: u8,
+) -> Result<Cartridge> {
+    if prg_rom.is_empty() || !prg_rom.len().is_multiple_of(PRG_BANK_SIZE) {
+        return Err("Mapper 34 PRG ROM size must be a multiple of 32K".into());
+    }
+    let nina = match submapper {
+        1 => true,
+        2 => false,
+        _ => chr_rom.len() > 8 * 1024,
+    };
+    if !nina {
+        return Ok(Box::new(Bnrom {
+            prg_rom: prg_rom.to_vec(),
+            chr_rom: chr_rom.to_vec(),
+            chr_ram: [0; 8 * 1024],
+            prg_bank: 0,
+            mirror_type,
+        }));
+    }
+    if chr_rom.is_empty() || !chr_rom.len().is_multiple_of(0x1000) {
+        return Err("NINA-001 CHR ROM size must be a multiple of 4K".into());
+    }
+    Ok(Box::new(Nina001 {
+        prg_rom: prg_rom.to_vec(),
+        chr_rom: chr_rom.to_vec(),
+        prg_ram: [0; 8 * 1024],
+        prg_bank: 0,
+        chr_banks: [0, 1],
+        mirror_type,
+    }))
+}
+
+#[cfg(test)]
+mod mapper34_tests {
+    use super::build_mapper34;
+    use crate::cart::mock::numbered_banks;
+    use crate::ines::parse::MirrorType;
+
+    // Each 32K of PRG and 4K of CHR is filled with its bank number
+    fn roms() -> (Vec<u8>, Vec<u8>) {
+        (numbered_banks(32 * 1024, 4), numbered_banks(0x1000, 4))
+    }
+
+    #[test]
+    fn bnrom() {
+        let (mut prg, _) = roms();
+        // Somewhere in bank 0 to write bank numbers through without a bus conflict
+        prg[1] = 0xFF;
+        let mut vram = [0; 2048];
+        let mut cart = build_mapper34(&prg, &[], MirrorType::Vertical, 0).unwrap();
+        assert_eq!(cart.name(), "BNROM");
+        cart.write(0x8001, 0x03).unwrap();
+        assert_eq!(cart.read(0x8000).unwrap(), 3);
+        // Bus conflict with the 3 at $8000
+        cart.write(0x8000, 0x06).unwrap();
+        assert_eq!(cart.read(0x8000).unwrap(), 2);
+        assert_eq!(cart.prg_rom_offset(0xFFFF), Some(3 * 32 * 1024 - 1));
+        cart.ppu_write(0x0ABC, 0x12, &mut vram).unwrap();
+        assert_eq!(cart.ppu_read(0x0ABC, &vram).unwrap(), 0x12);
+    }
+
+    #[test]
+    fn nina001() {
+        let (prg, chr) = roms();
+        let vram = [0; 2048];
+        let mut cart = build_mapper34(&prg[..64 * 1024], &chr, MirrorType::Vertical, 0).unwrap();
+        assert_eq!(cart.name(), "NINA-001");
+        assert_eq!(cart.ppu_read(0x1000, &vram).unwrap(), 1);
+
+        cart.write(0x7FFD, 0x01).unwrap();
+        cart.write(0x7FFE, 0x03).unwrap();
+        cart.write(0x7FFF, 0x02).unwrap();
+        assert_eq!(cart.read(0x8000).unwrap(), 1);
+        assert_eq!(cart.ppu_read(0x0FFF, &vram).unwrap(), 3);
+        assert_eq!(cart.ppu_read(0x1000, &vram).unwrap(), 2);
+        assert_eq!(cart.chr_rom_offset(0x1010), Some(0x2010));
+        // Registers are still RAM as far as reads go
+        assert_eq!(cart.read(0x7FFE).unwrap(), 0x03);
+        cart.write(0x6000, 0x42).unwrap();
+        assert_eq!(cart.read(0x6000).unwrap(), 0x42);
+        assert!(cart.write(0x8000, 0).is_err());
+    }
+}
@@ -0,0 +1,444 @@
+use crate::error::Result;
+use crate::state::{StateReader, StateWriter};
+use crate::{ines::parse::MirrorType, mem::error::inv_addr};
+
+use super::cart::{nametable_addr, ppu_inv_addr, ppu_rd_only, Cart, Cartridge};
+
+const PRG_BANK_SIZE: usize = 8 * 1024;
+const CHR_BANK_SIZE: usize = 1024;
+// A12 has to stay low for this many CPU cycles before a rising edge clocks the counter,
+// this filters out the toggling between background and sprite fetches.
+const A12_LOW_CYCLES: u8 = 3;
+
+// TxROM
+// https://www.nesdev.org/wiki/MMC3
+#[derive(Debug)]
+pub struct Mmc3 {
+    prg_ram: [u8; 8 * 1024],
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+    chr_ram: [u8; 8 * 1024],
+    // Internal registers
+    bank_select: u8,
+    banks: [u8; 8],
+    prg_ram_enabled: bool,
+    prg_ram_write_protect: bool,
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_flag: bool,
+    a12_high: bool,
+    a12_low_cycles: u8,
+    // Offsets into PRG/CHR memory for each 8K/1K window
+    prg_bases: [usize; 4],
+    chr_bases: [usize; 8],
+    mirror_type: MirrorType,
+    // Four-screen boards carry the other 2K of nametable RAM ($2800-$2FFF)
+    four_screen_vram: Option<Box<[u8; 2 * 1024]>>,
+    battery: bool,
+}
+
+impl Mmc3 {
+    fn chr_len(&self) -> usize {
+        if self.chr_rom.is_empty() {
+            self.chr_ram.len()
+        } else {
+            self.chr_rom.len()
+        }
+    }
+
+    fn update_banks(&mut self) {
+        let prg_banks = self.prg_rom.len() / PRG_BANK_SIZE;
+        let prg = |bank: usize| (bank % prg_banks) * PRG_BANK_SIZE;
+        let second_last = prg(prg_banks - 2);
+        let r6 = prg(self.banks[6] as usize);
+        let r7 = prg(self.banks[7] as usize);
+        // Bit 6: swap $8000 and $C000
+        self.prg_bases = if self.bank_select & 0x40 == 0 {
+            [r6, r7, second_last, prg(prg_banks - 1)]
+        } else {
+            [second_last, r7, r6, prg(prg_banks - 1)]
+        };
+
+        let chr_banks = self.chr_len() / CHR_BANK_SIZE;
+        let chr = |bank: u8| (bank as usize % chr_banks) * CHR_BANK_SIZE;
+        // R0 and R1 select 2K banks (ignoring the low bit)
+        let two_k = [
+            chr(self.banks[0] & 0xFE),
+            chr(self.banks[0] | 1),
+            chr(self.banks[1] & 0xFE),
+            chr(self.banks[1] | 1),
+        ];
+        let one_k = [
+            chr(self.banks[2]),
+            chr(self.banks[3]),
+            chr(self.banks[4]),
+            chr(self.banks[5]),
+        ];
+        // Bit 7: swap the 2K and 1K halves of the pattern tables
+        let (lo, hi) = if self.bank_select & 0x80 == 0 {
+            (two_k, one_k)
+        } else {
+            (one_k, two_k)
+        };
+        self.chr_bases[..4].copy_from_slice(&lo);
+        self.chr_bases[4..].copy_from_slice(&hi);
+    }
+
+    fn map_cpu_addr(&self, addr: u16) -> usize {
+        let window = ((addr - 0x8000) as usize) / PRG_BANK_SIZE;
+        self.prg_bases[window] + (addr as usize % PRG_BANK_SIZE)
+    }
+
+    fn map_chr_addr(&self, addr: u16) -> usize {
+        self.chr_bases[addr as usize / CHR_BANK_SIZE] + (addr as usize % CHR_BANK_SIZE)
+    }
+
+    fn clock_irq_counter(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_flag = true;
+        }
+    }
+}
+
+impl Cart for Mmc3 {
+    fn name(&self) -> String {
+        "MMC3".into()
+    }
+
+    fn peek(&self, addr: u16) -> Result<u8> {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled => Ok(self.prg_ram[(addr - 0x6000) as usize]),
+            // Open bus
+            0x6000..=0x7FFF => Ok(0),
+            0x8000..=0xFFFF => Ok(self.prg_rom[self.map_cpu_addr(addr)]),
+            _ => Err(inv_addr(addr)),
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr >= 0x8000).then(|| self.map_cpu_addr(addr))
+    }
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr < 0x2000 && !self.chr_rom.is_empty()).then(|| self.map_chr_addr(addr))
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) -> Result<()> {
+        let even = addr & 1 == 0;
+        match addr {
+            0x6000..=0x7FFF => {
+                if self.prg_ram_enabled && !self.prg_ram_write_protect {
+                    self.prg_ram[(addr - 0x6000) as usize] = byte;
+                }
+            }
+            0x8000..=0x9FFF if even => {
+                self.bank_select = byte;
+                self.update_banks();
+            }
+            0x8000..=0x9FFF => {
+                self.banks[(self.bank_select & 0x7) as usize] = byte;
+                self.update_banks();
+            }
+            0xA000..=0xBFFF if even => {
+                self.mirror_type = if byte & 1 == 0 {
+                    MirrorType::Vertical
+                } else {
+                    MirrorType::Horizontal
+                };
+            }
+            0xA000..=0xBFFF => {
+                self.prg_ram_enabled = byte & 0x80 != 0;
+                self.prg_ram_write_protect = byte & 0x40 != 0;
+            }
+            0xC000..=0xDFFF if even => self.irq_latch = byte,
+            0xC000..=0xDFFF => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            0xE000..=0xFFFF if even => {
+                // Disabling also acknowledges any pending interrupt
+                self.irq_enabled = false;
+                self.irq_flag = false;
+            }
+            0xE000..=0xFFFF => self.irq_enabled = true,
+            _ => return Err(inv_addr(addr)),
+        }
+        Ok(())
+    }
+
+    fn ppu_peek(&self, addr: u16, vram: &[u8]) -> Result<u8> {
+        match addr {
+            0x0000..=0x1FFF => {
+                if self.chr_rom.is_empty() {
+                    Ok(self.chr_ram[self.map_chr_addr(addr)])
+                } else {
+                    Ok(self.chr_rom[self.map_chr_addr(addr)])
+                }
+            }
+            0x2000..=0x3EFF => match self.four_screen_vram.as_ref() {
+                Some(extra) if addr & 0x800 != 0 => Ok(extra[(addr & 0x7FF) as usize]),
+                Some(_) => Ok(vram[(addr & 0x7FF) as usize]),
+                None => Ok(vram[nametable_addr(addr, self.mirror_type) as usize]),
+            },
+            _ => Err(ppu_inv_addr(addr)),
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, byte: u8, vram: &mut [u8]) -> Result<()> {
+        match addr {
+            0x0000..=0x1FFF => {
+                if self.chr_rom.is_empty() {
+                    self.chr_ram[self.map_chr_addr(addr)] = byte;
+                    Ok(())
+                } else {
+                    Err(ppu_rd_only(addr))
+                }
+            }
+            0x2000..=0x3EFF => {
+                match self.four_screen_vram.as_mut() {
+                    Some(extra) if addr & 0x800 != 0 => extra[(addr & 0x7FF) as usize] = byte,
+                    Some(_) => vram[(addr & 0x7FF) as usize] = byte,
+                    None => vram[nametable_addr(addr, self.mirror_type) as usize] = byte,
+                }
+                Ok(())
+            }
+            _ => Err(ppu_inv_addr(addr)),
+        }
+    }
+
+    fn ppu_bus(&mut self, addr: u16) {
+        let a12 = addr & 0x1000 != 0;
+        if a12 && !self.a12_high && self.a12_low_cycles >= A12_LOW_CYCLES {
+            self.clock_irq_counter();
+        }
+        if a12 {
+            self.a12_low_cycles = 0;
+        }
+        self.a12_high = a12;
+    }
+
+    fn cpu_cycle(&mut self) {
+        if !self.a12_high {
+            self.a12_low_cycles = self.a12_low_cycles.saturating_add(1);
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_flag
+    }
+
+    fn nvram(&self) -> Option<&[u8]> {
+        self.battery.then_some(&self.prg_ram[..])
+    }
+
+    fn load_nvram(&mut self, data: &[u8]) {
+        let len = data.len().min(self.prg_ram.len());
+        self.prg_ram[..len].copy_from_slice(&data[..len]);
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.prg_ram);
+        w.bytes(&self.chr_ram);
+        w.u8(self.bank_select);
+        w.bytes(&self.banks);
+        w.bool(self.prg_ram_enabled);
+        w.bool(self.prg_ram_write_protect);
+        w.u8(self.irq_latch);
+        w.u8(self.irq_counter);
+        w.bool(self.irq_reload);
+        w.bool(self.irq_enabled);
+        w.bool(self.irq_flag);
+        w.bool(self.a12_high);
+        w.u8(self.a12_low_cycles);
+        w.u8(self.mirror_type as u8);
+        if let Some(extra) = self.four_screen_vram.as_ref() {
+            w.bytes(&extra[..]);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        r.bytes_into(&mut self.prg_ram)?;
+        r.bytes_into(&mut self.chr_ram)?;
+        self.bank_select = r.u8()?;
+        r.bytes_into(&mut self.banks)?;
+        self.prg_ram_enabled = r.bool()?;
+        self.prg_ram_write_protect = r.bool()?;
+        self.irq_latch = r.u8()?;
+        self.irq_counter = r.u8()?;
+        self.irq_reload = r.bool()?;
+        self.irq_enabled = r.bool()?;
+        self.irq_flag = r.bool()?;
+        self.a12_high = r.bool()?;
+        self.a12_low_cycles = r.u8()?;
+        self.mirror_type =
+            MirrorType::try_from(r.u8()?).map_err(|_| "Invalid mirroring in save state")?;
+        if let Some(extra) = self.four_screen_vram.as_mut() {
+            r.bytes_into(&mut extra[..])?;
+        }
+        self.update_banks();
+        Ok(())
+    }
+}
+
+pub fn build_mmc3_cart(
+    prg_rom: &[u8],
+    chr_rom: &[u8],
+    mirror_type: MirrorType,
+    four_screen: bool,
+    battery: bool,
+) -> Result<Cartridge> {
+    if prg_rom.len() < 2 * PRG_BANK_SIZE || !prg_rom.len().is_multiple_of(PRG_BANK_SIZE) {
+        return Err("MMC3 PRG ROM size must be a multiple of 8K".into());
+    }
+    let mut cart = Mmc3 {
+        prg_ram: [0; 8 * 1024],
+        prg_rom: prg_rom.to_vec(),
+        chr_rom: chr_rom.to_vec(),
+        chr_ram: [0; 8 * 1024],
+        bank_select: 0,
+        banks: [0, 2, 4, 5, 6, 7, 0, 1],
+        prg_ram_enabled: true,
+        prg_ram_write_protect: false,
+        irq_latch: 0,
+        irq_counter: 0,
+        irq_reload: false,
+        irq_enabled: false,
+        irq_flag: false,
+        a12_high: false,
+        a12_low_cycles: 0,
+        prg_bases: [0; 4],
+        chr_bases: [0; 8],
+        mirror_type,
+        four_screen_vram: four_screen.then(|| Box::new([0; 2 * 1024])),
+        battery,
+    };
+    cart.update_banks();
+    Ok(Box::new(cart))
+}
+
+#[cfg(test)]
+mod mmc3_tests {
+    use super::build_mmc3_cart;
+    use crate::cart::cart::Cartridge;
+    use crate::cart::mock::numbered_banks;
+    use crate::ines::parse::MirrorType;
+
+    // 8 PRG banks and 16 CHR banks, each filled with its own bank number
+    fn test_cart() -> Cartridge {
+        let prg = numbered_banks(8 * 1024, 8);
+        let chr = numbered_banks(1024, 16);
+        build_mmc3_cart(&prg, &chr, MirrorType::Vertical, false, true).unwrap()
+    }
+
+    // One scanline's worth of fetches: background from $0000, sprites from $1000
+    fn scanline(cart: &mut Cartridge) {
+        for _ in 0..85 {
+            cart.cpu_cycle();
+        }
+        cart.ppu_bus(0x0000);
+        for _ in 0..21 {
+            cart.cpu_cycle();
+        }
+        cart.ppu_bus(0x1FF0);
+        cart.ppu_bus(0x2000);
+        cart.ppu_bus(0x1FF8);
+    }
+
+    #[test]
+    fn prg_banking() {
+        let mut cart = test_cart();
+        cart.write(0x8000, 6).unwrap();
+        cart.write(0x8001, 3).unwrap();
+        cart.write(0x8000, 7).unwrap();
+        cart.write(0x8001, 4).unwrap();
+        assert_eq!(cart.read(0x8000).unwrap(), 3);
+        assert_eq!(cart.read(0xA000).unwrap(), 4);
+        assert_eq!(cart.read(0xC000).unwrap(), 6);
+        assert_eq!(cart.read(0xE000).unwrap(), 7);
+
+        // PRG mode 1 swaps $8000 and $C000
+        cart.write(0x8000, 0x46).unwrap();
+        assert_eq!(cart.read(0x8000).unwrap(), 6);
+        assert_eq!(cart.read(0xC000).unwrap(), 3);
+    }
+
+    #[test]
+    fn chr_banking() {
+        let mut cart = test_cart();
+        let vram = [0; 2048];
+        cart.write(0x8000, 0).unwrap();
+        cart.write(0x8001, 5).unwrap();
+        cart.write(0x8000, 2).unwrap();
+        cart.write(0x8001, 9).unwrap();
+        assert_eq!(cart.ppu_read(0x0000, &vram).unwrap(), 4);
+        assert_eq!(cart.ppu_read(0x0400, &vram).unwrap(), 5);
+        assert_eq!(cart.ppu_read(0x1000, &vram).unwrap(), 9);
+
+        // CHR inversion
+        cart.write(0x8000, 0x80).unwrap();
+        assert_eq!(cart.ppu_read(0x0000, &vram).unwrap(), 9);
+        assert_eq!(cart.ppu_read(0x1400, &vram).unwrap(), 5);
+    }
+
+    #[test]
+    fn scanline_irq() {
+        let mut cart = test_cart();
+        cart.write(0xC000, 3).unwrap();
+        cart.write(0xC001, 0).unwrap();
+        cart.write(0xE001, 0).unwrap();
+
+        // Reload on the first clock, then count down 3, 2, 1, 0
+        for _ in 0..3 {
+            scanline(&mut cart);
+            assert!(!cart.irq());
+        }
+        scanline(&mut cart);
+        assert!(cart.irq());
+
+        // Acknowledge
+        cart.write(0xE000, 0).unwrap();
+        assert!(!cart.irq());
+    }
+
+    #[test]
+    fn a12_filter() {
+        let mut cart = test_cart();
+        cart.write(0xC000, 0).unwrap();
+        cart.write(0xE001, 0).unwrap();
+        for _ in 0..3 {
+            cart.cpu_cycle();
+        }
+        cart.ppu_bus(0x1000);
+        assert!(cart.irq());
+        cart.write(0xE000, 0).unwrap();
+        cart.write(0xE001, 0).unwrap();
+
+        // A12 wasn't low long enough, no clock
+        cart.ppu_bus(0x0000);
+        cart.cpu_cycle();
+        cart.ppu_bus(0x1000);
+        assert!(!cart.irq());
+    }
+
+    #[test]
+    fn battery_ram() {
+        let mut cart = test_cart();
+        cart.write(0x6000, 0x12).unwrap();
+        cart.write(0x7FFF, 0x34).unwrap();
+        let saved = cart.nvram().unwrap().to_vec();
+        assert_eq!((saved[0], saved[0x1FFF]), (0x12, 0x34));
+
+        let mut cart = test_cart();
+        cart.load_nvram(&saved);
+        assert_eq!(cart.read(0x6000).unwrap(), 0x12);
+        assert_eq!(cart.read(0x7FFF).unwrap(), 0x34);
+    }
+}
@@ -0,0 +1,938 @@
+use crate::apu::pulse::{Pulse, PulseChannel};
+use crate::error::Result;
+use crate::state::{StateReader, StateWriter};
+use crate::mem::error::inv_addr;
+
+use super::cart::{ppu_inv_addr, ppu_rd_only, Cart, Cartridge, PpuFetch};
+
+const PRG_BANK_SIZE: usize = 8 * 1024;
+// iNES 1 headers don't say how much PRG RAM there is, no board was made with more than 32K
+const DEFAULT_PRG_RAM_SIZE: usize = 32 * 1024;
+// CPU cycles between the audio's envelope/length clocks (240Hz, not tied to the APU's)
+const AUDIO_FRAME_CYCLES: u16 = 7457;
+// Without a PPU read for this many CPU cycles, rendering has stopped
+const IDLE_CYCLES: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PrgWindow {
+    Rom(usize),
+    Ram(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChrSet {
+    // $5120-$5127
+    A,
+    // $5128-$512B
+    B,
+}
+
+// MMC5, mapper 5 (ExROM boards). PRG banks down to 8K with RAM mappable into the ROM windows,
+// 1K CHR banks with separate sets for sprites and background, 1K of ExRAM usable as an extra
+// nametable or per-tile attributes, a scanline IRQ, a vertical split and two extra pulses.
+// https://www.nesdev.org/wiki/MMC5
+#[derive(Debug)]
+pub struct Mmc5 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr_rom: Vec<u8>,
+    chr_ram: [u8; 8 * 1024],
+    exram: [u8; 1024],
+    // Registers
+    prg_mode: u8,
+    chr_mode: u8,
+    prg_ram_protect: [u8; 2],
+    exram_mode: u8,
+    nametables: u8,
+    fill_tile: u8,
+    fill_attr: u8,
+    prg_ram_bank: u8,
+    // $5114-$5117
+    prg_regs: [u8; 4],
+    chr_a: [u16; 8],
+    chr_b: [u16; 4],
+    chr_upper: u8,
+    last_set: ChrSet,
+    split_mode: u8,
+    split_scroll: u8,
+    split_bank: u8,
+    irq_compare: u8,
+    irq_enabled: bool,
+    irq_pending: bool,
+    multiplicand: u8,
+    multiplier: u8,
+    // Snooped from $2000
+    large_sprites: bool,
+    // Scanline detection, from watching the PPU's reads
+    in_frame: bool,
+    scanline: u8,
+    last_fetch: u16,
+    fetch_matches: u8,
+    idle_cycles: u8,
+    // Tile column being fetched, 0 and 1 are fetched at the end of the previous scanline
+    tile: u8,
+    sprite_fetched: bool,
+    // The tile being fetched comes from the split region / its ExRAM byte in extended
+    // attribute mode
+    split_tile: bool,
+    ext_attr: u8,
+    // Audio
+    pulse1: Pulse,
+    pulse2: Pulse,
+    pcm: u8,
+    pcm_irq_enabled: bool,
+    odd_cycle: bool,
+    audio_cycles: u16,
+    // Offset into PRG ROM or RAM bank number for each 8K window at $8000-$FFFF
+    prg_windows: [PrgWindow; 4],
+    // Battery backed bytes at the start of the PRG RAM
+    nvram_size: usize,
+}
+
+impl Mmc5 {
+    fn update_prg_banks(&mut self) {
+        let rom_banks = self.prg_rom.len() / PRG_BANK_SIZE;
+        for (window, out) in self.prg_windows.iter_mut().enumerate() {
+            // Which register covers the window and how many 8K banks that register switches
+            let (reg, size) = match (self.prg_mode & 3, window) {
+                (0, _) => (3, 4),
+                (1, 0 | 1) | (2, 0 | 1) => (1, 2),
+                (1, _) => (3, 2),
+                (_, w) => (w, 1),
+            };
+            let value = self.prg_regs[reg];
+            // The low bits of the register are replaced by the 8K bank's place in the window
+            let bank = ((value & 0x7F) as usize & !(size - 1)) | (window % size);
+            *out = if reg == 3 || value & 0x80 != 0 {
+                PrgWindow::Rom((bank % rom_banks) * PRG_BANK_SIZE)
+            } else {
+                PrgWindow::Ram(bank & 7)
+            };
+        }
+    }
+
+    // Where an address in an 8K RAM bank ends up, smaller boards ignore some of the bank bits
+    fn prg_ram_offset(&self, bank: usize, addr: u16) -> usize {
+        let base = match self.prg_ram.len() {
+            // Two 8K chips, bit 2 picks the chip
+            0x4000 => ((bank >> 2) & 1) * PRG_BANK_SIZE,
+            len => bank * PRG_BANK_SIZE % len,
+        };
+        base + addr as usize % PRG_BANK_SIZE
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        self.prg_ram_protect == [0b10, 0b01]
+    }
+
+    fn chr_len(&self) -> usize {
+        if self.chr_rom.is_empty() {
+            self.chr_ram.len()
+        } else {
+            self.chr_rom.len()
+        }
+    }
+
+    fn map_chr_addr(&self, addr: u16, set: ChrSet) -> usize {
+        let addr = addr as usize;
+        let size = (8 * 1024) >> self.chr_mode;
+        let window = addr / size;
+        // Set B only covers 4K, the upper pattern table sees the same banks
+        let reg = match set {
+            ChrSet::A => self.chr_a[(window + 1) * (8 >> self.chr_mode) - 1],
+            ChrSet::B => self.chr_b[((window + 1) * (8 >> self.chr_mode) - 1) & 3],
+        };
+        (reg as usize * size + addr % size) % self.chr_len()
+    }
+
+    fn chr_byte(&self, offset: usize) -> u8 {
+        if self.chr_rom.is_empty() {
+            self.chr_ram[offset]
+        } else {
+            self.chr_rom[offset]
+        }
+    }
+
+    // Sprites and background only get their own banks with 8x16 sprites, with 8x8 sprites
+    // $5128-$512B are ignored
+    fn fetch_set(&self, kind: PpuFetch) -> ChrSet {
+        match kind {
+            PpuFetch::Sprite => ChrSet::A,
+            _ if self.large_sprites => ChrSet::B,
+            _ => ChrSet::A,
+        }
+    }
+
+    // $2007 goes through whichever set was written last, but only with 8x16 sprites
+    fn data_set(&self) -> ChrSet {
+        match self.large_sprites {
+            true => self.last_set,
+            false => ChrSet::A,
+        }
+    }
+
+    fn nametable_read(&self, addr: u16, vram: &[u8]) -> u8 {
+        let offset = (addr & 0x3FF) as usize;
+        match (self.nametables >> ((addr >> 9) & 0x6)) & 3 {
+            0 => vram[offset],
+            1 => vram[0x400 | offset],
+            2 if self.exram_mode < 2 => self.exram[offset],
+            2 => 0,
+            // Fill mode
+            _ if offset >= 0x3C0 => (self.fill_attr & 3) * 0x55,
+            _ => self.fill_tile,
+        }
+    }
+
+    // A new scanline starts when the PPU reads the same nametable address three times in a row
+    // (the dummy fetches at the end of each line)
+    fn watch_fetch(&mut self, addr: u16, kind: PpuFetch) {
+        self.idle_cycles = 0;
+        if addr == self.last_fetch {
+            self.fetch_matches += 1;
+        } else {
+            self.last_fetch = addr;
+            self.fetch_matches = 1;
+        }
+        match kind {
+            PpuFetch::Sprite => self.sprite_fetched = true,
+            PpuFetch::Nametable if self.sprite_fetched => {
+                self.tile = 0;
+                self.sprite_fetched = false;
+            }
+            PpuFetch::Nametable => self.tile = self.tile.saturating_add(1),
+            _ => {}
+        }
+        if kind == PpuFetch::Nametable && self.fetch_matches == 3 {
+            if self.in_frame {
+                self.scanline = self.scanline.wrapping_add(1);
+                if self.scanline == self.irq_compare {
+                    self.irq_pending = true;
+                }
+            } else {
+                self.in_frame = true;
+                self.scanline = 0;
+                self.irq_pending = false;
+            }
+            // The next fetch is for tile 2, 0 and 1 were prefetched
+            self.tile = 1;
+        }
+    }
+
+    fn in_split(&self) -> bool {
+        if self.split_mode & 0x80 == 0 || self.exram_mode >= 2 || self.tile >= 34 {
+            return false;
+        }
+        let threshold = self.split_mode & 0x1F;
+        let tile = self.tile & 31;
+        if self.split_mode & 0x40 == 0 {
+            tile < threshold
+        } else {
+            tile >= threshold
+        }
+    }
+
+    // Row of the split region being fetched, it scrolls on its own
+    fn split_y(&self) -> u16 {
+        // Tiles 0 and 1 are for the next scanline
+        let line = match self.in_frame {
+            true => self.scanline as u16 + (self.tile < 2) as u16,
+            false => 0,
+        };
+        (self.split_scroll as u16 + line) % 240
+    }
+
+    fn clock_audio(&mut self) {
+        if self.odd_cycle {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.odd_cycle = !self.odd_cycle;
+        self.audio_cycles += 1;
+        if self.audio_cycles >= AUDIO_FRAME_CYCLES {
+            self.audio_cycles = 0;
+            for pulse in [&mut self.pulse1, &mut self.pulse2] {
+                pulse.clock_quarter_frame();
+                pulse.clock_half_frame();
+            }
+        }
+    }
+
+    fn status(&self) -> u8 {
+        ((self.irq_pending as u8) << 7) | ((self.in_frame as u8) << 6)
+    }
+}
+
+impl Cart for Mmc5 {
+    fn name(&self) -> String {
+        "MMC5".into()
+    }
+
+    fn peek(&self, addr: u16) -> Result<u8> {
+        match addr {
+            0x5015 => {
+                let p1 = self.pulse1.length.active() as u8;
+                Ok(p1 | ((self.pulse2.length.active() as u8) << 1))
+            }
+            0x5204 => Ok(self.status()),
+            0x5205 => Ok((self.multiplicand as u16 * self.multiplier as u16) as u8),
+            0x5206 => Ok(((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8),
+            0x5C00..=0x5FFF if self.exram_mode >= 2 => Ok(self.exram[(addr - 0x5C00) as usize]),
+            // Open bus
+            0x4020..=0x5FFF => Ok(0),
+            0x6000..=0x7FFF => {
+                Ok(self.prg_ram[self.prg_ram_offset(self.prg_ram_bank as usize, addr)])
+            }
+            0x8000..=0xFFFF => {
+                match self.prg_windows[(addr - 0x8000) as usize / PRG_BANK_SIZE] {
+                    PrgWindow::Rom(base) => Ok(self.prg_rom[base + addr as usize % PRG_BANK_SIZE]),
+                    PrgWindow::Ram(bank) => Ok(self.prg_ram[self.prg_ram_offset(bank, addr)]),
+                }
+            }
+            _ => Err(inv_addr(addr)),
+        }
+    }
+
+    fn read(&mut self, addr: u16) -> Result<u8> {
+        let byte = self.peek(addr)?;
+        match addr {
+            0x5204 => self.irq_pending = false,
+            // The CPU fetching the NMI vector means the frame is over
+            0xFFFA | 0xFFFB => {
+                self.in_frame = false;
+                self.last_fetch = 0;
+            }
+            _ => {}
+        }
+        Ok(byte)
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < 0x8000 {
+            return None;
+        }
+        match self.prg_windows[(addr - 0x8000) as usize / PRG_BANK_SIZE] {
+            PrgWindow::Rom(base) => Some(base + addr as usize % PRG_BANK_SIZE),
+            PrgWindow::Ram(_) => None,
+        }
+    }
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr < 0x2000 && !self.chr_rom.is_empty()).then(|| self.map_chr_addr(addr, self.data_set()))
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) -> Result<()> {
+        match addr {
+            0x5000..=0x5003 => self.pulse1.write(addr - 0x5000, byte),
+            0x5004..=0x5007 => self.pulse2.write(addr - 0x5004, byte),
+            // PCM read mode and its IRQ aren't emulated, nothing is known to use them
+            0x5010 => self.pcm_irq_enabled = byte & 0x80 != 0,
+            0x5011 if byte != 0 => self.pcm = byte,
+            0x5015 => {
+                self.pulse1.length.set_enabled(byte & 1 != 0);
+                self.pulse2.length.set_enabled(byte & 2 != 0);
+            }
+            0x5100 => {
+                self.prg_mode = byte & 3;
+                self.update_prg_banks();
+            }
+            0x5101 => self.chr_mode = byte & 3,
+            0x5102 => self.prg_ram_protect[0] = byte & 3,
+            0x5103 => self.prg_ram_protect[1] = byte & 3,
+            0x5104 => self.exram_mode = byte & 3,
+            0x5105 => self.nametables = byte,
+            0x5106 => self.fill_tile = byte,
+            0x5107 => self.fill_attr = byte & 3,
+            0x5113 => self.prg_ram_bank = byte & 7,
+            0x5114..=0x5117 => {
+                self.prg_regs[(addr - 0x5114) as usize] = byte;
+                self.update_prg_banks();
+            }
+            0x5120..=0x5127 => {
+                self.chr_a[(addr - 0x5120) as usize] = ((self.chr_upper as u16) << 8) | byte as u16;
+                self.last_set = ChrSet::A;
+            }
+            0x5128..=0x512B => {
+                self.chr_b[(addr - 0x5128) as usize] = ((self.chr_upper as u16) << 8) | byte as u16;
+                self.last_set = ChrSet::B;
+            }
+            0x5130 => self.chr_upper = byte & 3,
+            0x5200 => self.split_mode = byte,
+            0x5201 => self.split_scroll = byte,
+            0x5202 => self.split_bank = byte,
+            0x5203 => self.irq_compare = byte,
+            0x5204 => self.irq_enabled = byte & 0x80 != 0,
+            0x5205 => self.multiplicand = byte,
+            0x5206 => self.multiplier = byte,
+            0x5C00..=0x5FFF => {
+                let offset = (addr - 0x5C00) as usize;
+                match self.exram_mode {
+                    // Only writable while the PPU is rendering, otherwise 0 gets written
+                    0 | 1 => self.exram[offset] = if self.in_frame { byte } else { 0 },
+                    2 => self.exram[offset] = byte,
+                    _ => {}
+                }
+            }
+            0x4020..=0x5FFF => {}
+            0x6000..=0x7FFF => {
+                if self.prg_ram_writable() {
+                    let offset = self.prg_ram_offset(self.prg_ram_bank as usize, addr);
+                    self.prg_ram[offset] = byte;
+                }
+            }
+            0x8000..=0xFFFF => {
+                let window = self.prg_windows[(addr - 0x8000) as usize / PRG_BANK_SIZE];
+                if let (PrgWindow::Ram(bank), true) = (window, self.prg_ram_writable()) {
+                    let offset = self.prg_ram_offset(bank, addr);
+                    self.prg_ram[offset] = byte;
+                }
+            }
+            _ => return Err(inv_addr(addr)),
+        }
+        Ok(())
+    }
+
+    fn ppu_peek(&self, addr: u16, vram: &[u8]) -> Result<u8> {
+        match addr {
+            0x0000..=0x1FFF => Ok(self.chr_byte(self.map_chr_addr(addr, self.data_set()))),
+            0x2000..=0x3EFF => Ok(self.nametable_read(addr, vram)),
+            _ => Err(ppu_inv_addr(addr)),
+        }
+    }
+
+    fn ppu_fetch(&mut self, addr: u16, kind: PpuFetch, vram: &[u8]) -> Result<u8> {
+        self.watch_fetch(addr, kind);
+        match kind {
+            PpuFetch::Nametable => {
+                self.split_tile = self.in_split();
+                if self.split_tile {
+                    let y = self.split_y();
+                    return Ok(self.exram[(y / 8 * 32) as usize + (self.tile & 31) as usize]);
+                }
+                self.ext_attr = self.exram[(addr & 0x3FF) as usize];
+                Ok(self.nametable_read(addr, vram))
+            }
+            // The PPU picks out the quadrant itself, so the palette goes in all four
+            PpuFetch::Attribute if self.split_tile => {
+                let (y, tile) = (self.split_y(), (self.tile & 31) as u16);
+                let byte = self.exram[(0x3C0 + y / 32 * 8 + tile / 4) as usize];
+                let shift = ((y / 16) & 1) * 4 + ((tile / 2) & 1) * 2;
+                Ok(((byte >> shift) & 3) * 0x55)
+            }
+            PpuFetch::Attribute if self.exram_mode == 1 => Ok((self.ext_attr >> 6) * 0x55),
+            PpuFetch::Background if self.split_tile => {
+                let row = (self.split_y() & 7) as usize;
+                let offset = self.split_bank as usize * 0x1000 + ((addr as usize & 0x0FF8) | row);
+                Ok(self.chr_byte(offset % self.chr_len()))
+            }
+            // Every tile picks its own 4K bank
+            PpuFetch::Background if self.exram_mode == 1 => {
+                let bank = ((self.chr_upper as usize) << 6) | (self.ext_attr & 0x3F) as usize;
+                Ok(self.chr_byte((bank * 0x1000 + (addr as usize & 0x0FFF)) % self.chr_len()))
+            }
+            PpuFetch::Attribute => Ok(self.nametable_read(addr, vram)),
+            PpuFetch::Background | PpuFetch::Sprite => {
+                Ok(self.chr_byte(self.map_chr_addr(addr, self.fetch_set(kind))))
+            }
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, byte: u8, vram: &mut [u8]) -> Result<()> {
+        match addr {
+            0x0000..=0x1FFF if self.chr_rom.is_empty() => {
+                let offset = self.map_chr_addr(addr, self.data_set());
+                self.chr_ram[offset] = byte;
+            }
+            0x0000..=0x1FFF => return Err(ppu_rd_only(addr)),
+            0x2000..=0x3EFF => {
+                let offset = (addr & 0x3FF) as usize;
+                match (self.nametables >> ((addr >> 9) & 0x6)) & 3 {
+                    0 => vram[offset] = byte,
+                    1 => vram[0x400 | offset] = byte,
+                    2 => self.exram[offset] = byte,
+                    _ => {}
+                }
+            }
+            _ => return Err(ppu_inv_addr(addr)),
+        }
+        Ok(())
+    }
+
+    fn ppu_register_write(&mut self, addr: u16, byte: u8) {
+        if addr == 0x2000 {
+            self.large_sprites = byte & 0x20 != 0;
+        }
+    }
+
+    fn cpu_cycle(&mut self) {
+        self.idle_cycles = self.idle_cycles.saturating_add(1);
+        if self.idle_cycles >= IDLE_CYCLES {
+            self.in_frame = false;
+            self.last_fetch = 0;
+        }
+        self.clock_audio();
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending && self.irq_enabled
+    }
+
+    // The pulses use the same DAC curve as the APU's, the PCM channel roughly the DMC's
+    fn audio_output(&self) -> f32 {
+        let pulse = (self.pulse1.output() + self.pulse2.output()) as f32;
+        let pulse = if pulse == 0.0 { 0.0 } else { 95.52 / (8128.0 / pulse + 100.0) };
+        let pcm = self.pcm as f32 / 2.0;
+        let pcm = if pcm == 0.0 { 0.0 } else { 163.67 / (24329.0 / pcm + 100.0) };
+        pulse + pcm
+    }
+
+    fn nvram(&self) -> Option<&[u8]> {
+        (self.nvram_size > 0).then_some(&self.prg_ram[..self.nvram_size])
+    }
+
+    fn load_nvram(&mut self, data: &[u8]) {
+        let len = data.len().min(self.nvram_size);
+        self.prg_ram[..len].copy_from_slice(&data[..len]);
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.prg_ram);
+        w.bytes(&self.chr_ram);
+        w.bytes(&self.exram);
+        w.u8(self.prg_mode);
+        w.u8(self.chr_mode);
+        w.bytes(&self.prg_ram_protect);
+        w.u8(self.exram_mode);
+        w.u8(self.nametables);
+        w.u8(self.fill_tile);
+        w.u8(self.fill_attr);
+        w.u8(self.prg_ram_bank);
+        w.bytes(&self.prg_regs);
+        for bank in self.chr_a.iter().chain(self.chr_b.iter()) {
+            w.u16(*bank);
+        }
+        w.u8(self.chr_upper);
+        w.bool(self.last_set == ChrSet::B);
+        w.u8(self.split_mode);
+        w.u8(self.split_scroll);
+        w.u8(self.split_bank);
+        w.u8(self.irq_compare);
+        w.bool(self.irq_enabled);
+        w.bool(self.irq_pending);
+        w.u8(self.multiplicand);
+        w.u8(self.multiplier);
+        w.bool(self.large_sprites);
+        w.bool(self.in_frame);
+        w.u8(self.scanline);
+        w.u16(self.last_fetch);
+        w.u8(self.fetch_matches);
+        w.u8(self.idle_cycles);
+        w.u8(self.tile);
+        w.bool(self.sprite_fetched);
+        w.bool(self.split_tile);
+        w.u8(self.ext_attr);
+        self.pulse1.save_state(w);
+        self.pulse2.save_state(w);
+        w.u8(self.pcm);
+        w.bool(self.pcm_irq_enabled);
+        w.bool(self.odd_cycle);
+        w.u16(self.audio_cycles);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        r.bytes_into(&mut self.prg_ram)?;
+        r.bytes_into(&mut self.chr_ram)?;
+        r.bytes_into(&mut self.exram)?;
+        self.prg_mode = r.u8()?;
+        self.chr_mode = r.u8()?;
+        r.bytes_into(&mut self.prg_ram_protect)?;
+        self.exram_mode = r.u8()?;
+        self.nametables = r.u8()?;
+        self.fill_tile = r.u8()?;
+        self.fill_attr = r.u8()?;
+        self.prg_ram_bank = r.u8()?;
+        r.bytes_into(&mut self.prg_regs)?;
+        for bank in self.chr_a.iter_mut().chain(self.chr_b.iter_mut()) {
+            *bank = r.u16()?;
+        }
+        self.chr_upper = r.u8()?;
+        self.last_set = if r.bool()? { ChrSet::B } else { ChrSet::A };
+        self.split_mode = r.u8()?;
+        self.split_scroll = r.u8()?;
+        self.split_bank = r.u8()?;
+        self.irq_compare = r.u8()?;
+        self.irq_enabled = r.bool()?;
+        self.irq_pending = r.bool()?;
+        self.multiplicand = r.u8()?;
+        self.multiplier = r.u8()?;
+        self.large_sprites = r.bool()?;
+        self.in_frame = r.bool()?;
+        self.scanline = r.u8()?;
+        self.last_fetch = r.u16()?;
+        self.fetch_matches = r.u8()?;
+        self.idle_cycles = r.u8()?;
+        self.tile = r.u8()?;
+        self.sprite_fetched = r.bool()?;
+        self.split_tile = r.bool()?;
+        self.ext_attr = r.u8()?;
+        self.pulse1.load_state(r)?;
+        self.pulse2.load_state(r)?;
+        self.pcm = r.u8()?;
+        self.pcm_irq_enabled = r.bool()?;
+        self.odd_cycle = r.bool()?;
+        self.audio_cycles = r.u16()?;
+        // The windows are derived from the registers
+        self.update_prg_banks();
+        Ok(())
+    }
+}
+
+// Mirroring comes from $5105, not the header. The PRG RAM sizes are the NES 2.0 header's, with
+// both 0 (iNES 1) the board is assumed to have 32K, all of it battery backed if there's a battery.
+pub fn build_mmc5_cart(
+    prg_rom: &[u8],
+    chr_rom: &[u8],
+    prg_ram_size: usize,
+    prg_nvram_size: usize,
+    battery: bool,
+) -> Result<Cartridge> {
+    if prg_rom.is_empty() || !prg_rom.len().is_multiple_of(PRG_BANK_SIZE) {
+        return Err("MMC5 PRG ROM size must be a multiple of 8K".into());
+    }
+    let (ram_size, nvram_size) = match (prg_ram_size, prg_nvram_size) {
+        (0, 0) if battery => (DEFAULT_PRG_RAM_SIZE, DEFAULT_PRG_RAM_SIZE),
+        (0, 0) => (DEFAULT_PRG_RAM_SIZE, 0),
+        (ram, nvram) => (ram + nvram, nvram),
+    };
+    let mut cart = Mmc5 {
+        prg_rom: prg_rom.to_vec(),
+        prg_ram: vec![0; ram_size.next_multiple_of(PRG_BANK_SIZE)],
+        chr_rom: chr_rom.to_vec(),
+        chr_ram: [0; 8 * 1024],
+        exram: [0; 1024],
+        prg_mode: 3,
+        chr_mode: 3,
+        prg_ram_protect: [0; 2],
+        exram_mode: 0,
+        nametables: 0,
+        fill_tile: 0,
+        fill_attr: 0,
+        prg_ram_bank: 0,
+        prg_regs: [0xFF; 4],
+        chr_a: [0; 8],
+        chr_b: [0; 4],
+        chr_upper: 0,
+        last_set: ChrSet::A,
+        split_mode: 0,
+        split_scroll: 0,
+        split_bank: 0,
+        irq_compare: 0,
+        irq_enabled: false,
+        irq_pending: false,
+        multiplicand: 0xFF,
+        multiplier: 0xFF,
+        large_sprites: false,
+        in_frame: false,
+        scanline: 0,
+        last_fetch: 0,
+        fetch_matches: 0,
+        idle_cycles: 0,
+        tile: 0,
+        sprite_fetched: false,
+        split_tile: false,
+        ext_attr: 0,
+        pulse1: Pulse::new(PulseChannel::Mmc5),
+        pulse2: Pulse::new(PulseChannel::Mmc5),
+        pcm: 0,
+        pcm_irq_enabled: false,
+        odd_cycle: false,
+        audio_cycles: 0,
+        prg_windows: [PrgWindow::Rom(0); 4],
+        nvram_size,
+    };
+    cart.update_prg_banks();
+    Ok(Box::new(cart))
+}
+
+#[cfg(test)]
+mod mmc5_tests {
+    use super::build_mmc5_cart;
+    use crate::cart::cart::{Cartridge, PpuFetch};
+    use crate::cart::mock::{numbered_banks, numbered_roms};
+
+    // Each 8K of PRG and 1K of CHR is filled with its bank number
+    fn cart() -> Cartridge {
+        let (prg, chr) = numbered_roms(16, 128);
+        build_mmc5_cart(&prg, &chr, 0, 0, true).unwrap()
+    }
+
+    // Fetches like the PPU does for one scanline: tiles 2-33, the sprites, tiles 0 and 1 of the
+    // next line and the dummy nametable reads. Returns the (nametable, pattern) bytes of tiles
+    // 2-33.
+    fn render_line(cart: &mut Cartridge, vram: &[u8]) -> Vec<(u8, u8)> {
+        let mut tiles = vec![];
+        let tile = |cart: &mut Cartridge, x: u16| {
+            let nt = cart.ppu_fetch(0x2000 + x % 32, PpuFetch::Nametable, vram).unwrap();
+            cart.ppu_fetch(0x23C0 + (x % 32) / 4, PpuFetch::Attribute, vram).unwrap();
+            let lo = cart.ppu_fetch(0x1000 + nt as u16 * 16, PpuFetch::Background, vram).unwrap();
+            cart.ppu_fetch(0x1008 + nt as u16 * 16, PpuFetch::Background, vram).unwrap();
+            (nt, lo)
+        };
+        for x in 2..34 {
+            tiles.push(tile(cart, x));
+        }
+        for _ in 0..8 {
+            cart.ppu_fetch(0x1FF0, PpuFetch::Sprite, vram).unwrap();
+            cart.ppu_fetch(0x1FF8, PpuFetch::Sprite, vram).unwrap();
+        }
+        tile(cart, 0);
+        tile(cart, 1);
+        for _ in 0..3 {
+            cart.ppu_fetch(0x2002, PpuFetch::Nametable, vram).unwrap();
+        }
+        tiles
+    }
+
+    #[test]
+    fn prg_banking() {
+        let mut cart = cart();
+        // Mode 3 at power on, everything in the last bank
+        assert_eq!(cart.read(0x8000).unwrap(), 15);
+        cart.write(0x5114, 0x83).unwrap();
+        cart.write(0x5115, 0x84).unwrap();
+        cart.write(0x5116, 0x85).unwrap();
+        assert_eq!(cart.read(0x8000).unwrap(), 3);
+        assert_eq!(cart.read(0xA000).unwrap(), 4);
+        assert_eq!(cart.read(0xC000).unwrap(), 5);
+        assert_eq!(cart.read(0xE000).unwrap(), 15);
+
+        // 16K + 8K + 8K, the low bit of $5115 is ignored
+        cart.write(0x5100, 2).unwrap();
+        assert_eq!(cart.read(0x8000).unwrap(), 4);
+        assert_eq!(cart.read(0xA000).unwrap(), 5);
+        assert_eq!(cart.prg_rom_offset(0xA000), Some(5 * 8 * 1024));
+        // 32K
+        cart.write(0x5100, 0).unwrap();
+        cart.write(0x5117, 0x85).unwrap();
+        assert_eq!(cart.read(0x8000).unwrap(), 4);
+        assert_eq!(cart.read(0xE000).unwrap(), 7);
+
+        // RAM in a ROM window, only writable once unlocked
+        cart.write(0x5100, 3).unwrap();
+        cart.write(0x5114, 0x02).unwrap();
+        cart.write(0x8000, 0x42).unwrap();
+        assert_eq!(cart.read(0x8000).unwrap(), 0);
+        cart.write(0x5102, 0x02).unwrap();
+        cart.write(0x5103, 0x01).unwrap();
+        cart.write(0x8000, 0x42).unwrap();
+        assert_eq!(cart.read(0x8000).unwrap(), 0x42);
+        assert_eq!(cart.prg_rom_offset(0x8000), None);
+        cart.write(0x5113, 0x02).unwrap();
+        assert_eq!(cart.read(0x6000).unwrap(), 0x42);
+        assert_eq!(cart.nvram().unwrap()[2 * 8 * 1024], 0x42);
+
+        cart.write(0x5205, 200).unwrap();
+        cart.write(0x5206, 100).unwrap();
+        assert_eq!(cart.read(0x5205).unwrap(), (20000 & 0xFF) as u8);
+        assert_eq!(cart.read(0x5206).unwrap(), (20000 >> 8) as u8);
+    }
+
+    #[test]
+    fn chr_banking() {
+        let mut cart = cart();
+        let vram = [0; 2048];
+        // 1K banks
+        for i in 0..8 {
+            cart.write(0x5120 + i, 8 + i as u8).unwrap();
+        }
+        for i in 0..4 {
+            cart.write(0x5128 + i, 32 + i as u8).unwrap();
+        }
+        // 8x8 sprites, only $5120-$5127 are used even though $512B was written last
+        for kind in [PpuFetch::Background, PpuFetch::Sprite] {
+            assert_eq!(cart.ppu_fetch(0x1400, kind, &vram).unwrap(), 13);
+        }
+        assert_eq!(cart.ppu_read(0x0000, &vram).unwrap(), 8);
+        cart.ppu_register_write(0x2000, 0x20);
+        assert_eq!(cart.ppu_fetch(0x1400, PpuFetch::Sprite, &vram).unwrap(), 13);
+        assert_eq!(cart.ppu_fetch(0x1400, PpuFetch::Background, &vram).unwrap(), 33);
+        assert_eq!(cart.ppu_read(0x0000, &vram).unwrap(), 32);
+
+        // 2K banks use the odd registers
+        cart.write(0x5101, 2).unwrap();
+        assert_eq!(cart.ppu_fetch(0x0C00, PpuFetch::Sprite, &vram).unwrap(), 2 * 11 + 1);
+        assert_eq!(cart.ppu_fetch(0x1C00, PpuFetch::Background, &vram).unwrap(), 2 * 35 + 1);
+        // 8K, $5127 and $512B
+        cart.write(0x5101, 0).unwrap();
+        cart.write(0x5127, 2).unwrap();
+        assert_eq!(cart.ppu_fetch(0x1C00, PpuFetch::Sprite, &vram).unwrap(), 23);
+        assert_eq!(cart.chr_rom_offset(0x0010), Some(2 * 8 * 1024 + 0x10));
+    }
+
+    #[test]
+    fn nametables() {
+        let mut cart = cart();
+        let mut vram = [0; 2048];
+        // CIRAM 0, CIRAM 1, ExRAM, fill
+        cart.write(0x5105, 0b11_10_01_00).unwrap();
+        cart.write(0x5104, 2).unwrap();
+        cart.write(0x5C05, 0x33).unwrap();
+        cart.write(0x5106, 0x77).unwrap();
+        cart.write(0x5107, 0x02).unwrap();
+        cart.ppu_write(0x2005, 0x11, &mut vram).unwrap();
+        cart.ppu_write(0x2405, 0x22, &mut vram).unwrap();
+        assert_eq!(vram[0x005], 0x11);
+        assert_eq!(vram[0x405], 0x22);
+        // ExRAM only works as a nametable in modes 0 and 1
+        assert_eq!(cart.ppu_read(0x2805, &vram).unwrap(), 0);
+        cart.write(0x5104, 1).unwrap();
+        assert_eq!(cart.ppu_read(0x2805, &vram).unwrap(), 0x33);
+        assert_eq!(cart.ppu_read(0x2C05, &vram).unwrap(), 0x77);
+        assert_eq!(cart.ppu_read(0x2FC5, &vram).unwrap(), 0xAA);
+        // Not writable while the PPU isn't rendering
+        cart.write(0x5C05, 0x44).unwrap();
+        assert_eq!(cart.ppu_read(0x2805, &vram).unwrap(), 0);
+
+        // Extended attributes: palette and 4K CHR bank from the tile's ExRAM byte
+        cart.write(0x5104, 2).unwrap();
+        cart.write(0x5C03, 0xC5).unwrap();
+        cart.write(0x5104, 1).unwrap();
+        assert_eq!(cart.ppu_fetch(0x2003, PpuFetch::Nametable, &vram).unwrap(), 0);
+        assert_eq!(cart.ppu_fetch(0x23C0, PpuFetch::Attribute, &vram).unwrap(), 0xFF);
+        assert_eq!(cart.ppu_fetch(0x1000, PpuFetch::Background, &vram).unwrap(), 20);
+    }
+
+    #[test]
+    fn scanline_irq() {
+        let mut cart = cart();
+        let vram = [0; 2048];
+        cart.write(0x5203, 5).unwrap();
+        cart.write(0x5204, 0x80).unwrap();
+        // Pre-render line
+        render_line(&mut cart, &vram);
+        assert_eq!(cart.read(0x5204).unwrap(), 0x40);
+        for _ in 0..4 {
+            render_line(&mut cart, &vram);
+        }
+        assert!(!cart.irq());
+        render_line(&mut cart, &vram);
+        assert!(cart.irq());
+        assert_eq!(cart.peek(0x5204).unwrap(), 0xC0);
+        // Reading the status acknowledges it
+        assert_eq!(cart.read(0x5204).unwrap(), 0xC0);
+        assert!(!cart.irq());
+
+        // The PPU stopped reading
+        for _ in 0..3 {
+            cart.cpu_cycle();
+        }
+        assert_eq!(cart.read(0x5204).unwrap(), 0);
+
+        // An IRQ nobody acknowledged is dropped when the next frame starts
+        for _ in 0..6 {
+            render_line(&mut cart, &vram);
+        }
+        assert!(cart.irq());
+        for _ in 0..3 {
+            cart.cpu_cycle();
+        }
+        render_line(&mut cart, &vram);
+        assert!(!cart.irq());
+        assert_eq!(cart.peek(0x5204).unwrap(), 0x40);
+    }
+
+    #[test]
+    fn prg_ram_sizes() {
+        let prg = numbered_banks(8 * 1024, 4);
+        let unlock = |cart: &mut Cartridge| {
+            cart.write(0x5102, 0x02).unwrap();
+            cart.write(0x5103, 0x01).unwrap();
+        };
+
+        // NES 2.0 8K + 8K battery backed, two chips picked by bit 2 of the bank
+        let mut cart = build_mmc5_cart(&prg, &[], 8 * 1024, 8 * 1024, true).unwrap();
+        unlock(&mut cart);
+        cart.write(0x5113, 0x03).unwrap();
+        cart.write(0x6000, 0x11).unwrap();
+        cart.write(0x5113, 0x04).unwrap();
+        cart.write(0x6000, 0x22).unwrap();
+        cart.write(0x5113, 0x01).unwrap();
+        assert_eq!(cart.read(0x6000).unwrap(), 0x11);
+        let nvram = cart.nvram().unwrap();
+        assert_eq!(nvram.len(), 8 * 1024);
+        assert_eq!(nvram[0], 0x11);
+
+        // iNES 1 without a battery, 32K and no save
+        let cart = build_mmc5_cart(&prg, &[], 0, 0, false).unwrap();
+        assert!(cart.nvram().is_none());
+
+        // A single 8K chip shows up in every bank
+        let mut cart = build_mmc5_cart(&prg, &[], 8 * 1024, 0, false).unwrap();
+        unlock(&mut cart);
+        cart.write(0x5114, 0x05).unwrap();
+        cart.write(0x8000, 0x33).unwrap();
+        assert_eq!(cart.read(0x6000).unwrap(), 0x33);
+    }
+
+    #[test]
+    fn split() {
+        let mut cart = cart();
+        let mut vram = [0; 2048];
+        for (x, byte) in vram[..32].iter_mut().enumerate() {
+            *byte = 0x80 + x as u8;
+        }
+        cart.write(0x5104, 2).unwrap();
+        // Split rows 1 and 2 (scrolled down by 12)
+        for x in 0..64 {
+            cart.write(0x5C20 + x, 0x40 + x as u8).unwrap();
+        }
+        cart.write(0x5104, 0).unwrap();
+        cart.write(0x5128, 0).unwrap();
+        // Tiles 0-3 on the left, from 4K CHR bank 3
+        cart.write(0x5200, 0x84).unwrap();
+        cart.write(0x5201, 12).unwrap();
+        cart.write(0x5202, 3).unwrap();
+
+        render_line(&mut cart, &vram);
+        let line = render_line(&mut cart, &vram);
+        // Tiles $42 and $43 are in the second 1K of the bank
+        assert_eq!(line[0], (0x40 + 2, 13));
+        assert_eq!(line[1], (0x40 + 3, 13));
+        assert_eq!(line[2].0, 0x80 + 4);
+        assert_eq!(line[29].0, 0x80 + 31);
+        // 4 scanlines further down it's the next row
+        for _ in 0..3 {
+            render_line(&mut cart, &vram);
+        }
+        let line = render_line(&mut cart, &vram);
+        assert_eq!(line[0].0, 0x60 + 2);
+
+        // On the right from tile 30 on
+        cart.write(0x5200, 0xDE).unwrap();
+        let line = render_line(&mut cart, &vram);
+        assert_eq!(line[0].0, 0x80 + 2);
+        assert_eq!(line[28].0, 0x60 + 30);
+        assert_eq!(line[29].0, 0x60 + 31);
+    }
+
+    #[test]
+    fn audio() {
+        let mut cart = cart();
+        assert_eq!(cart.audio_output(), 0.0);
+        cart.write(0x5011, 0x80).unwrap();
+        let pcm = cart.audio_output();
+        assert!(pcm > 0.0);
+        // Constant volume 15, period below 8 still plays
+        cart.write(0x5015, 0x01).unwrap();
+        cart.write(0x5000, 0xBF).unwrap();
+        cart.write(0x5002, 0x04).unwrap();
+        cart.write(0x5003, 0x08).unwrap();
+        assert_eq!(cart.read(0x5015).unwrap(), 0x01);
+        let mut max: f32 = 0.0;
+        for _ in 0..100 {
+            cart.cpu_cycle();
+            max = max.max(cart.audio_output());
+        }
+        assert!(max - pcm > 0.1);
+    }
+}
@@ -0,0 +1,132 @@
+use crate::error::Result;
+use crate::state::{StateReader, StateWriter};
+use crate::{ines::parse::MirrorType, mem::error::inv_addr};
+
+use super::cart::{nametable_addr, ppu_inv_addr, ppu_rd_only, Cart, Cartridge};
+
+const PRG_BANK_SIZE: usize = 32 * 1024;
+const CHR_BANK_SIZE: usize = 8 * 1024;
+
+// GxROM: one register, bits 4-5 pick a 32K PRG bank and bits 0-1 an 8K CHR bank
+// https://www.nesdev.org/wiki/GxROM
+#[derive(Debug)]
+pub struct Gxrom {
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+    bank_select: u8,
+    mirror_type: MirrorType,
+}
+
+impl Gxrom {
+    fn map_chr_addr(&self, addr: u16) -> usize {
+        let banks = self.chr_rom.len() / CHR_BANK_SIZE;
+        ((self.bank_select & 0x03) as usize % banks) * CHR_BANK_SIZE + addr as usize
+    }
+}
+
+impl Cart for Gxrom {
+    fn name(&self) -> String {
+        "GxROM".into()
+    }
+
+    fn peek(&self, addr: u16) -> Result<u8> {
+        match self.prg_rom_offset(addr) {
+            Some(offset) => Ok(self.prg_rom[offset]),
+            None => Err(inv_addr(addr)),
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        let banks = self.prg_rom.len() / PRG_BANK_SIZE;
+        let base = ((self.bank_select >> 4) as usize & 0x03) % banks * PRG_BANK_SIZE;
+        (addr >= 0x8000).then(|| base + (addr - 0x8000) as usize)
+    }
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr < 0x2000).then(|| self.map_chr_addr(addr))
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) -> Result<()> {
+        match addr {
+            // Always has bus conflicts
+            0x8000..=0xFFFF => {
+                self.bank_select = byte & self.peek(addr)?;
+                Ok(())
+            }
+            _ => Err(inv_addr(addr)),
+        }
+    }
+
+    fn ppu_peek(&self, addr: u16, vram: &[u8]) -> Result<u8> {
+        match addr {
+            0x0000..=0x1FFF => Ok(self.chr_rom[self.map_chr_addr(addr)]),
+            0x2000..=0x3EFF => Ok(vram[nametable_addr(addr, self.mirror_type) as usize]),
+            _ => Err(ppu_inv_addr(addr)),
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, byte: u8, vram: &mut [u8]) -> Result<()> {
+        match addr {
+            0x0000..=0x1FFF => Err(ppu_rd_only(addr)),
+            0x2000..=0x3EFF => {
+                vram[nametable_addr(addr, self.mirror_type) as usize] = byte;
+                Ok(())
+            }
+            _ => Err(ppu_inv_addr(addr)),
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.bank_select);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.bank_select = r.u8()?;
+        Ok(())
+    }
+}
+
+pub fn build_gxrom(prg_rom: &[u8], chr_rom: &[u8], mirror_type: MirrorType) -> Result<Cartridge> {
+    if prg_rom.is_empty() || !prg_rom.len().is_multiple_of(PRG_BANK_SIZE) {
+        return Err("GxROM PRG ROM size must be a multiple of 32K".into());
+    }
+    if chr_rom.is_empty() || !chr_rom.len().is_multiple_of(CHR_BANK_SIZE) {
+        return Err("GxROM CHR ROM size must be a multiple of 8K".into());
+    }
+    Ok(Box::new(Gxrom {
+        prg_rom: prg_rom.to_vec(),
+        chr_rom: chr_rom.to_vec(),
+        bank_select: 0,
+        mirror_type,
+    }))
+}
+
+#[cfg(test)]
+mod gxrom_tests {
+    use super::build_gxrom;
+    use crate::cart::mock::numbered_banks;
+    use crate::ines::parse::MirrorType;
+
+    #[test]
+    fn banking() {
+        // Each PRG and CHR bank is filled with its own bank number, plus somewhere in bank 0 to
+        // write bank numbers through without a bus conflict
+        let mut prg = numbered_banks(32 * 1024, 4);
+        prg[1] = 0xFF;
+        let chr = numbered_banks(8 * 1024, 4);
+        let vram = [0; 2048];
+        let mut cart = build_gxrom(&prg, &chr, MirrorType::Horizontal).unwrap();
+        assert_eq!(cart.read(0x8000).unwrap(), 0);
+
+        // Bus conflict with the 0 at $8000
+        cart.write(0x8000, 0x21).unwrap();
+        assert_eq!(cart.read(0x8000).unwrap(), 0);
+        assert_eq!(cart.ppu_read(0x0000, &vram).unwrap(), 0);
+
+        cart.write(0x8001, 0x21).unwrap();
+        assert_eq!(cart.read(0x8000).unwrap(), 2);
+        assert_eq!(cart.ppu_read(0x1FFF, &vram).unwrap(), 1);
+        assert_eq!(cart.prg_rom_offset(0x8000), Some(2 * 32 * 1024));
+        assert_eq!(cart.chr_rom_offset(0x0010), Some(8 * 1024 + 0x10));
+    }
+}
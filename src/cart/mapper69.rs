@@ -0,0 +1,570 @@
+use crate::error::Result;
+use crate::state::{StateReader, StateWriter};
+use crate::{ines::parse::MirrorType, mem::error::inv_addr};
+
+use super::cart::{nametable_addr, ppu_inv_addr, ppu_rd_only, Cart, Cartridge};
+
+const PRG_BANK_SIZE: usize = 8 * 1024;
+const CHR_BANK_SIZE: usize = 1024;
+
+// The 5B runs its tone and envelope counters off the CPU clock divided by 16
+const AUDIO_DIVIDER: u8 = 16;
+
+// Sunsoft 5B audio, a licensed AY-3-8910: three square channels sharing one noise generator and
+// one envelope, with a logarithmic volume curve.
+// https://www.nesdev.org/wiki/Sunsoft_5B_audio
+#[derive(Debug)]
+struct Sunsoft5b {
+    regs: [u8; 16],
+    select: u8,
+    divider: u8,
+    tone_counters: [u16; 3],
+    tone_outputs: [bool; 3],
+    noise_counter: u8,
+    // The noise period counts at half the tone rate
+    noise_half: bool,
+    lfsr: u32,
+    envelope_counter: u16,
+    envelope_step: u8,
+    envelope_attack: bool,
+    envelope_holding: bool,
+}
+
+impl Sunsoft5b {
+    fn new() -> Self {
+        Self {
+            regs: [0; 16],
+            select: 0,
+            divider: 0,
+            tone_counters: [0; 3],
+            tone_outputs: [false; 3],
+            noise_counter: 0,
+            noise_half: false,
+            lfsr: 1,
+            envelope_counter: 0,
+            envelope_step: 0,
+            envelope_attack: false,
+            envelope_holding: false,
+        }
+    }
+
+    fn write(&mut self, byte: u8) {
+        let reg = self.select as usize;
+        self.regs[reg] = byte;
+        // Writing the shape restarts the envelope
+        if reg == 0xD {
+            self.envelope_counter = 0;
+            self.envelope_step = 0;
+            self.envelope_attack = byte & 0x4 != 0;
+            self.envelope_holding = false;
+        }
+    }
+
+    fn tone_period(&self, channel: usize) -> u16 {
+        let period =
+            self.regs[channel * 2] as u16 | ((self.regs[channel * 2 + 1] as u16 & 0xF) << 8);
+        period.max(1)
+    }
+
+    fn envelope_level(&self) -> u8 {
+        if self.envelope_attack {
+            self.envelope_step
+        } else {
+            15 - self.envelope_step
+        }
+    }
+
+    // Called once per CPU cycle
+    fn clock(&mut self) {
+        self.divider += 1;
+        if self.divider < AUDIO_DIVIDER {
+            return;
+        }
+        self.divider = 0;
+
+        for channel in 0..3 {
+            self.tone_counters[channel] += 1;
+            if self.tone_counters[channel] >= self.tone_period(channel) {
+                self.tone_counters[channel] = 0;
+                self.tone_outputs[channel] = !self.tone_outputs[channel];
+            }
+        }
+
+        self.noise_half = !self.noise_half;
+        if self.noise_half {
+            self.noise_counter += 1;
+            if self.noise_counter >= (self.regs[6] & 0x1F).max(1) {
+                self.noise_counter = 0;
+                let feedback = (self.lfsr ^ (self.lfsr >> 3)) & 1;
+                self.lfsr = (self.lfsr >> 1) | (feedback << 16);
+            }
+        }
+
+        let envelope_period = (self.regs[0xB] as u16 | ((self.regs[0xC] as u16) << 8)).max(1);
+        self.envelope_counter += 1;
+        if self.envelope_counter >= envelope_period {
+            self.envelope_counter = 0;
+            self.clock_envelope();
+        }
+    }
+
+    // Shape bits are CAtH: continue, attack, alternate, hold
+    fn clock_envelope(&mut self) {
+        if self.envelope_holding {
+            return;
+        }
+        if self.envelope_step < 15 {
+            self.envelope_step += 1;
+            return;
+        }
+        let shape = self.regs[0xD];
+        if shape & 0x8 == 0 {
+            // Drops to 0 and stays there
+            self.envelope_attack = false;
+            self.envelope_holding = true;
+        } else if shape & 0x1 != 0 {
+            // Holds the last level, or the opposite one when alternating
+            if shape & 0x2 != 0 {
+                self.envelope_attack = !self.envelope_attack;
+            }
+            self.envelope_holding = true;
+        } else {
+            if shape & 0x2 != 0 {
+                self.envelope_attack = !self.envelope_attack;
+            }
+            self.envelope_step = 0;
+        }
+    }
+
+    fn output(&self) -> f32 {
+        let noise = self.lfsr & 1 != 0;
+        let mut out = 0.0;
+        for channel in 0..3 {
+            let tone_off = self.regs[7] & (1 << channel) != 0;
+            let noise_off = self.regs[7] & (8 << channel) != 0;
+            if !((tone_off || self.tone_outputs[channel]) && (noise_off || noise)) {
+                continue;
+            }
+            let volume = self.regs[8 + channel];
+            let level = if volume & 0x10 != 0 {
+                self.envelope_level()
+            } else {
+                volume & 0xF
+            };
+            // 3dB per step
+            if level > 0 {
+                out += 10f32.powf((level as f32 - 15.0) * 3.0 / 20.0);
+            }
+        }
+        out
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.regs);
+        w.u8(self.select);
+        w.u8(self.divider);
+        for counter in self.tone_counters {
+            w.u16(counter);
+        }
+        for output in self.tone_outputs {
+            w.bool(output);
+        }
+        w.u8(self.noise_counter);
+        w.bool(self.noise_half);
+        w.u32(self.lfsr);
+        w.u16(self.envelope_counter);
+        w.u8(self.envelope_step);
+        w.bool(self.envelope_attack);
+        w.bool(self.envelope_holding);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        r.bytes_into(&mut self.regs)?;
+        self.select = r.u8()? & 0xF;
+        self.divider = r.u8()?;
+        for counter in self.tone_counters.iter_mut() {
+            *counter = r.u16()?;
+        }
+        for output in self.tone_outputs.iter_mut() {
+            *output = r.bool()?;
+        }
+        self.noise_counter = r.u8()?;
+        self.noise_half = r.bool()?;
+        self.lfsr = r.u32()?;
+        self.envelope_counter = r.u16()?;
+        self.envelope_step = r.u8()?;
+        self.envelope_attack = r.bool()?;
+        self.envelope_holding = r.bool()?;
+        Ok(())
+    }
+}
+
+// Sunsoft FME-7, mapper 69. A command register picks which bank/IRQ register the parameter
+// register writes to. The 5B is the same chip with audio added, FME-7 boards just never write
+// to the audio registers so both get it.
+// https://www.nesdev.org/wiki/Sunsoft_FME-7
+#[derive(Debug)]
+pub struct Fme7 {
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+    chr_ram: [u8; 8 * 1024],
+    prg_ram: [u8; 8 * 1024],
+    command: u8,
+    chr_banks: [u8; 8],
+    // $6000 bank: bit 7 RAM enable, bit 6 RAM instead of ROM, bits 0-5 ROM bank
+    prg_6000: u8,
+    prg_banks: [u8; 3],
+    mirror_type: MirrorType,
+    irq_enabled: bool,
+    counter_enabled: bool,
+    counter: u16,
+    irq_pending: bool,
+    audio: Sunsoft5b,
+    battery: bool,
+}
+
+impl Fme7 {
+    fn map_cpu_addr(&self, addr: u16) -> usize {
+        let banks = self.prg_rom.len() / PRG_BANK_SIZE;
+        let bank = match addr {
+            0x6000..=0x7FFF => (self.prg_6000 & 0x3F) as usize,
+            0x8000..=0xDFFF => self.prg_banks[(addr - 0x8000) as usize / PRG_BANK_SIZE] as usize,
+            _ => banks - 1,
+        };
+        (bank % banks) * PRG_BANK_SIZE + addr as usize % PRG_BANK_SIZE
+    }
+
+    fn map_chr_addr(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[addr as usize / CHR_BANK_SIZE] as usize;
+        (bank * CHR_BANK_SIZE + addr as usize % CHR_BANK_SIZE) % self.chr_len()
+    }
+
+    fn chr_len(&self) -> usize {
+        if self.chr_rom.is_empty() {
+            self.chr_ram.len()
+        } else {
+            self.chr_rom.len()
+        }
+    }
+
+    fn ram_selected(&self) -> bool {
+        self.prg_6000 & 0x40 != 0
+    }
+
+    fn write_parameter(&mut self, byte: u8) {
+        match self.command {
+            0x0..=0x7 => self.chr_banks[self.command as usize] = byte,
+            0x8 => self.prg_6000 = byte,
+            0x9..=0xB => self.prg_banks[(self.command - 0x9) as usize] = byte & 0x3F,
+            0xC => {
+                self.mirror_type = match byte & 3 {
+                    0 => MirrorType::Vertical,
+                    1 => MirrorType::Horizontal,
+                    2 => MirrorType::OneScreenLow,
+                    _ => MirrorType::OneScreenHigh,
+                }
+            }
+            0xD => {
+                self.irq_enabled = byte & 0x01 != 0;
+                self.counter_enabled = byte & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0xE => self.counter = (self.counter & 0xFF00) | byte as u16,
+            _ => self.counter = (self.counter & 0x00FF) | ((byte as u16) << 8),
+        }
+    }
+}
+
+impl Cart for Fme7 {
+    fn name(&self) -> String {
+        "FME-7".into()
+    }
+
+    fn peek(&self, addr: u16) -> Result<u8> {
+        match addr {
+            0x6000..=0x7FFF if !self.ram_selected() => Ok(self.prg_rom[self.map_cpu_addr(addr)]),
+            0x6000..=0x7FFF if self.prg_6000 & 0x80 != 0 => {
+                Ok(self.prg_ram[(addr - 0x6000) as usize])
+            }
+            // Open bus
+            0x6000..=0x7FFF => Ok(0),
+            0x8000..=0xFFFF => Ok(self.prg_rom[self.map_cpu_addr(addr)]),
+            _ => Err(inv_addr(addr)),
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x6000..=0x7FFF if !self.ram_selected() => Some(self.map_cpu_addr(addr)),
+            0x8000..=0xFFFF => Some(self.map_cpu_addr(addr)),
+            _ => None,
+        }
+    }
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr < 0x2000 && !self.chr_rom.is_empty()).then(|| self.map_chr_addr(addr))
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) -> Result<()> {
+        match addr {
+            0x6000..=0x7FFF => {
+                if self.prg_6000 & 0xC0 == 0xC0 {
+                    self.prg_ram[(addr - 0x6000) as usize] = byte;
+                }
+            }
+            0x8000..=0x9FFF => self.command = byte & 0xF,
+            0xA000..=0xBFFF => self.write_parameter(byte),
+            0xC000..=0xDFFF => self.audio.select = byte & 0xF,
+            0xE000..=0xFFFF => self.audio.write(byte),
+            _ => return Err(inv_addr(addr)),
+        }
+        Ok(())
+    }
+
+    fn ppu_peek(&self, addr: u16, vram: &[u8]) -> Result<u8> {
+        match addr {
+            0x0000..=0x1FFF if self.chr_rom.is_empty() => Ok(self.chr_ram[self.map_chr_addr(addr)]),
+            0x0000..=0x1FFF => Ok(self.chr_rom[self.map_chr_addr(addr)]),
+            0x2000..=0x3EFF => Ok(vram[nametable_addr(addr, self.mirror_type) as usize]),
+            _ => Err(ppu_inv_addr(addr)),
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, byte: u8, vram: &mut [u8]) -> Result<()> {
+        match addr {
+            0x0000..=0x1FFF if self.chr_rom.is_empty() => {
+                self.chr_ram[self.map_chr_addr(addr)] = byte;
+                Ok(())
+            }
+            0x0000..=0x1FFF => Err(ppu_rd_only(addr)),
+            0x2000..=0x3EFF => {
+                vram[nametable_addr(addr, self.mirror_type) as usize] = byte;
+                Ok(())
+            }
+            _ => Err(ppu_inv_addr(addr)),
+        }
+    }
+
+    // The counter decrements every CPU cycle and fires when it wraps from 0 to $FFFF
+    fn cpu_cycle(&mut self) {
+        if self.counter_enabled {
+            if self.counter == 0 && self.irq_enabled {
+                self.irq_pending = true;
+            }
+            self.counter = self.counter.wrapping_sub(1);
+        }
+        self.audio.clock();
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    // A full volume square comes out about as loud as one of the APU's pulses
+    fn audio_output(&self) -> f32 {
+        self.audio.output() * 0.15
+    }
+
+    fn nvram(&self) -> Option<&[u8]> {
+        self.battery.then_some(&self.prg_ram[..])
+    }
+
+    fn load_nvram(&mut self, data: &[u8]) {
+        let len = data.len().min(self.prg_ram.len());
+        self.prg_ram[..len].copy_from_slice(&data[..len]);
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.chr_ram);
+        w.bytes(&self.prg_ram);
+        w.u8(self.command);
+        w.bytes(&self.chr_banks);
+        w.u8(self.prg_6000);
+        w.bytes(&self.prg_banks);
+        w.u8(self.mirror_type as u8);
+        w.bool(self.irq_enabled);
+        w.bool(self.counter_enabled);
+        w.u16(self.counter);
+        w.bool(self.irq_pending);
+        self.audio.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        r.bytes_into(&mut self.chr_ram)?;
+        r.bytes_into(&mut self.prg_ram)?;
+        self.command = r.u8()? & 0xF;
+        r.bytes_into(&mut self.chr_banks)?;
+        self.prg_6000 = r.u8()?;
+        r.bytes_into(&mut self.prg_banks)?;
+        self.mirror_type =
+            MirrorType::try_from(r.u8()?).map_err(|_| "Invalid mirroring in save state")?;
+        self.irq_enabled = r.bool()?;
+        self.counter_enabled = r.bool()?;
+        self.counter = r.u16()?;
+        self.irq_pending = r.bool()?;
+        self.audio.load_state(r)
+    }
+}
+
+pub fn build_fme7_cart(prg_rom: &[u8], chr_rom: &[u8], battery: bool) -> Result<Cartridge> {
+    if prg_rom.is_empty() || !prg_rom.len().is_multiple_of(PRG_BANK_SIZE) {
+        return Err("FME-7 PRG ROM size must be a multiple of 8K".into());
+    }
+    Ok(Box::new(Fme7 {
+        prg_rom: prg_rom.to_vec(),
+        chr_rom: chr_rom.to_vec(),
+        chr_ram: [0; 8 * 1024],
+        prg_ram: [0; 8 * 1024],
+        command: 0,
+        chr_banks: [0; 8],
+        prg_6000: 0,
+        prg_banks: [0; 3],
+        mirror_type: MirrorType::Vertical,
+        irq_enabled: false,
+        counter_enabled: false,
+        counter: 0,
+        irq_pending: false,
+        audio: Sunsoft5b::new(),
+        battery,
+    }))
+}
+
+#[cfg(test)]
+mod fme7_tests {
+    use super::build_fme7_cart;
+    use crate::cart::mock::numbered_roms;
+
+    fn command(cart: &mut super::Cartridge, command: u8, byte: u8) {
+        cart.write(0x8000, command).unwrap();
+        cart.write(0xA000, byte).unwrap();
+    }
+
+    #[test]
+    fn banking() {
+        let (prg, chr) = numbered_roms(16, 64);
+        let mut vram = [0; 2048];
+        let mut cart = build_fme7_cart(&prg, &chr, true).unwrap();
+        command(&mut cart, 0x9, 3);
+        command(&mut cart, 0xA, 4);
+        command(&mut cart, 0xB, 5);
+        assert_eq!(cart.read(0x8000).unwrap(), 3);
+        assert_eq!(cart.read(0xA000).unwrap(), 4);
+        assert_eq!(cart.read(0xC000).unwrap(), 5);
+        assert_eq!(cart.read(0xE000).unwrap(), 15);
+
+        command(&mut cart, 0x7, 0x2A);
+        assert_eq!(cart.ppu_read(0x1C00, &vram).unwrap(), 0x2A);
+        assert_eq!(cart.chr_rom_offset(0x1C01), Some(0x2A * 1024 + 1));
+
+        // ROM at $6000
+        command(&mut cart, 0x8, 0x07);
+        assert_eq!(cart.read(0x6000).unwrap(), 7);
+        assert_eq!(cart.prg_rom_offset(0x6000), Some(7 * 8 * 1024));
+        // RAM, disabled then enabled
+        command(&mut cart, 0x8, 0x40);
+        cart.write(0x6000, 0x42).unwrap();
+        assert_eq!(cart.read(0x6000).unwrap(), 0);
+        command(&mut cart, 0x8, 0xC0);
+        cart.write(0x6000, 0x42).unwrap();
+        assert_eq!(cart.read(0x6000).unwrap(), 0x42);
+        assert_eq!(cart.nvram().unwrap()[0], 0x42);
+        assert_eq!(cart.prg_rom_offset(0x6000), None);
+
+        command(&mut cart, 0xC, 0x01);
+        cart.ppu_write(0x2400, 0x11, &mut vram).unwrap();
+        assert_eq!(vram[0], 0x11);
+    }
+
+    #[test]
+    fn irq() {
+        let (prg, chr) = numbered_roms(16, 64);
+        let mut cart = build_fme7_cart(&prg, &chr, false).unwrap();
+        command(&mut cart, 0xE, 0x02);
+        command(&mut cart, 0xF, 0x00);
+        command(&mut cart, 0xD, 0x81);
+        // 2, 1, then the wrap from 0
+        for _ in 0..2 {
+            cart.cpu_cycle();
+        }
+        assert!(!cart.irq());
+        cart.cpu_cycle();
+        assert!(cart.irq());
+        // Acknowledged by any write to the control register
+        command(&mut cart, 0xD, 0x81);
+        assert!(!cart.irq());
+        // The counter keeps going without IRQs when they're disabled
+        command(&mut cart, 0xD, 0x80);
+        for _ in 0..0x20000 {
+            cart.cpu_cycle();
+        }
+        assert!(!cart.irq());
+    }
+
+    fn audio(cart: &mut super::Cartridge, reg: u8, byte: u8) {
+        cart.write(0xC000, reg).unwrap();
+        cart.write(0xE000, byte).unwrap();
+    }
+
+    #[test]
+    fn tone() {
+        let (prg, chr) = numbered_roms(16, 64);
+        let mut cart = build_fme7_cart(&prg, &chr, false).unwrap();
+        // Everything disabled in the mixer is a constant output at the channel volume
+        audio(&mut cart, 7, 0x3F);
+        assert_eq!(cart.audio_output(), 0.0);
+        audio(&mut cart, 8, 0x0F);
+        assert!((cart.audio_output() - 0.15).abs() < 1e-6);
+        // One step down is 3dB quieter
+        audio(&mut cart, 8, 0x0E);
+        assert!((cart.audio_output() / 0.15 - 0.708).abs() < 1e-3);
+
+        // Tone A with period 2, toggling every 32 CPU cycles
+        audio(&mut cart, 8, 0x0F);
+        audio(&mut cart, 0, 0x02);
+        audio(&mut cart, 1, 0x00);
+        audio(&mut cart, 7, 0x3E);
+        let mut toggles = 0;
+        let mut last = cart.audio_output();
+        for _ in 0..32 * 8 {
+            cart.cpu_cycle();
+            if cart.audio_output() != last {
+                toggles += 1;
+                last = cart.audio_output();
+            }
+        }
+        assert_eq!(toggles, 8);
+    }
+
+    #[test]
+    fn envelope() {
+        let (prg, chr) = numbered_roms(16, 64);
+        let mut cart = build_fme7_cart(&prg, &chr, false).unwrap();
+        audio(&mut cart, 7, 0x3F);
+        audio(&mut cart, 8, 0x10);
+        // Period 1, attack and hold: ramps up over 16 steps and stays at full volume
+        audio(&mut cart, 0xB, 0x01);
+        audio(&mut cart, 0xC, 0x00);
+        audio(&mut cart, 0xD, 0x0D);
+        assert_eq!(cart.audio_output(), 0.0);
+        let mut last = 0.0;
+        for _ in 0..15 {
+            for _ in 0..16 {
+                cart.cpu_cycle();
+            }
+            assert!(cart.audio_output() > last);
+            last = cart.audio_output();
+        }
+        for _ in 0..16 * 20 {
+            cart.cpu_cycle();
+        }
+        assert!((cart.audio_output() - 0.15).abs() < 1e-6);
+
+        // Decay without continue: down to 0 and held
+        audio(&mut cart, 0xD, 0x00);
+        assert!((cart.audio_output() - 0.15).abs() < 1e-6);
+        for _ in 0..16 * 40 {
+            cart.cpu_cycle();
+        }
+        assert_eq!(cart.audio_output(), 0.0);
+    }
+}
@@ -0,0 +1,163 @@
+use crate::error::Result;
+use crate::state::{StateReader, StateWriter};
+use crate::{ines::parse::MirrorType, mem::error::inv_addr};
+
+use super::cart::{nametable_addr, ppu_inv_addr, ppu_rd_only, Cart, Cartridge};
+
+const PRG_BANK_SIZE: usize = 32 * 1024;
+
+// AxROM: 32K PRG banks and one-screen mirroring picked by the same register, 8K of CHR RAM
+// https://www.nesdev.org/wiki/AxROM
+#[derive(Debug)]
+pub struct Axrom {
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+    chr_ram: [u8; 8 * 1024],
+    prg_bank: u8,
+    mirror_type: MirrorType,
+    // Only AMROM and AN1ROM have them, AOROM games count on not having them
+    bus_conflicts: bool,
+}
+
+impl Cart for Axrom {
+    fn name(&self) -> String {
+        "AxROM".into()
+    }
+
+    fn peek(&self, addr: u16) -> Result<u8> {
+        match self.prg_rom_offset(addr) {
+            Some(offset) => Ok(self.prg_rom[offset]),
+            None => Err(inv_addr(addr)),
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        let banks = self.prg_rom.len() / PRG_BANK_SIZE;
+        let base = (self.prg_bank as usize % banks) * PRG_BANK_SIZE;
+        (addr >= 0x8000).then(|| base + (addr - 0x8000) as usize)
+    }
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr < 0x2000 && !self.chr_rom.is_empty()).then_some(addr as usize)
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) -> Result<()> {
+        match addr {
+            0x8000..=0xFFFF => {
+                let byte = if self.bus_conflicts {
+                    byte & self.peek(addr)?
+                } else {
+                    byte
+                };
+                self.prg_bank = byte & 0x0F;
+                self.mirror_type = if byte & 0x10 == 0 {
+                    MirrorType::OneScreenLow
+                } else {
+                    MirrorType::OneScreenHigh
+                };
+                Ok(())
+            }
+            _ => Err(inv_addr(addr)),
+        }
+    }
+
+    fn ppu_peek(&self, addr: u16, vram: &[u8]) -> Result<u8> {
+        match addr {
+            0x0000..=0x1FFF => {
+                if self.chr_rom.is_empty() {
+                    Ok(self.chr_ram[addr as usize])
+                } else {
+                    Ok(self.chr_rom[addr as usize])
+                }
+            }
+            0x2000..=0x3EFF => Ok(vram[nametable_addr(addr, self.mirror_type) as usize]),
+            _ => Err(ppu_inv_addr(addr)),
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, byte: u8, vram: &mut [u8]) -> Result<()> {
+        match addr {
+            0x0000..=0x1FFF => {
+                if self.chr_rom.is_empty() {
+                    self.chr_ram[addr as usize] = byte;
+                    Ok(())
+                } else {
+                    Err(ppu_rd_only(addr))
+                }
+            }
+            0x2000..=0x3EFF => {
+                vram[nametable_addr(addr, self.mirror_type) as usize] = byte;
+                Ok(())
+            }
+            _ => Err(ppu_inv_addr(addr)),
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.chr_ram);
+        w.u8(self.prg_bank);
+        w.bool(self.mirror_type == MirrorType::OneScreenHigh);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        r.bytes_into(&mut self.chr_ram)?;
+        self.prg_bank = r.u8()?;
+        self.mirror_type = if r.bool()? {
+            MirrorType::OneScreenHigh
+        } else {
+            MirrorType::OneScreenLow
+        };
+        Ok(())
+    }
+}
+
+// Submapper 2 is the boards with bus conflicts
+pub fn build_axrom(prg_rom: &[u8], chr_rom: &[u8], submapper: u8) -> Result<Cartridge> {
+    if prg_rom.is_empty() || !prg_rom.len().is_multiple_of(PRG_BANK_SIZE) {
+        return Err("AxROM PRG ROM size must be a multiple of 32K".into());
+    }
+    // Starts out in the last bank, like most of these boards power up
+    Ok(Box::new(Axrom {
+        prg_rom: prg_rom.to_vec(),
+        chr_rom: chr_rom.to_vec(),
+        chr_ram: [0; 8 * 1024],
+        prg_bank: (prg_rom.len() / PRG_BANK_SIZE - 1) as u8,
+        mirror_type: MirrorType::OneScreenLow,
+        bus_conflicts: submapper == 2,
+    }))
+}
+
+#[cfg(test)]
+mod axrom_tests {
+    use super::build_axrom;
+    use crate::cart::mock::numbered_banks;
+
+    #[test]
+    fn banking() {
+        // 8 PRG banks, each filled with its own bank number
+        let prg = numbered_banks(32 * 1024, 8);
+        let mut vram = [0; 2048];
+        let mut cart = build_axrom(&prg, &[], 0).unwrap();
+        assert_eq!(cart.read(0xFFFC).unwrap(), 7);
+
+        cart.write(0x8000, 0x03).unwrap();
+        assert_eq!(cart.read(0x8000).unwrap(), 3);
+        assert_eq!(cart.prg_rom_offset(0xFFFF), Some(4 * 32 * 1024 - 1));
+        // One screen, all four nametables are the same 1K
+        cart.ppu_write(0x2000, 0x11, &mut vram).unwrap();
+        assert_eq!(cart.ppu_read(0x2C00, &vram).unwrap(), 0x11);
+        cart.write(0x8000, 0x10).unwrap();
+        assert_eq!(cart.ppu_read(0x2000, &vram).unwrap(), 0);
+        cart.ppu_write(0x2400, 0x22, &mut vram).unwrap();
+        assert_eq!(vram[0x400], 0x22);
+
+        // CHR RAM
+        cart.ppu_write(0x1234, 0x56, &mut vram).unwrap();
+        assert_eq!(cart.ppu_read(0x1234, &vram).unwrap(), 0x56);
+
+        // Bus conflicts with the bank 7 in ROM
+        let mut cart = build_axrom(&prg, &[], 2).unwrap();
+        cart.write(0x8000, 0x12).unwrap();
+        assert_eq!(cart.read(0x8000).unwrap(), 2);
+    }
+}
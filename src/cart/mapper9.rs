@@ -0,0 +1,310 @@
+use crate::error::Result;
+use crate::state::{StateReader, StateWriter};
+use crate::{ines::parse::MirrorType, mem::error::inv_addr};
+
+use super::cart::{nametable_addr, ppu_inv_addr, ppu_rd_only, Cart, Cartridge};
+
+const CHR_BANK_SIZE: usize = 4 * 1024;
+
+// MMC2 (PxROM) and MMC4 (FxROM), mapper 9 and 10. Each 4K pattern table has two CHR banks and a
+// latch picking between them, which flips when the PPU reads tile $FD or $FE from that table.
+// MMC4 switches 16K of PRG instead of 8K and its boards have PRG RAM.
+// https://www.nesdev.org/wiki/MMC2
+// https://www.nesdev.org/wiki/MMC4
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Variant {
+    Mmc2,
+    Mmc4,
+}
+
+#[derive(Debug)]
+pub struct Mmc2 {
+    variant: Variant,
+    prg_ram: [u8; 8 * 1024],
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+    prg_bank: u8,
+    // [$FD, $FE] banks for $0000 and for $1000
+    chr_banks: [[u8; 2]; 2],
+    // Per pattern table, false for $FD and true for $FE
+    latches: [bool; 2],
+    mirror_type: MirrorType,
+    battery: bool,
+}
+
+impl Mmc2 {
+    fn prg_bank_size(&self) -> usize {
+        match self.variant {
+            Variant::Mmc2 => 8 * 1024,
+            Variant::Mmc4 => 16 * 1024,
+        }
+    }
+
+    fn map_cpu_addr(&self, addr: u16) -> usize {
+        let size = self.prg_bank_size();
+        let banks = self.prg_rom.len() / size;
+        let window = (addr - 0x8000) as usize / size;
+        // Only the first window switches, the rest are the last banks
+        let bank = if window == 0 {
+            self.prg_bank as usize % banks
+        } else {
+            banks - (0x8000 / size) + window
+        };
+        bank * size + (addr as usize % size)
+    }
+
+    fn map_chr_addr(&self, addr: u16) -> usize {
+        let table = (addr >> 12) as usize & 1;
+        let banks = self.chr_rom.len() / CHR_BANK_SIZE;
+        let bank = self.chr_banks[table][self.latches[table] as usize] as usize % banks;
+        bank * CHR_BANK_SIZE + (addr as usize % CHR_BANK_SIZE)
+    }
+
+    fn update_latches(&mut self, addr: u16) {
+        let table = (addr >> 12) as usize & 1;
+        // MMC2 only looks at the exact address for the $0000 table
+        let tile_addr = if self.variant == Variant::Mmc2 && table == 0 {
+            addr & 0x1FFF
+        } else {
+            addr & 0x1FF8
+        };
+        match tile_addr & 0x0FFF {
+            0x0FD8 => self.latches[table] = false,
+            0x0FE8 => self.latches[table] = true,
+            _ => {}
+        }
+    }
+}
+
+impl Cart for Mmc2 {
+    fn name(&self) -> String {
+        match self.variant {
+            Variant::Mmc2 => "MMC2".into(),
+            Variant::Mmc4 => "MMC4".into(),
+        }
+    }
+
+    fn peek(&self, addr: u16) -> Result<u8> {
+        match addr {
+            0x6000..=0x7FFF if self.variant == Variant::Mmc4 => {
+                Ok(self.prg_ram[(addr - 0x6000) as usize])
+            }
+            // Open bus
+            0x6000..=0x7FFF => Ok(0),
+            0x8000..=0xFFFF => Ok(self.prg_rom[self.map_cpu_addr(addr)]),
+            _ => Err(inv_addr(addr)),
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr >= 0x8000).then(|| self.map_cpu_addr(addr))
+    }
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        (addr < 0x2000).then(|| self.map_chr_addr(addr))
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) -> Result<()> {
+        match addr {
+            0x6000..=0x7FFF => {
+                if self.variant == Variant::Mmc4 {
+                    self.prg_ram[(addr - 0x6000) as usize] = byte;
+                }
+                Ok(())
+            }
+            0x8000..=0x9FFF => Ok(()),
+            0xA000..=0xAFFF => {
+                self.prg_bank = byte & 0x0F;
+                Ok(())
+            }
+            0xB000..=0xEFFF => {
+                let reg = (addr - 0xB000) as usize >> 12;
+                self.chr_banks[reg / 2][reg % 2] = byte & 0x1F;
+                Ok(())
+            }
+            0xF000..=0xFFFF => {
+                self.mirror_type = if byte & 1 == 0 {
+                    MirrorType::Vertical
+                } else {
+                    MirrorType::Horizontal
+                };
+                Ok(())
+            }
+            _ => Err(inv_addr(addr)),
+        }
+    }
+
+    fn ppu_peek(&self, addr: u16, vram: &[u8]) -> Result<u8> {
+        match addr {
+            0x0000..=0x1FFF => Ok(self.chr_rom[self.map_chr_addr(addr)]),
+            0x2000..=0x3EFF => Ok(vram[nametable_addr(addr, self.mirror_type) as usize]),
+            _ => Err(ppu_inv_addr(addr)),
+        }
+    }
+
+    // The tile that flips the latch is still read from the old bank
+    fn ppu_read(&mut self, addr: u16, vram: &[u8]) -> Result<u8> {
+        let byte = self.ppu_peek(addr, vram)?;
+        if addr < 0x2000 {
+            self.update_latches(addr);
+        }
+        Ok(byte)
+    }
+
+    fn ppu_write(&mut self, addr: u16, byte: u8, vram: &mut [u8]) -> Result<()> {
+        match addr {
+            0x0000..=0x1FFF => Err(ppu_rd_only(addr)),
+            0x2000..=0x3EFF => {
+                vram[nametable_addr(addr, self.mirror_type) as usize] = byte;
+                Ok(())
+            }
+            _ => Err(ppu_inv_addr(addr)),
+        }
+    }
+
+    fn nvram(&self) -> Option<&[u8]> {
+        self.battery.then_some(&self.prg_ram[..])
+    }
+
+    fn load_nvram(&mut self, data: &[u8]) {
+        let len = data.len().min(self.prg_ram.len());
+        self.prg_ram[..len].copy_from_slice(&data[..len]);
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.prg_ram);
+        w.u8(self.prg_bank);
+        w.bytes(self.chr_banks.as_flattened());
+        w.bool(self.latches[0]);
+        w.bool(self.latches[1]);
+        w.u8(self.mirror_type as u8);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        r.bytes_into(&mut self.prg_ram)?;
+        self.prg_bank = r.u8()?;
+        r.bytes_into(self.chr_banks.as_flattened_mut())?;
+        self.latches = [r.bool()?, r.bool()?];
+        self.mirror_type =
+            MirrorType::try_from(r.u8()?).map_err(|_| "Invalid mirroring in save state")?;
+        Ok(())
+    }
+}
+
+fn build_cart(
+    variant: Variant,
+    prg_rom: &[u8],
+    chr_rom: &[u8],
+    mirror_type: MirrorType,
+    battery: bool,
+) -> Result<Cartridge> {
+    let cart = Mmc2 {
+        variant,
+        prg_ram: [0; 8 * 1024],
+        prg_rom: prg_rom.to_vec(),
+        chr_rom: chr_rom.to_vec(),
+        prg_bank: 0,
+        chr_banks: [[0; 2]; 2],
+        latches: [true; 2],
+        mirror_type,
+        battery: battery && variant == Variant::Mmc4,
+    };
+    if prg_rom.len() < 0x8000 || !prg_rom.len().is_multiple_of(cart.prg_bank_size()) {
+        return Err(format!("Unsupported PRG ROM size for {}", cart.name()).into());
+    }
+    if chr_rom.is_empty() || !chr_rom.len().is_multiple_of(CHR_BANK_SIZE) {
+        return Err(format!("{} CHR ROM size must be a multiple of 4K", cart.name()).into());
+    }
+    Ok(Box::new(cart))
+}
+
+pub fn build_mmc2_cart(prg_rom: &[u8], chr_rom: &[u8], mirror_type: MirrorType) -> Result<Cartridge> {
+    build_cart(Variant::Mmc2, prg_rom, chr_rom, mirror_type, false)
+}
+
+pub fn build_mmc4_cart(
+    prg_rom: &[u8],
+    chr_rom: &[u8],
+    mirror_type: MirrorType,
+    battery: bool,
+) -> Result<Cartridge> {
+    build_cart(Variant::Mmc4, prg_rom, chr_rom, mirror_type, battery)
+}
+
+#[cfg(test)]
+mod mmc2_tests {
+    use super::{build_mmc2_cart, build_mmc4_cart};
+    use crate::cart::mock::numbered_banks;
+    use crate::ines::parse::MirrorType;
+
+    // Each 4K CHR bank filled with its own bank number
+    fn chr() -> Vec<u8> {
+        numbered_banks(4 * 1024, 32)
+    }
+
+    // Each 8K PRG bank filled with its own bank number
+    fn prg(banks: u8) -> Vec<u8> {
+        numbered_banks(8 * 1024, banks as usize)
+    }
+
+    #[test]
+    fn latches() {
+        let vram = [0; 2048];
+        let mut cart = build_mmc2_cart(&prg(16), &chr(), MirrorType::Vertical).unwrap();
+        cart.write(0xB000, 1).unwrap(); // $0000, $FD
+        cart.write(0xC000, 2).unwrap(); // $0000, $FE
+        cart.write(0xD000, 3).unwrap(); // $1000, $FD
+        cart.write(0xE000, 4).unwrap(); // $1000, $FE
+        assert_eq!(cart.ppu_read(0x0000, &vram).unwrap(), 2);
+        assert_eq!(cart.ppu_read(0x1000, &vram).unwrap(), 4);
+
+        // Tile $FD flips the latch after it's been read
+        assert_eq!(cart.ppu_read(0x0FD8, &vram).unwrap(), 2);
+        assert_eq!(cart.ppu_read(0x0000, &vram).unwrap(), 1);
+        // MMC2 only flips the $0000 latch on the first byte of the tile
+        cart.ppu_read(0x0FE9, &vram).unwrap();
+        assert_eq!(cart.ppu_peek(0x0000, &vram).unwrap(), 1);
+        cart.ppu_read(0x0FE8, &vram).unwrap();
+        assert_eq!(cart.ppu_peek(0x0000, &vram).unwrap(), 2);
+        // But any byte of it for $1000, the other table's latch doesn't change
+        cart.ppu_read(0x1FDF, &vram).unwrap();
+        assert_eq!(cart.ppu_peek(0x1000, &vram).unwrap(), 3);
+        assert_eq!(cart.ppu_peek(0x0000, &vram).unwrap(), 2);
+        assert_eq!(cart.chr_rom_offset(0x1010), Some(3 * 4 * 1024 + 0x10));
+        // Peeking doesn't touch the latches
+        cart.ppu_peek(0x1FE8, &vram).unwrap();
+        assert_eq!(cart.ppu_peek(0x1000, &vram).unwrap(), 3);
+
+        // 8K switchable at $8000, the last three fixed
+        cart.write(0xA000, 5).unwrap();
+        assert_eq!(cart.read(0x8000).unwrap(), 5);
+        assert_eq!(cart.read(0xA000).unwrap(), 13);
+        assert_eq!(cart.read(0xFFFF).unwrap(), 15);
+    }
+
+    #[test]
+    fn mmc4() {
+        let vram = [0; 2048];
+        let mut cart = build_mmc4_cart(&prg(16), &chr(), MirrorType::Vertical, true).unwrap();
+        // 16K switchable at $8000, the last 16K fixed
+        cart.write(0xA000, 2).unwrap();
+        assert_eq!(cart.read(0x8000).unwrap(), 4);
+        assert_eq!(cart.read(0xBFFF).unwrap(), 5);
+        assert_eq!(cart.read(0xC000).unwrap(), 14);
+        assert_eq!(cart.prg_rom_offset(0xC000), Some(14 * 8 * 1024));
+
+        // Any byte of the tile flips either latch
+        cart.write(0xB000, 1).unwrap();
+        cart.ppu_read(0x0FDC, &vram).unwrap();
+        assert_eq!(cart.ppu_peek(0x0000, &vram).unwrap(), 1);
+
+        cart.write(0x6000, 0x42).unwrap();
+        assert_eq!(cart.read(0x6000).unwrap(), 0x42);
+        assert_eq!(cart.nvram().unwrap()[0], 0x42);
+
+        cart.write(0xF000, 1).unwrap();
+        let mut vram = [0; 2048];
+        cart.ppu_write(0x2400, 0x11, &mut vram).unwrap();
+        assert_eq!(vram[0], 0x11);
+    }
+}
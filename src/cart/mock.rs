@@ -1,4 +1,5 @@
 use crate::mem::error::MemoryError;
+use crate::state::{StateReader, StateWriter};
 
 use super::cart::{Cart, Cartridge};
 
@@ -10,22 +11,40 @@ impl Cart for MockCartridge {
         "Mock Cartridge".into()
     }
 
-    fn read(&mut self, addr: u16) -> crate::error::Result<u8> {
+    fn peek(&self, addr: u16) -> crate::error::Result<u8> {
         Err(Box::new(MemoryError::InvalidAddress(addr)))
     }
 
     fn write(&mut self, addr: u16, _byte: u8) -> crate::error::Result<()> {
         Err(Box::new(MemoryError::InvalidAddress(addr)))
     }
-    fn ppu_read(&self, addr: u16, _vram: &[u8]) -> crate::error::Result<u8> {
+    fn ppu_peek(&self, addr: u16, _vram: &[u8]) -> crate::error::Result<u8> {
         Err(Box::new(MemoryError::InvalidAddress(addr)))
     }
 
     fn ppu_write(&mut self, addr: u16, _byte: u8, _vram: &mut [u8]) -> crate::error::Result<()> {
         Err(Box::new(MemoryError::InvalidAddress(addr)))
     }
+
+    fn save_state(&self, _w: &mut StateWriter) {}
+
+    fn load_state(&mut self, _r: &mut StateReader) -> crate::error::Result<()> {
+        Ok(())
+    }
 }
 
 pub fn mock_cart() -> Cartridge {
     Box::new(MockCartridge {})
 }
+
+// Test ROM made of `count` banks of `size` bytes, each filled with its own bank number
+#[cfg(test)]
+pub fn numbered_banks(size: usize, count: usize) -> Vec<u8> {
+    (0..count).flat_map(|b| vec![b as u8; size]).collect()
+}
+
+// 8K PRG banks and 1K CHR banks, numbered like `numbered_banks`
+#[cfg(test)]
+pub fn numbered_roms(prg_banks: usize, chr_banks: usize) -> (Vec<u8>, Vec<u8>) {
+    (numbered_banks(8 * 1024, prg_banks), numbered_banks(1024, chr_banks))
+}
@@ -0,0 +1,144 @@
+use crate::error::Result;
+use crate::state::{StateReader, StateWriter};
+
+// PPU dots per scanline, the prescaler counts them down 3 per CPU cycle
+const PRESCALER_PERIOD: i16 = 341;
+
+// IRQ counter shared by Konami's VRC4, VRC6 and VRC7. It counts CPU cycles, either directly or
+// divided down to roughly one clock per scanline, and fires when it overflows.
+// https://www.nesdev.org/wiki/VRC_IRQ
+#[derive(Debug, Default)]
+pub struct VrcIrq {
+    latch: u8,
+    counter: u8,
+    prescaler: i16,
+    enabled: bool,
+    enable_after_ack: bool,
+    cycle_mode: bool,
+    pending: bool,
+}
+
+impl VrcIrq {
+    pub fn write_latch(&mut self, byte: u8) {
+        self.latch = byte;
+    }
+
+    // VRC4 takes the latch 4 bits at a time
+    pub fn write_latch_low(&mut self, byte: u8) {
+        self.latch = (self.latch & 0xF0) | (byte & 0x0F);
+    }
+
+    pub fn write_latch_high(&mut self, byte: u8) {
+        self.latch = (self.latch & 0x0F) | (byte << 4);
+    }
+
+    // ---- -MEA: mode (1 for CPU cycles), enable, enable again after acknowledging
+    pub fn write_control(&mut self, byte: u8) {
+        self.enable_after_ack = byte & 0x1 != 0;
+        self.enabled = byte & 0x2 != 0;
+        self.cycle_mode = byte & 0x4 != 0;
+        self.pending = false;
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = PRESCALER_PERIOD;
+        }
+    }
+
+    pub fn acknowledge(&mut self) {
+        self.pending = false;
+        self.enabled = self.enable_after_ack;
+    }
+
+    // Called once per CPU cycle
+    pub fn clock(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if self.cycle_mode {
+            self.clock_counter();
+        } else {
+            self.prescaler -= 3;
+            if self.prescaler <= 0 {
+                self.prescaler += PRESCALER_PERIOD;
+                self.clock_counter();
+            }
+        }
+    }
+
+    fn clock_counter(&mut self) {
+        if self.counter == 0xFF {
+            self.counter = self.latch;
+            self.pending = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+
+    pub fn pending(&self) -> bool {
+        self.pending
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.latch);
+        w.u8(self.counter);
+        w.u16(self.prescaler as u16);
+        w.bool(self.enabled);
+        w.bool(self.enable_after_ack);
+        w.bool(self.cycle_mode);
+        w.bool(self.pending);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.latch = r.u8()?;
+        self.counter = r.u8()?;
+        self.prescaler = r.u16()? as i16;
+        self.enabled = r.bool()?;
+        self.enable_after_ack = r.bool()?;
+        self.cycle_mode = r.bool()?;
+        self.pending = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod vrc_irq_tests {
+    use super::VrcIrq;
+
+    #[test]
+    fn modes() {
+        let mut irq = VrcIrq::default();
+        // CPU cycle mode, 4 cycles until the overflow
+        irq.write_latch(0xFC);
+        irq.write_control(0x07);
+        for _ in 0..3 {
+            irq.clock();
+        }
+        assert!(!irq.pending());
+        irq.clock();
+        assert!(irq.pending());
+        // Enabled again right away, and the counter was reloaded
+        irq.acknowledge();
+        assert!(!irq.pending());
+        for _ in 0..4 {
+            irq.clock();
+        }
+        assert!(irq.pending());
+
+        // Scanline mode, 2 scanlines is 227 or 228 CPU cycles
+        irq.write_latch_low(0x0E);
+        irq.write_latch_high(0x0F);
+        irq.write_control(0x02);
+        for _ in 0..227 {
+            irq.clock();
+        }
+        assert!(!irq.pending());
+        irq.clock();
+        assert!(irq.pending());
+        // Not enabled again after acknowledging
+        irq.acknowledge();
+        for _ in 0..1000 {
+            irq.clock();
+        }
+        assert!(!irq.pending());
+    }
+}
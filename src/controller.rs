@@ -2,6 +2,9 @@ use std::{rc::Rc, cell::RefCell, sync::{Arc, Mutex}};
 
 use bitflags::bitflags;
 
+use crate::error::Result;
+use crate::state::{StateReader, StateWriter};
+
 bitflags! {
     pub struct Inputs: u8 {
         const A      = (1 << 0);
@@ -32,16 +35,33 @@ impl Controller {
     }
 
     pub fn read(&mut self) -> u8 {
-        let bit = self.read_state & 1;
+        let bit = self.peek();
         self.read_state >>= 1;
         bit
     }
 
+    // Next bit read() returns, without shifting
+    pub fn peek(&self) -> u8 {
+        self.read_state & 1
+    }
+
     pub fn write(&mut self, value: u8) {
         self.strobe = (value & 1) != 0;
         self.read_state = self.inputs.bits;
     }
 
+    // Only the shift register, the buttons being held down stay as they are
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.read_state);
+        w.bool(self.strobe);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.read_state = r.u8()?;
+        self.strobe = r.bool()?;
+        Ok(())
+    }
+
     pub fn clear(&mut self) {
         self.inputs.bits = 0;
     }
@@ -2,6 +2,6 @@ pub mod cpu;
 pub mod reg;
 pub mod isa;
 pub mod decode;
+pub mod trace;
 mod exec;
-mod deref;
 mod utils;
\ No newline at end of file
@@ -1,17 +1,19 @@
-use sdl2::pixels::Color;
-
-use super::decode::fetch_instr;
-use super::exec::{exec_instr, push_stack, push_stack_addr};
+use super::decode::decode_instr;
+use super::exec::{begin_instr, exec_cycle, ExecState};
 use super::isa::Instr;
 use super::reg::{Registers, StatusFlags};
+use super::trace::TraceLogger;
+use crate::apu::sink::AudioSink;
 use crate::cart::cart::Cartridge;
 use crate::controller::ControllerRef;
 use crate::error::Result;
-use crate::graphics::graphics::CpuInfo;
+use crate::mem::access::{AddressSpace, MemAccess};
+use crate::mem::cdl::{CdlRef, PrgFlags};
 use crate::mem::bus::MemoryBus;
 use crate::mem::bus::MemoryBusBuilder;
 use crate::mem::utils::make_address;
-use crate::ppu::ppu::{Frame, OamSprite, PatternTable};
+use crate::ppu::ppu::{Frame, NametableView, OamSprite, PatternTable};
+use crate::state::{StateReader, StateWriter, STATE_MAGIC, STATE_VERSION};
 
 pub const STACK_OFFSET: u16 = 0x100;
 
@@ -30,26 +32,119 @@ impl Interrupt {
             Self::Request => 0xFFFE,
         }
     }
-    pub fn num_cycles(&self) -> u16 {
-        match self {
-            Self::NonMaskable | Self::Reset => 8,
-            Self::Request => 7,
+}
+
+const NUM_TICKS_PER_CPU_CYCLE: u16 = 3;
+// Cycles the CPU is halted for while the DMC fetches a sample byte
+const DMC_DMA_CYCLES: u16 = 4;
+// Cycles a DMC fetch adds to an OAM DMA, it reads on one of the DMA's get cycles and then
+// realigns. https://www.nesdev.org/wiki/DMA
+const DMC_DMA_OAM_CYCLES: u16 = 2;
+// Cycles the reset sequence takes
+const RESET_CYCLES: u16 = 7;
+
+// The interrupt inputs and what the CPU sampled from them. They're polled at the end of every
+// cycle, but the CPU acts on what was polled during the second-to-last cycle of an instruction.
+// https://www.nesdev.org/wiki/CPU_interrupts
+#[derive(Debug, Default, Clone, Copy)]
+pub(super) struct InterruptLines {
+    nmi_line: bool,        // NMI input last cycle, NMIs trigger on its rising edge
+    pub need_nmi: bool,    // Edge detected, waiting to be serviced
+    pub prev_need_nmi: bool,
+    pub delay_nmi: bool,   // Hide need_nmi from the next poll (taken branches)
+    irq_line: bool,        // IRQ input, held while any source (APU, DMC, mapper) asserts it
+    pub run_irq: bool,     // IRQ asserted and not masked
+    pub prev_run_irq: bool,
+}
+
+impl InterruptLines {
+    fn poll(&mut self, nmi_line: bool, interrupts_enabled: bool) {
+        self.prev_need_nmi = self.need_nmi && !self.delay_nmi;
+        self.delay_nmi = false;
+        if nmi_line && !self.nmi_line {
+            self.need_nmi = true;
+        }
+        self.nmi_line = nmi_line;
+
+        self.prev_run_irq = self.run_irq;
+        self.run_irq = self.irq_line && interrupts_enabled;
+    }
+
+    // Whether the instruction that just finished is followed by an interrupt
+    fn pending(&self) -> bool {
+        self.prev_need_nmi || self.prev_run_irq
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        for line in [
+            self.nmi_line,
+            self.need_nmi,
+            self.prev_need_nmi,
+            self.delay_nmi,
+            self.irq_line,
+            self.run_irq,
+            self.prev_run_irq,
+        ] {
+            w.bool(line);
         }
     }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.nmi_line = r.bool()?;
+        self.need_nmi = r.bool()?;
+        self.prev_need_nmi = r.bool()?;
+        self.delay_nmi = r.bool()?;
+        self.irq_line = r.bool()?;
+        self.run_irq = r.bool()?;
+        self.prev_run_irq = r.bool()?;
+        Ok(())
+    }
 }
 
-const NUM_TICKS_PER_CPU_CYCLE: u16 = 3;
+// Copy of a page to OAM started by writing $4014. After halting the CPU (and an alignment
+// cycle if needed) it reads a byte on every even cycle and writes it to OAM on the next one.
+#[derive(Debug, Default, Clone, Copy)]
+struct OamDma {
+    page: Option<u8>, // Page being copied, None if there's no DMA running
+    halted: bool,     // Whether the halt cycle has gone by
+    index: u8,        // Next byte to copy
+    latch: Option<u8>, // Byte from the last get cycle, waiting for its put cycle
+}
+
+impl OamDma {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.page.is_some());
+        w.u8(self.page.unwrap_or_default());
+        w.bool(self.halted);
+        w.u8(self.index);
+        w.bool(self.latch.is_some());
+        w.u8(self.latch.unwrap_or_default());
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        let running = r.bool()?;
+        self.page = Some(r.u8()?).filter(|_| running);
+        self.halted = r.bool()?;
+        self.index = r.u8()?;
+        let latched = r.bool()?;
+        self.latch = Some(r.u8()?).filter(|_| latched);
+        Ok(())
+    }
+}
 
 pub struct Cpu {
     pub reg: Registers,
     bus: MemoryBus,
-    pub interrupt: Option<Interrupt>,
-    cycles_left: u16,      // Cycles left before next instruction
+    pub(super) interrupts: InterruptLines,
+    pub(super) exec: ExecState, // Instruction in progress
+    stall: u16,            // Cycles the CPU is halted for (reset, DMC DMA)
+    oam_dma: OamDma,
     ticks_left: u16,       // Ticks left before next CPU cycle
     num_cpu_cycles: u64,   // Number of CPU cycles elapsed
     num_system_ticks: u64, // Number of system ticks elapsed
-    sample_freq: f64,
-    audio_time: f64,
+    num_frames: u64,       // Number of frames the PPU has finished
+    tracer: Option<TraceLogger>,
+    cdl: Option<CdlRef>,
 }
 
 impl Cpu {
@@ -68,14 +163,18 @@ impl Cpu {
             bus: MemoryBusBuilder::new()
                 .with_cart(cart)
                 .with_controllers(controller1, controller2)
+                .with_sample_rate(audio_sample_freq)
                 .build(),
-            interrupt: None,
-            cycles_left: 0,
+            interrupts: InterruptLines::default(),
+            exec: ExecState::default(),
+            stall: 0,
+            oam_dma: OamDma::default(),
             ticks_left: 0,
             num_cpu_cycles: 0,
             num_system_ticks: 0,
-            sample_freq: audio_sample_freq,
-            audio_time: 0.0,
+            num_frames: 0,
+            tracer: None,
+            cdl: None,
         }
     }
 
@@ -85,13 +184,16 @@ impl Cpu {
         Self {
             reg: Default::default(),
             bus: MemoryBusBuilder::new().with_ram(init_ram).build(),
-            interrupt: None,
-            cycles_left: 0,
+            interrupts: InterruptLines::default(),
+            exec: ExecState::default(),
+            stall: 0,
+            oam_dma: OamDma::default(),
             ticks_left: 0,
             num_cpu_cycles: 0,
             num_system_ticks: 0,
-            sample_freq: 0.0,
-            audio_time: 0.0,
+            num_frames: 0,
+            tracer: None,
+            cdl: None,
         }
     }
 
@@ -106,124 +208,230 @@ impl Cpu {
         self.reg.status = StatusFlags::from_bits(0).unwrap();
         self.reg.status.insert(StatusFlags::UNUSED);
         self.reg.status.insert(StatusFlags::INTERRUPT_DISABLE);
-        self.cycles_left = 7;
+        self.exec = ExecState::default();
+        self.oam_dma = OamDma::default();
+        self.stall = RESET_CYCLES;
 
         self.bus.ppu.reset()?;
+        self.bus.apu.reset();
 
         Ok(())
     }
 
-    // Returns the number of cycles the instruction takes
-    fn run_next_instr(&mut self, log: Option<&mut String>) -> Result<u16> {
-        // Check for interrupts
-        if let Some(interrupt) = self.interrupt {
-            let interrupts_enabled = !self.reg.status.contains(StatusFlags::INTERRUPT_DISABLE);
-            if interrupt != Interrupt::Request || interrupts_enabled {
-                let vector = interrupt.vector();
-                let isr_addr = make_address(self.bus.read(vector)?, self.bus.read(vector + 1)?);
-
-                push_stack_addr(self, self.reg.pc)?;
-                self.reg.status.remove(StatusFlags::BREAK);
-                self.reg.status.insert(StatusFlags::UNUSED);
-                self.reg.status.insert(StatusFlags::INTERRUPT_DISABLE);
-                push_stack(self, self.reg.status.bits())?;
-
-                // Jump to Interrupt Handler
-                // self.reg.pc = make_address(self.bus.read(isr_addr)?, self.bus.read(isr_addr + 1)?);
-                self.reg.pc = isr_addr;
-
-                // Clear interrupt
-                self.interrupt = None;
-
-                return Ok(interrupt.num_cycles());
-            }
+    // The `offset`th instruction from the PC (and its address), decoded with peek()
+    pub fn peek_next_instr(&self, offset: u16) -> Result<(u16, Instr)> {
+        let mut addr = self.reg.pc;
+        for _ in 0..offset {
+            let (instr, _) = decode_instr(addr, |a| self.peek(a))?;
+            addr = addr.wrapping_add(instr.length() as u16);
         }
+        let (instr, _) = decode_instr(addr, |a| self.peek(a))?;
+        Ok((addr, instr))
+    }
 
-        // Address of the instruction we're about to fetch (fetch_instr modifies self.reg.pc)
-        let pc = self.reg.pc;
-        // Decode and run the next instruction
-        let (i, ncycles) = fetch_instr(self)?;
+    // Whether the next system tick starts a new instruction
+    pub fn at_instr_boundary(&self) -> bool {
+        self.exec.step == 0
+            && self.stall == 0
+            && self.oam_dma.page.is_none()
+            && self.ticks_left == 0
+    }
 
-        // Log instructions being run
-        if let Some(log) = log {
-            log.push_str(&format!(
-                "{:04X} {:?} {} PPU:{:3},{:3} CYC:{}\n",
-                pc, i.op, self.reg, self.bus.ppu.scanline, self.bus.ppu.cycle, self.num_cpu_cycles
-            ));
-        };
+    // Starts the next instruction, or the interrupt sequence if one is pending. Whether it's
+    // an NMI or IRQ is only decided partway through the sequence (see exec.rs).
+    fn begin_next_instr(&mut self) -> Result<()> {
+        let interrupt = self.interrupts.pending().then_some(Interrupt::Request);
 
-        match exec_instr(i, self) {
-            Ok(extra_cycles) => Ok(ncycles + extra_cycles),
-            Err(e) => {
-                eprintln!("An error occurred trying to execute {i}");
-                eprintln!("Program Counter: 0x{pc:04X}");
-                eprintln!("Error: {:?}", e);
-                eprintln!("CPU Registers: {}", self.reg);
-                Err(e)
+        // Log instructions being run, the logger is dropped (and its file closed) once it's done
+        if let (Some(mut tracer), None) = (self.tracer.take(), interrupt) {
+            tracer.log(self)?;
+            if !tracer.is_done() {
+                self.tracer = Some(tracer);
             }
         }
+        begin_instr(self, interrupt)?;
+        Ok(())
     }
 
-    pub fn peek_next_instr(&mut self, offset: u16) -> Result<(u16, Instr)> {
-        let restore_pc = self.reg.pc;
-        let mut prev_pc = restore_pc;
-        let mut i = 0;
-        while let Ok((instr, _)) = fetch_instr(self) {
-            if i >= offset {
-                self.reg.pc = restore_pc;
-                return Ok((prev_pc, instr));
-            }
-            prev_pc = self.reg.pc;
-            i += 1;
+    fn cycle(&mut self) -> Result<()> {
+        // End of the last cycle, the PPU has caught up since
+        let interrupts_enabled = !self.reg.status.contains(StatusFlags::INTERRUPT_DISABLE);
+        self.interrupts.poll(self.bus.ppu.nmi(), interrupts_enabled);
+
+        if self.stall > 0 {
+            self.stall -= 1;
+        } else if let Some(page) = self.oam_dma.page {
+            self.oam_dma_cycle(page)?;
+        } else if self.exec.step == 0 {
+            self.begin_next_instr()?;
+        } else if let Err(e) = exec_cycle(self) {
+            eprintln!("An error occurred trying to execute {:?}", self.exec);
+            eprintln!("Program Counter: 0x{:04X}", self.reg.pc);
+            eprintln!("Error: {:?}", e);
+            eprintln!("CPU Registers: {}", self.reg);
+            return Err(e);
         }
-        self.reg.pc = restore_pc;
-        Err("no valid instructions left".into())
-    }
 
-    fn cycle(&mut self, log: Option<&mut String>) -> Result<()> {
-        if self.cycles_left == 0 {
-            self.cycles_left = self.run_next_instr(log)?;
+        self.bus.apu.tick();
+        if let Some(addr) = self.bus.apu.dmc_dma_addr() {
+            let byte = self.read_logged(addr, PrgFlags::PCM)?;
+            self.bus.apu.dmc_dma_fill(byte);
+            self.stall += match self.oam_dma.halted {
+                true => DMC_DMA_OAM_CYCLES,
+                false => DMC_DMA_CYCLES,
+            };
         }
+        let (cart_irq, cart_audio) = {
+            let mut cart = self.bus.cart.lock().unwrap();
+            cart.cpu_cycle();
+            (cart.irq(), cart.audio_output())
+        };
+        self.bus.apu.set_expansion_output(cart_audio);
+        self.interrupts.irq_line = self.bus.apu.irq() || cart_irq;
 
-        self.cycles_left -= 1;
         self.num_cpu_cycles += 1;
         Ok(())
     }
 
-    pub fn system_tick(
-        &mut self,
-        log: Option<&mut String>,
-    ) -> Result<(Option<Frame>, Option<f64>)> {
+    // One cycle of an OAM DMA, the CPU is halted until it's done
+    fn oam_dma_cycle(&mut self, page: u8) -> Result<()> {
+        let dma = &mut self.oam_dma;
+        if !dma.halted {
+            dma.halted = true;
+            return Ok(());
+        }
+        match dma.latch.take() {
+            // Put
+            Some(byte) => {
+                self.bus.ppu.oam_write(dma.index, byte);
+                dma.index = dma.index.wrapping_add(1);
+                if dma.index == 0 {
+                    self.oam_dma = OamDma::default();
+                }
+            }
+            // Get
+            None if self.num_cpu_cycles.is_multiple_of(2) => {
+                let index = dma.index;
+                self.oam_dma.latch = Some(self.read(make_address(index, page))?);
+            }
+            // Alignment, gets only happen on even cycles
+            None => (),
+        }
+        Ok(())
+    }
+
+    pub fn system_tick(&mut self) -> Result<Option<Frame>> {
         if self.ticks_left == 0 {
-            self.cycle(log)?;
+            self.cycle()?;
             self.ticks_left = NUM_TICKS_PER_CPU_CYCLE;
         }
         self.ticks_left -= 1;
 
-        let (ret_frame, int) = self.bus.ppu.tick()?;
-        if int.is_some() {
-            self.interrupt = int;
+        let ret_frame = self.bus.ppu.tick()?;
+        if ret_frame.is_some() {
+            self.num_frames += 1;
+        }
+
+        self.num_system_ticks += 1;
+        Ok(ret_frame)
+    }
+
+    // Contents of the cartridge's battery-backed RAM, if it has any
+    pub fn nvram(&self) -> Option<Vec<u8>> {
+        self.bus.cart.lock().unwrap().nvram().map(|ram| ram.to_vec())
+    }
+
+    pub fn load_nvram(&mut self, data: &[u8]) {
+        self.bus.cart.lock().unwrap().load_nvram(data);
+    }
+
+    // Snapshot of the whole machine, see state.rs for the format
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.u32(u32::from_le_bytes(*STATE_MAGIC));
+        w.u16(STATE_VERSION);
+        w.bytes(self.bus.cart.lock().unwrap().name().as_bytes());
+
+        w.u8(self.reg.a);
+        w.u8(self.reg.x);
+        w.u8(self.reg.y);
+        w.u16(self.reg.pc);
+        w.u8(self.reg.sp);
+        w.u8(self.reg.status.bits());
+        self.interrupts.save_state(&mut w);
+        self.exec.save_state(&mut w);
+        w.u16(self.stall);
+        self.oam_dma.save_state(&mut w);
+        w.u16(self.ticks_left);
+        w.u64(self.num_cpu_cycles);
+        w.u64(self.num_system_ticks);
+        w.u64(self.num_frames);
+
+        self.bus.save_state(&mut w);
+        w.into_bytes()
+    }
+
+    // Restores a snapshot from save_state(), the machine is left untouched if it fails
+    pub fn load_state(&mut self, data: &[u8]) -> Result<()> {
+        let backup = self.save_state();
+        let res = self.load_state_unchecked(data);
+        if res.is_err() {
+            self.load_state_unchecked(&backup)
+                .expect("Could not restore the state from before the load");
         }
+        res
+    }
 
-        // Tick APU
-        let mut ret_audio = None;
-        let time_per_system_tick = 1.0 / 5369318.0;
-        let time_per_sample = 1.0 / self.sample_freq;
-        self.audio_time += time_per_system_tick;
-        if self.audio_time >= time_per_sample {
-            self.audio_time -= time_per_sample;
-            // TODO: get audio sample from APU instead
-            ret_audio = Some(0.0);
+    fn load_state_unchecked(&mut self, data: &[u8]) -> Result<()> {
+        let mut r = StateReader::new(data);
+        if r.u32()? != u32::from_le_bytes(*STATE_MAGIC) {
+            return Err("Not a save state".into());
+        }
+        let version = r.u16()?;
+        if version != STATE_VERSION {
+            return Err(format!("Unsupported save state version {version}").into());
+        }
+        let cart_name = self.bus.cart.lock().unwrap().name();
+        if r.bytes()? != cart_name.as_bytes() {
+            return Err("Save state is for a different cartridge".into());
         }
 
-        self.num_system_ticks += 1;
-        Ok((ret_frame, ret_audio))
+        self.reg.a = r.u8()?;
+        self.reg.x = r.u8()?;
+        self.reg.y = r.u8()?;
+        self.reg.pc = r.u16()?;
+        self.reg.sp = r.u8()?;
+        self.reg.status = StatusFlags::from_bits_truncate(r.u8()?);
+        self.interrupts.load_state(&mut r)?;
+        self.exec.load_state(&mut r)?;
+        self.stall = r.u16()?;
+        self.oam_dma.load_state(&mut r)?;
+        self.ticks_left = r.u16()?;
+        self.num_cpu_cycles = r.u64()?;
+        self.num_system_ticks = r.u64()?;
+        self.num_frames = r.u64()?;
+
+        self.bus.load_state(&mut r)?;
+        if !r.is_empty() {
+            return Err("Unexpected data at the end of the save state".into());
+        }
+        Ok(())
+    }
+
+    // Used by the emulator thread for dynamic rate control
+    pub fn set_audio_rate(&mut self, sample_rate: f64) {
+        self.bus.apu.set_sample_rate(sample_rate);
+    }
+
+    // Passes along the audio samples generated since the last call
+    pub fn flush_audio(&mut self, sink: &mut dyn AudioSink) {
+        self.bus.apu.flush(sink);
     }
 
     #[allow(dead_code)] // Used for benchmarking
     pub fn next_frame(&mut self) -> Result<Frame> {
         loop {
-            if let (Some(frame), _) = self.system_tick(None)? {
+            if let Some(frame) = self.system_tick()? {
                 return Ok(frame);
             }
         }
@@ -233,32 +441,94 @@ impl Cpu {
         self.bus.read(addr)
     }
 
-    pub fn write(&mut self, addr: u16, byte: u8) -> Result<()> {
-        if addr == 0x4014 {
-            // Intercept this write, PPU won't see it
-            // Do the entire DMA
-            for off in 0..=0xFFu8 {
-                let addr = make_address(off, byte);
-                let data = self.read(addr)?;
-                self.bus.ppu.oam_write(off, data);
-            }
+    // Reads that go in the code/data log (if one is being kept) as `flags`
+    pub(super) fn read_logged(&mut self, addr: u16, flags: PrgFlags) -> Result<u8> {
+        let byte = self.read(addr)?;
+        self.log_prg(addr, flags);
+        Ok(byte)
+    }
 
-            self.cycles_left = 512;
-            if self.num_cpu_cycles % 2 == 1 {
-                self.num_cpu_cycles += 1
+    pub(super) fn log_prg(&self, addr: u16, flags: PrgFlags) {
+        if let Some(cdl) = &self.cdl {
+            if let Some(offset) = self.prg_rom_offset(addr) {
+                cdl.lock().unwrap().mark_prg(offset, addr, flags);
             }
+        }
+    }
+
+    // Records which ROM bytes get run, read or drawn from here on, None stops logging
+    pub fn set_cdl(&mut self, cdl: Option<CdlRef>) {
+        self.bus.ppu.cdl = cdl.clone();
+        self.cdl = cdl;
+    }
 
+    // Reads without side effects, for debuggers and the debug view
+    pub fn peek(&self, addr: u16) -> Result<u8> {
+        self.bus.peek(addr)
+    }
+
+    // Where in PRG ROM an address is currently mapped to, None if it isn't ROM
+    pub fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        self.bus.cart.lock().unwrap().prg_rom_offset(addr)
+    }
+
+    pub fn write(&mut self, addr: u16, byte: u8) -> Result<()> {
+        if addr == 0x4014 {
+            // Intercept this write, PPU won't see it. The copy runs a cycle at a time from the
+            // next cycle on.
+            self.oam_dma = OamDma {
+                page: Some(byte),
+                ..OamDma::default()
+            };
             Ok(())
         } else {
             self.bus.write(addr, byte)
         }
     }
 
-    pub fn debug_pattern_tables(&mut self) -> Result<(PatternTable, PatternTable)> {
+    // Memory accesses are only recorded for the debugger while this is on
+    pub fn set_access_logging(&mut self, enabled: bool) {
+        self.bus.accesses.set_enabled(enabled);
+        self.bus.ppu.accesses.set_enabled(enabled);
+    }
+
+    // Hands over the accesses recorded since the last call
+    pub fn drain_accesses(&mut self, mut f: impl FnMut(AddressSpace, MemAccess)) {
+        self.bus.accesses.drain().for_each(|a| f(AddressSpace::Cpu, a));
+        self.bus.ppu.accesses.drain().for_each(|a| f(AddressSpace::Ppu, a));
+    }
+
+    // Logs every instruction from here on, None stops logging
+    pub fn set_tracer(&mut self, tracer: Option<TraceLogger>) {
+        self.tracer = tracer;
+    }
+
+    pub fn cycle_count(&self) -> u64 {
+        self.num_cpu_cycles
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.num_frames
+    }
+
+    // (scanline, dot) the PPU is at
+    pub fn ppu_position(&self) -> (i32, u64) {
+        (self.bus.ppu.scanline, self.bus.ppu.cycle)
+    }
+
+    pub fn debug_pattern_tables(&self) -> Result<(PatternTable, PatternTable)> {
         self.bus.ppu.debug_pattern_tables()
     }
 
-    pub fn debug_palettes(&mut self) -> Vec<Vec<Color>> {
+    pub fn debug_nametables(&self) -> Result<NametableView> {
+        self.bus.ppu.debug_nametables()
+    }
+
+    pub fn debug_scroll(&self) -> (u16, u16) {
+        self.bus.ppu.debug_scroll()
+    }
+
+    pub fn debug_palettes(&self) -> Vec<Vec<u8>> {
         self.bus.ppu.debug_palettes()
     }
 
@@ -267,34 +537,20 @@ impl Cpu {
             .map(|idx| self.bus.ppu.oam_read(idx))
             .collect::<Vec<OamSprite>>()
     }
-
-    pub fn get_info(&mut self) -> CpuInfo {
-        const NUM_INSTR: u16 = 10;
-        let mut instr = vec![];
-        for off in 0..NUM_INSTR {
-            match self.peek_next_instr(off) {
-                Ok((addr, i)) => instr.push((addr, i)),
-                Err(_) => {
-                    break;
-                }
-            }
-        }
-        CpuInfo {
-            sprites: self.debug_oam(),
-            palettes: self.debug_palettes(),
-            pattern_tables: self.debug_pattern_tables().unwrap(),
-            instructions: instr,
-            registers: self.reg.clone(),
-        }
-    }
 }
 
 #[cfg(test)]
 mod cpu_test {
+    use super::STACK_OFFSET;
     use crate::{cart::builder::build_cartridge, cpu::cpu::Cpu, ines::parse::INesFile};
+    use crate::controller::{make_controller, Inputs};
+    use crate::cpu::reg::StatusFlags;
+    use crate::cpu::trace::{TraceBuffer, TraceFormat, TraceLogger};
+    use crate::mem::cdl::{ChrFlags, CodeDataLog, PrgFlags};
+    use std::sync::{Arc, Mutex};
 
     static NESTEST: &'static [u8] = include_bytes!("../../test_files/nestest.nes");
-    static NESTEST_LOG: &'static str = include_str!("../../test_files/nestest-trimmed.log");
+    static NESTEST_LOG: &str = include_str!("../../test_files/nestest.log");
 
     #[test]
     fn nestest() {
@@ -303,23 +559,355 @@ mod cpu_test {
         cpu.reset().unwrap();
         cpu.reg.pc = 0xC000;
 
-        const N_INSTR: usize = 5000;
-        let mut log = String::new();
-        let mut n = 0;
-        while n < N_INSTR {
-            if cpu.cycles_left == 0 && cpu.ticks_left == 0 {
-                n += 1;
+        // The whole log, including the unofficial opcodes at the end
+        let n_instr = NESTEST_LOG.lines().count();
+        let log = run_instrs(&mut cpu, n_instr);
+
+        // The trace has the bank at the end, nestest.log doesn't
+        let log: Vec<&str> = log.lines().map(|l| l.rsplit_once(" BANK:").unwrap().0).collect();
+        assert_eq!(log, NESTEST_LOG.lines().collect::<Vec<&str>>());
+    }
+
+    #[test]
+    fn code_data_log() {
+        let rom = INesFile::try_from(&NESTEST.to_vec()).unwrap();
+        let mut cpu = Cpu::new(build_cartridge(&rom).unwrap(), 44410.0, None, None);
+        cpu.reset().unwrap();
+        cpu.reg.pc = 0xC000;
+        let cdl = Arc::new(Mutex::new(CodeDataLog::new(rom.prg_rom.len(), rom.chr_rom.len())));
+        cpu.set_cdl(Some(cdl.clone()));
+        run_instrs(&mut cpu, NESTEST_LOG.lines().count());
+
+        let cdl = cdl.lock().unwrap();
+        // JMP $C5F5, all three bytes are code, mapped in at $C000-$DFFF
+        for offset in 0..3 {
+            assert_eq!(cdl.to_bytes()[offset], PrgFlags::CODE.bits() | 0x08);
+        }
+        // JSR's last operand byte
+        assert!(cdl.prg_flags(0x072C).unwrap().contains(PrgFlags::CODE));
+        // Skipped by the JMP
+        assert!(cdl.prg_flags(0x0003).unwrap().is_empty());
+        assert!(cdl.prg_summary().0 > 0x1000);
+        drop(cdl);
+
+        // The menu reads its text out of ROM and draws it
+        let cdl = Arc::new(Mutex::new(CodeDataLog::new(rom.prg_rom.len(), rom.chr_rom.len())));
+        cpu.set_cdl(Some(cdl.clone()));
+        cpu.reset().unwrap();
+        for _ in 0..10 {
+            cpu.next_frame().unwrap();
+        }
+        let cdl = cdl.lock().unwrap();
+        assert!(cdl.prg_summary().1 > 0);
+        let drawn = (0..rom.chr_rom.len())
+            .filter(|i| cdl.chr_flags(*i).unwrap().contains(ChrFlags::DRAWN))
+            .count();
+        assert!(drawn > 0);
+    }
+
+    // Log of the next `n` instructions
+    fn run_instrs(cpu: &mut Cpu, n: usize) -> String {
+        let log = TraceBuffer::default();
+        cpu.set_tracer(Some(TraceLogger::new(log.clone(), TraceFormat::Nestest)));
+        let mut i = 0;
+        while i < n {
+            if cpu.at_instr_boundary() {
+                i += 1;
             }
-            cpu.system_tick(Some(&mut log)).unwrap();
+            cpu.system_tick().unwrap();
+        }
+        cpu.set_tracer(None);
+        log.contents()
+    }
+
+    // Runs up to the start of the next instruction (or interrupt), returns the PC there
+    fn next_boundary(cpu: &mut Cpu) -> u16 {
+        cpu.system_tick().unwrap();
+        while !cpu.at_instr_boundary() {
+            cpu.system_tick().unwrap();
+        }
+        cpu.reg.pc
+    }
+
+    fn load_code(cpu: &mut Cpu, code: &[u8]) {
+        for (i, byte) in code.iter().enumerate() {
+            cpu.write(0x0300 + i as u16, *byte).unwrap();
         }
+        cpu.reg.pc = 0x0300;
+    }
 
-        assert_eq!(
-            log.trim_end(),
-            NESTEST_LOG
-                .lines()
-                .take(N_INSTR)
-                .collect::<Vec<&str>>()
-                .join("\n")
-        )
+    #[test]
+    fn interrupt_polling() {
+        let rom = INesFile::try_from(&NESTEST.to_vec()).unwrap();
+        let mut cpu = Cpu::new(build_cartridge(&rom).unwrap(), 44410.0, None, None);
+        cpu.reset().unwrap();
+        let irq_handler = 0xC5F4;
+
+        // Wait for the APU frame IRQ with interrupts disabled, JMP $0300
+        load_code(&mut cpu, &[0x4C, 0x00, 0x03]);
+        cpu.write(0x4017, 0x00).unwrap();
+        while !cpu.bus.apu.irq() {
+            next_boundary(&mut cpu);
+        }
+        next_boundary(&mut cpu);
+
+        // CLI takes effect after the next instruction: CLI, NOP, NOP
+        load_code(&mut cpu, &[0x58, 0xEA, 0xEA]);
+        assert_eq!(next_boundary(&mut cpu), 0x0301);
+        assert_eq!(next_boundary(&mut cpu), 0x0302);
+        assert_eq!(next_boundary(&mut cpu), irq_handler);
+    }
+
+    #[test]
+    fn nmi_hijacks_brk() {
+        let rom = INesFile::try_from(&NESTEST.to_vec()).unwrap();
+        let mut cpu = Cpu::new(build_cartridge(&rom).unwrap(), 44410.0, None, None);
+        cpu.reset().unwrap();
+        let nmi_handler = 0xC5AF;
+
+        // An NMI coming in partway through BRK takes over its vector, BRK
+        next_boundary(&mut cpu);
+        load_code(&mut cpu, &[0x00, 0x00]);
+        for _ in 0..3 {
+            cpu.cycle().unwrap();
+        }
+        cpu.interrupts.need_nmi = true;
+        for _ in 0..4 {
+            cpu.cycle().unwrap();
+        }
+        assert!(cpu.at_instr_boundary());
+        assert_eq!(cpu.reg.pc, nmi_handler);
+        // It still pushed the BRK's return address and B flag
+        let sp = STACK_OFFSET + cpu.reg.sp as u16;
+        assert_ne!(cpu.read(sp + 1).unwrap() & StatusFlags::BREAK.bits(), 0);
+        assert_eq!(cpu.read(sp + 2).unwrap(), 0x02);
+        assert!(!cpu.interrupts.need_nmi);
+    }
+
+    #[test]
+    fn branch_delays_nmi() {
+        let rom = INesFile::try_from(&NESTEST.to_vec()).unwrap();
+        let mut cpu = Cpu::new(build_cartridge(&rom).unwrap(), 44410.0, None, None);
+        cpu.reset().unwrap();
+        let nmi_handler = 0xC5AF;
+        while cpu.ppu_position() != (241, 2) {
+            cpu.bus.ppu.tick().unwrap();
+        }
+        cpu.write(0x2000, 0x00).unwrap();
+
+        // NMI comes in during the last cycle of a taken branch: BEQ +0, NOP, NOP
+        next_boundary(&mut cpu);
+        load_code(&mut cpu, &[0xF0, 0x00, 0xEA, 0xEA]);
+        cpu.reg.status.insert(StatusFlags::ZERO);
+        for _ in 0..2 {
+            cpu.cycle().unwrap();
+        }
+        cpu.write(0x2000, 0x80).unwrap();
+        cpu.cycle().unwrap();
+        assert!(cpu.at_instr_boundary());
+        assert_eq!(cpu.reg.pc, 0x0302);
+        // One more instruction runs before it's taken
+        assert_eq!(next_boundary(&mut cpu), 0x0303);
+        assert_eq!(next_boundary(&mut cpu), nmi_handler);
+    }
+
+    #[test]
+    fn oam_dma() {
+        let rom = INesFile::try_from(&NESTEST.to_vec()).unwrap();
+        let mut cpu = Cpu::new(build_cartridge(&rom).unwrap(), 44410.0, None, None);
+        cpu.reset().unwrap();
+        next_boundary(&mut cpu);
+        for i in 0..=0xFF {
+            cpu.write(0x0200 + i, i as u8 ^ 0x5A).unwrap();
+        }
+
+        // STA $4014, LDA $00, STA $4014, STA $4014
+        load_code(&mut cpu, &[0x8D, 0x14, 0x40, 0xA5, 0x00, 0x8D, 0x14, 0x40, 0x8D, 0x14, 0x40]);
+        cpu.write(0x0000, 0x02).unwrap();
+        cpu.reg.a = 0x02;
+        assert_eq!(next_boundary(&mut cpu), 0x0303);
+        assert_eq!(next_boundary(&mut cpu), 0x0305);
+
+        // Written on an even cycle: halt, then 256 gets and puts
+        let start = cpu.cycle_count();
+        for _ in 0..4 {
+            cpu.cycle().unwrap();
+        }
+        cpu.bus.ppu.oam_write(0, 0);
+        // The first byte only lands after the halt and get cycles
+        cpu.cycle().unwrap();
+        cpu.cycle().unwrap();
+        assert_eq!(cpu.bus.ppu.oam_read(0).y, 0);
+        cpu.cycle().unwrap();
+        assert_eq!(cpu.bus.ppu.oam_read(0).y, 0x5A);
+        assert_eq!(next_boundary(&mut cpu), 0x0308);
+        assert_eq!(cpu.cycle_count() - start, 4 + 513);
+        for i in 0..64 {
+            let sprite = cpu.bus.ppu.oam_read(i);
+            assert_eq!((sprite.y, sprite.x), ((i * 4) ^ 0x5A, (i * 4 + 3) ^ 0x5A));
+        }
+
+        // Written on an odd cycle, there's an alignment cycle too
+        let start = cpu.cycle_count();
+        assert_eq!(next_boundary(&mut cpu), 0x030B);
+        assert_eq!(cpu.cycle_count() - start, 4 + 514);
+
+        // DMC fetches take a get cycle from the copy and realign, STA $4014 again
+        cpu.write(0x4010, 0x0F).unwrap();
+        cpu.write(0x4013, 0xFF).unwrap();
+        cpu.write(0x4015, 0x10).unwrap();
+        load_code(&mut cpu, &[0xA5, 0x00, 0x8D, 0x14, 0x40]);
+        next_boundary(&mut cpu);
+        let start = cpu.cycle_count();
+        assert_eq!(next_boundary(&mut cpu), 0x0305);
+        let extra = cpu.cycle_count() - start - (4 + 513);
+        assert!(extra > 0 && extra.is_multiple_of(2), "{extra} extra cycles");
+    }
+
+    // blargg's cpu_interrupts_v2 singles, from https://github.com/christopherpow/nes-test-roms
+    const CPU_INTERRUPTS: [&str; 5] = [
+        "1-cli_latency",
+        "2-nmi_and_brk",
+        "3-nmi_and_irq",
+        "4-irq_and_dma",
+        "5-branch_delays_irq",
+    ];
+
+    // Runs one of blargg's test ROMs until it reports a result at $6000 (0 for passed), along
+    // with the text it printed at $6004
+    fn run_blargg_rom(path: &str) -> (u8, String) {
+        let data = std::fs::read(path).unwrap_or_else(|e| panic!("Couldn't read {path}: {e}"));
+        let rom = INesFile::try_from(&data).unwrap();
+        let mut cpu = Cpu::new(build_cartridge(&rom).unwrap(), 44410.0, None, None);
+        cpu.reset().unwrap();
+        for _ in 0..60 * 30 {
+            cpu.next_frame().unwrap();
+            let signature = [0x6001, 0x6002, 0x6003].map(|a| cpu.peek(a).unwrap());
+            if signature != [0xDE, 0xB0, 0x61] {
+                continue;
+            }
+            match cpu.peek(0x6000).unwrap() {
+                // Still running
+                0x80 => (),
+                // Wants the reset button pressed
+                0x81 => cpu.reset().unwrap(),
+                result => {
+                    let text = (0x6004..0x7000)
+                        .map(|a| cpu.peek(a).unwrap())
+                        .take_while(|b| *b != 0)
+                        .map(|b| b as char)
+                        .collect();
+                    return (result, text);
+                }
+            }
+        }
+        panic!("{path} didn't finish");
+    }
+
+    #[test]
+    #[ignore = "needs the cpu_interrupts_v2 ROMs in test_files/cpu_interrupts_v2"]
+    fn blargg_cpu_interrupts() {
+        for name in CPU_INTERRUPTS {
+            let (result, text) = run_blargg_rom(&format!("test_files/cpu_interrupts_v2/{name}.nes"));
+            assert_eq!(result, 0, "{name}: {text}");
+        }
+    }
+
+    #[test]
+    fn peek() {
+        let rom = INesFile::try_from(&NESTEST.to_vec()).unwrap();
+        let controller = make_controller();
+        let cart = build_cartridge(&rom).unwrap();
+        let mut cpu = Cpu::new(cart, 44410.0, Some(controller.clone()), None);
+        cpu.reset().unwrap();
+        while cpu.ppu_position() != (241, 2) {
+            cpu.bus.ppu.tick().unwrap();
+        }
+        cpu.write(0x2006, 0x20).unwrap();
+        cpu.write(0x2006, 0x00).unwrap();
+        controller.lock().unwrap().input(Inputs::A | Inputs::B);
+        cpu.write(0x4016, 1).unwrap();
+
+        // Peeking leaves vblank, the VRAM address and the controller's shift register alone
+        let state = cpu.save_state();
+        for addr in [0x2002, 0x2007, 0x4015, 0x4016, 0xC000] {
+            assert_eq!(cpu.peek(addr).unwrap(), cpu.peek(addr).unwrap());
+        }
+        assert_eq!(cpu.peek(0x2002).unwrap() & 0x80, 0x80);
+        assert_eq!(cpu.peek(0x4016).unwrap(), 1);
+        assert!(cpu.peek(0x2000).is_err());
+        assert_eq!(cpu.save_state(), state);
+
+        // Unlike reading
+        assert_eq!(cpu.read(0x2002).unwrap() & 0x80, 0x80);
+        assert_eq!(cpu.peek(0x2002).unwrap() & 0x80, 0);
+        assert_eq!(cpu.read(0x4016).unwrap(), 1);
+        assert_eq!(cpu.read(0x4016).unwrap(), 1);
+        assert_eq!(cpu.peek(0x4016).unwrap(), 0);
+        cpu.read(0x2007).unwrap();
+        assert_ne!(cpu.save_state(), state);
+
+        // The debug view decodes through peek too
+        cpu.reg.pc = 0xC000;
+        assert_eq!(cpu.peek_next_instr(0).unwrap().1.to_string(), "JMP $C5F5 (Abs)");
+        assert_eq!(cpu.peek_next_instr(1).unwrap().0, 0xC003);
+    }
+
+    #[test]
+    fn debug_nametables() {
+        let rom = INesFile::try_from(&NESTEST.to_vec()).unwrap();
+        let mut cpu = Cpu::new(build_cartridge(&rom).unwrap(), 44410.0, None, None);
+        cpu.reset().unwrap();
+        let mut vram_write = |addr: u16, data: &[u8]| {
+            cpu.write(0x2006, (addr >> 8) as u8).unwrap();
+            cpu.write(0x2006, addr as u8).unwrap();
+            for byte in data {
+                cpu.write(0x2007, *byte).unwrap();
+            }
+        };
+        // Tile $41 in the top left and bottom right corner of $2000, palette 1 for the top left
+        vram_write(0x2000, &[0x41]);
+        vram_write(0x23BF, &[0x41, 0b01]);
+        vram_write(0x3F00, &[0x0F, 0x16, 0x27, 0x30, 0x0F, 0x01, 0x02, 0x03]);
+        // Scroll to (12, 34) in $2C00
+        cpu.write(0x2000, 0b11).unwrap();
+        cpu.write(0x2005, 12).unwrap();
+        cpu.write(0x2005, 34).unwrap();
+
+        let view = cpu.debug_nametables().unwrap();
+        let (pattern_table, _) = cpu.debug_pattern_tables().unwrap();
+        let palettes = cpu.debug_palettes();
+        for r in 0..8 {
+            for c in 0..8 {
+                let pixel = pattern_table[32 + r][8 + c] as usize;
+                assert_eq!(view[r][c], palettes[1][pixel] as u16);
+                assert_eq!(view[232 + r][248 + c], palettes[0][pixel] as u16);
+            }
+        }
+        assert!(view[..8].iter().any(|row| row[..8].iter().any(|p| *p != 0x0F)));
+        // nestest mirrors horizontally, $2400 is $2000
+        assert!(view[..240].iter().all(|row| row[..256] == row[256..]));
+        assert!(view[..240] != view[240..]);
+        assert_eq!(cpu.debug_scroll(), (256 + 12, 240 + 34));
+    }
+
+    #[test]
+    fn save_state() {
+        let rom = INesFile::try_from(&NESTEST.to_vec()).unwrap();
+        let mut cpu = Cpu::new(build_cartridge(&rom).unwrap(), 44410.0, None, None);
+        cpu.reset().unwrap();
+        cpu.reg.pc = 0xC000;
+        run_instrs(&mut cpu, 2000);
+
+        let state = cpu.save_state();
+        let expected = run_instrs(&mut cpu, 2000);
+        cpu.load_state(&state).unwrap();
+        assert_eq!(run_instrs(&mut cpu, 2000), expected);
+
+        // Failed loads leave the machine as it was
+        let before = cpu.save_state();
+        assert!(cpu.load_state(&state[..state.len() - 1]).is_err());
+        assert!(cpu.load_state(b"garbage").is_err());
+        assert_eq!(cpu.save_state(), before);
     }
 }
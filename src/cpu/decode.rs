@@ -1,4 +1,5 @@
 use crate::error::Result;
+use crate::mem::cdl::PrgFlags;
 use crate::{cpu::isa::AddressingMode, mem::utils::make_address};
 use std::{error::Error, fmt};
 
@@ -49,83 +50,84 @@ pub mod instr_lookup {
     type InstrTup = (Opcode, Mode, u8);
     const INV: InstrTup = (INVALID, Imp, 0);
 
+    // Includes the unofficial opcodes, the only INV slots left are the JAM opcodes (which
+    // lock up the CPU)
     #[rustfmt::skip]
     pub const LOOKUP: [[InstrTup; 16]; 16] = [
-        [(BRK, Imp, 7), (ORA, XInd, 6), INV, INV, (NOP, Zpg, 3),  (ORA, Zpg, 3),  (ASL, Zpg, 5),  INV, (PHP, Imp, 3), (ORA, Imm, 2),  (ASL, Acc, 2), INV, (NOP, Abs, 4), (ORA, Abs, 4), (ASL, Abs, 6), INV],
-        [(BPL, Rel, 2), (ORA, IndY, 5), INV, INV, (NOP, ZpgX, 4), (ORA, ZpgX, 4), (ASL, ZpgX, 6), INV, (CLC, Imp, 2), (ORA, AbsY, 4), (NOP, Imp, 2), INV, (NOP, AbsX, 4), (ORA, AbsX, 4), (ASL, AbsX, 7), INV],
-        [(JSR, Abs, 6), (AND, XInd, 6), INV, INV, (BIT, Zpg, 3),  (AND, Zpg, 3),  (ROL, Zpg, 5),  INV, (PLP, Imp, 4), (AND, Imm, 2),  (ROL, Acc, 2), INV, (BIT, Abs, 4), (AND, Abs, 4), (ROL, Abs, 6), INV],
-        [(BMI, Rel, 2), (AND, IndY, 5), INV, INV, (NOP, ZpgX, 4), (AND, ZpgX, 4), (ROL, ZpgX, 6), INV, (SEC, Imp, 2), (AND, AbsY, 4), (NOP, Imp, 2), INV, (NOP, AbsX, 4), (AND, AbsX, 4), (ROL, AbsX, 7), INV],
-        [(RTI, Imp, 6), (EOR, XInd, 6), INV, INV, (NOP, Zpg, 3),  (EOR, Zpg, 3),  (LSR, Zpg, 5),  INV, (PHA, Imp, 3), (EOR, Imm, 2),  (LSR, Acc, 2), INV, (JMP, Abs, 3), (EOR, Abs, 4), (LSR, Abs, 6), INV],
-        [(BVC, Rel, 2), (EOR, IndY, 5), INV, INV, (NOP, ZpgX, 4), (EOR, ZpgX, 4), (LSR, ZpgX, 6), INV, (CLI, Imp, 2), (EOR, AbsY, 4), (NOP, Imp, 2), INV, (NOP, AbsX, 4), (EOR, AbsX, 4), (LSR, AbsX, 7), INV],
-        [(RTS, Imp, 6), (ADC, XInd, 6), INV, INV, (NOP, Zpg, 3),  (ADC, Zpg, 3),  (ROR, Zpg, 5),  INV, (PLA, Imp, 4), (ADC, Imm, 2),  (ROR, Acc, 2), INV, (JMP, Ind, 5), (ADC, Abs, 4), (ROR, Abs, 6), INV],
-        [(BVS, Rel, 2), (ADC, IndY, 5), INV, INV, (NOP, ZpgX, 4), (ADC, ZpgX, 4), (ROR, ZpgX, 6), INV, (SEI, Imp, 2), (ADC, AbsY, 4), (NOP, Imp, 2), INV, (NOP, AbsX, 4), (ADC, AbsX, 4), (ROR, AbsX, 7), INV],
-        [(NOP, Imm, 2), (STA, XInd, 6), INV, INV, (STY, Zpg, 3),  (STA, Zpg, 3),  (STX, Zpg, 3),  INV, (DEY, Imp, 2), (NOP, Imm, 2),  (TXA, Imp, 2), INV, (STY, Abs, 4), (STA, Abs, 4), (STX, Abs, 4), INV],
-        [(BCC, Rel, 2), (STA, IndY, 6), INV, INV, (STY, ZpgX, 4), (STA, ZpgX, 4), (STX, ZpgY, 4), INV, (TYA, Imp, 2), (STA, AbsY, 5), (TXS, Imp, 2), INV, (NOP, AbsX, 4), (STA, AbsX, 5), INV, INV],
-        [(LDY, Imm, 2), (LDA, XInd, 6), (LDX, Imm, 2), (LAX, XInd, 6), (LDY, Zpg, 3), (LDA, Zpg, 3), (LDX, Zpg, 3), (LAX, Zpg, 3), (TAY, Imp, 2), (LDA, Imm, 2), (TAX, Imp, 2), (LAX, Imm, 3), (LDY, Abs, 4), (LDA, Abs, 4), (LDX, Abs, 4), (LAX, Abs, 4)],
-        [(BCS, Rel, 2), (LDA, IndY, 5), INV, (LAX, IndY, 5), (LDY, ZpgX, 4), (LDA, ZpgX, 4), (LDX, ZpgY, 4), (LAX, ZpgY, 4), (CLV, Imp, 2), (LDA, AbsY, 4), (TSX, Imp, 2), INV, (LDY, AbsX, 4), (LDA, AbsX, 4), (LDX, AbsY, 4), (LAX, AbsY, 4)],
-        [(CPY, Imm, 2), (CMP, XInd, 6), INV, INV, (CPY, Zpg, 3),  (CMP, Zpg, 3),  (DEC, Zpg, 5),  INV, (INY, Imp, 2), (CMP, Imm, 2),  (DEX, Imp, 2), INV, (CPY, Abs, 4), (CMP, Abs, 4), (DEC, Abs, 6), INV],
-        [(BNE, Rel, 2), (CMP, IndY, 5), INV, INV, (NOP, ZpgX, 4), (CMP, ZpgX, 4), (DEC, ZpgX, 6), INV, (CLD, Imp, 2), (CMP, AbsY, 4), (NOP, Imp, 2), INV, (NOP, AbsX, 4), (CMP, AbsX, 4), (DEC, AbsX, 7), INV],
-        [(CPX, Imm, 2), (SBC, XInd, 6), INV, INV, (CPX, Zpg, 3),  (SBC, Zpg, 3),  (INC, Zpg, 5),  INV, (INX, Imp, 2), (SBC, Imm, 2),  (NOP, Imp, 2), INV, (CPX, Abs, 4), (SBC, Abs, 4), (INC, Abs, 6), INV],
-        [(BEQ, Rel, 2), (SBC, IndY, 5), INV, INV, (NOP, ZpgX, 4), (SBC, ZpgX, 4), (INC, ZpgX, 6), INV, (SED, Imp, 2), (SBC, AbsY, 4), (NOP, Imp, 2), INV, (NOP, AbsX, 4), (SBC, AbsX, 4), (INC, AbsX, 7), INV],
+        [(BRK, Imp, 7), (ORA, XInd, 6), INV,           (SLO, XInd, 8), (NOP, Zpg, 3),  (ORA, Zpg, 3),  (ASL, Zpg, 5),  (SLO, Zpg, 5),  (PHP, Imp, 3), (ORA, Imm, 2),  (ASL, Acc, 2), (ANC, Imm, 2),  (NOP, Abs, 4),  (ORA, Abs, 4),  (ASL, Abs, 6),  (SLO, Abs, 6)],
+        [(BPL, Rel, 2), (ORA, IndY, 5), INV,           (SLO, IndY, 8), (NOP, ZpgX, 4), (ORA, ZpgX, 4), (ASL, ZpgX, 6), (SLO, ZpgX, 6), (CLC, Imp, 2), (ORA, AbsY, 4), (NOP, Imp, 2), (SLO, AbsY, 7), (NOP, AbsX, 4), (ORA, AbsX, 4), (ASL, AbsX, 7), (SLO, AbsX, 7)],
+        [(JSR, Abs, 6), (AND, XInd, 6), INV,           (RLA, XInd, 8), (BIT, Zpg, 3),  (AND, Zpg, 3),  (ROL, Zpg, 5),  (RLA, Zpg, 5),  (PLP, Imp, 4), (AND, Imm, 2),  (ROL, Acc, 2), (ANC, Imm, 2),  (BIT, Abs, 4),  (AND, Abs, 4),  (ROL, Abs, 6),  (RLA, Abs, 6)],
+        [(BMI, Rel, 2), (AND, IndY, 5), INV,           (RLA, IndY, 8), (NOP, ZpgX, 4), (AND, ZpgX, 4), (ROL, ZpgX, 6), (RLA, ZpgX, 6), (SEC, Imp, 2), (AND, AbsY, 4), (NOP, Imp, 2), (RLA, AbsY, 7), (NOP, AbsX, 4), (AND, AbsX, 4), (ROL, AbsX, 7), (RLA, AbsX, 7)],
+        [(RTI, Imp, 6), (EOR, XInd, 6), INV,           (SRE, XInd, 8), (NOP, Zpg, 3),  (EOR, Zpg, 3),  (LSR, Zpg, 5),  (SRE, Zpg, 5),  (PHA, Imp, 3), (EOR, Imm, 2),  (LSR, Acc, 2), (ALR, Imm, 2),  (JMP, Abs, 3),  (EOR, Abs, 4),  (LSR, Abs, 6),  (SRE, Abs, 6)],
+        [(BVC, Rel, 2), (EOR, IndY, 5), INV,           (SRE, IndY, 8), (NOP, ZpgX, 4), (EOR, ZpgX, 4), (LSR, ZpgX, 6), (SRE, ZpgX, 6), (CLI, Imp, 2), (EOR, AbsY, 4), (NOP, Imp, 2), (SRE, AbsY, 7), (NOP, AbsX, 4), (EOR, AbsX, 4), (LSR, AbsX, 7), (SRE, AbsX, 7)],
+        [(RTS, Imp, 6), (ADC, XInd, 6), INV,           (RRA, XInd, 8), (NOP, Zpg, 3),  (ADC, Zpg, 3),  (ROR, Zpg, 5),  (RRA, Zpg, 5),  (PLA, Imp, 4), (ADC, Imm, 2),  (ROR, Acc, 2), (ARR, Imm, 2),  (JMP, Ind, 5),  (ADC, Abs, 4),  (ROR, Abs, 6),  (RRA, Abs, 6)],
+        [(BVS, Rel, 2), (ADC, IndY, 5), INV,           (RRA, IndY, 8), (NOP, ZpgX, 4), (ADC, ZpgX, 4), (ROR, ZpgX, 6), (RRA, ZpgX, 6), (SEI, Imp, 2), (ADC, AbsY, 4), (NOP, Imp, 2), (RRA, AbsY, 7), (NOP, AbsX, 4), (ADC, AbsX, 4), (ROR, AbsX, 7), (RRA, AbsX, 7)],
+        [(NOP, Imm, 2), (STA, XInd, 6), (NOP, Imm, 2), (SAX, XInd, 6), (STY, Zpg, 3),  (STA, Zpg, 3),  (STX, Zpg, 3),  (SAX, Zpg, 3),  (DEY, Imp, 2), (NOP, Imm, 2),  (TXA, Imp, 2), (XAA, Imm, 2),  (STY, Abs, 4),  (STA, Abs, 4),  (STX, Abs, 4),  (SAX, Abs, 4)],
+        [(BCC, Rel, 2), (STA, IndY, 6), INV,           (AHX, IndY, 6), (STY, ZpgX, 4), (STA, ZpgX, 4), (STX, ZpgY, 4), (SAX, ZpgY, 4), (TYA, Imp, 2), (STA, AbsY, 5), (TXS, Imp, 2), (TAS, AbsY, 5), (SHY, AbsX, 5), (STA, AbsX, 5), (SHX, AbsY, 5), (AHX, AbsY, 5)],
+        [(LDY, Imm, 2), (LDA, XInd, 6), (LDX, Imm, 2), (LAX, XInd, 6), (LDY, Zpg, 3),  (LDA, Zpg, 3),  (LDX, Zpg, 3),  (LAX, Zpg, 3),  (TAY, Imp, 2), (LDA, Imm, 2),  (TAX, Imp, 2), (LAX, Imm, 2),  (LDY, Abs, 4),  (LDA, Abs, 4),  (LDX, Abs, 4),  (LAX, Abs, 4)],
+        [(BCS, Rel, 2), (LDA, IndY, 5), INV,           (LAX, IndY, 5), (LDY, ZpgX, 4), (LDA, ZpgX, 4), (LDX, ZpgY, 4), (LAX, ZpgY, 4), (CLV, Imp, 2), (LDA, AbsY, 4), (TSX, Imp, 2), (LAS, AbsY, 4), (LDY, AbsX, 4), (LDA, AbsX, 4), (LDX, AbsY, 4), (LAX, AbsY, 4)],
+        [(CPY, Imm, 2), (CMP, XInd, 6), (NOP, Imm, 2), (DCP, XInd, 8), (CPY, Zpg, 3),  (CMP, Zpg, 3),  (DEC, Zpg, 5),  (DCP, Zpg, 5),  (INY, Imp, 2), (CMP, Imm, 2),  (DEX, Imp, 2), (AXS, Imm, 2),  (CPY, Abs, 4),  (CMP, Abs, 4),  (DEC, Abs, 6),  (DCP, Abs, 6)],
+        [(BNE, Rel, 2), (CMP, IndY, 5), INV,           (DCP, IndY, 8), (NOP, ZpgX, 4), (CMP, ZpgX, 4), (DEC, ZpgX, 6), (DCP, ZpgX, 6), (CLD, Imp, 2), (CMP, AbsY, 4), (NOP, Imp, 2), (DCP, AbsY, 7), (NOP, AbsX, 4), (CMP, AbsX, 4), (DEC, AbsX, 7), (DCP, AbsX, 7)],
+        [(CPX, Imm, 2), (SBC, XInd, 6), (NOP, Imm, 2), (ISC, XInd, 8), (CPX, Zpg, 3),  (SBC, Zpg, 3),  (INC, Zpg, 5),  (ISC, Zpg, 5),  (INX, Imp, 2), (SBC, Imm, 2),  (NOP, Imp, 2), (SBC, Imm, 2),  (CPX, Abs, 4),  (SBC, Abs, 4),  (INC, Abs, 6),  (ISC, Abs, 6)],
+        [(BEQ, Rel, 2), (SBC, IndY, 5), INV,           (ISC, IndY, 8), (NOP, ZpgX, 4), (SBC, ZpgX, 4), (INC, ZpgX, 6), (ISC, ZpgX, 6), (SED, Imp, 2), (SBC, AbsY, 4), (NOP, Imp, 2), (ISC, AbsY, 7), (NOP, AbsX, 4), (SBC, AbsX, 4), (INC, AbsX, 7), (ISC, AbsX, 7)],
     ];
 }
 
 // Reads byte at current PC, then advances PC
-fn read_next_byte(cpu: &mut Cpu) -> Result<u8> {
-    let byte = cpu.read(cpu.reg.pc)?;
-    cpu.reg.pc += 1;
+pub(super) fn read_next_byte(cpu: &mut Cpu) -> Result<u8> {
+    let byte = cpu.read_logged(cpu.reg.pc, PrgFlags::CODE)?;
+    cpu.reg.pc = cpu.reg.pc.wrapping_add(1);
     Ok(byte)
 }
 
-// Reads the 16-bit low endian value after the PC and converts
-// it from low endian back to the objectively-better-endian ;)
-fn read_little_endian_u16(cpu: &mut Cpu) -> Result<u16> {
-    let lo = read_next_byte(cpu)?;
-    let hi = read_next_byte(cpu)?;
-    Ok(make_address(lo, hi))
-}
-
-pub fn fetch_instr(cpu: &mut Cpu) -> Result<(Instr, u16)> {
-    let next_instr = read_next_byte(cpu)?;
-    let row = (next_instr & 0xF0) >> 4;
-    let col = next_instr & 0xF;
+// Opcode and addressing mode of an opcode byte fetched from `addr`
+pub fn decode_opcode(byte: u8, addr: u16) -> Result<(Opcode, instr_lookup::Mode)> {
+    let row = (byte & 0xF0) >> 4;
+    let col = byte & 0xF;
 
-    let (opcode, mode, ncycles) = instr_lookup::LOOKUP[row as usize][col as usize];
+    let (opcode, mode, _) = instr_lookup::LOOKUP[row as usize][col as usize];
     if opcode == Opcode::INVALID {
-        return Err(Box::new(DecodeError::InvalidOpcode(
-            next_instr,
-            cpu.reg.pc - 1,
-        )));
+        return Err(Box::new(DecodeError::InvalidOpcode(byte, addr)));
     }
+    Ok((opcode, mode))
+}
+
+// Decodes the instruction at `addr` without running it, `read` provides the bytes (e.g.
+// Cpu::peek). Returns the instruction and its base number of cycles.
+pub fn decode_instr(addr: u16, mut read: impl FnMut(u16) -> Result<u8>) -> Result<(Instr, u16)> {
+    let byte = read(addr)?;
+    let row = (byte & 0xF0) >> 4;
+    let col = byte & 0xF;
+
+    let (opcode, mode) = decode_opcode(byte, addr)?;
+    let ncycles = instr_lookup::LOOKUP[row as usize][col as usize].2;
 
-    // functors an dat
+    let mut operand = |off: u16| read(addr.wrapping_add(off));
     type M = instr_lookup::Mode;
     type AM = AddressingMode;
     let address_mode = match mode {
-        M::Acc => Ok(AM::Accumulator),
-        M::Abs => read_little_endian_u16(cpu).map(|addr| AM::Absolute(addr)),
-        M::AbsX => read_little_endian_u16(cpu).map(|addr| AM::AbsoluteX(addr)),
-        M::AbsY => read_little_endian_u16(cpu).map(|addr| AM::AbsoluteY(addr)),
-        M::Imm => read_next_byte(cpu).map(|b| AM::Immediate(b)),
-        M::Imp => Ok(AM::Implied),
-        M::Ind => read_little_endian_u16(cpu).map(|addr| AM::Indirect(addr)),
-        M::XInd => read_next_byte(cpu).map(|b| AM::XIndirect(b)),
-        M::IndY => read_next_byte(cpu).map(|b| AM::IndirectY(b)),
-        M::Rel => read_next_byte(cpu).map(|b| AM::Relative(b)),
-        M::Zpg => read_next_byte(cpu).map(|b| AM::ZeroPage(b)),
-        M::ZpgX => read_next_byte(cpu).map(|b| AM::ZeroPageX(b)),
-        M::ZpgY => read_next_byte(cpu).map(|b| AM::ZeroPageY(b)),
+        M::Acc => AM::Accumulator,
+        M::Abs => AM::Absolute(make_address(operand(1)?, operand(2)?)),
+        M::AbsX => AM::AbsoluteX(make_address(operand(1)?, operand(2)?)),
+        M::AbsY => AM::AbsoluteY(make_address(operand(1)?, operand(2)?)),
+        M::Imm => AM::Immediate(operand(1)?),
+        M::Imp => AM::Implied,
+        M::Ind => AM::Indirect(make_address(operand(1)?, operand(2)?)),
+        M::XInd => AM::XIndirect(operand(1)?),
+        M::IndY => AM::IndirectY(operand(1)?),
+        M::Rel => AM::Relative(operand(1)?),
+        M::Zpg => AM::ZeroPage(operand(1)?),
+        M::ZpgX => AM::ZeroPageX(operand(1)?),
+        M::ZpgY => AM::ZeroPageY(operand(1)?),
     };
 
-    address_mode.map(|am| {
-        (
-            Instr {
-                op: opcode,
-                mode: am,
-            },
-            ncycles as u16,
-        )
-    })
+    Ok((
+        Instr {
+            op: opcode,
+            mode: address_mode,
+        },
+        ncycles as u16,
+    ))
 }
 
 #[cfg(test)]
@@ -135,18 +137,17 @@ mod decode_tests {
         isa::{AddressingMode, Instr, Opcode},
     };
 
-    use super::fetch_instr;
+    use super::decode_instr;
 
     // Checks proper decode of instruction (get correct instruction, read correct # of bytes) and
     // and checks the # of cycles for the instruction
     fn check_decode(binary: &[u8], op: Opcode, mode: AddressingMode, n_cycles: u16) {
-        let mut cpu = Cpu::mock(Some(binary));
-        cpu.reg.pc = 0;
+        let cpu = Cpu::mock(Some(binary));
         let instr = Instr { op, mode };
 
-        let (act_instr, act_n_cycles) = fetch_instr(&mut cpu).unwrap();
+        let (act_instr, act_n_cycles) = decode_instr(0, |addr| cpu.peek(addr)).unwrap();
         assert_eq!(act_instr, instr);
-        assert_eq!(binary.len(), cpu.reg.pc as usize);
+        assert_eq!(binary.len(), act_instr.length() as usize);
         assert_eq!(act_n_cycles, n_cycles);
     }
 
@@ -1,139 +1,513 @@
-use std::fmt;
 use std::num::Wrapping;
-use std::error::Error;
 
 use crate::error::Result;
+use crate::state::{StateReader, StateWriter};
 
-use super::cpu::STACK_OFFSET;
+use super::cpu::{Interrupt, STACK_OFFSET};
+use super::decode::{decode_opcode, instr_lookup::Mode, read_next_byte};
 use super::utils::is_negative;
-use super::{
-    deref::effective_addr,
-    isa::{AddressingMode, Instr, Opcode},
-    reg::StatusFlags,
-};
+use super::{isa::Opcode, reg::StatusFlags};
 use crate::cpu::cpu::Cpu;
+use crate::mem::cdl::PrgFlags;
 use crate::mem::utils::{hi_byte, lo_byte, make_address, page_num};
 
-use super::deref::{deref_address, deref_byte};
+// Instructions run one cycle at a time with exactly one bus access per cycle (dummy reads
+// and writes included), following https://www.nesdev.org/6502_cpu.txt
+
+// How an instruction uses its operand, which decides the bus accesses it makes
+#[derive(Clone, Copy)]
+enum OpcodeFn {
+    // Reads the operand
+    Read(fn(&mut Cpu, u8)),
+    // Writes the returned value
+    Write(fn(&mut Cpu) -> u8),
+    // Reads the operand, writes it back unmodified and then writes the result
+    // (or works on the accumulator)
+    Modify(fn(&mut Cpu, u8) -> u8),
+    // Only touches registers
+    Implied(fn(&mut Cpu)),
+    // Taken if the flag is set/clear
+    Branch(StatusFlags, bool),
+    // Stack and jump instructions, which all have their own sequence of cycles
+    Special,
+}
 
-#[derive(Debug, Clone)]
-enum ExecutionError {
-    InvalidInstruction(Instr),
+fn lookup_opcode_fn(op: Opcode) -> OpcodeFn {
+    use OpcodeFn::*;
+    use Opcode::*;
+    match op {
+        ADC => Read(adc),
+        AHX => Write(ahx),
+        ALR => Read(alr),
+        ANC => Read(anc),
+        AND => Read(and),
+        ARR => Read(arr),
+        ASL => Modify(asl),
+        AXS => Read(axs),
+        BCC => Branch(StatusFlags::CARRY, false),
+        BCS => Branch(StatusFlags::CARRY, true),
+        BEQ => Branch(StatusFlags::ZERO, true),
+        BIT => Read(bit),
+        BMI => Branch(StatusFlags::NEGATIVE, true),
+        BNE => Branch(StatusFlags::ZERO, false),
+        BPL => Branch(StatusFlags::NEGATIVE, false),
+        BRK => Special,
+        BVC => Branch(StatusFlags::OVERFLOW, false),
+        BVS => Branch(StatusFlags::OVERFLOW, true),
+        CLC => Implied(clc),
+        CLD => Implied(cld),
+        CLI => Implied(cli),
+        CLV => Implied(clv),
+        CMP => Read(cmp),
+        CPX => Read(cpx),
+        CPY => Read(cpy),
+        DCP => Modify(dcp),
+        DEC => Modify(dec),
+        DEX => Implied(dex),
+        DEY => Implied(dey),
+        EOR => Read(eor),
+        INC => Modify(inc),
+        INX => Implied(inx),
+        INY => Implied(iny),
+        ISC => Modify(isc),
+        JMP => Special,
+        JSR => Special,
+        LAS => Read(las),
+        LAX => Read(lax),
+        LDA => Read(lda),
+        LDX => Read(ldx),
+        LDY => Read(ldy),
+        LSR => Modify(lsr),
+        NOP => Read(nop),
+        ORA => Read(ora),
+        PHA => Special,
+        PHP => Special,
+        PLA => Special,
+        PLP => Special,
+        RLA => Modify(rla),
+        ROL => Modify(rol),
+        ROR => Modify(ror),
+        RRA => Modify(rra),
+        RTI => Special,
+        RTS => Special,
+        SAX => Write(sax),
+        SBC => Read(sbc),
+        SEC => Implied(sec),
+        SED => Implied(sed),
+        SEI => Implied(sei),
+        SHX => Write(shx),
+        SHY => Write(shy),
+        SLO => Modify(slo),
+        SRE => Modify(sre),
+        STA => Write(sta),
+        STX => Write(stx),
+        STY => Write(sty),
+        TAS => Write(tas),
+        TAX => Implied(tax),
+        TAY => Implied(tay),
+        TSX => Implied(tsx),
+        TXA => Implied(txa),
+        TXS => Implied(txs),
+        TYA => Implied(tya),
+        XAA => Read(xaa),
+        // Never makes it past decoding
+        INVALID => Special,
+    }
 }
 
-impl Error for ExecutionError {}
+// Progress through the instruction currently running
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ExecState {
+    opcode: u8,
+    // Cycle of the instruction to run next, 0 starts the next instruction
+    pub step: u8,
+    // Interrupts run as a BRK
+    interrupt: Option<Interrupt>,
+    // Effective address, and what it was before indexing
+    addr: u16,
+    base: u16,
+    // Zero page pointer of the indirect modes
+    ptr: u8,
+    // Operand/temporary byte
+    val: u8,
+    // Indexing crossed a page
+    crossed: bool,
+}
+
+impl ExecState {
+    fn index(&mut self, base: u16, index: u8) {
+        self.base = base;
+        self.addr = base.wrapping_add(index as u16);
+        self.crossed = page_num(base) != page_num(self.addr);
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.opcode);
+        w.u8(self.step);
+        w.u8(match self.interrupt {
+            None => 0,
+            Some(Interrupt::Request) => 1,
+            Some(Interrupt::Reset) => 2,
+            Some(Interrupt::NonMaskable) => 3,
+        });
+        w.u16(self.addr);
+        w.u16(self.base);
+        w.u8(self.ptr);
+        w.u8(self.val);
+        w.bool(self.crossed);
+    }
 
-impl fmt::Display for ExecutionError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            ExecutionError::InvalidInstruction(i) => {
-                write!(f, "Attempted to run invalid instruction: {:?}", i)
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.opcode = r.u8()?;
+        self.step = r.u8()?;
+        self.interrupt = match r.u8()? {
+            0 => None,
+            1 => Some(Interrupt::Request),
+            2 => Some(Interrupt::Reset),
+            3 => Some(Interrupt::NonMaskable),
+            _ => return Err("Invalid interrupt in save state".into()),
+        };
+        self.addr = r.u16()?;
+        self.base = r.u16()?;
+        self.ptr = r.u8()?;
+        self.val = r.u8()?;
+        self.crossed = r.bool()?;
+        if self.step != 0 {
+            let (op, mode) = decode_opcode(self.opcode, 0)?;
+            if self.step > last_step(op, mode) {
+                return Err("Invalid instruction cycle in save state".into());
             }
         }
+        Ok(())
     }
 }
 
-// Function which implements the behavior of an instruction
-// returns the number of _extra_ cycles needed to run the instruction
-// (this extra will be added to number of cycles in instruction matrix)
-type OpcodeFn = fn(AddressingMode, &mut Cpu) -> Result<u16>;
+// First cycle of an instruction, fetches the opcode. When servicing an interrupt the opcode
+// is thrown away and a BRK runs instead.
+pub fn begin_instr(cpu: &mut Cpu, interrupt: Option<Interrupt>) -> Result<Opcode> {
+    let pc = cpu.reg.pc;
+    let opcode = match interrupt {
+        Some(_) => {
+            cpu.read(pc)?;
+            0x00
+        }
+        None => read_next_byte(cpu)?,
+    };
+    let (op, _) = decode_opcode(opcode, pc)?;
+    cpu.exec = ExecState {
+        opcode,
+        step: 1,
+        interrupt,
+        ..Default::default()
+    };
+    Ok(op)
+}
+
+// Runs the next cycle of the current instruction
+pub fn exec_cycle(cpu: &mut Cpu) -> Result<()> {
+    let (op, mode) = decode_opcode(cpu.exec.opcode, cpu.reg.pc)?;
+    let step = cpu.exec.step;
+    cpu.exec.step += 1;
+
+    let done = match (lookup_opcode_fn(op), mode) {
+        (OpcodeFn::Special, _) => special_cycle(cpu, op, mode, step)?,
+        (OpcodeFn::Branch(flag, set), _) => branch_cycle(cpu, flag, set, step)?,
+        (f, Mode::Imp | Mode::Acc) => {
+            let dummy = cpu.read(cpu.reg.pc)?;
+            match f {
+                OpcodeFn::Implied(f) => f(cpu),
+                OpcodeFn::Modify(f) => cpu.reg.a = f(cpu, cpu.reg.a),
+                // Implied NOPs
+                OpcodeFn::Read(f) => f(cpu, dummy),
+                _ => panic!("{op:?} has no implied mode"),
+            }
+            true
+        }
+        (OpcodeFn::Read(f), Mode::Imm) => {
+            let m = read_next_byte(cpu)?;
+            f(cpu, m);
+            true
+        }
+        (f, _) => memory_cycle(cpu, f, mode, step)?,
+    };
+    if done {
+        cpu.exec.step = 0;
+    }
+    Ok(())
+}
 
-fn lookup_opcode_fn(op: Opcode) -> OpcodeFn {
+// Last cycle an instruction can get to (the opcode fetch is cycle 0), page crossings included
+fn last_step(op: Opcode, mode: Mode) -> u8 {
     use Opcode::*;
-    match op {
-        ADC => adc,
-        AND => and,
-        ASL => asl,
-        BCC => bcc,
-        BCS => bcs,
-        BEQ => beq,
-        BIT => bit,
-        BMI => bmi,
-        BNE => bne,
-        BPL => bpl,
-        BRK => brk,
-        BVC => bvc,
-        BVS => bvs,
-        CLC => clc,
-        CLD => cld,
-        CLI => cli,
-        CLV => clv,
-        CMP => cmp,
-        CPX => cpx,
-        CPY => cpy,
-        DEC => dec,
-        DEX => dex,
-        DEY => dey,
-        EOR => eor,
-        INC => inc,
-        INX => inx,
-        INY => iny,
-        JMP => jmp,
-        JSR => jsr,
-        LAX => lax,
-        LDA => lda,
-        LDX => ldx,
-        LDY => ldy,
-        LSR => lsr,
-        NOP => nop,
-        ORA => ora,
-        PHA => pha,
-        PHP => php,
-        PLA => pla,
-        PLP => plp,
-        ROL => rol,
-        ROR => ror,
-        RTI => rti,
-        RTS => rts,
-        SBC => sbc,
-        SEC => sec,
-        SED => sed,
-        SEI => sei,
-        STA => sta,
-        STX => stx,
-        STY => sty,
-        TAX => tax,
-        TAY => tay,
-        TSX => tsx,
-        TXA => txa,
-        TXS => txs,
-        TYA => tya,
-        INVALID => invalid_op,
+    match (lookup_opcode_fn(op), mode) {
+        (OpcodeFn::Special, _) => match op {
+            BRK => 6,
+            JSR | RTI | RTS => 5,
+            JMP if mode == Mode::Ind => 4,
+            PLA | PLP => 3,
+            _ => 2,
+        },
+        (OpcodeFn::Branch(..), _) => 3,
+        (_, Mode::Imp | Mode::Acc | Mode::Imm) => 1,
+        (OpcodeFn::Modify(_), _) => access_step(mode) + 2,
+        (_, _) => access_step(mode),
+    }
+}
+
+// First cycle which accesses the effective address
+fn access_step(mode: Mode) -> u8 {
+    match mode {
+        Mode::Zpg => 2,
+        Mode::ZpgX | Mode::ZpgY | Mode::Abs => 3,
+        Mode::AbsX | Mode::AbsY => 4,
+        Mode::XInd | Mode::IndY => 5,
+        _ => panic!("{mode:?} has no effective address"),
     }
 }
 
+// How an operand read shows up in the code/data log
+fn data_flags(mode: Mode) -> PrgFlags {
+    match mode {
+        Mode::XInd | Mode::IndY => PrgFlags::DATA | PrgFlags::INDIRECT_DATA,
+        _ => PrgFlags::DATA,
+    }
+}
+
+// Cycles of instructions with an operand in memory, returns whether the instruction is done
+fn memory_cycle(cpu: &mut Cpu, f: OpcodeFn, mode: Mode, step: u8) -> Result<bool> {
+    let access_step = access_step(mode);
+    if step < access_step {
+        return address_cycle(cpu, f, mode, step);
+    }
+
+    let addr = cpu.exec.addr;
+    let data = data_flags(mode);
+    match (f, step - access_step) {
+        (OpcodeFn::Read(f), 0) => {
+            let m = cpu.read_logged(addr, data)?;
+            f(cpu, m);
+            Ok(true)
+        }
+        (OpcodeFn::Write(f), 0) => {
+            // (Might move the address)
+            let val = f(cpu);
+            cpu.write(cpu.exec.addr, val)?;
+            Ok(true)
+        }
+        (OpcodeFn::Modify(_), 0) => {
+            cpu.exec.val = cpu.read_logged(addr, data)?;
+            Ok(false)
+        }
+        (OpcodeFn::Modify(_), 1) => {
+            // The unmodified value gets written back while the ALU works
+            cpu.write(addr, cpu.exec.val)?;
+            Ok(false)
+        }
+        (OpcodeFn::Modify(f), 2) => {
+            let val = f(cpu, cpu.exec.val);
+            cpu.write(addr, val)?;
+            Ok(true)
+        }
+        _ => panic!("Invalid cycle {step} for {mode:?}"),
+    }
+}
 
-pub fn exec_instr(i: Instr, cpu: &mut Cpu) -> Result<u16> {
-    // Lookup opcode function
-    // Run instruction, return # of extra cycles needed
-    lookup_opcode_fn(i.op)(i.mode, cpu)
+// Cycles spent working out the effective address
+fn address_cycle(cpu: &mut Cpu, f: OpcodeFn, mode: Mode, step: u8) -> Result<bool> {
+    let index = match mode {
+        Mode::ZpgX | Mode::AbsX => cpu.reg.x,
+        _ => cpu.reg.y,
+    };
+    let st = cpu.exec;
+    match (mode, step) {
+        (Mode::XInd | Mode::IndY, 1) => cpu.exec.ptr = read_next_byte(cpu)?,
+        (_, 1) => cpu.exec.addr = read_next_byte(cpu)? as u16,
+        (Mode::ZpgX | Mode::ZpgY, 2) => {
+            // Reads the unindexed address, indexing wraps around the zero page
+            cpu.read(st.addr)?;
+            cpu.exec.addr = (st.addr + index as u16) & 0xFF;
+        }
+        (Mode::Abs, 2) => {
+            let hi = read_next_byte(cpu)?;
+            cpu.exec.addr = make_address(lo_byte(st.addr), hi);
+        }
+        (Mode::AbsX | Mode::AbsY, 2) => {
+            let hi = read_next_byte(cpu)?;
+            cpu.exec.index(make_address(lo_byte(st.addr), hi), index);
+        }
+        (Mode::XInd, 2) => {
+            cpu.read(st.ptr as u16)?;
+            cpu.exec.ptr = st.ptr.wrapping_add(cpu.reg.x);
+        }
+        (Mode::XInd, 3) | (Mode::IndY, 2) => cpu.exec.addr = cpu.read(st.ptr as u16)? as u16,
+        (Mode::XInd, 4) => {
+            let hi = cpu.read(st.ptr.wrapping_add(1) as u16)?;
+            cpu.exec.addr = make_address(lo_byte(st.addr), hi);
+        }
+        (Mode::IndY, 3) => {
+            let hi = cpu.read(st.ptr.wrapping_add(1) as u16)?;
+            cpu.exec.index(make_address(lo_byte(st.addr), hi), index);
+        }
+        (Mode::AbsX | Mode::AbsY, 3) | (Mode::IndY, 4) => {
+            // The high byte isn't fixed up yet, so this only reads the right address if
+            // indexing didn't cross a page. Reads are done then, everything else gets a
+            // dummy read.
+            let addr = make_address(lo_byte(st.addr), hi_byte(st.base));
+            if let (OpcodeFn::Read(f), false) = (f, st.crossed) {
+                let m = cpu.read_logged(addr, data_flags(mode))?;
+                f(cpu, m);
+                return Ok(true);
+            }
+            cpu.read(addr)?;
+        }
+        _ => panic!("Invalid cycle {step} for {mode:?}"),
+    }
+    Ok(false)
 }
 
-fn invalid_op(am: AddressingMode, _: &mut Cpu) -> Result<u16> {
-    Err(Box::new(ExecutionError::InvalidInstruction(Instr {
-        op: Opcode::INVALID,
-        mode: am,
-    })))
+fn branch_cycle(cpu: &mut Cpu, flag: StatusFlags, set: bool, step: u8) -> Result<bool> {
+    match step {
+        1 => {
+            cpu.exec.val = read_next_byte(cpu)?;
+            // Done unless the branch is taken
+            Ok(cpu.reg.status.contains(flag) != set)
+        }
+        2 => {
+            // A taken branch doesn't poll for interrupts on this cycle, so an IRQ or NMI that
+            // came in during the last one waits for another instruction
+            if cpu.interrupts.run_irq && !cpu.interrupts.prev_run_irq {
+                cpu.interrupts.run_irq = false;
+            }
+            if cpu.interrupts.need_nmi && !cpu.interrupts.prev_need_nmi {
+                // Still latched, it's only the decision to take it that gets pushed back
+                cpu.interrupts.delay_nmi = true;
+            }
+            cpu.read(cpu.reg.pc)?;
+            let target = cpu.reg.pc.wrapping_add(cpu.exec.val as i8 as u16);
+            cpu.exec.addr = target;
+            cpu.exec.crossed = page_num(target) != page_num(cpu.reg.pc);
+            cpu.reg.pc = make_address(lo_byte(target), hi_byte(cpu.reg.pc));
+            Ok(!cpu.exec.crossed)
+        }
+        3 => {
+            // Crossed a page, one more cycle to fix the high byte
+            cpu.read(cpu.reg.pc)?;
+            cpu.reg.pc = cpu.exec.addr;
+            Ok(true)
+        }
+        _ => panic!("Invalid branch cycle {step}"),
+    }
 }
 
-// Returns whether this addressing mode will cross a page boundary
-// (which typically incurs an extra cycle cost)
-fn cross_page_boundary(am: AddressingMode, cpu: &mut Cpu) -> bool {
-    use AddressingMode::*;
-    match am {
-        AbsoluteX(addr) => page_num(addr) != page_num(addr + (cpu.reg.x as u16)),
-        AbsoluteY(addr) => page_num(addr) != page_num(addr.wrapping_add(cpu.reg.y as u16)),
-        IndirectY(offset) => {
-            let lo = cpu.read(offset as u16).unwrap();
-            let hi = cpu.read((offset.wrapping_add(1)) as u16).unwrap();
-            let effective = make_address(lo, hi);
+fn special_cycle(cpu: &mut Cpu, op: Opcode, mode: Mode, step: u8) -> Result<bool> {
+    use Opcode::*;
+    let st = cpu.exec;
+    let pc = cpu.reg.pc;
+    let stack_addr = STACK_OFFSET + cpu.reg.sp as u16;
+    match (op, step) {
+        (BRK, 1) => {
+            // BRK skips the byte after it, interrupts don't
+            if st.interrupt.is_some() {
+                cpu.read(pc)?;
+            } else {
+                read_next_byte(cpu)?;
+            }
+        }
+        (BRK, 2) => push_interrupt_byte(cpu, hi_byte(pc))?,
+        (BRK, 3) => push_interrupt_byte(cpu, lo_byte(pc))?,
+        (BRK, 4) => {
+            let mut flags = cpu.reg.status;
+            flags.insert(StatusFlags::UNUSED);
+            flags.set(StatusFlags::BREAK, st.interrupt.is_none());
+            push_interrupt_byte(cpu, flags.bits())?;
+            cpu.reg.status.insert(StatusFlags::INTERRUPT_DISABLE);
+
+            // The vector is picked now, so an NMI arriving up to here hijacks the sequence
+            // (even a BRK's, which then returns to the instruction after the BRK)
+            let interrupt = match st.interrupt {
+                Some(Interrupt::Reset) => Interrupt::Reset,
+                _ if cpu.interrupts.need_nmi => {
+                    cpu.interrupts.need_nmi = false;
+                    Interrupt::NonMaskable
+                }
+                _ => Interrupt::Request,
+            };
+            cpu.exec.addr = interrupt.vector();
+        }
+        (BRK, 5) => cpu.exec.val = cpu.read_logged(st.addr, PrgFlags::DATA)?,
+        (BRK, 6) => {
+            cpu.reg.pc = make_address(st.val, cpu.read_logged(st.addr + 1, PrgFlags::DATA)?);
+            return Ok(true);
+        }
+
+        (JSR, 1) => cpu.exec.val = read_next_byte(cpu)?,
+        (JSR, 2) | (PLA, 2) | (PLP, 2) | (RTI, 2) | (RTS, 2) => {
+            cpu.read(stack_addr)?;
+        }
+        (JSR, 3) => push_stack(cpu, hi_byte(pc))?,
+        (JSR, 4) => push_stack(cpu, lo_byte(pc))?,
+        (JSR, 5) => {
+            cpu.reg.pc = make_address(st.val, cpu.read_logged(pc, PrgFlags::CODE)?);
+            return Ok(true);
+        }
+
+        (PHA, 1) | (PHP, 1) | (PLA, 1) | (PLP, 1) | (RTI, 1) | (RTS, 1) => {
+            cpu.read(pc)?;
+        }
+        (PHA, 2) => {
+            push_stack(cpu, cpu.reg.a)?;
+            return Ok(true);
+        }
+        (PHP, 2) => {
+            let mut flags = cpu.reg.status;
+            flags.insert(StatusFlags::BREAK);
+            flags.insert(StatusFlags::UNUSED);
+            push_stack(cpu, flags.bits())?;
+            return Ok(true);
+        }
+        (PLA, 3) => {
+            cpu.reg.a = pop_stack(cpu)?;
+            set_zero_flag(cpu, cpu.reg.a);
+            set_negative_flag(cpu, cpu.reg.a);
+            return Ok(true);
+        }
+        (PLP, 3) => {
+            pull_status(cpu)?;
+            return Ok(true);
+        }
+
+        (RTI, 3) => pull_status(cpu)?,
+        (RTI, 4) | (RTS, 3) => cpu.exec.val = pop_stack(cpu)?,
+        (RTI, 5) => {
+            cpu.reg.pc = make_address(st.val, pop_stack(cpu)?);
+            return Ok(true);
+        }
+        (RTS, 4) => cpu.reg.pc = make_address(st.val, pop_stack(cpu)?),
+        (RTS, 5) => {
+            read_next_byte(cpu)?;
+            return Ok(true);
+        }
 
-            page_num(effective) != page_num(effective.wrapping_add(cpu.reg.y as u16))
+        (JMP, 1) => cpu.exec.val = read_next_byte(cpu)?,
+        (JMP, 2) => {
+            let addr = make_address(st.val, read_next_byte(cpu)?);
+            if mode == Mode::Abs {
+                cpu.reg.pc = addr;
+                return Ok(true);
+            }
+            cpu.exec.addr = addr;
+        }
+        (JMP, 3) => cpu.exec.val = cpu.read_logged(st.addr, PrgFlags::DATA)?,
+        (JMP, 4) => {
+            // Hardware bug: the pointer's high byte is read without carrying into the page
+            let hi_addr = make_address(lo_byte(st.addr).wrapping_add(1), hi_byte(st.addr));
+            cpu.reg.pc = make_address(st.val, cpu.read_logged(hi_addr, PrgFlags::DATA)?);
+            cpu.log_prg(cpu.reg.pc, PrgFlags::INDIRECT_CODE);
+            return Ok(true);
         }
-        Relative(_) => page_num(cpu.reg.pc) != page_num(deref_address(am, cpu).unwrap()),
-        // Other addressing modes don't cross page boundaries
-        _ => false,
+        _ => panic!("Invalid cycle {step} for {op:?}"),
     }
+    Ok(false)
 }
 
 // Conditionally set the "Negative" status flag based on a value
@@ -153,66 +527,47 @@ fn set_zero_flag(cpu: &mut Cpu, val: u8) {
 pub fn push_stack(cpu: &mut Cpu, byte: u8) -> Result<()> {
     let sp = (cpu.reg.sp as u16) + STACK_OFFSET;
     cpu.write(sp, byte)?;
-    // println!("push_stack: sp: {:X}, val: {:X}", cpu.reg.sp, byte);
-    cpu.reg.sp -= 1;
-    Ok(())
-}
-
-pub fn push_stack_addr(cpu: &mut Cpu, addr: u16) -> Result<()> {
-    push_stack(cpu, hi_byte(addr))?;
-    push_stack(cpu, lo_byte(addr))?;
+    cpu.reg.sp = cpu.reg.sp.wrapping_sub(1);
     Ok(())
 }
 
 pub fn pop_stack(cpu: &mut Cpu) -> Result<u8> {
-    cpu.reg.sp += 1;
+    cpu.reg.sp = cpu.reg.sp.wrapping_add(1);
     let sp = (cpu.reg.sp as u16) + STACK_OFFSET;
-    let val = cpu.read(sp)?;
-    // println!("pop stack: sp: {:X}, val: {:X}", cpu.reg.sp, val);
-    Ok(val)
-}
-
-pub fn pop_stack_addr(cpu: &mut Cpu) -> Result<u16> {
-    let lo = pop_stack(cpu)?;
-    let hi = pop_stack(cpu)?;
-    Ok(make_address(lo, hi))
+    cpu.read(sp)
 }
 
-fn branch_on_predicate(am: AddressingMode, cpu: &mut Cpu, take_branch: bool) -> Result<u16> {
-    let cross = cross_page_boundary(am, cpu);
-
-    if take_branch {
-        let addr = deref_address(am, cpu)?;
-        cpu.reg.pc = addr;
-        if cross {
-            Ok(2)
-        } else {
-            Ok(1)
-        }
+// A reset goes through the same motions as the other interrupts, with the writes turned
+// into reads
+fn push_interrupt_byte(cpu: &mut Cpu, byte: u8) -> Result<()> {
+    if cpu.exec.interrupt == Some(Interrupt::Reset) {
+        cpu.read(STACK_OFFSET + cpu.reg.sp as u16)?;
+        cpu.reg.sp = cpu.reg.sp.wrapping_sub(1);
+        Ok(())
     } else {
-        // branch not taken
-        Ok(0)
+        push_stack(cpu, byte)
     }
 }
 
-fn compare(am: AddressingMode, cpu: &mut Cpu, val: u8) -> Result<u16> {
-    let cross = cross_page_boundary(am, cpu);
-    let m = deref_byte(am, cpu)?;
+fn pull_status(cpu: &mut Cpu) -> Result<()> {
+    // Every bit is used, so all values are valid
+    let mut status = StatusFlags::from_bits_truncate(pop_stack(cpu)?);
+    status.remove(StatusFlags::BREAK);
+    status.insert(StatusFlags::UNUSED);
+    cpu.reg.status = status;
+    Ok(())
+}
+
+fn set_compare_flags(cpu: &mut Cpu, val: u8, m: u8) {
     let diff = (Wrapping(val) - Wrapping(m)).0;
 
     cpu.reg.status.set(StatusFlags::NEGATIVE, diff & 0x80 != 0);
     cpu.reg.status.set(StatusFlags::ZERO, m == val);
     cpu.reg.status.set(StatusFlags::CARRY, val >= m);
-    if cross {
-        Ok(1)
-    } else {
-        Ok(0)
-    }
 }
 
-fn adc(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    let m = deref_byte(am, cpu)?;
-    let crossed = cross_page_boundary(am, cpu);
+// A + M + C into A, shared by ADC, SBC and the unofficial opcodes built on them
+fn add_with_carry(cpu: &mut Cpu, m: u8) {
     let c = if cpu.reg.status.contains(StatusFlags::CARRY) {
         1u8
     } else {
@@ -233,62 +588,37 @@ fn adc(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
         StatusFlags::OVERFLOW,
         (is_negative(m) == is_negative(a)) && (is_negative(masked) != is_negative(a)),
     );
-
-    if crossed {
-        Ok(1)
-    } else {
-        Ok(0)
-    }
-}
-
-fn and(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    let a = cpu.reg.a;
-    let m = deref_byte(am, cpu)?;
-    let crossed = cross_page_boundary(am, cpu);
-    cpu.reg.a = a & m;
-    set_negative_flag(cpu, cpu.reg.a);
-    set_zero_flag(cpu, cpu.reg.a);
-
-    if crossed {
-        Ok(1)
-    } else {
-        Ok(0)
-    }
 }
 
-fn asl(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    if matches!(am, AddressingMode::Accumulator) {
-        let carry = is_negative(cpu.reg.a);
-        cpu.reg.a <<= 1;
-        cpu.reg.status.set(StatusFlags::CARRY, carry);
-        set_zero_flag(cpu, cpu.reg.a);
-        set_negative_flag(cpu, cpu.reg.a);
-    } else {
-        let addr = effective_addr(am, cpu)?;
-        let val = deref_byte(am, cpu)?;
-        let carry = is_negative(val);
-        cpu.write(addr, val << 1)?;
-        cpu.reg.status.set(StatusFlags::CARRY, carry);
-        set_zero_flag(cpu, val << 1);
-        set_negative_flag(cpu, val << 1);
+// AHX, SHX, SHY and TAS store `val` ANDed with the high byte of the base address + 1. When
+// the indexing crosses a page that value also ends up as the high byte of the address.
+fn store_and_high(cpu: &mut Cpu, val: u8) -> u8 {
+    let byte = val & hi_byte(cpu.exec.base).wrapping_add(1);
+    if cpu.exec.crossed {
+        cpu.exec.addr = make_address(lo_byte(cpu.exec.addr), byte);
     }
-    Ok(0)
+    byte
 }
 
-fn bcc(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    branch_on_predicate(am, cpu, !cpu.reg.status.contains(StatusFlags::CARRY))
+fn adc(cpu: &mut Cpu, m: u8) {
+    add_with_carry(cpu, m);
 }
 
-fn bcs(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    branch_on_predicate(am, cpu, cpu.reg.status.contains(StatusFlags::CARRY))
+fn and(cpu: &mut Cpu, m: u8) {
+    cpu.reg.a &= m;
+    set_negative_flag(cpu, cpu.reg.a);
+    set_zero_flag(cpu, cpu.reg.a);
 }
 
-fn beq(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    branch_on_predicate(am, cpu, cpu.reg.status.contains(StatusFlags::ZERO))
+fn asl(cpu: &mut Cpu, m: u8) -> u8 {
+    cpu.reg.status.set(StatusFlags::CARRY, is_negative(m));
+    let val = m << 1;
+    set_zero_flag(cpu, val);
+    set_negative_flag(cpu, val);
+    val
 }
 
-fn bit(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    let m = deref_byte(am, cpu)?;
+fn bit(cpu: &mut Cpu, m: u8) {
     cpu.reg
         .status
         .set(StatusFlags::NEGATIVE, m & 0b10000000 != 0);
@@ -296,521 +626,474 @@ fn bit(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
         .status
         .set(StatusFlags::OVERFLOW, m & 0b01000000 != 0);
     set_zero_flag(cpu, cpu.reg.a & m);
-    Ok(0)
-}
-
-fn bmi(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    branch_on_predicate(am, cpu, cpu.reg.status.contains(StatusFlags::NEGATIVE))
-}
-
-fn bne(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    branch_on_predicate(am, cpu, !cpu.reg.status.contains(StatusFlags::ZERO))
-}
-
-fn bpl(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    branch_on_predicate(am, cpu, !cpu.reg.status.contains(StatusFlags::NEGATIVE))
-}
-
-fn brk(_am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    // panic!("BRK");
-    push_stack_addr(cpu, cpu.reg.pc + 2)?;
-
-    cpu.reg.status.insert(StatusFlags::INTERRUPT_DISABLE);
-
-    let mut flags = cpu.reg.status.clone();
-    flags.insert(StatusFlags::BREAK);
-    push_stack(cpu, cpu.reg.status.bits())?;
-
-    cpu.reg.pc = make_address(cpu.read(0xFFFE)?, cpu.read(0xFFFF)?);
-    // No need to set interrupt field here, we've already handled it
-    // cpu.interrupt = Some(super::cpu::Interrupt::Request);
-
-    Ok(0)
-}
-
-fn bvc(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    branch_on_predicate(am, cpu, !cpu.reg.status.contains(StatusFlags::OVERFLOW))
-}
-
-fn bvs(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    branch_on_predicate(am, cpu, cpu.reg.status.contains(StatusFlags::OVERFLOW))
 }
 
-fn clc(_am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
+fn clc(cpu: &mut Cpu) {
     cpu.reg.status.remove(StatusFlags::CARRY);
-    Ok(0)
 }
 
-fn cld(_am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
+fn cld(cpu: &mut Cpu) {
     cpu.reg.status.remove(StatusFlags::DECIMAL);
-    Ok(0)
 }
 
-fn cli(_am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
+fn cli(cpu: &mut Cpu) {
     cpu.reg.status.remove(StatusFlags::INTERRUPT_DISABLE);
-    Ok(0)
 }
 
-fn clv(_am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
+fn clv(cpu: &mut Cpu) {
     cpu.reg.status.remove(StatusFlags::OVERFLOW);
-    Ok(0)
 }
 
-fn cmp(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    compare(am, cpu, cpu.reg.a)
+fn cmp(cpu: &mut Cpu, m: u8) {
+    set_compare_flags(cpu, cpu.reg.a, m);
 }
 
-fn cpx(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    compare(am, cpu, cpu.reg.x)
+fn cpx(cpu: &mut Cpu, m: u8) {
+    set_compare_flags(cpu, cpu.reg.x, m);
 }
 
-fn cpy(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    compare(am, cpu, cpu.reg.y)
+fn cpy(cpu: &mut Cpu, m: u8) {
+    set_compare_flags(cpu, cpu.reg.y, m);
 }
 
-fn dec(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    let e = effective_addr(am, cpu)?;
-    let val = deref_byte(am, cpu)?.wrapping_sub(1);
-    cpu.write(e, val)?;
+fn dec(cpu: &mut Cpu, m: u8) -> u8 {
+    let val = m.wrapping_sub(1);
     set_negative_flag(cpu, val);
     set_zero_flag(cpu, val);
-    Ok(0)
+    val
 }
 
-fn dex(_am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
+fn dex(cpu: &mut Cpu) {
     cpu.reg.x = cpu.reg.x.wrapping_sub(1);
     set_negative_flag(cpu, cpu.reg.x);
     set_zero_flag(cpu, cpu.reg.x);
-    Ok(0)
 }
 
-fn dey(_am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
+fn dey(cpu: &mut Cpu) {
     cpu.reg.y = cpu.reg.y.wrapping_sub(1);
     set_negative_flag(cpu, cpu.reg.y);
     set_zero_flag(cpu, cpu.reg.y);
-    Ok(0)
 }
 
-fn eor(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    let cross = cross_page_boundary(am, cpu);
-    let m = deref_byte(am, cpu)?;
-
+fn eor(cpu: &mut Cpu, m: u8) {
     cpu.reg.a ^= m;
-
     set_negative_flag(cpu, cpu.reg.a);
     set_zero_flag(cpu, cpu.reg.a);
-    if cross {
-        Ok(1)
-    } else {
-        Ok(0)
-    }
 }
 
-fn inc(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    let addr = effective_addr(am, cpu)?;
-    let val = cpu.read(addr)?.wrapping_add(1);
-    cpu.write(addr, val)?;
-
+fn inc(cpu: &mut Cpu, m: u8) -> u8 {
+    let val = m.wrapping_add(1);
     set_negative_flag(cpu, val);
     set_zero_flag(cpu, val);
-    Ok(0)
+    val
 }
 
-fn inx(_: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
+fn inx(cpu: &mut Cpu) {
     cpu.reg.x = cpu.reg.x.wrapping_add(1);
     set_negative_flag(cpu, cpu.reg.x);
     set_zero_flag(cpu, cpu.reg.x);
-    Ok(0)
 }
 
-fn iny(_: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
+fn iny(cpu: &mut Cpu) {
     cpu.reg.y = cpu.reg.y.wrapping_add(1);
     set_negative_flag(cpu, cpu.reg.y);
     set_zero_flag(cpu, cpu.reg.y);
-    Ok(0)
-}
-
-fn jmp(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    cpu.reg.pc = deref_address(am, cpu)?;
-    Ok(0)
-}
-
-fn jsr(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    push_stack_addr(cpu, cpu.reg.pc - 1)?;
-    cpu.reg.pc = deref_address(am, cpu)?;
-    Ok(0)
 }
 
-fn lax(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    let crossed = cross_page_boundary(am, cpu);
-    let m = deref_byte(am, cpu)?;
+fn lax(cpu: &mut Cpu, m: u8) {
     cpu.reg.x = m;
     cpu.reg.a = m;
     set_negative_flag(cpu, m);
     set_zero_flag(cpu, m);
-    if crossed {
-        Ok(1)
-    } else {
-        Ok(0)
-    }
 }
 
-fn lda(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    cpu.reg.a = deref_byte(am, cpu)?;
-    // Check if we cross a boundary before updating any state!
-    let crossed = cross_page_boundary(am, cpu);
-
+fn lda(cpu: &mut Cpu, m: u8) {
+    cpu.reg.a = m;
     set_negative_flag(cpu, cpu.reg.a);
     set_zero_flag(cpu, cpu.reg.a);
-
-    if crossed {
-        Ok(1)
-    } else {
-        Ok(0)
-    }
 }
 
-fn ldx(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    cpu.reg.x = deref_byte(am, cpu)?;
-    let crossed = cross_page_boundary(am, cpu);
-
+fn ldx(cpu: &mut Cpu, m: u8) {
+    cpu.reg.x = m;
     set_negative_flag(cpu, cpu.reg.x);
     set_zero_flag(cpu, cpu.reg.x);
-
-    if crossed {
-        Ok(1)
-    } else {
-        Ok(0)
-    }
 }
 
-fn ldy(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    cpu.reg.y = deref_byte(am, cpu)?;
-    let crossed = cross_page_boundary(am, cpu);
-
+fn ldy(cpu: &mut Cpu, m: u8) {
+    cpu.reg.y = m;
     set_negative_flag(cpu, cpu.reg.y);
     set_zero_flag(cpu, cpu.reg.y);
-
-    if crossed {
-        Ok(1)
-    } else {
-        Ok(0)
-    }
 }
 
-fn lsr(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    if matches!(am, AddressingMode::Accumulator) {
-        let carry = cpu.reg.a & 0b1 == 1;
-        cpu.reg.a >>= 1;
-        cpu.reg.status.set(StatusFlags::CARRY, carry);
-        set_zero_flag(cpu, cpu.reg.a);
-        cpu.reg.status.remove(StatusFlags::NEGATIVE);
-    } else {
-        let addr = effective_addr(am, cpu)?;
-        let val = deref_byte(am, cpu)?;
-        let carry = val & 0b1 == 1;
-        cpu.write(addr, val >> 1)?;
-
-        cpu.reg.status.set(StatusFlags::CARRY, carry);
-        set_zero_flag(cpu, val >> 1);
-        cpu.reg.status.remove(StatusFlags::NEGATIVE);
-    }
-    Ok(0)
+fn lsr(cpu: &mut Cpu, m: u8) -> u8 {
+    cpu.reg.status.set(StatusFlags::CARRY, m & 0b1 == 1);
+    let val = m >> 1;
+    set_zero_flag(cpu, val);
+    cpu.reg.status.remove(StatusFlags::NEGATIVE);
+    val
 }
 
-fn nop(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    if cross_page_boundary(am, cpu) {
-        Ok(1)
-    } else {
-        Ok(0)
-    }
-}
+// (Still reads its operand)
+fn nop(_: &mut Cpu, _: u8) {}
 
-fn ora(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    let cross = cross_page_boundary(am, cpu);
-    let m = deref_byte(am, cpu)?;
+fn ora(cpu: &mut Cpu, m: u8) {
     cpu.reg.a |= m;
-
     set_negative_flag(cpu, cpu.reg.a);
     set_zero_flag(cpu, cpu.reg.a);
-    if cross {
-        Ok(1)
-    } else {
-        Ok(0)
-    }
-}
-
-fn pha(_: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    push_stack(cpu, cpu.reg.a)?;
-    Ok(0)
-}
-
-fn php(_: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    let mut flags = cpu.reg.status.clone();
-    flags.insert(StatusFlags::BREAK);
-    flags.insert(StatusFlags::UNUSED);
-    push_stack(cpu, flags.bits())?;
-    Ok(0)
-}
-
-fn pla(_: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    cpu.reg.a = pop_stack(cpu)?;
-    set_zero_flag(cpu, cpu.reg.a);
-    set_negative_flag(cpu, cpu.reg.a);
-    Ok(0)
-}
-
-fn plp(_: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    // Invalid flags should never happen, we use all 8 bits
-
-    cpu.reg.status =
-        StatusFlags::from_bits(pop_stack(cpu)?).expect("Flags popped from stack invalid.");
-    cpu.reg.status.remove(StatusFlags::BREAK);
-    cpu.reg.status.insert(StatusFlags::UNUSED);
-    // cpu.reg.status.remove(StatusFlags::UNUSED);
-    Ok(0)
-}
-
-fn rol(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    let carry = if cpu.reg.status.contains(StatusFlags::CARRY) {
-        1
-    } else {
-        0
-    };
-    if matches!(am, AddressingMode::Accumulator) {
-        let a = cpu.reg.a;
-        let new_carry = is_negative(a);
-        let rot = ((a as u16) << 1) | carry;
-        let masked = (rot & 0xFF) as u8;
-        cpu.reg.status.set(StatusFlags::CARRY, new_carry);
-        set_negative_flag(cpu, masked);
-        set_zero_flag(cpu, masked);
-
-        cpu.reg.a = masked;
-    } else {
-        let addr = effective_addr(am, cpu)?;
-        let m = deref_byte(am, cpu)?;
-        let new_carry = is_negative(m);
-        let rot = ((m as u16) << 1) | carry;
-        let masked = (rot & 0xFF) as u8;
-
-        cpu.reg.status.set(StatusFlags::CARRY, new_carry);
-        set_negative_flag(cpu, masked);
-        set_zero_flag(cpu, masked);
-        cpu.write(addr, masked)?;
-    }
-    Ok(0)
-}
-
-fn ror(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    let carry = if cpu.reg.status.contains(StatusFlags::CARRY) {
-        0x80
-    } else {
-        0
-    };
-    if matches!(am, AddressingMode::Accumulator) {
-        let a = cpu.reg.a;
-        let new_carry = a & 1 == 1;
-        let rot = ((a as u16) >> 1) | carry;
-        let masked = (rot & 0xFF) as u8;
-
-        cpu.reg.status.set(StatusFlags::CARRY, new_carry);
-        set_negative_flag(cpu, masked);
-        set_zero_flag(cpu, masked);
-        cpu.reg.a = masked;
-    } else {
-        let addr = effective_addr(am, cpu)?;
-        let m = deref_byte(am, cpu)?;
-        let new_carry = m & 1 == 1;
-        let rot = ((m as u16) >> 1) | carry;
-        let masked = (rot & 0xFF) as u8;
-
-        cpu.reg.status.set(StatusFlags::CARRY, new_carry);
-        set_negative_flag(cpu, masked);
-        set_zero_flag(cpu, masked);
-        cpu.write(addr, masked)?;
-    }
-    Ok(0)
 }
 
-fn rti(_: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    let mut status = StatusFlags::from_bits(pop_stack(cpu)?)
-        .expect("Status flags popped from stack are invalid.");
-    status.remove(StatusFlags::BREAK);
-    status.insert(StatusFlags::UNUSED);
-    cpu.reg.status = status;
-
-    cpu.reg.pc = pop_stack_addr(cpu)?;
-    Ok(0)
+fn rol(cpu: &mut Cpu, m: u8) -> u8 {
+    let carry = cpu.reg.status.contains(StatusFlags::CARRY) as u8;
+    cpu.reg.status.set(StatusFlags::CARRY, is_negative(m));
+    let val = (m << 1) | carry;
+    set_negative_flag(cpu, val);
+    set_zero_flag(cpu, val);
+    val
 }
 
-fn rts(_: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    cpu.reg.pc = pop_stack_addr(cpu)? + 1;
-    Ok(0)
+fn ror(cpu: &mut Cpu, m: u8) -> u8 {
+    let carry = (cpu.reg.status.contains(StatusFlags::CARRY) as u8) << 7;
+    cpu.reg.status.set(StatusFlags::CARRY, m & 1 == 1);
+    let val = (m >> 1) | carry;
+    set_negative_flag(cpu, val);
+    set_zero_flag(cpu, val);
+    val
 }
 
-fn sbc(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
+fn sbc(cpu: &mut Cpu, m: u8) {
     // literally the same as adc() but with m inverted
     // https://stackoverflow.com/a/29224684
     // A - M - ~C = A + -M - ~C = A + ~M + 1 - (1-C) = A + ~M + C
-    let m = !(deref_byte(am, cpu)?);
-    let crossed = cross_page_boundary(am, cpu);
-    let c = if cpu.reg.status.contains(StatusFlags::CARRY) {
-        1u8
-    } else {
-        0u8
-    };
-    let a = cpu.reg.a;
-    let sum = (a as u16) + (m as u16) + (c as u16);
-
-    let masked = (sum & 0xFF) as u8;
-    cpu.reg.a = masked;
-
-    // https://www.righto.com/2012/12/the-6502-overflow-flag-explained.html
-    set_negative_flag(cpu, masked);
-    set_zero_flag(cpu, masked);
-    let carry = sum & 0xFF00 != 0;
-    cpu.reg.status.set(StatusFlags::CARRY, carry);
-    cpu.reg.status.set(
-        StatusFlags::OVERFLOW,
-        (is_negative(m) == is_negative(a)) && (is_negative(masked) != is_negative(a)),
-    );
-
-    if crossed {
-        Ok(1)
-    } else {
-        Ok(0)
-    }
+    add_with_carry(cpu, !m);
 }
 
-fn sec(_: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
+fn sec(cpu: &mut Cpu) {
     cpu.reg.status.insert(StatusFlags::CARRY);
-    Ok(0)
 }
 
-fn sed(_: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
+fn sed(cpu: &mut Cpu) {
     cpu.reg.status.insert(StatusFlags::DECIMAL);
-    Ok(0)
 }
 
-fn sei(_: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
+fn sei(cpu: &mut Cpu) {
     cpu.reg.status.insert(StatusFlags::INTERRUPT_DISABLE);
-    Ok(0)
 }
 
-fn sta(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    let addr = effective_addr(am, cpu)?;
-    cpu.write(addr, cpu.reg.a)?;
-    Ok(0)
+fn sta(cpu: &mut Cpu) -> u8 {
+    cpu.reg.a
 }
 
-fn stx(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    let addr = effective_addr(am, cpu)?;
-    cpu.write(addr, cpu.reg.x)?;
-    Ok(0)
+fn stx(cpu: &mut Cpu) -> u8 {
+    cpu.reg.x
 }
 
-fn sty(am: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
-    let addr = effective_addr(am, cpu)?;
-    cpu.write(addr, cpu.reg.y)?;
-    Ok(0)
+fn sty(cpu: &mut Cpu) -> u8 {
+    cpu.reg.y
 }
 
-fn tax(_: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
+fn tax(cpu: &mut Cpu) {
     cpu.reg.x = cpu.reg.a;
     set_negative_flag(cpu, cpu.reg.x);
     set_zero_flag(cpu, cpu.reg.x);
-    Ok(0)
 }
 
-fn tay(_: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
+fn tay(cpu: &mut Cpu) {
     cpu.reg.y = cpu.reg.a;
     set_negative_flag(cpu, cpu.reg.y);
     set_zero_flag(cpu, cpu.reg.y);
-    Ok(0)
 }
 
-fn tsx(_: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
+fn tsx(cpu: &mut Cpu) {
     cpu.reg.x = cpu.reg.sp;
     set_negative_flag(cpu, cpu.reg.x);
     set_zero_flag(cpu, cpu.reg.x);
-    Ok(0)
 }
 
-fn txa(_: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
+fn txa(cpu: &mut Cpu) {
     cpu.reg.a = cpu.reg.x;
     set_negative_flag(cpu, cpu.reg.a);
     set_zero_flag(cpu, cpu.reg.a);
-    Ok(0)
 }
 
-fn txs(_: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
+fn txs(cpu: &mut Cpu) {
     cpu.reg.sp = cpu.reg.x;
-    Ok(0)
 }
 
-fn tya(_: AddressingMode, cpu: &mut Cpu) -> Result<u16> {
+fn tya(cpu: &mut Cpu) {
     cpu.reg.a = cpu.reg.y;
     set_negative_flag(cpu, cpu.reg.a);
     set_zero_flag(cpu, cpu.reg.a);
-    Ok(0)
+}
+
+/*
+ *       Unofficial Opcodes
+ *       https://www.nesdev.org/wiki/Programming_with_unofficial_opcodes
+ */
+
+// Unstable opcodes (XAA) mix in a chip-dependent constant
+const UNSTABLE_MAGIC: u8 = 0xEE;
+
+fn ahx(cpu: &mut Cpu) -> u8 {
+    store_and_high(cpu, cpu.reg.a & cpu.reg.x)
+}
+
+fn alr(cpu: &mut Cpu, m: u8) {
+    cpu.reg.a &= m;
+    cpu.reg.a = lsr(cpu, cpu.reg.a);
+}
+
+fn anc(cpu: &mut Cpu, m: u8) {
+    and(cpu, m);
+    cpu.reg.status.set(StatusFlags::CARRY, is_negative(cpu.reg.a));
+}
+
+fn arr(cpu: &mut Cpu, m: u8) {
+    let carry = if cpu.reg.status.contains(StatusFlags::CARRY) {
+        0x80
+    } else {
+        0
+    };
+    let val = ((cpu.reg.a & m) >> 1) | carry;
+    cpu.reg.a = val;
+    set_negative_flag(cpu, val);
+    set_zero_flag(cpu, val);
+    // Flags come out of the ADC circuitry rather than the shift
+    let bit6 = val & 0x40 != 0;
+    let bit5 = val & 0x20 != 0;
+    cpu.reg.status.set(StatusFlags::CARRY, bit6);
+    cpu.reg.status.set(StatusFlags::OVERFLOW, bit6 != bit5);
+}
+
+fn axs(cpu: &mut Cpu, m: u8) {
+    let ax = cpu.reg.a & cpu.reg.x;
+    cpu.reg.x = ax.wrapping_sub(m);
+    cpu.reg.status.set(StatusFlags::CARRY, ax >= m);
+    set_negative_flag(cpu, cpu.reg.x);
+    set_zero_flag(cpu, cpu.reg.x);
+}
+
+fn dcp(cpu: &mut Cpu, m: u8) -> u8 {
+    let val = m.wrapping_sub(1);
+    cmp(cpu, val);
+    val
+}
+
+fn isc(cpu: &mut Cpu, m: u8) -> u8 {
+    let val = m.wrapping_add(1);
+    sbc(cpu, val);
+    val
+}
+
+fn las(cpu: &mut Cpu, m: u8) {
+    let val = m & cpu.reg.sp;
+    cpu.reg.a = val;
+    cpu.reg.x = val;
+    cpu.reg.sp = val;
+    set_negative_flag(cpu, val);
+    set_zero_flag(cpu, val);
+}
+
+fn rla(cpu: &mut Cpu, m: u8) -> u8 {
+    let val = rol(cpu, m);
+    and(cpu, val);
+    val
+}
+
+fn rra(cpu: &mut Cpu, m: u8) -> u8 {
+    let val = ror(cpu, m);
+    adc(cpu, val);
+    val
+}
+
+fn sax(cpu: &mut Cpu) -> u8 {
+    cpu.reg.a & cpu.reg.x
+}
+
+fn shx(cpu: &mut Cpu) -> u8 {
+    store_and_high(cpu, cpu.reg.x)
+}
+
+fn shy(cpu: &mut Cpu) -> u8 {
+    store_and_high(cpu, cpu.reg.y)
+}
+
+fn slo(cpu: &mut Cpu, m: u8) -> u8 {
+    let val = asl(cpu, m);
+    ora(cpu, val);
+    val
+}
+
+fn sre(cpu: &mut Cpu, m: u8) -> u8 {
+    let val = lsr(cpu, m);
+    eor(cpu, val);
+    val
+}
+
+fn tas(cpu: &mut Cpu) -> u8 {
+    cpu.reg.sp = cpu.reg.a & cpu.reg.x;
+    store_and_high(cpu, cpu.reg.sp)
+}
+
+fn xaa(cpu: &mut Cpu, m: u8) {
+    cpu.reg.a = (cpu.reg.a | UNSTABLE_MAGIC) & cpu.reg.x & m;
+    set_negative_flag(cpu, cpu.reg.a);
+    set_zero_flag(cpu, cpu.reg.a);
 }
 
 #[cfg(test)]
 mod exec_tests {
-    use super::AddressingMode::*;
+    use super::{begin_instr, exec_cycle, last_step, ExecState};
     use crate::cpu::cpu::Cpu;
+    use crate::cpu::decode::instr_lookup::{Mode, LOOKUP};
+    use crate::cpu::isa::Opcode;
+    use crate::cpu::reg::StatusFlags;
+    use crate::state::{StateReader, StateWriter};
+
+    // Runs the instruction at the PC, returns how many cycles it took
+    fn run(cpu: &mut Cpu) -> u16 {
+        begin_instr(cpu, None).unwrap();
+        let mut cycles = 1;
+        while cpu.exec.step != 0 {
+            exec_cycle(cpu).unwrap();
+            cycles += 1;
+        }
+        cycles
+    }
 
-    use super::cross_page_boundary;
+    // Runs `code` from $0300
+    fn run_code(cpu: &mut Cpu, code: &[u8]) -> u16 {
+        for (i, byte) in code.iter().enumerate() {
+            cpu.write(0x0300 + i as u16, *byte).unwrap();
+        }
+        cpu.reg.pc = 0x0300;
+        run(cpu)
+    }
 
     #[test]
-    fn cross_page_boundary_test() {
-        let mut cpu = Cpu::mock(None);
-        cpu.reg.x = 0xFF;
-        cpu.reg.y = 0xFF;
-        assert!(cross_page_boundary(AbsoluteX(0x17), &mut cpu));
-        assert!(cross_page_boundary(AbsoluteX(0x01), &mut cpu));
-        assert!(cross_page_boundary(AbsoluteX(0xFF), &mut cpu));
-        assert!(cross_page_boundary(AbsoluteY(0x17), &mut cpu));
-        assert!(cross_page_boundary(AbsoluteY(0x01), &mut cpu));
-        assert!(cross_page_boundary(AbsoluteY(0xFF), &mut cpu));
+    fn instr_cycles() {
+        // Without page crossings every instruction takes as long as the lookup table says
+        for opcode in 0..=0xFFu8 {
+            let (op, mode, ncycles) = LOOKUP[opcode as usize >> 4][opcode as usize & 0xF];
+            // (The mock has no interrupt vectors for BRK)
+            if op == Opcode::INVALID || op == Opcode::BRK || mode == Mode::Rel {
+                continue;
+            }
+            let mut cpu = Cpu::mock(None);
+            cpu.reg.sp = 0xFD;
+            // Operand $10, $0410 through the pointer at $10
+            cpu.write(0x10, 0x10).unwrap();
+            cpu.write(0x11, 0x04).unwrap();
+            let cycles = run_code(&mut cpu, &[opcode, 0x10, 0x04]);
+            assert_eq!(cycles, ncycles as u16, "{opcode:02X} {op:?} {mode:?}");
+        }
+    }
 
+    #[test]
+    fn page_crossing() {
+        let mut cpu = Cpu::mock(None);
         cpu.reg.x = 0x20;
+        cpu.write(0x0510, 0x42).unwrap();
+
+        // Reads take a cycle longer, LDA $04F0,X
+        assert_eq!(run_code(&mut cpu, &[0xBD, 0xD0, 0x04]), 4);
+        assert_eq!(run_code(&mut cpu, &[0xBD, 0xF0, 0x04]), 5);
+        assert_eq!(cpu.reg.a, 0x42);
+        // Writes always take the extra cycle, STA $0400,X
+        assert_eq!(run_code(&mut cpu, &[0x9D, 0x00, 0x04]), 5);
+        assert_eq!(cpu.read(0x0420).unwrap(), 0x42);
+
+        // Branches: not taken, taken, taken across a page
+        cpu.reg.status.insert(StatusFlags::ZERO);
+        assert_eq!(run_code(&mut cpu, &[0xD0, 0x10]), 2);
+        assert_eq!(run_code(&mut cpu, &[0xF0, 0x10]), 3);
+        assert_eq!(cpu.reg.pc, 0x0312);
+        assert_eq!(run_code(&mut cpu, &[0xF0, 0x80]), 4);
+        assert_eq!(cpu.reg.pc, 0x0282);
+    }
+
+    #[test]
+    fn stack_instrs() {
+        let mut cpu = Cpu::mock(None);
+        cpu.reg.sp = 0xFD;
+
+        // JSR $0400, RTS
+        cpu.write(0x0400, 0x60).unwrap();
+        assert_eq!(run_code(&mut cpu, &[0x20, 0x00, 0x04]), 6);
+        assert_eq!(cpu.reg.pc, 0x0400);
+        assert_eq!(cpu.read(0x01FD).unwrap(), 0x03);
+        assert_eq!(cpu.read(0x01FC).unwrap(), 0x02);
+        assert_eq!(run(&mut cpu), 6);
+        assert_eq!(cpu.reg.pc, 0x0303);
+        assert_eq!(cpu.reg.sp, 0xFD);
+
+        // JMP ($04FF) doesn't carry into the next page
+        cpu.write(0x04FF, 0x34).unwrap();
+        cpu.write(0x0400, 0x12).unwrap();
+        assert_eq!(run_code(&mut cpu, &[0x6C, 0xFF, 0x04]), 5);
+        assert_eq!(cpu.reg.pc, 0x1234);
+    }
+
+    #[test]
+    fn unofficial_opcodes() {
+        let mut cpu = Cpu::mock(None);
+
+        // AXS #$10: X = (A & X) - imm
+        cpu.reg.a = 0xF0;
+        cpu.reg.x = 0x3C;
+        run_code(&mut cpu, &[0xCB, 0x10]);
+        assert_eq!(cpu.reg.x, 0x20);
+        assert!(cpu.reg.status.contains(StatusFlags::CARRY));
+
+        // ARR #$C0: carry from bit 6, overflow from bit 6 ^ bit 5
+        cpu.reg.a = 0xFF;
+        cpu.reg.status.remove(StatusFlags::CARRY);
+        run_code(&mut cpu, &[0x6B, 0xC0]);
+        assert_eq!(cpu.reg.a, 0x60);
+        assert!(cpu.reg.status.contains(StatusFlags::CARRY));
+        assert!(!cpu.reg.status.contains(StatusFlags::OVERFLOW));
+
+        // DCP $10: decrement memory, then compare with A
+        cpu.write(0x10, 0x43).unwrap();
+        cpu.reg.a = 0x42;
+        run_code(&mut cpu, &[0xC7, 0x10]);
+        assert_eq!(cpu.read(0x10).unwrap(), 0x42);
+        assert!(cpu.reg.status.contains(StatusFlags::ZERO | StatusFlags::CARRY));
+
+        // SHX $02F0,Y: the stored value replaces the high byte when the index crosses a page
+        cpu.reg.x = 0x05;
         cpu.reg.y = 0x20;
-        assert!(!cross_page_boundary(AbsoluteX(0x17), &mut cpu));
-        assert!(cross_page_boundary(AbsoluteX(0xEE), &mut cpu));
-        assert!(!cross_page_boundary(AbsoluteX(0x00), &mut cpu));
-        assert!(!cross_page_boundary(AbsoluteY(0x17), &mut cpu));
-        assert!(cross_page_boundary(AbsoluteY(0xEE), &mut cpu));
-        assert!(!cross_page_boundary(AbsoluteY(0x00), &mut cpu));
-
-        cpu.reg.y = 0x80;
-        cpu.write(0x42, 0xFF).unwrap();
-        cpu.write(0x43, 0x00).unwrap();
-        assert!(cross_page_boundary(IndirectY(0x42), &mut cpu));
-
-        cpu.write(0x42, 0x00).unwrap();
-        cpu.write(0x43, 0x10).unwrap();
-        assert!(!cross_page_boundary(IndirectY(0x42), &mut cpu));
-
-        cpu.reg.pc = 0x01FF;
-        // -1 jump
-        assert!(!cross_page_boundary(Relative(0xFF), &mut cpu));
-        // -64 jump
-        assert!(!cross_page_boundary(Relative(0xC0), &mut cpu));
-        // + 1 jump
-        assert!(cross_page_boundary(Relative(0x1), &mut cpu));
-        // + 64 jump
-        assert!(cross_page_boundary(Relative(0x40), &mut cpu));
-
-        cpu.reg.pc = 0x0100;
-        // -1 jump
-        assert!(cross_page_boundary(Relative(0xFF), &mut cpu));
-        // -64 jump
-        assert!(cross_page_boundary(Relative(0xC0), &mut cpu));
-        // + 1 jump
-        assert!(!cross_page_boundary(Relative(0x1), &mut cpu));
-        // + 64 jump
-        assert!(!cross_page_boundary(Relative(0x40), &mut cpu));
+        run_code(&mut cpu, &[0x9E, 0xF0, 0x02]);
+        assert_eq!(cpu.read(0x0110).unwrap(), 0x05 & 0x03);
+        assert_eq!(cpu.read(0x0310).unwrap(), 0x00);
+        run_code(&mut cpu, &[0x9E, 0x00, 0x04]);
+        assert_eq!(cpu.read(0x0420).unwrap(), 0x05 & 0x05);
+    }
+
+    #[test]
+    fn load_state_checks_step() {
+        // LDA #imm is done after cycle 1, INC abs,X and BRK after 6
+        let cases = [
+            (0xA9, 1, true),
+            (0xA9, 2, false),
+            (0xFE, 6, true),
+            (0xFE, 7, false),
+            (0x00, 6, true),
+            (0x00, 200, false),
+        ];
+        for (opcode, step, valid) in cases {
+            let exec = ExecState { opcode, step, ..Default::default() };
+            let mut w = StateWriter::new();
+            exec.save_state(&mut w);
+            let data = w.into_bytes();
+            let res = ExecState::default().load_state(&mut StateReader::new(&data));
+            assert_eq!(res.is_ok(), valid, "{opcode:02X} step {step}");
+        }
+        // Never shorter than the lookup table
+        for opcode in 0..=0xFFu8 {
+            let (op, mode, ncycles) = LOOKUP[opcode as usize >> 4][opcode as usize & 0xF];
+            if op != Opcode::INVALID {
+                assert!(last_step(op, mode) >= ncycles - 1, "{opcode:02X} {op:?} {mode:?}");
+            }
+        }
     }
 }
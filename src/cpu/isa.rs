@@ -43,8 +43,13 @@ impl fmt::Display for AddressingMode {
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, EnumIter)]
 pub enum Opcode {
     ADC,
+    AHX,
+    ALR,
+    ANC,
     AND,
+    ARR,
     ASL,
+    AXS,
     BCC,
     BCS,
     BEQ,
@@ -62,6 +67,7 @@ pub enum Opcode {
     CMP,
     CPX,
     CPY,
+    DCP,
     DEC,
     DEX,
     DEY,
@@ -69,8 +75,10 @@ pub enum Opcode {
     INC,
     INX,
     INY,
+    ISC,
     JMP,
     JSR,
+    LAS,
     LAX,
     LDA,
     LDX,
@@ -82,23 +90,32 @@ pub enum Opcode {
     PHP,
     PLA,
     PLP,
+    RLA,
     ROL,
     ROR,
+    RRA,
     RTI,
     RTS,
+    SAX,
     SBC,
     SEC,
     SED,
     SEI,
+    SHX,
+    SHY,
+    SLO,
+    SRE,
     STA,
     STX,
     STY,
+    TAS,
     TAX,
     TAY,
     TSX,
     TXA,
     TXS,
     TYA,
+    XAA,
     INVALID,
 }
 
@@ -120,7 +137,6 @@ impl fmt::Display for Instr {
 }
 
 impl Instr {
-    #[allow(dead_code)]
     pub fn length(&self) -> u8 {
         // Returns the number of bytes this instruction takes
         use AddressingMode::*;
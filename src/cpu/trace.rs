@@ -0,0 +1,408 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use super::cpu::Cpu;
+use super::decode::decode_instr;
+use super::isa::{AddressingMode, Instr, Opcode};
+use crate::error::Result;
+use crate::mem::utils::{hi_byte, lo_byte, make_address};
+
+// Streams one line per instruction to a file (or any writer):
+//
+//   nestest:  C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7 BANK:00
+//   counters: f0      c7           A:00 X:00 Y:00 S:FD P:nvUbdIzc SL:0   DOT:21   $00:C000: 4C F5 C5  JMP $C5F5
+//   banked:   00:C000  4C F5 C5  JMP $C5F5           A:00 X:00 Y:00 S:FD P:nvUbdIzc V:0   H:21  Fr:0 Cyc:7
+//
+// nestest is the layout of nestest.log (Nintendulator's) with the bank added at the end, so
+// everything before it can be diffed against that log. The bank is the 16K PRG ROM bank the
+// instruction was fetched from, "--" outside of PRG ROM.
+
+// Bank size the logs number PRG banks by (the same as FCEUX's)
+const PRG_BANK_SIZE: usize = 0x4000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    Nestest,
+    // Frame and cycle counters first
+    Counters,
+    // Bank and address first
+    Banked,
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "nestest" => Ok(Self::Nestest),
+            "counters" => Ok(Self::Counters),
+            "banked" => Ok(Self::Banked),
+            _ => Err(format!("Unknown trace format {s}, expected nestest, counters or banked")),
+        }
+    }
+}
+
+// When to start or stop logging, "$C000" (about to run the instruction there) or "frame:120"
+// (from the start of that frame on, counting from 0)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceCondition {
+    Addr(u16),
+    Frame(u64),
+}
+
+impl TraceCondition {
+    fn hit(&self, cpu: &Cpu) -> bool {
+        match *self {
+            Self::Addr(addr) => cpu.reg.pc == addr,
+            Self::Frame(frame) => cpu.frame_count() >= frame,
+        }
+    }
+}
+
+impl FromStr for TraceCondition {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Some(frame) = s.strip_prefix("frame:") {
+            return frame.parse().map(Self::Frame).map_err(|_| format!("Invalid frame number {frame}"));
+        }
+        let hex = s.strip_prefix('$').or_else(|| s.strip_prefix("0x")).unwrap_or(s);
+        u16::from_str_radix(hex, 16)
+            .map(Self::Addr)
+            .map_err(|_| format!("Invalid trace condition {s}, expected $<addr> or frame:<n>"))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TraceState {
+    Waiting,
+    Logging,
+    Done,
+}
+
+pub struct TraceLogger {
+    out: Box<dyn Write + Send>,
+    format: TraceFormat,
+    start: Option<TraceCondition>,
+    stop: Option<TraceCondition>,
+    state: TraceState,
+}
+
+impl TraceLogger {
+    pub fn new(out: impl Write + Send + 'static, format: TraceFormat) -> Self {
+        Self {
+            out: Box::new(out),
+            format,
+            start: None,
+            stop: None,
+            state: TraceState::Waiting,
+        }
+    }
+
+    pub fn create(path: &Path, format: TraceFormat) -> Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?), format))
+    }
+
+    // Logs nothing until the condition is hit, otherwise logging starts right away
+    pub fn start_at(mut self, cond: Option<TraceCondition>) -> Self {
+        self.start = cond;
+        self
+    }
+
+    // The instruction that hits the condition isn't logged anymore
+    pub fn stop_at(mut self, cond: Option<TraceCondition>) -> Self {
+        self.stop = cond;
+        self
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == TraceState::Done
+    }
+
+    // Called with the CPU about to run the instruction at PC
+    pub(super) fn log(&mut self, cpu: &Cpu) -> Result<()> {
+        if self.state == TraceState::Waiting && self.start.is_none_or(|c| c.hit(cpu)) {
+            self.state = TraceState::Logging;
+        }
+        if self.state == TraceState::Logging && self.stop.is_some_and(|c| c.hit(cpu)) {
+            self.state = TraceState::Done;
+            self.out.flush()?;
+        }
+        if self.state == TraceState::Logging {
+            let line = self.format_line(cpu)?;
+            writeln!(self.out, "{line}")?;
+        }
+        Ok(())
+    }
+
+    fn format_line(&self, cpu: &Cpu) -> Result<String> {
+        let pc = cpu.reg.pc;
+        // Nothing gets disturbed, write-only registers read as 0
+        let (instr, _) = decode_instr(pc, |addr| Ok(cpu.peek(addr).unwrap_or(0)))?;
+        let (scanline, dot) = cpu.ppu_position();
+        let reg = &cpu.reg;
+        let bytes = (0..instr.length() as u16)
+            .map(|i| format!("{:02X}", cpu.peek(pc.wrapping_add(i)).unwrap_or(0)))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let bank = match cpu.prg_rom_offset(pc) {
+            Some(offset) => format!("{:02X}", offset / PRG_BANK_SIZE),
+            None => "--".to_string(),
+        };
+        let regs = format!(
+            "A:{:02X} X:{:02X} Y:{:02X} S:{:02X} P:{}",
+            reg.a,
+            reg.x,
+            reg.y,
+            reg.sp,
+            flags(reg.status.bits())
+        );
+        let (frame, cycles) = (cpu.frame_count(), cpu.cycle_count());
+        if self.format == TraceFormat::Nestest {
+            let text = nestest_text(cpu, pc, &instr);
+            return Ok(format!(
+                "{pc:04X}  {bytes:<8} {text:<33}{reg} PPU:{scanline:3},{dot:3} CYC:{cycles} BANK:{bank}"
+            ));
+        }
+
+        let text = instr_text(pc, &instr);
+        Ok(match self.format {
+            TraceFormat::Counters => format!(
+                "f{frame:<6} c{cycles:<11} {regs} SL:{scanline:<3} DOT:{dot:<3}  ${bank}:{pc:04X}: {bytes:<9} {text}"
+            ),
+            _ => format!(
+                "{bank}:{pc:04X}  {bytes:<9} {text:<20}{regs} V:{scanline:<3} H:{dot:<3} Fr:{frame} Cyc:{cycles}"
+            ),
+        })
+    }
+}
+
+// NV-BDIZC, upper case when set
+fn flags(p: u8) -> String {
+    "nvubdizc"
+        .chars()
+        .enumerate()
+        .map(|(i, c)| if p & (0x80 >> i) != 0 { c.to_ascii_uppercase() } else { c })
+        .collect()
+}
+
+// "LDA ($20),Y", branch targets as addresses
+fn instr_text(pc: u16, instr: &Instr) -> String {
+    use AddressingMode::*;
+    let operand = match instr.mode {
+        Implied => return format!("{:?}", instr.op),
+        Accumulator => "A".to_string(),
+        Immediate(val) => format!("#${val:02X}"),
+        ZeroPage(addr) => format!("${addr:02X}"),
+        ZeroPageX(addr) => format!("${addr:02X},X"),
+        ZeroPageY(addr) => format!("${addr:02X},Y"),
+        Absolute(addr) => format!("${addr:04X}"),
+        AbsoluteX(addr) => format!("${addr:04X},X"),
+        AbsoluteY(addr) => format!("${addr:04X},Y"),
+        Indirect(addr) => format!("(${addr:04X})"),
+        XIndirect(addr) => format!("(${addr:02X},X)"),
+        IndirectY(addr) => format!("(${addr:02X}),Y"),
+        Relative(offset) => format!("${:04X}", branch_target(pc, offset)),
+    };
+    format!("{:?} {operand}", instr.op)
+}
+
+fn branch_target(pc: u16, offset: u8) -> u16 {
+    pc.wrapping_add(2).wrapping_add(offset as i8 as u16)
+}
+
+// Unofficial opcodes get a '*' in front in nestest.log
+fn is_unofficial(opcode: u8, op: Opcode) -> bool {
+    use Opcode::*;
+    match op {
+        NOP => opcode != 0xEA,
+        SBC => opcode == 0xEB,
+        AHX | ALR | ANC | ARR | AXS | DCP | ISC | LAS | LAX | RLA | RRA | SAX | SHX | SHY | SLO
+        | SRE | TAS | XAA => true,
+        _ => false,
+    }
+}
+
+// Nintendulator's disassembly, with the effective address and the value there as the
+// instruction is about to run
+fn nestest_text(cpu: &Cpu, pc: u16, instr: &Instr) -> String {
+    use AddressingMode::*;
+    // Registers aren't read (that could have side effects), nestest.log shows them as $FF
+    let peek = |addr: u16| match addr {
+        0x2000..=0x401F => 0xFF,
+        _ => cpu.peek(addr).unwrap_or(0),
+    };
+    // Pointers in the zero page wrap around it
+    let zpg_ptr = |ptr: u8| make_address(peek(ptr as u16), peek(ptr.wrapping_add(1) as u16));
+    let (x, y) = (cpu.reg.x, cpu.reg.y);
+    let operand = match instr.mode {
+        Implied => String::new(),
+        Accumulator => "A".to_string(),
+        Immediate(val) => format!("#${val:02X}"),
+        ZeroPage(addr) => format!("${addr:02X} = {:02X}", peek(addr as u16)),
+        ZeroPageX(addr) | ZeroPageY(addr) => {
+            let (index, reg) = if matches!(instr.mode, ZeroPageX(_)) { (x, 'X') } else { (y, 'Y') };
+            let ea = addr.wrapping_add(index);
+            format!("${addr:02X},{reg} @ {ea:02X} = {:02X}", peek(ea as u16))
+        }
+        Absolute(addr) if matches!(instr.op, Opcode::JMP | Opcode::JSR) => format!("${addr:04X}"),
+        Absolute(addr) => format!("${addr:04X} = {:02X}", peek(addr)),
+        AbsoluteX(addr) | AbsoluteY(addr) => {
+            let (index, reg) = if matches!(instr.mode, AbsoluteX(_)) { (x, 'X') } else { (y, 'Y') };
+            let ea = addr.wrapping_add(index as u16);
+            format!("${addr:04X},{reg} @ {ea:04X} = {:02X}", peek(ea))
+        }
+        Indirect(addr) => {
+            // With the JMP bug, the high byte comes from the same page
+            let hi_addr = make_address(lo_byte(addr).wrapping_add(1), hi_byte(addr));
+            format!("(${addr:04X}) = {:04X}", make_address(peek(addr), peek(hi_addr)))
+        }
+        XIndirect(ptr) => {
+            let indexed = ptr.wrapping_add(x);
+            let ea = zpg_ptr(indexed);
+            format!("(${ptr:02X},X) @ {indexed:02X} = {ea:04X} = {:02X}", peek(ea))
+        }
+        IndirectY(ptr) => {
+            let base = zpg_ptr(ptr);
+            let ea = base.wrapping_add(y as u16);
+            format!("(${ptr:02X}),Y = {base:04X} @ {ea:04X} = {:02X}", peek(ea))
+        }
+        Relative(offset) => format!("${:04X}", branch_target(pc, offset)),
+    };
+    let marker = if is_unofficial(peek(pc), instr.op) { '*' } else { ' ' };
+    // nestest calls ISC "ISB"
+    let mnemonic = match instr.op {
+        Opcode::ISC => "ISB".to_string(),
+        op => format!("{op:?}"),
+    };
+    format!("{marker}{mnemonic} {operand}").trim_end().to_string()
+}
+
+// Writer the tests can read back from while the CPU owns the logger
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct TraceBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl TraceBuffer {
+    pub fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Write for TraceBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod trace_tests {
+    use super::{TraceBuffer, TraceCondition, TraceFormat, TraceLogger};
+    use crate::cart::builder::build_cartridge;
+    use crate::cpu::cpu::Cpu;
+    use crate::ines::parse::INesFile;
+
+    static NESTEST: &[u8] = include_bytes!("../../test_files/nestest.nes");
+    static NESTEST_LOG: &str = include_str!("../../test_files/nestest.log");
+
+    fn nestest_cpu() -> Cpu {
+        let rom = INesFile::try_from(&NESTEST.to_vec()).unwrap();
+        let mut cpu = Cpu::new(build_cartridge(&rom).unwrap(), 44410.0, None, None);
+        cpu.reset().unwrap();
+        cpu.reg.pc = 0xC000;
+        cpu
+    }
+
+    #[test]
+    fn formats() {
+        for (format, first) in [
+            (
+                TraceFormat::Counters,
+                "f0      c7           A:00 X:00 Y:00 S:FD P:nvUbdIzc SL:0   DOT:21   $00:C000: 4C F5 C5  JMP $C5F5",
+            ),
+            (
+                TraceFormat::Banked,
+                "00:C000  4C F5 C5  JMP $C5F5           A:00 X:00 Y:00 S:FD P:nvUbdIzc V:0   H:21  Fr:0 Cyc:7",
+            ),
+        ] {
+            let mut cpu = nestest_cpu();
+            let buf = TraceBuffer::default();
+            cpu.set_tracer(Some(TraceLogger::new(buf.clone(), format)));
+            while cpu.cycle_count() < 20 {
+                cpu.system_tick().unwrap();
+            }
+            let log = buf.contents();
+            assert_eq!(log.lines().next(), Some(first));
+            assert!(log.contains("A2 00     LDX #$00"));
+            assert!(log.contains("STX $10"));
+        }
+    }
+
+    #[test]
+    fn nestest_log() {
+        let mut cpu = nestest_cpu();
+        let buf = TraceBuffer::default();
+        cpu.set_tracer(Some(TraceLogger::new(buf.clone(), TraceFormat::Nestest)));
+        while cpu.cycle_count() < 2000 {
+            cpu.system_tick().unwrap();
+        }
+        // nestest is NROM-128, everything runs from bank 0
+        let log = buf.contents();
+        let expected = NESTEST_LOG.lines().map(|l| format!("{l} BANK:00"));
+        let lines: Vec<&str> = log.lines().collect();
+        assert!(lines.len() > 200);
+        for (line, expected) in lines.iter().zip(expected) {
+            assert_eq!(*line, expected);
+        }
+    }
+
+    #[test]
+    fn conditions() {
+        assert_eq!("$C5F5".parse(), Ok(TraceCondition::Addr(0xC5F5)));
+        assert_eq!("0xc5f5".parse(), Ok(TraceCondition::Addr(0xC5F5)));
+        assert_eq!("frame:3".parse(), Ok(TraceCondition::Frame(3)));
+        assert!("frame:x".parse::<TraceCondition>().is_err());
+        assert!("$12345".parse::<TraceCondition>().is_err());
+        assert_eq!("Banked".parse(), Ok(TraceFormat::Banked));
+        assert!("fceux".parse::<TraceFormat>().is_err());
+        assert!("bizhawk".parse::<TraceFormat>().is_err());
+
+        // Just the first test's NOP and SEC
+        let mut cpu = nestest_cpu();
+        let buf = TraceBuffer::default();
+        let tracer = TraceLogger::new(buf.clone(), TraceFormat::Nestest)
+            .start_at(Some(TraceCondition::Addr(0xC72D)))
+            .stop_at(Some(TraceCondition::Addr(0xC72F)));
+        cpu.set_tracer(Some(tracer));
+        for _ in 0..1000 {
+            cpu.system_tick().unwrap();
+        }
+        let log = buf.contents();
+        let instrs: Vec<&str> = log.lines().map(|l| &l[..19]).collect();
+        assert_eq!(instrs, ["C72D  EA        NOP", "C72E  38        SEC"]);
+
+        // The second frame, nestest sits in its menu
+        let mut cpu = nestest_cpu();
+        cpu.reset().unwrap();
+        let buf = TraceBuffer::default();
+        let tracer = TraceLogger::new(buf.clone(), TraceFormat::Nestest)
+            .start_at(Some(TraceCondition::Frame(1)))
+            .stop_at(Some(TraceCondition::Frame(2)));
+        cpu.set_tracer(Some(tracer));
+        while cpu.frame_count() < 3 {
+            cpu.system_tick().unwrap();
+        }
+        let log = buf.contents();
+        assert!(log.lines().next().unwrap().contains("PPU: -1,"));
+        assert!(log.lines().last().unwrap().contains("PPU:260,"));
+    }
+}
@@ -0,0 +1,4 @@
+pub mod debugger;
+pub mod expr;
+pub mod gdb;
+pub mod repl;
\ No newline at end of file
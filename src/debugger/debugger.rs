@@ -0,0 +1,459 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use super::expr::Expr;
+use crate::cpu::cpu::Cpu;
+use crate::cpu::decode::decode_opcode;
+use crate::cpu::isa::Opcode;
+use crate::cpu::reg::Registers;
+use crate::disasm::symbols::SymbolTable;
+use crate::error::Result;
+use crate::mem::access::{AccessKind, AddressSpace, MemAccess};
+use crate::ppu::ppu::Frame;
+
+// What a breakpoint triggers on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakOn {
+    // The CPU is about to run an instruction in the range
+    Execute,
+    // Memory in the range is read and/or written
+    Access {
+        space: AddressSpace,
+        read: bool,
+        write: bool,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    pub on: BreakOn,
+    // Inclusive range
+    pub start: u16,
+    pub end: u16,
+    // Only stops when this holds, (source, parsed)
+    condition: Option<(String, Expr)>,
+}
+
+impl Breakpoint {
+    pub fn exec(start: u16, end: u16) -> Self {
+        Self {
+            on: BreakOn::Execute,
+            start,
+            end,
+            condition: None,
+        }
+    }
+
+    pub fn watch(space: AddressSpace, read: bool, write: bool, start: u16, end: u16) -> Self {
+        Self {
+            on: BreakOn::Access { space, read, write },
+            start,
+            end,
+            condition: None,
+        }
+    }
+
+    pub fn with_condition(mut self, condition: &str) -> Result<Self> {
+        self.condition = Some((condition.to_string(), Expr::parse(condition)?));
+        Ok(self)
+    }
+
+    fn condition_holds(&self, reg: &Registers) -> bool {
+        self.condition.as_ref().is_none_or(|(_, e)| e.is_true(reg))
+    }
+
+    fn hit_by_exec(&self, reg: &Registers) -> bool {
+        self.on == BreakOn::Execute
+            && (self.start..=self.end).contains(&reg.pc)
+            && self.condition_holds(reg)
+    }
+
+    fn hit_by_access(&self, space: AddressSpace, access: &MemAccess, reg: &Registers) -> bool {
+        let BreakOn::Access { space: s, read, write } = self.on else {
+            return false;
+        };
+        let kind_matches = match access.kind {
+            AccessKind::Read => read,
+            AccessKind::Write => write,
+        };
+        s == space
+            && kind_matches
+            && (self.start..=self.end).contains(&access.addr)
+            && self.condition_holds(reg)
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.on {
+            BreakOn::Execute => write!(f, "exec")?,
+            BreakOn::Access { space, read, write } => {
+                let space = if space == AddressSpace::Ppu { "ppu " } else { "" };
+                let kind = match (read, write) {
+                    (true, true) => "rw",
+                    (true, false) => "r",
+                    _ => "w",
+                };
+                write!(f, "watch {space}{kind}")?
+            }
+        }
+        write!(f, " ${:04X}", self.start)?;
+        if self.end != self.start {
+            write!(f, "-${:04X}", self.end)?;
+        }
+        if let Some((condition, _)) = &self.condition {
+            write!(f, " if {condition}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Breakpoint(usize),
+    Watchpoint(usize, AddressSpace, MemAccess),
+    Step,
+    Scanline(i32),
+    Frame,
+    Pause,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::Breakpoint(id) => write!(f, "Breakpoint {id}"),
+            StopReason::Watchpoint(id, space, access) => {
+                let space = if *space == AddressSpace::Ppu { "PPU " } else { "" };
+                let kind = match access.kind {
+                    AccessKind::Read => "read",
+                    AccessKind::Write => "write",
+                };
+                write!(
+                    f,
+                    "Watchpoint {id}: {space}{kind} ${:04X} = ${:02X}",
+                    access.addr, access.value
+                )
+            }
+            StopReason::Step => write!(f, "Step"),
+            StopReason::Scanline(scanline) => write!(f, "Scanline {scanline}"),
+            StopReason::Frame => write!(f, "Frame done"),
+            StopReason::Pause => write!(f, "Paused"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RunMode {
+    Continue,
+    StepInto,
+    // Until the JSR returns
+    StepOver { return_pc: u16, sp: u8 },
+    // Until a return pops the current stack frame
+    StepOut { sp: u8 },
+    Scanline(i32),
+    Frame,
+}
+
+// Runs the CPU, stopping at breakpoints and after steps. Stops always happen at instruction
+// boundaries, watchpoints and the like stop after the instruction that triggered them.
+pub struct Debugger {
+    breakpoints: BTreeMap<usize, Breakpoint>,
+    next_id: usize,
+    // None while stopped
+    mode: Option<RunMode>,
+    // Stop at the next instruction boundary
+    pending: Option<StopReason>,
+    // The instruction that's running
+    current_op: Option<Opcode>,
+    // Whether an instruction started since the last resume (steps need to run one)
+    started: bool,
+    last_scanline: i32,
+    // Labels for the REPL's disassembly
+    pub symbols: SymbolTable,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self {
+            breakpoints: BTreeMap::new(),
+            next_id: 1,
+            mode: Some(RunMode::Continue),
+            pending: None,
+            current_op: None,
+            started: false,
+            last_scanline: 0,
+            symbols: SymbolTable::default(),
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, cpu: &mut Cpu, bp: Breakpoint) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.insert(id, bp);
+        cpu.set_access_logging(self.watching());
+        id
+    }
+
+    pub fn remove_breakpoint(&mut self, cpu: &mut Cpu, id: usize) -> Result<()> {
+        self.breakpoints
+            .remove(&id)
+            .ok_or_else(|| format!("No breakpoint {id}"))?;
+        cpu.set_access_logging(self.watching());
+        Ok(())
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = (&usize, &Breakpoint)> {
+        self.breakpoints.iter()
+    }
+
+    fn watching(&self) -> bool {
+        self.breakpoints
+            .values()
+            .any(|bp| matches!(bp.on, BreakOn::Access { .. }))
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.mode.is_none()
+    }
+
+    // Stops at the next instruction boundary
+    pub fn pause(&mut self) {
+        if !self.is_stopped() {
+            self.pending.get_or_insert(StopReason::Pause);
+        }
+    }
+
+    fn run(&mut self, mode: RunMode) {
+        self.mode = Some(mode);
+        self.started = false;
+    }
+
+    pub fn resume(&mut self) {
+        self.run(RunMode::Continue);
+    }
+
+    pub fn step_into(&mut self) {
+        self.run(RunMode::StepInto);
+    }
+
+    // Runs over subroutine calls, anything else is a single step
+    pub fn step_over(&mut self, cpu: &Cpu) {
+        let (pc, sp) = (cpu.reg.pc, cpu.reg.sp);
+        self.run(match opcode_at(cpu, pc) {
+            Some(Opcode::JSR) => RunMode::StepOver {
+                return_pc: pc.wrapping_add(3),
+                sp,
+            },
+            _ => RunMode::StepInto,
+        });
+    }
+
+    // Runs until the current subroutine (or interrupt handler) returns
+    pub fn step_out(&mut self, cpu: &Cpu) {
+        self.run(RunMode::StepOut { sp: cpu.reg.sp });
+    }
+
+    // Runs until the PPU starts on `scanline` (-1 to 260)
+    pub fn run_to_scanline(&mut self, scanline: i32) {
+        self.run(RunMode::Scanline(scanline));
+    }
+
+    // Runs until the end of the current frame
+    pub fn frame_advance(&mut self) {
+        self.run(RunMode::Frame);
+    }
+
+    // Runs until the PPU finishes a frame or the debugger stops, whichever comes first
+    pub fn run_frame(&mut self, cpu: &mut Cpu) -> Result<(Option<Frame>, Option<StopReason>)> {
+        let Some(mode) = self.mode else {
+            return Ok((None, None));
+        };
+        let watching = self.watching();
+        loop {
+            self.started |= cpu.at_instr_boundary();
+            let frame = cpu.system_tick()?;
+            if watching {
+                self.check_accesses(cpu);
+            }
+            let (scanline, _) = cpu.ppu_position();
+            if mode == RunMode::Scanline(scanline) && self.last_scanline != scanline {
+                self.pending.get_or_insert(StopReason::Scanline(scanline));
+            }
+            self.last_scanline = scanline;
+            if frame.is_some() && mode == RunMode::Frame {
+                self.pending.get_or_insert(StopReason::Frame);
+            }
+
+            if cpu.at_instr_boundary() {
+                if let Some(reason) = self.check_boundary(cpu, mode) {
+                    self.mode = None;
+                    return Ok((frame, Some(reason)));
+                }
+            }
+            if frame.is_some() {
+                return Ok((frame, None));
+            }
+        }
+    }
+
+    fn check_accesses(&mut self, cpu: &mut Cpu) {
+        let reg = cpu.reg.clone();
+        let mut hit = None;
+        cpu.drain_accesses(|space, access| {
+            if hit.is_none() {
+                hit = self
+                    .breakpoints
+                    .iter()
+                    .find(|(_, bp)| bp.hit_by_access(space, &access, &reg))
+                    .map(|(id, _)| StopReason::Watchpoint(*id, space, access));
+            }
+        });
+        if let Some(reason) = hit {
+            self.pending.get_or_insert(reason);
+        }
+    }
+
+    // Whether to stop before running the instruction at the PC
+    fn check_boundary(&mut self, cpu: &mut Cpu, mode: RunMode) -> Option<StopReason> {
+        let finished = self.current_op;
+        self.current_op = opcode_at(cpu, cpu.reg.pc);
+
+        if let Some(reason) = self.pending.take() {
+            return Some(reason);
+        }
+        if let Some((id, _)) = self.breakpoints.iter().find(|(_, bp)| bp.hit_by_exec(&cpu.reg)) {
+            return Some(StopReason::Breakpoint(*id));
+        }
+        let stop = match mode {
+            _ if !self.started => false,
+            RunMode::StepInto => true,
+            RunMode::StepOver { return_pc, sp } => cpu.reg.pc == return_pc && cpu.reg.sp == sp,
+            RunMode::StepOut { sp } => {
+                matches!(finished, Some(Opcode::RTS | Opcode::RTI)) && cpu.reg.sp > sp
+            }
+            _ => false,
+        };
+        stop.then_some(StopReason::Step)
+    }
+}
+
+fn opcode_at(cpu: &Cpu, addr: u16) -> Option<Opcode> {
+    let byte = cpu.peek(addr).ok()?;
+    decode_opcode(byte, addr).ok().map(|(op, _)| op)
+}
+
+#[cfg(test)]
+mod debugger_tests {
+    use super::{Breakpoint, Debugger, StopReason};
+    use crate::cart::builder::build_cartridge;
+    use crate::cpu::cpu::Cpu;
+    use crate::ines::parse::INesFile;
+    use crate::mem::access::{AccessKind, AddressSpace};
+
+    static NESTEST: &[u8] = include_bytes!("../../test_files/nestest.nes");
+
+    // nestest's automated mode starting at $C000
+    fn nestest() -> Cpu {
+        let rom = INesFile::try_from(&NESTEST.to_vec()).unwrap();
+        let mut cpu = Cpu::new(build_cartridge(&rom).unwrap(), 44100.0, None, None);
+        cpu.reset().unwrap();
+        cpu.reg.pc = 0xC000;
+        cpu
+    }
+
+    fn run_until_stop(dbg: &mut Debugger, cpu: &mut Cpu) -> StopReason {
+        for _ in 0..100 {
+            if let (_, Some(reason)) = dbg.run_frame(cpu).unwrap() {
+                return reason;
+            }
+        }
+        panic!("Debugger never stopped");
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut cpu = nestest();
+        let mut dbg = Debugger::new();
+
+        // $C72D is nestest's first subroutine
+        let id = dbg.add_breakpoint(&mut cpu, Breakpoint::exec(0xC72D, 0xC72D));
+        assert_eq!(run_until_stop(&mut dbg, &mut cpu), StopReason::Breakpoint(id));
+        assert_eq!(cpu.reg.pc, 0xC72D);
+        assert!(dbg.is_stopped());
+        assert_eq!(dbg.run_frame(&mut cpu).unwrap(), (None, None));
+
+        // Conditions, it sets the carry two instructions in
+        dbg.remove_breakpoint(&mut cpu, id).unwrap();
+        let bp = Breakpoint::exec(0xC72D, 0xC7FF).with_condition("x == 0 && c").unwrap();
+        let id = dbg.add_breakpoint(&mut cpu, bp);
+        dbg.resume();
+        assert_eq!(run_until_stop(&mut dbg, &mut cpu), StopReason::Breakpoint(id));
+        assert_eq!(cpu.reg.pc, 0xC72F);
+        assert!(dbg.remove_breakpoint(&mut cpu, 1).is_err());
+    }
+
+    #[test]
+    fn stepping() {
+        let mut cpu = nestest();
+        let mut dbg = Debugger::new();
+
+        // JMP $C5F5
+        dbg.step_into();
+        assert_eq!(run_until_stop(&mut dbg, &mut cpu), StopReason::Step);
+        assert_eq!(cpu.reg.pc, 0xC5F5);
+
+        // Step over the JSR at $C5FD, then into the one at $C600 and back out
+        let id = dbg.add_breakpoint(&mut cpu, Breakpoint::exec(0xC5FD, 0xC5FD));
+        dbg.resume();
+        run_until_stop(&mut dbg, &mut cpu);
+        dbg.remove_breakpoint(&mut cpu, id).unwrap();
+        dbg.step_over(&cpu);
+        assert_eq!(run_until_stop(&mut dbg, &mut cpu), StopReason::Step);
+        assert_eq!((cpu.reg.pc, cpu.reg.sp), (0xC600, 0xFD));
+
+        dbg.step_into();
+        run_until_stop(&mut dbg, &mut cpu);
+        assert_eq!((cpu.reg.pc, cpu.reg.sp), (0xC7DB, 0xFB));
+        dbg.step_into();
+        run_until_stop(&mut dbg, &mut cpu);
+        dbg.step_out(&cpu);
+        assert_eq!(run_until_stop(&mut dbg, &mut cpu), StopReason::Step);
+        assert_eq!((cpu.reg.pc, cpu.reg.sp), (0xC603, 0xFD));
+
+        // Spin in RAM, JMP $0300
+        for (i, byte) in [0x4C, 0x00, 0x03].into_iter().enumerate() {
+            cpu.write(0x0300 + i as u16, byte).unwrap();
+        }
+        cpu.reg.pc = 0x0300;
+        dbg.run_to_scanline(100);
+        assert_eq!(run_until_stop(&mut dbg, &mut cpu), StopReason::Scanline(100));
+        assert_eq!(cpu.ppu_position().0, 100);
+        dbg.frame_advance();
+        assert_eq!(run_until_stop(&mut dbg, &mut cpu), StopReason::Frame);
+        assert_eq!(cpu.ppu_position().0, -1);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut cpu = nestest();
+        let mut dbg = Debugger::new();
+
+        // nestest keeps the number of the failed test in $00-$01
+        let id = dbg.add_breakpoint(&mut cpu, Breakpoint::watch(AddressSpace::Cpu, false, true, 0x00, 0x01));
+        match run_until_stop(&mut dbg, &mut cpu) {
+            StopReason::Watchpoint(i, AddressSpace::Cpu, access) => {
+                assert_eq!(i, id);
+                assert_eq!(access.kind, AccessKind::Write);
+                assert!(access.addr <= 0x01);
+            }
+            reason => panic!("Stopped for {reason}"),
+        }
+        assert!(cpu.at_instr_boundary());
+    }
+}
@@ -0,0 +1,281 @@
+use std::fmt;
+
+use crate::cpu::reg::{Registers, StatusFlags};
+use crate::error::Result;
+
+// Breakpoint conditions on the CPU registers, e.g. "a == $10 && (x > 3 || c)"
+//
+//   registers: a x y sp pc p, flags: c z i d v n (0 or 1)
+//   numbers:   $1F, 0x1F, 31
+//   operators: ! * + - & | == != < <= > >= && ||  (C precedence)
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    A,
+    X,
+    Y,
+    Sp,
+    Pc,
+    P,
+    Flag(StatusFlags),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Mul,
+    Add,
+    Sub,
+    BitAnd,
+    BitOr,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Num(i64),
+    Reg(Operand),
+    Not(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    pub fn parse(s: &str) -> Result<Expr> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+        };
+        let expr = parser.binary(0)?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(expr),
+            Some(t) => Err(format!("Unexpected {t} in expression").into()),
+        }
+    }
+
+    pub fn eval(&self, reg: &Registers) -> i64 {
+        match self {
+            Expr::Num(n) => *n,
+            Expr::Reg(op) => match op {
+                Operand::A => reg.a as i64,
+                Operand::X => reg.x as i64,
+                Operand::Y => reg.y as i64,
+                Operand::Sp => reg.sp as i64,
+                Operand::Pc => reg.pc as i64,
+                Operand::P => reg.status.bits() as i64,
+                Operand::Flag(flag) => reg.status.contains(*flag) as i64,
+            },
+            Expr::Not(e) => (e.eval(reg) == 0) as i64,
+            Expr::Binary(op, lhs, rhs) => {
+                let l = lhs.eval(reg);
+                // && and || short-circuit
+                match op {
+                    BinOp::And => return (l != 0 && rhs.eval(reg) != 0) as i64,
+                    BinOp::Or => return (l != 0 || rhs.eval(reg) != 0) as i64,
+                    _ => (),
+                }
+                let r = rhs.eval(reg);
+                match op {
+                    BinOp::Mul => l.wrapping_mul(r),
+                    BinOp::Add => l.wrapping_add(r),
+                    BinOp::Sub => l.wrapping_sub(r),
+                    BinOp::BitAnd => l & r,
+                    BinOp::BitOr => l | r,
+                    BinOp::Eq => (l == r) as i64,
+                    BinOp::Ne => (l != r) as i64,
+                    BinOp::Lt => (l < r) as i64,
+                    BinOp::Le => (l <= r) as i64,
+                    BinOp::Gt => (l > r) as i64,
+                    BinOp::Ge => (l >= r) as i64,
+                    BinOp::And | BinOp::Or => unreachable!(),
+                }
+            }
+        }
+    }
+
+    pub fn is_true(&self, reg: &Registers) -> bool {
+        self.eval(reg) != 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Num(i64),
+    Reg(Operand),
+    Op(&'static str),
+    LParen,
+    RParen,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Num(n) => write!(f, "{n}"),
+            Token::Reg(r) => write!(f, "{r:?}"),
+            Token::Op(op) => write!(f, "'{op}'"),
+            Token::LParen => write!(f, "'('"),
+            Token::RParen => write!(f, "')'"),
+        }
+    }
+}
+
+// Longest first, so "<=" isn't read as "<"
+const OPERATORS: [&str; 14] = [
+    "==", "!=", "<=", ">=", "&&", "||", "<", ">", "!", "*", "+", "-", "&", "|",
+];
+
+pub fn parse_number(s: &str) -> Result<i64> {
+    let n = if let Some(hex) = s.strip_prefix('$').or_else(|| s.strip_prefix("0x")) {
+        i64::from_str_radix(hex, 16)
+    } else {
+        s.parse()
+    };
+    n.map_err(|_| format!("Invalid number {s}").into())
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut rest = s.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if c == '(' || c == ')' {
+            tokens.push(if c == '(' { Token::LParen } else { Token::RParen });
+            rest = &rest[1..];
+        } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        } else if c.is_ascii_alphanumeric() || c == '$' {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '$')
+                .unwrap_or(rest.len());
+            let word = &rest[..len];
+            tokens.push(match word.to_ascii_lowercase().as_str() {
+                "a" => Token::Reg(Operand::A),
+                "x" => Token::Reg(Operand::X),
+                "y" => Token::Reg(Operand::Y),
+                "sp" => Token::Reg(Operand::Sp),
+                "pc" => Token::Reg(Operand::Pc),
+                "p" => Token::Reg(Operand::P),
+                "c" => Token::Reg(Operand::Flag(StatusFlags::CARRY)),
+                "z" => Token::Reg(Operand::Flag(StatusFlags::ZERO)),
+                "i" => Token::Reg(Operand::Flag(StatusFlags::INTERRUPT_DISABLE)),
+                "d" => Token::Reg(Operand::Flag(StatusFlags::DECIMAL)),
+                "v" => Token::Reg(Operand::Flag(StatusFlags::OVERFLOW)),
+                "n" => Token::Reg(Operand::Flag(StatusFlags::NEGATIVE)),
+                _ => Token::Num(parse_number(word)?),
+            });
+            rest = &rest[len..];
+        } else {
+            return Err(format!("Unexpected '{c}' in expression").into());
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+// Binary operators from loosest to tightest binding
+const PRECEDENCE: [&[(&str, BinOp)]; 7] = [
+    &[("||", BinOp::Or)],
+    &[("&&", BinOp::And)],
+    &[
+        ("==", BinOp::Eq),
+        ("!=", BinOp::Ne),
+        ("<", BinOp::Lt),
+        ("<=", BinOp::Le),
+        (">", BinOp::Gt),
+        (">=", BinOp::Ge),
+    ],
+    &[("|", BinOp::BitOr)],
+    &[("&", BinOp::BitAnd)],
+    &[("+", BinOp::Add), ("-", BinOp::Sub)],
+    &[("*", BinOp::Mul)],
+];
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(Token::Op(op)) = self.tokens.get(self.pos) {
+            let Some((_, bin_op)) = PRECEDENCE[level].iter().find(|(s, _)| s == op) else {
+                break;
+            };
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr::Binary(*bin_op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::Op("!")) => Ok(Expr::Not(Box::new(self.unary()?))),
+            Some(Token::Op("-")) => Ok(Expr::Binary(
+                BinOp::Sub,
+                Box::new(Expr::Num(0)),
+                Box::new(self.unary()?),
+            )),
+            Some(Token::Num(n)) => Ok(Expr::Num(n)),
+            Some(Token::Reg(r)) => Ok(Expr::Reg(r)),
+            Some(Token::LParen) => {
+                let e = self.binary(0)?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(e),
+                    _ => Err("Missing ')' in expression".into()),
+                }
+            }
+            Some(t) => Err(format!("Unexpected {t} in expression").into()),
+            None => Err("Unexpected end of expression".into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod expr_tests {
+    use super::Expr;
+    use crate::cpu::reg::{Registers, StatusFlags};
+
+    #[test]
+    fn eval() {
+        let reg = Registers {
+            a: 0x10,
+            x: 3,
+            y: 0xFF,
+            pc: 0xC000,
+            sp: 0xFD,
+            status: StatusFlags::CARRY | StatusFlags::UNUSED,
+        };
+        let eval = |s: &str| Expr::parse(s).unwrap().eval(&reg);
+
+        assert_eq!(eval("a == $10"), 1);
+        assert_eq!(eval("A == 0x10 && x > 3"), 0);
+        assert_eq!(eval("a == 16 && x >= 3 || z"), 1);
+        assert_eq!(eval("pc == $C000 && !z && c"), 1);
+        assert_eq!(eval("(y & $F0) == $F0"), 1);
+        assert_eq!(eval("x + 1 * 2 - 5"), 0);
+        assert_eq!(eval("p"), 0x21);
+        assert_eq!(eval("sp - -2"), 0xFF);
+
+        assert!(Expr::parse("a ==").is_err());
+        assert!(Expr::parse("(a == 1").is_err());
+        assert!(Expr::parse("a == 1)").is_err());
+        assert!(Expr::parse("q == 1").is_err());
+        assert!(Expr::parse("a = 1").is_err());
+    }
+}
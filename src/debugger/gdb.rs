@@ -0,0 +1,549 @@
+// GDB remote serial protocol stub, so an external debugger can attach over TCP
+// https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+//
+// GDB has no 6502 target, registers are numbered:
+//   0 A, 1 X, 2 Y, 3 P, 4 SP (8 bits each), 5 PC (16 bits, little endian)
+// and 'g' returns them in that order.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use super::debugger::{Breakpoint, Debugger, StopReason};
+use crate::cpu::cpu::Cpu;
+use crate::cpu::reg::StatusFlags;
+use crate::error::Result;
+use crate::mem::access::{AccessKind, AddressSpace};
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+// Ctrl-C from the client, sent outside of a packet
+const INTERRUPT: u8 = 0x03;
+
+// Why the client is waiting for the debugger to stop
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Waiting {
+    // Asked for the stop reason while attaching
+    Query,
+    Resume,
+}
+
+struct Connection {
+    stream: TcpStream,
+    buf: Vec<u8>,
+    waiting_for_stop: Option<Waiting>,
+    no_ack: bool,
+    // (Z packet type, address) -> debugger breakpoint
+    breakpoints: HashMap<(u8, u16), usize>,
+}
+
+pub struct GdbServer {
+    listener: TcpListener,
+    conn: Option<Connection>,
+}
+
+impl GdbServer {
+    pub fn bind(addr: impl ToSocketAddrs) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            conn: None,
+        })
+    }
+
+    pub fn port(&self) -> Result<u16> {
+        Ok(self.listener.local_addr()?.port())
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.conn.is_some()
+    }
+
+    // Accepts a client and handles whatever it sent, never blocks
+    pub fn poll(&mut self, dbg: &mut Debugger, cpu: &mut Cpu) -> Result<()> {
+        if self.conn.is_none() {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(true)?;
+                    stream.set_nodelay(true)?;
+                    self.conn = Some(Connection {
+                        stream,
+                        buf: vec![],
+                        waiting_for_stop: None,
+                        no_ack: false,
+                        breakpoints: HashMap::new(),
+                    });
+                    // GDB expects the target to be stopped when it attaches
+                    dbg.pause();
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let conn = self.conn.as_mut().unwrap();
+        let mut chunk = [0; 1024];
+        loop {
+            match conn.stream.read(&mut chunk) {
+                Ok(0) => {
+                    // Client went away, let the game run again
+                    self.disconnect(dbg, cpu);
+                    return Ok(());
+                }
+                Ok(n) => conn.buf.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if is_disconnect(e.kind()) => {
+                    // Client went away without detaching
+                    self.disconnect(dbg, cpu);
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        while let Some(packet) = conn.next_packet()? {
+            if packet == [INTERRUPT] {
+                dbg.pause();
+                continue;
+            }
+            // Everything in the protocol is ASCII, which keeps the string slicing below safe
+            if !packet.is_ascii() {
+                conn.send("E01")?;
+                continue;
+            }
+            let packet = String::from_utf8_lossy(&packet).to_string();
+            if packet == "k" || packet == "D" {
+                if packet == "D" {
+                    conn.send("OK")?;
+                }
+                self.disconnect(dbg, cpu);
+                return Ok(());
+            }
+            if let Some(reply) = conn.handle(&packet, dbg, cpu) {
+                conn.send(&reply)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Tells the client why the debugger stopped, if it's waiting for that
+    pub fn notify_stop(&mut self, reason: &StopReason) -> Result<()> {
+        if let Some(conn) = self.conn.as_mut() {
+            match conn.waiting_for_stop.take() {
+                Some(Waiting::Query) => conn.send(&format!("S{SIGTRAP:02x}"))?,
+                Some(Waiting::Resume) => conn.send(&stop_reply(reason))?,
+                None => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn disconnect(&mut self, dbg: &mut Debugger, cpu: &mut Cpu) {
+        if let Some(conn) = self.conn.take() {
+            for id in conn.breakpoints.values() {
+                let _ = dbg.remove_breakpoint(cpu, *id);
+            }
+        }
+        dbg.resume();
+    }
+}
+
+impl Connection {
+    // Next complete packet (or interrupt) in the buffer, acknowledging it
+    fn next_packet(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            match self.buf.first() {
+                None => return Ok(None),
+                Some(b'+' | b'-') => {
+                    // We never resend, so acks don't matter
+                    self.buf.remove(0);
+                }
+                Some(&INTERRUPT) => {
+                    self.buf.remove(0);
+                    return Ok(Some(vec![INTERRUPT]));
+                }
+                Some(b'$') => {
+                    let Some(end) = self.buf.iter().position(|b| *b == b'#') else {
+                        return Ok(None);
+                    };
+                    if self.buf.len() < end + 3 {
+                        return Ok(None);
+                    }
+                    let data = self.buf[1..end].to_vec();
+                    let checksum = std::str::from_utf8(&self.buf[end + 1..end + 3])
+                        .ok()
+                        .and_then(|s| u8::from_str_radix(s, 16).ok());
+                    self.buf.drain(..end + 3);
+                    if checksum == Some(checksum_of(&data)) {
+                        if !self.no_ack {
+                            self.stream.write_all(b"+")?;
+                        }
+                        return Ok(Some(data));
+                    } else if !self.no_ack {
+                        self.stream.write_all(b"-")?;
+                    }
+                }
+                Some(_) => {
+                    // Garbage between packets
+                    self.buf.remove(0);
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> Result<()> {
+        let packet = format!("${data}#{:02x}", checksum_of(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())?;
+        Ok(())
+    }
+
+    // Reply to a packet, None if there isn't one (yet). Malformed packets get an error reply
+    // rather than taking the emulator down.
+    fn handle(&mut self, packet: &str, dbg: &mut Debugger, cpu: &mut Cpu) -> Option<String> {
+        self.command(packet, dbg, cpu).unwrap_or_else(|_| Some("E01".to_string()))
+    }
+
+    fn command(
+        &mut self,
+        packet: &str,
+        dbg: &mut Debugger,
+        cpu: &mut Cpu,
+    ) -> Result<Option<String>> {
+        let (cmd, args) = packet.split_at(packet.len().min(1));
+        let reply = match cmd {
+            "?" => {
+                if dbg.is_stopped() {
+                    format!("S{SIGTRAP:02x}")
+                } else {
+                    // Stopping at the next instruction
+                    self.waiting_for_stop = Some(Waiting::Query);
+                    return Ok(None);
+                }
+            }
+            "g" => {
+                let reg = &cpu.reg;
+                let bytes = [reg.a, reg.x, reg.y, reg.status.bits(), reg.sp];
+                let pc = reg.pc.to_le_bytes();
+                to_hex(bytes.iter().chain(pc.iter()))
+            }
+            "G" => {
+                let bytes = from_hex(args)?;
+                if bytes.len() != 7 {
+                    return Ok(Some("E01".to_string()));
+                }
+                for (n, _) in bytes.iter().enumerate().take(5) {
+                    set_register(cpu, n, &bytes[n..n + 1]);
+                }
+                set_register(cpu, 5, &bytes[5..]);
+                "OK".to_string()
+            }
+            "p" => {
+                let n = usize::from_str_radix(args, 16)?;
+                match register(cpu, n) {
+                    Some(bytes) => to_hex(bytes.iter()),
+                    None => "E01".to_string(),
+                }
+            }
+            "P" => {
+                let (n, value) = args.split_once('=').ok_or("Malformed P packet")?;
+                let n = usize::from_str_radix(n, 16)?;
+                if set_register(cpu, n, &from_hex(value)?) {
+                    "OK".to_string()
+                } else {
+                    "E01".to_string()
+                }
+            }
+            "m" => {
+                let (addr, len) = parse_addr_len(args)?;
+                let bytes = (0..len).map(|i| read_memory(cpu, addr.wrapping_add(i)));
+                to_hex(bytes.collect::<Vec<u8>>().iter())
+            }
+            "M" => {
+                let (addr_len, data) = args.split_once(':').ok_or("Malformed M packet")?;
+                let (addr, _) = parse_addr_len(addr_len)?;
+                for (i, byte) in from_hex(data)?.into_iter().enumerate() {
+                    if !write_memory(cpu, addr.wrapping_add(i as u16), byte) {
+                        return Ok(Some("E0e".to_string()));
+                    }
+                }
+                "OK".to_string()
+            }
+            "c" | "s" => {
+                if !args.is_empty() {
+                    cpu.reg.pc = u16::from_str_radix(args, 16)?;
+                }
+                if cmd == "c" {
+                    dbg.resume();
+                } else {
+                    dbg.step_into();
+                }
+                self.waiting_for_stop = Some(Waiting::Resume);
+                return Ok(None);
+            }
+            "Z" | "z" => {
+                let mut parts = args.split(',');
+                let (Some(kind), Some(addr), Some(len)) = (parts.next(), parts.next(), parts.next())
+                else {
+                    return Ok(Some("E01".to_string()));
+                };
+                let kind: u8 = kind.parse()?;
+                let addr = u16::from_str_radix(addr, 16)?;
+                let end = addr.saturating_add(u16::from_str_radix(len, 16)?.max(1) - 1);
+                let bp = match kind {
+                    // Software and hardware breakpoints are the same thing here
+                    0 | 1 => Breakpoint::exec(addr, addr),
+                    2 => Breakpoint::watch(AddressSpace::Cpu, false, true, addr, end),
+                    3 => Breakpoint::watch(AddressSpace::Cpu, true, false, addr, end),
+                    4 => Breakpoint::watch(AddressSpace::Cpu, true, true, addr, end),
+                    _ => return Ok(Some(String::new())),
+                };
+                if cmd == "Z" {
+                    if let Entry::Vacant(e) = self.breakpoints.entry((kind, addr)) {
+                        e.insert(dbg.add_breakpoint(cpu, bp));
+                    }
+                } else if let Some(id) = self.breakpoints.remove(&(kind, addr)) {
+                    dbg.remove_breakpoint(cpu, id)?;
+                }
+                "OK".to_string()
+            }
+            "q" if args.starts_with("Supported") => "PacketSize=1000".to_string(),
+            "q" if args == "Attached" => "1".to_string(),
+            "q" if args == "C" => "QC1".to_string(),
+            "Q" if args == "StartNoAckMode" => {
+                // (Acknowledged before the switch)
+                self.no_ack = true;
+                "OK".to_string()
+            }
+            "H" => "OK".to_string(),
+            // Not supported
+            _ => String::new(),
+        };
+        Ok(Some(reply))
+    }
+}
+
+fn stop_reply(reason: &StopReason) -> String {
+    match reason {
+        StopReason::Pause => format!("S{SIGINT:02x}"),
+        StopReason::Watchpoint(_, _, access) => {
+            let kind = match access.kind {
+                AccessKind::Read => "rwatch",
+                AccessKind::Write => "watch",
+            };
+            format!("T{SIGTRAP:02x}{kind}:{:04x};", access.addr)
+        }
+        _ => format!("S{SIGTRAP:02x}"),
+    }
+}
+
+fn register(cpu: &Cpu, n: usize) -> Option<Vec<u8>> {
+    let reg = &cpu.reg;
+    Some(match n {
+        0 => vec![reg.a],
+        1 => vec![reg.x],
+        2 => vec![reg.y],
+        3 => vec![reg.status.bits()],
+        4 => vec![reg.sp],
+        5 => reg.pc.to_le_bytes().to_vec(),
+        _ => return None,
+    })
+}
+
+fn set_register(cpu: &mut Cpu, n: usize, bytes: &[u8]) -> bool {
+    let reg = &mut cpu.reg;
+    match (n, bytes) {
+        (0, [v]) => reg.a = *v,
+        (1, [v]) => reg.x = *v,
+        (2, [v]) => reg.y = *v,
+        (3, [v]) => reg.status = StatusFlags::from_bits_truncate(*v),
+        (4, [v]) => reg.sp = *v,
+        (5, [lo, hi]) => reg.pc = u16::from_le_bytes([*lo, *hi]),
+        _ => return false,
+    }
+    true
+}
+
+// Peeked so the client can look at the I/O registers too, write-only registers and unmapped
+// addresses read as 0
+fn read_memory(cpu: &Cpu, addr: u16) -> u8 {
+    cpu.peek(addr).unwrap_or(0)
+}
+
+// The console's RAM and the cart's PRG RAM can be written, ROM and the I/O registers can't.
+// Returns false if the address isn't writable.
+fn write_memory(cpu: &mut Cpu, addr: u16, byte: u8) -> bool {
+    match addr {
+        0x0000..=0x1FFF | 0x6000..=0x7FFF => cpu.write(addr, byte).is_ok(),
+        _ => false,
+    }
+}
+
+fn is_disconnect(kind: ErrorKind) -> bool {
+    matches!(kind, ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted)
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+fn to_hex<'a>(bytes: impl Iterator<Item = &'a u8>) -> String {
+    bytes.map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(s: &str) -> Result<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return Err("Odd number of hex digits".into());
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&s[i..i + 2], 16)?))
+        .collect()
+}
+
+// "<addr>,<len>"
+fn parse_addr_len(s: &str) -> Result<(u16, u16)> {
+    let (addr, len) = s.split_once(',').ok_or("Expected <addr>,<len>")?;
+    Ok((u16::from_str_radix(addr, 16)?, u16::from_str_radix(len, 16)?))
+}
+
+#[cfg(test)]
+mod gdb_tests {
+    use super::{checksum_of, GdbServer};
+    use crate::cart::builder::build_cartridge;
+    use crate::cpu::cpu::Cpu;
+    use crate::debugger::debugger::Debugger;
+    use crate::ines::parse::INesFile;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+
+    static NESTEST: &[u8] = include_bytes!("../../test_files/nestest.nes");
+
+    // Minimal client, sends a packet and returns the reply
+    fn request(stream: &mut TcpStream, data: &str) -> String {
+        let packet = format!("${data}#{:02x}", checksum_of(data.as_bytes()));
+        stream.write_all(packet.as_bytes()).unwrap();
+        let mut reply = vec![];
+        let mut byte = [0];
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            match byte[0] {
+                b'+' if reply.is_empty() => continue,
+                b'#' => break,
+                b => reply.push(b),
+            }
+        }
+        let mut checksum = [0; 2];
+        stream.read_exact(&mut checksum).unwrap();
+        stream.write_all(b"+").unwrap();
+        let reply = String::from_utf8(reply[1..].to_vec()).unwrap();
+        let expected = format!("{:02x}", checksum_of(reply.as_bytes()));
+        assert_eq!(checksum, expected.as_bytes());
+        reply
+    }
+
+    #[test]
+    fn scripted_session() {
+        let rom = INesFile::try_from(&NESTEST.to_vec()).unwrap();
+        let mut cpu = Cpu::new(build_cartridge(&rom).unwrap(), 44100.0, None, None);
+        cpu.reset().unwrap();
+        cpu.reg.pc = 0xC000;
+        let mut dbg = Debugger::new();
+        // Don't let nestest run off while the client connects
+        dbg.pause();
+        dbg.run_frame(&mut cpu).unwrap();
+        let mut server = GdbServer::bind("127.0.0.1:0").unwrap();
+        let port = server.port().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut s = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            let mut replies = vec![];
+            for packet in [
+                "qSupported:swbreak+",
+                "?",
+                "m c000,3",
+                "Z0,c5fd,1",
+                "c",
+                "g",
+                "p5",
+                "s",
+                "p5",
+                "M 10,2:abcd",
+                "m10,2",
+                "M 8000,1:00",
+                "P0=42",
+                "p0",
+                "P0=00",
+                // Malformed and non-ASCII
+                "pzz",
+                "P0",
+                "m c000",
+                "M 10,1:0",
+                "cxyz",
+                "Z0,xyz,1",
+                "m\u{e9},1",
+                // NROM's PRG RAM
+                "M 6000,1:5a",
+                "m6000,1",
+                // The JSR at $C600 pushes the return address there
+                "Z2,1fc,2",
+                "c",
+                "D",
+            ] {
+                replies.push(request(&mut s, packet.replace(' ', "").as_str()));
+            }
+            replies
+        });
+
+        while !server.is_connected() {
+            server.poll(&mut dbg, &mut cpu).unwrap();
+        }
+        while server.is_connected() {
+            server.poll(&mut dbg, &mut cpu).unwrap();
+            if server.is_connected() && !dbg.is_stopped() {
+                if let (_, Some(reason)) = dbg.run_frame(&mut cpu).unwrap() {
+                    server.notify_stop(&reason).unwrap();
+                }
+            }
+        }
+        let replies = client.join().unwrap();
+        assert_eq!(
+            replies,
+            [
+                "PacketSize=1000",
+                "S05",
+                "4cf5c5",
+                "OK",
+                "S05",
+                // A X Y P SP PC
+                "00000026fdfdc5",
+                "fdc5",
+                "S05",
+                "2dc7",
+                "OK",
+                "abcd",
+                "E0e",
+                "OK",
+                "42",
+                "OK",
+                "E01",
+                "E01",
+                "E01",
+                "E01",
+                "E01",
+                "E01",
+                "E01",
+                "OK",
+                "5a",
+                "OK",
+                "T05watch:01fd;",
+                "OK",
+            ]
+        );
+        // Detaching removes the client's breakpoints and lets the game run
+        assert_eq!(dbg.breakpoints().count(), 0);
+        assert!(!dbg.is_stopped());
+    }
+}
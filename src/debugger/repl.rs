@@ -0,0 +1,221 @@
+use std::fmt::Write;
+
+use super::debugger::{Breakpoint, Debugger, StopReason};
+use super::expr::parse_number;
+use crate::cpu::cpu::Cpu;
+use crate::disasm::disasm::{format_instr, Disassembler};
+use crate::error::Result;
+use crate::mem::access::AddressSpace;
+
+pub const HELP: &str = "\
+Commands:
+  break <addr>[-<addr>] [if <cond>]          stop before running code in the range
+  watch [ppu] r|w|rw <addr>[-<addr>] [if <cond>]  stop after memory in the range is accessed
+  delete <id>                                remove a breakpoint/watchpoint
+  list                                       list breakpoints and watchpoints
+  continue | step | next | finish            resume, step into/over/out of subroutines
+  scanline <n>                               run until the PPU starts scanline n
+  frame                                      run until the end of the frame
+  pause                                      stop at the next instruction
+  regs                                       show the registers
+  mem <addr> [len]                           dump CPU memory
+  disasm [<addr>[-<addr>]]                   disassemble (16 bytes from the PC by default)
+Conditions are expressions on the registers (a x y sp pc p) and flags (c z i d v n),
+e.g. \"a == $10 && !z\". Commands can be shortened to their first letter.";
+
+// Runs a REPL command line, returns what to show
+pub fn run_command(dbg: &mut Debugger, cpu: &mut Cpu, line: &str) -> Result<String> {
+    let (cmd, args) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+    let args = args.trim();
+    match cmd {
+        "" => Ok(String::new()),
+        "b" | "break" => {
+            let bp = with_condition(args, |range| {
+                let (start, end) = parse_range(range)?;
+                Ok(Breakpoint::exec(start, end))
+            })?;
+            let desc = bp.to_string();
+            Ok(format!("{} {desc}", dbg.add_breakpoint(cpu, bp)))
+        }
+        "w" | "watch" => {
+            let bp = with_condition(args, |watch| {
+                let (space, watch) = match watch.strip_prefix("ppu ") {
+                    Some(rest) => (AddressSpace::Ppu, rest.trim_start()),
+                    None => (AddressSpace::Cpu, watch),
+                };
+                let (kind, range) = watch.split_once(' ').ok_or("Expected r|w|rw <addr>")?;
+                let (read, write) = match kind {
+                    "r" => (true, false),
+                    "w" => (false, true),
+                    "rw" => (true, true),
+                    _ => return Err(format!("Unknown access {kind}, expected r, w or rw").into()),
+                };
+                let (start, end) = parse_range(range.trim())?;
+                Ok(Breakpoint::watch(space, read, write, start, end))
+            })?;
+            let desc = bp.to_string();
+            Ok(format!("{} {desc}", dbg.add_breakpoint(cpu, bp)))
+        }
+        "d" | "delete" => {
+            let id = args.parse().map_err(|_| format!("Invalid breakpoint {args}"))?;
+            dbg.remove_breakpoint(cpu, id)?;
+            Ok(String::new())
+        }
+        "l" | "list" => Ok(dbg
+            .breakpoints()
+            .map(|(id, bp)| format!("{id} {bp}\n"))
+            .collect()),
+        "c" | "continue" => {
+            dbg.resume();
+            Ok(String::new())
+        }
+        "s" | "step" => {
+            dbg.step_into();
+            Ok(String::new())
+        }
+        "n" | "next" => {
+            dbg.step_over(cpu);
+            Ok(String::new())
+        }
+        "f" | "finish" => {
+            dbg.step_out(cpu);
+            Ok(String::new())
+        }
+        "scanline" => {
+            let scanline = parse_number(args)?;
+            if !(-1..=260).contains(&scanline) {
+                return Err("Scanlines go from -1 to 260".into());
+            }
+            dbg.run_to_scanline(scanline as i32);
+            Ok(String::new())
+        }
+        "frame" => {
+            dbg.frame_advance();
+            Ok(String::new())
+        }
+        "p" | "pause" => {
+            dbg.pause();
+            Ok(String::new())
+        }
+        "r" | "regs" => Ok(location(dbg, cpu)),
+        "m" | "mem" => {
+            let (addr, len) = args.split_once(' ').unwrap_or((args, "16"));
+            let addr = parse_address(addr)?;
+            let len = parse_number(len.trim())?.clamp(1, 0x10000) as usize;
+            let mut out = String::new();
+            for row in (0..len).step_by(16) {
+                let row_addr = addr.wrapping_add(row as u16);
+                write!(out, "{row_addr:04X}:")?;
+                for i in 0..(len - row).min(16) {
+                    write!(out, " {:02X}", cpu.peek(row_addr.wrapping_add(i as u16))?)?;
+                }
+                out.push('\n');
+            }
+            Ok(out)
+        }
+        "u" | "disasm" => {
+            let (start, end) = match args {
+                "" => (cpu.reg.pc, cpu.reg.pc.saturating_add(15)),
+                _ if !args.contains('-') => {
+                    let addr = parse_address(args)?;
+                    (addr, addr.saturating_add(15))
+                }
+                _ => parse_range(args)?,
+            };
+            let lines = Disassembler::new(&dbg.symbols).range(cpu, start, end);
+            Ok(lines.iter().map(|line| format!("{line}\n")).collect())
+        }
+        "h" | "help" => Ok(HELP.to_string()),
+        _ => Err(format!("Unknown command {cmd}, try help").into()),
+    }
+}
+
+// What gets shown when the debugger stops
+pub fn describe_stop(dbg: &Debugger, cpu: &Cpu, reason: &StopReason) -> String {
+    format!("{reason}\n{}", location(dbg, cpu))
+}
+
+// Next instruction, registers and where the PPU is at
+fn location(dbg: &Debugger, cpu: &Cpu) -> String {
+    let label = |addr| {
+        let symbol = dbg.symbols.lookup(addr, cpu.prg_rom_offset(addr));
+        symbol.map(|s| s.name.clone())
+    };
+    let instr = match cpu.peek_next_instr(0) {
+        Ok((pc, instr)) => format_instr(pc, &instr, label),
+        Err(_) => "???".to_string(),
+    };
+    let (scanline, dot) = cpu.ppu_position();
+    format!(
+        "{:04X}  {instr:<20} {} PPU:{scanline:3},{dot:3}",
+        cpu.reg.pc, cpu.reg
+    )
+}
+
+// "<args> [if <condition>]"
+fn with_condition(
+    args: &str,
+    make: impl FnOnce(&str) -> Result<Breakpoint>,
+) -> Result<Breakpoint> {
+    match args.split_once(" if ") {
+        Some((args, condition)) => make(args.trim())?.with_condition(condition),
+        None => make(args),
+    }
+}
+
+fn parse_address(s: &str) -> Result<u16> {
+    let n = parse_number(s)?;
+    u16::try_from(n).map_err(|_| format!("Invalid address {s}").into())
+}
+
+// "<addr>" or "<start>-<end>"
+fn parse_range(s: &str) -> Result<(u16, u16)> {
+    let (start, end) = match s.split_once('-') {
+        Some((start, end)) => (parse_address(start.trim())?, parse_address(end.trim())?),
+        None => {
+            let addr = parse_address(s)?;
+            (addr, addr)
+        }
+    };
+    if end < start {
+        return Err(format!("Invalid range {s}").into());
+    }
+    Ok((start, end))
+}
+
+#[cfg(test)]
+mod repl_tests {
+    use super::run_command;
+    use crate::cpu::cpu::Cpu;
+    use crate::debugger::debugger::Debugger;
+
+    #[test]
+    fn commands() {
+        let mut cpu = Cpu::mock(None);
+        let mut dbg = Debugger::new();
+        let mut run = |line: &str| run_command(&mut dbg, &mut cpu, line);
+
+        assert_eq!(run("b $C000").unwrap(), "1 exec $C000");
+        assert_eq!(run("break $8000-$80FF if a == 1").unwrap(), "2 exec $8000-$80FF if a == 1");
+        assert_eq!(run("w ppu rw $2000-$23FF").unwrap(), "3 watch ppu rw $2000-$23FF");
+        assert_eq!(run("watch w 0x10 if x").unwrap(), "4 watch w $0010 if x");
+        assert_eq!(run("d 2").unwrap(), "");
+        assert_eq!(
+            run("list").unwrap(),
+            "1 exec $C000\n3 watch ppu rw $2000-$23FF\n4 watch w $0010 if x\n"
+        );
+        assert_eq!(run("mem $0 4").unwrap(), "0000: 00 00 00 00\n");
+        assert_eq!(
+            run("disasm $0-$1").unwrap(),
+            "        brk                             ; $0000  00\n        brk                             ; $0001  00\n"
+        );
+
+        assert!(run("b $10000").is_err());
+        assert!(run("b $20-$10").is_err());
+        assert!(run("b $10 if a =").is_err());
+        assert!(run("w x $10").is_err());
+        assert!(run("d 2").is_err());
+        assert!(run("scanline 300").is_err());
+        assert!(run("jump").is_err());
+    }
+}
@@ -0,0 +1,268 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use super::symbols::SymbolTable;
+use crate::cpu::cpu::Cpu;
+use crate::cpu::decode::{decode_instr, instr_lookup::LOOKUP};
+use crate::cpu::isa::{AddressingMode, Instr, Opcode};
+
+// Disassembles into ca65 syntax (.setcpu "6502X"), labels come from a SymbolTable and
+// otherwise get generated for branch and jump targets inside the listing. Anything ca65
+// wouldn't assemble back into the same bytes (data, JAM, unstable or duplicate opcodes) is
+// written out as .byte.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub addr: u16,
+    // Where the line is in PRG ROM, if it's in ROM
+    pub prg_offset: Option<usize>,
+    pub bytes: Vec<u8>,
+    // None for data
+    pub instr: Option<Instr>,
+    // Label defined here
+    pub label: Option<String>,
+    // ca65 source, e.g. "lda #$10"
+    pub text: String,
+    pub comment: Option<String>,
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(label) = &self.label {
+            writeln!(f, "{label}:")?;
+        }
+        let bytes = self.bytes.iter().map(|b| format!("{b:02X}")).collect::<Vec<_>>();
+        let comment = self.comment.as_deref().unwrap_or_default();
+        let bytes = bytes.join(" ");
+        let line = format!("        {:<32}; ${:04X}  {bytes:<8}  {comment}", self.text, self.addr);
+        write!(f, "{}", line.trim_end())
+    }
+}
+
+pub struct Disassembler<'a> {
+    symbols: &'a SymbolTable,
+}
+
+impl<'a> Disassembler<'a> {
+    pub fn new(symbols: &'a SymbolTable) -> Self {
+        Self { symbols }
+    }
+
+    // `len` bytes of PRG ROM from `offset`, as seen by the CPU when mapped in at `base`
+    #[allow(dead_code)] // Used by nes-disasm
+    pub fn prg_rom(&self, prg_rom: &[u8], offset: usize, len: usize, base: u16) -> Vec<Line> {
+        let len = len.min(prg_rom.len().saturating_sub(offset)).min(0x10000 - base as usize);
+        if len == 0 {
+            return vec![];
+        }
+        let end = base + (len - 1) as u16;
+        self.disassemble(
+            base,
+            end,
+            |addr| (base..=end).contains(&addr).then(|| prg_rom[offset + (addr - base) as usize]),
+            |addr| (base..=end).contains(&addr).then(|| offset + (addr - base) as usize),
+        )
+    }
+
+    // Whatever is mapped in at start..=end right now, nothing gets disturbed
+    pub fn range(&self, cpu: &Cpu, start: u16, end: u16) -> Vec<Line> {
+        self.disassemble(
+            start,
+            end,
+            |addr| cpu.peek(addr).ok(),
+            |addr| cpu.prg_rom_offset(addr),
+        )
+    }
+
+    fn disassemble(
+        &self,
+        start: u16,
+        end: u16,
+        read: impl Fn(u16) -> Option<u8>,
+        prg_offset: impl Fn(u16) -> Option<usize>,
+    ) -> Vec<Line> {
+        // Decode everything first, the labels need to know where the instructions start
+        let mut decoded = vec![];
+        let mut addr = start as u32;
+        while addr <= end as u32 {
+            let pc = addr as u16;
+            let instr = decode_instr(pc, |a| read(a).ok_or_else(|| "Unmapped".into()))
+                .ok()
+                .map(|(instr, _)| instr)
+                .filter(|instr| pc as u32 + instr.length() as u32 - 1 <= end as u32);
+            let len = instr.map_or(1, |i| i.length() as u32);
+            let bytes: Vec<u8> = (0..len).map(|i| read(pc + i as u16).unwrap_or_default()).collect();
+            // Still takes up the whole instruction if ca65 can't write it
+            let assembles = instr.filter(|instr| assembles_back(bytes[0], instr));
+            decoded.push((pc, instr, assembles, bytes));
+            addr += len;
+        }
+
+        let starts: HashSet<u16> = decoded.iter().map(|(pc, _, _, _)| *pc).collect();
+        let mut targets = HashSet::new();
+        for (pc, _, instr, _) in &decoded {
+            if let Some(target) = instr.and_then(|i| jump_target(*pc, &i)) {
+                if starts.contains(&target) && self.lookup(target, &prg_offset).is_none() {
+                    targets.insert(target);
+                }
+            }
+        }
+        let label = |addr: u16| match self.lookup(addr, &prg_offset) {
+            Some(name) => Some(name),
+            None => targets.contains(&addr).then(|| format!("L{addr:04X}")),
+        };
+
+        decoded
+            .into_iter()
+            .map(|(pc, decoded, instr, bytes)| {
+                let text = match instr {
+                    Some(instr) => format_instr(pc, &instr, label),
+                    None => format_bytes(&bytes),
+                };
+                let symbol = self.symbols.lookup(pc, prg_offset(pc));
+                let mut comment = symbol.and_then(|s| s.comment.clone());
+                if let (Some(decoded), None) = (decoded, instr) {
+                    // What it is, since the .byte doesn't say
+                    let text = format_instr(pc, &decoded, label);
+                    comment = Some(comment.map_or(text.clone(), |c| format!("{text}  {c}")));
+                }
+                Line {
+                    addr: pc,
+                    prg_offset: prg_offset(pc),
+                    bytes,
+                    instr,
+                    label: label(pc).filter(|name| !name.contains('+')),
+                    text,
+                    comment,
+                }
+            })
+            .collect()
+    }
+
+    fn lookup(&self, addr: u16, prg_offset: &impl Fn(u16) -> Option<usize>) -> Option<String> {
+        self.symbols
+            .lookup(addr, prg_offset(addr))
+            .map(|s| s.name.clone())
+    }
+}
+
+// Where a branch, JMP or JSR goes
+fn jump_target(pc: u16, instr: &Instr) -> Option<u16> {
+    match (instr.op, instr.mode) {
+        (_, AddressingMode::Relative(offset)) => {
+            Some(pc.wrapping_add(2).wrapping_add(offset as i8 as u16))
+        }
+        (Opcode::JMP | Opcode::JSR, AddressingMode::Absolute(addr)) => Some(addr),
+        _ => None,
+    }
+}
+
+// Whether ca65 turns the mnemonic back into `byte`
+fn assembles_back(byte: u8, instr: &Instr) -> bool {
+    use Opcode::*;
+    match instr.op {
+        // Only $EA is the official NOP
+        NOP => byte == 0xEA,
+        // Unstable, ca65 doesn't know these
+        AHX | SHX | SHY | TAS | XAA | INVALID => false,
+        // The first encoding of an opcode/addressing mode pair is the one ca65 picks
+        op => {
+            let (_, mode, _) = LOOKUP[byte as usize >> 4][byte as usize & 0xF];
+            LOOKUP.iter().flatten().position(|(o, m, _)| *o == op && *m == mode)
+                == Some(byte as usize)
+        }
+    }
+}
+
+// ca65 source for an instruction at `pc`, `label` names addresses
+pub fn format_instr(pc: u16, instr: &Instr, label: impl Fn(u16) -> Option<String>) -> String {
+    use AddressingMode::*;
+    let mnemonic = format!("{:?}", instr.op).to_lowercase();
+    let zpg = |addr: u8| label(addr as u16).unwrap_or_else(|| format!("${addr:02X}"));
+    // a: keeps ca65 from turning it into a zero page access
+    let abs = |addr: u16| {
+        let prefix = if addr < 0x100 { "a:" } else { "" };
+        format!("{prefix}{}", label(addr).unwrap_or_else(|| format!("${addr:04X}")))
+    };
+    let operand = match instr.mode {
+        Implied => return mnemonic,
+        Accumulator => "a".to_string(),
+        Immediate(val) => format!("#${val:02X}"),
+        ZeroPage(addr) => zpg(addr),
+        ZeroPageX(addr) => format!("{},x", zpg(addr)),
+        ZeroPageY(addr) => format!("{},y", zpg(addr)),
+        Absolute(addr) => abs(addr),
+        AbsoluteX(addr) => format!("{},x", abs(addr)),
+        AbsoluteY(addr) => format!("{},y", abs(addr)),
+        Indirect(addr) => format!("({})", abs(addr)),
+        XIndirect(addr) => format!("({},x)", zpg(addr)),
+        IndirectY(addr) => format!("({}),y", zpg(addr)),
+        Relative(_) => {
+            let target = jump_target(pc, instr).unwrap_or_default();
+            label(target).unwrap_or_else(|| format!("${target:04X}"))
+        }
+    };
+    format!("{mnemonic:<8}{operand}")
+}
+
+fn format_bytes(bytes: &[u8]) -> String {
+    let bytes = bytes.iter().map(|b| format!("${b:02X}")).collect::<Vec<_>>();
+    format!("{:<8}{}", ".byte", bytes.join(","))
+}
+
+#[cfg(test)]
+mod disasm_tests {
+    use super::{Disassembler, Line};
+    use crate::disasm::symbols::SymbolTable;
+
+    fn listing(lines: &[Line]) -> Vec<String> {
+        lines.iter().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn prg_rom() {
+        let code = [
+            0x78, // sei
+            0xA9, 0x10, // lda #$10
+            0x8D, 0x10, 0x00, // sta a:$0010
+            0xB5, 0x10, // lda $10,x
+            0xB1, 0x20, // lda ($20),y
+            0xD0, 0xF5, // bne $8001
+            0x20, 0x00, 0x90, // jsr Sub
+            0xEB, 0x01, // sbc #$01 (unofficial duplicate)
+            0xA7, 0x10, // lax $10
+            0x02, // JAM
+            0x6C, 0xFC, // jmp ($..FC) cut off at the end
+        ];
+        let mut rom = vec![0; 0x8000];
+        rom[0x1000..0x1000 + code.len()].copy_from_slice(&code);
+        rom[0x5000..0x5000 + code.len()].copy_from_slice(&code);
+        let mut symbols = SymbolTable::default();
+        symbols.add_nl("$0020#ptr#\n$9000#Sub#Does things\n", Some(0)).unwrap();
+        symbols.add_nl("$9000#Other#\n", Some(1)).unwrap();
+
+        let disasm = Disassembler::new(&symbols);
+        let lines = disasm.prg_rom(&rom, 0x1000, code.len(), 0x9000);
+        assert_eq!(
+            listing(&lines),
+            [
+                "Sub:\n        sei                             ; $9000  78        Does things",
+                "L9001:\n        lda     #$10                    ; $9001  A9 10",
+                "        sta     a:$0010                 ; $9003  8D 10 00",
+                "        lda     $10,x                   ; $9006  B5 10",
+                "        lda     (ptr),y                 ; $9008  B1 20",
+                "        bne     L9001                   ; $900A  D0 F5",
+                "        jsr     Sub                     ; $900C  20 00 90",
+                "        .byte   $EB,$01                 ; $900F  EB 01     sbc     #$01",
+                "        lax     $10                     ; $9011  A7 10",
+                "        .byte   $02                     ; $9013  02",
+                "        .byte   $6C                     ; $9014  6C",
+                "        .byte   $FC                     ; $9015  FC",
+            ]
+        );
+        assert_eq!(lines[1].prg_offset, Some(0x1001));
+        // Bank 1 has its own label at $9000
+        let lines = disasm.prg_rom(&rom, 0x5000, 1, 0x9000);
+        assert_eq!(listing(&lines), ["Other:\n        sei                             ; $9000  78"]);
+    }
+}
@@ -0,0 +1,133 @@
+// Dumps an annotated ca65 listing of a ROM's PRG banks.
+//
+//   nes-disasm rom.nes --bank 0 --bank 3 --symbols rom.nes.0.nl --output rom.s
+//
+// Symbols next to the ROM (rom.nes.*.nl, rom.dbg) are picked up on their own. Banks get listed
+// at the address the cartridge maps them to after a reset, the rest at $8000 unless --base says
+// otherwise.
+
+use clap::Parser;
+use nes_emu::cart::builder::build_cartridge;
+use nes_emu::cpu::cpu::Cpu;
+use nes_emu::disasm::disasm::Disassembler;
+use nes_emu::disasm::symbols::{SymbolTable, NL_BANK_SIZE};
+use nes_emu::ines::parse::INesFile;
+use std::error::Error;
+use std::fmt::Write;
+use std::fs;
+use std::path::PathBuf;
+use std::process::exit;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+#[derive(Parser)]
+struct CliArgs {
+    rom_path: PathBuf,
+    /// 16K PRG bank to list, can be repeated (defaults to all of them)
+    #[arg(short, long)]
+    bank: Vec<usize>,
+    /// Address the listed banks are mapped in at
+    #[arg(long, value_parser = parse_addr)]
+    base: Option<u16>,
+    /// FCEUX .nl or ca65 .dbg symbol file, can be repeated
+    #[arg(short, long)]
+    symbols: Vec<PathBuf>,
+    /// Write the listing here instead of stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+fn parse_addr(s: &str) -> std::result::Result<u16, String> {
+    let hex = s.strip_prefix('$').or_else(|| s.strip_prefix("0x")).unwrap_or(s);
+    u16::from_str_radix(hex, 16).map_err(|_| format!("Invalid address {s}"))
+}
+
+// Where the cartridge has the bank mapped in after a reset, if it does. Mirrored banks
+// (NROM-128) go where the vectors are.
+fn reset_base(cpu: &Cpu, bank: usize) -> Option<u16> {
+    (0x8000..=0xFFFFu16)
+        .step_by(NL_BANK_SIZE)
+        .rev()
+        .find(|addr| cpu.prg_rom_offset(*addr) == Some(bank * NL_BANK_SIZE))
+}
+
+fn listing(args: &CliArgs) -> Result<String> {
+    let rom = INesFile::try_from(&fs::read(&args.rom_path)?)?;
+    let cpu = Cpu::new(build_cartridge(&rom)?, 44100.0, None, None);
+
+    let mut symbols = SymbolTable::for_rom(&args.rom_path)?;
+    for path in &args.symbols {
+        symbols.load(path)?;
+    }
+    let disasm = Disassembler::new(&symbols);
+
+    let num_banks = rom.prg_rom.len().div_ceil(NL_BANK_SIZE);
+    let banks = if args.bank.is_empty() {
+        (0..num_banks).collect()
+    } else {
+        args.bank.clone()
+    };
+
+    let mut out = String::new();
+    writeln!(out, "; {}", args.rom_path.display())?;
+    writeln!(out, ".setcpu \"6502X\"")?;
+    for bank in banks {
+        if bank >= num_banks {
+            return Err(format!("The ROM only has {num_banks} PRG banks").into());
+        }
+        let base = args.base.or_else(|| reset_base(&cpu, bank)).unwrap_or(0x8000);
+        let offset = bank * NL_BANK_SIZE;
+        writeln!(out, "\n; PRG bank {bank:X} (${offset:05X})")?;
+        writeln!(out, ".org ${base:04X}")?;
+        for line in disasm.prg_rom(&rom.prg_rom, offset, NL_BANK_SIZE, base) {
+            writeln!(out, "{line}")?;
+        }
+    }
+    Ok(out)
+}
+
+fn main() {
+    let args = CliArgs::parse();
+    let res = listing(&args).and_then(|out| match &args.output {
+        Some(path) => fs::write(path, out).map_err(|e| e.into()),
+        None => {
+            print!("{out}");
+            Ok(())
+        }
+    });
+    if let Err(e) = res {
+        eprintln!("Error: {e}");
+        exit(1);
+    }
+}
+
+#[cfg(test)]
+mod nes_disasm_tests {
+    use super::{listing, CliArgs};
+    use std::path::PathBuf;
+
+    #[test]
+    fn nestest() {
+        let args = CliArgs {
+            rom_path: PathBuf::from("test_files/nestest.nes"),
+            bank: vec![0],
+            base: None,
+            symbols: vec![],
+            output: None,
+        };
+        let out = listing(&args).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines[..5],
+            [
+                "; test_files/nestest.nes",
+                ".setcpu \"6502X\"",
+                "",
+                "; PRG bank 0 ($00000)",
+                ".org $C000",
+            ]
+        );
+        assert!(out.contains("        jmp     LC5F5                   ; $C000  4C F5 C5\n"));
+        assert!(out.contains("LC5F5:\n        ldx     #$00"));
+    }
+}
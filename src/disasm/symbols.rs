@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::error::Result;
+
+// Symbol files from other tools:
+//
+//   FCEUX .nl:  "$C000#Reset#Comment" per line, "$0300/10#Buffer#" labels 0x10 bytes. The
+//               file name says which 16K PRG bank it's for (game.nes.0.nl, game.nes.1F.nl) or
+//               that it's for RAM (game.nes.ram.nl)
+//   ca65 .dbg:  the debug info written by ld65 --dbgfile, labels in segments that ended up in
+//               the ROM file are tied to their PRG offset
+//
+// ROM labels are keyed by PRG ROM offset so they only show up while their bank is mapped in.
+
+// Bank size FCEUX numbers .nl files by
+pub const NL_BANK_SIZE: usize = 0x4000;
+const INES_HEADER_SIZE: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    // RAM, registers and ROM labels with no known bank
+    cpu: HashMap<u16, Symbol>,
+    prg: HashMap<usize, Symbol>,
+}
+
+impl SymbolTable {
+    // Every <rom file>.*.nl and <rom stem>.dbg next to the ROM
+    pub fn for_rom(rom_path: &Path) -> Result<Self> {
+        let mut symbols = Self::default();
+        let dir = match rom_path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let rom_name = rom_path.file_name().unwrap_or_default().to_string_lossy();
+        let dbg_name = rom_path.with_extension("dbg");
+        let dbg_name = dbg_name.file_name().unwrap_or_default().to_string_lossy();
+        let mut paths = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            let nl = name.starts_with(&format!("{rom_name}.")) && name.ends_with(".nl");
+            if nl || name == dbg_name {
+                paths.push(path);
+            }
+        }
+        // Same labels no matter what order the directory is listed in
+        paths.sort();
+        for path in paths {
+            symbols.load(&path)?;
+        }
+        Ok(symbols)
+    }
+
+    // Loads a .nl or .dbg file, going by the extension
+    pub fn load(&mut self, path: &Path) -> Result<()> {
+        let text = fs::read_to_string(path)?;
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let res = match path.extension().and_then(|e| e.to_str()) {
+            Some("nl") => self.add_nl(&text, nl_bank(&name)),
+            Some("dbg") => self.add_dbg(&text),
+            _ => Err("Expected a .nl or .dbg symbol file".into()),
+        };
+        res.map_err(|e| format!("{}: {e}", path.display()).into())
+    }
+
+    // FCEUX .nl symbols, `bank` is the 16K PRG bank the ROM addresses are in
+    pub fn add_nl(&mut self, text: &str, bank: Option<usize>) -> Result<()> {
+        for (line_num, line) in text.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            let Some(line) = line.strip_prefix('$') else {
+                continue;
+            };
+            let err = || format!("Line {}: expected $<addr>#<label>#<comment>", line_num + 1);
+            let mut fields = line.splitn(3, '#');
+            let addr = fields.next().ok_or_else(err)?;
+            let (addr, len) = match addr.split_once('/') {
+                Some((addr, len)) => (addr, usize::from_str_radix(len, 16).map_err(|_| err())?),
+                None => (addr, 1),
+            };
+            let addr = u16::from_str_radix(addr, 16).map_err(|_| err())?;
+            let name = fields.next().ok_or_else(err)?.trim();
+            let comment = fields.next().map(str::trim).filter(|c| !c.is_empty());
+            if name.is_empty() {
+                continue;
+            }
+            for i in 0..len.max(1) {
+                let addr = addr.wrapping_add(i as u16);
+                let symbol = Symbol {
+                    name: if i == 0 { name.to_string() } else { format!("{name}+{i}") },
+                    comment: (i == 0).then(|| comment.map(str::to_string)).flatten(),
+                };
+                let prg_offset = match bank {
+                    Some(bank) if addr >= 0x8000 => {
+                        Some(bank * NL_BANK_SIZE + (addr as usize % NL_BANK_SIZE))
+                    }
+                    _ => None,
+                };
+                self.add(addr, prg_offset, symbol);
+            }
+        }
+        Ok(())
+    }
+
+    // ca65 debug info, only the labels are used (not equates, those are mostly constants)
+    pub fn add_dbg(&mut self, text: &str) -> Result<()> {
+        // segment id -> (start address, offset in the output file)
+        let mut segments: HashMap<&str, (u16, Option<usize>)> = HashMap::new();
+        let mut labels = vec![];
+        for (line_num, line) in text.lines().enumerate() {
+            let Some((kind, attrs)) = line.split_once('\t') else {
+                continue;
+            };
+            let attrs = dbg_attributes(attrs);
+            let get = |key: &str| attrs.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+            let number = |key: &str| -> Result<Option<usize>> {
+                get(key)
+                    .map(|v| parse_dbg_number(v).ok_or(format!("Line {}: bad {key}", line_num + 1)))
+                    .transpose()
+                    .map_err(|e| e.into())
+            };
+            match kind {
+                "seg" => {
+                    let id = get("id").ok_or(format!("Line {}: segment without id", line_num + 1))?;
+                    let start = number("start")?.unwrap_or_default() as u16;
+                    segments.insert(id, (start, number("ooffs")?));
+                }
+                "sym" if get("type") == Some("lab") => {
+                    let (Some(name), Some(val)) = (get("name"), number("val")?) else {
+                        continue;
+                    };
+                    labels.push((name.trim_matches('"'), val as u16, get("seg")));
+                }
+                _ => (),
+            }
+        }
+        for (name, addr, seg) in labels {
+            let prg_offset = match seg.and_then(|id| segments.get(id)) {
+                Some((start, Some(ooffs))) if addr >= 0x8000 => {
+                    (ooffs + addr.wrapping_sub(*start) as usize).checked_sub(INES_HEADER_SIZE)
+                }
+                _ => None,
+            };
+            let symbol = Symbol {
+                name: name.to_string(),
+                comment: None,
+            };
+            self.add(addr, prg_offset, symbol);
+        }
+        Ok(())
+    }
+
+    // The first symbol for an address wins
+    pub fn add(&mut self, addr: u16, prg_offset: Option<usize>, symbol: Symbol) {
+        match prg_offset {
+            Some(offset) => self.prg.entry(offset).or_insert(symbol),
+            None => self.cpu.entry(addr).or_insert(symbol),
+        };
+    }
+
+    // Symbol for a CPU address, `prg_offset` is where it's mapped to in PRG ROM (if it is)
+    pub fn lookup(&self, addr: u16, prg_offset: Option<usize>) -> Option<&Symbol> {
+        prg_offset
+            .and_then(|offset| self.prg.get(&offset))
+            .or_else(|| self.cpu.get(&addr))
+    }
+}
+
+// game.nes.ram.nl -> None, game.nes.1F.nl -> Some(0x1F)
+fn nl_bank(file_name: &str) -> Option<usize> {
+    let stem = file_name.strip_suffix(".nl")?;
+    let (_, bank) = stem.rsplit_once('.')?;
+    usize::from_str_radix(bank, 16).ok()
+}
+
+// key=value pairs separated by commas, values can be quoted
+fn dbg_attributes(s: &str) -> Vec<(&str, &str)> {
+    let mut attrs = vec![];
+    let mut rest = s;
+    while let Some((key, after)) = rest.split_once('=') {
+        let end = match after.strip_prefix('"') {
+            Some(quoted) => quoted.find('"').map_or(after.len(), |i| i + 2),
+            None => after.find(',').unwrap_or(after.len()),
+        };
+        attrs.push((key.trim(), &after[..end]));
+        rest = after[end..].strip_prefix(',').unwrap_or_default();
+    }
+    attrs
+}
+
+fn parse_dbg_number(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod symbols_tests {
+    use super::{nl_bank, SymbolTable};
+
+    #[test]
+    fn load() {
+        let mut symbols = SymbolTable::default();
+        symbols
+            .add_nl("$0300/3#Buffer#Input buffer\n$0010##\r\n$C000#Reset#\n", Some(1))
+            .unwrap();
+        symbols
+            .add_dbg(concat!(
+                "version\tmajor=2,minor=0\n",
+                "seg\tid=0,name=\"ZEROPAGE\",start=0x000000,size=0x0010,addrsize=zeropage,type=rw\n",
+                "seg\tid=1,name=\"CODE\",start=0x008000,size=0x4000,addrsize=absolute,type=ro,oname=\"a,b.nes\",ooffs=16\n",
+                "sym\tid=0,name=\"nmi\",addrsize=absolute,scope=0,def=1,val=0x8010,seg=1,type=lab\n",
+                "sym\tid=1,name=\"frame\",addrsize=zeropage,size=1,scope=0,def=2,val=0x2,seg=0,type=lab\n",
+                "sym\tid=2,name=\"BUTTON_A\",addrsize=zeropage,scope=0,def=3,val=0x80,type=equ\n",
+            ))
+            .unwrap();
+
+        let name = |addr, offset| symbols.lookup(addr, offset).map(|s| s.name.as_str());
+        assert_eq!(name(0x0300, None), Some("Buffer"));
+        assert_eq!(symbols.lookup(0x0300, None).unwrap().comment.as_deref(), Some("Input buffer"));
+        assert_eq!(name(0x0302, None), Some("Buffer+2"));
+        assert_eq!(name(0x0303, None), None);
+        assert_eq!(name(0x0010, None), None);
+        // Only while bank 1 is mapped in
+        assert_eq!(name(0xC000, Some(0x4000)), Some("Reset"));
+        assert_eq!(name(0xC000, Some(0x0000)), None);
+        assert_eq!(name(0x8010, Some(0x0010)), Some("nmi"));
+        assert_eq!(name(0x0002, None), Some("frame"));
+        assert_eq!(name(0x0080, None), None);
+
+        assert!(symbols.add_nl("$XYZ#Bad#\n", None).is_err());
+        assert_eq!(nl_bank("game.nes.ram.nl"), None);
+        assert_eq!(nl_bank("game.nes.1F.nl"), Some(0x1F));
+    }
+}
@@ -0,0 +1,332 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::apu::ring::AudioRing;
+use crate::cpu::cpu::Cpu;
+use crate::debugger::debugger::Debugger;
+use crate::debugger::gdb::GdbServer;
+use crate::debugger::repl::{describe_stop, run_command};
+use crate::error::Result;
+use crate::graphics::info::CpuInfo;
+use crate::ppu::ppu::Frame;
+
+// NTSC frame rate
+pub const FRAME_RATE: f64 = 60.0988;
+// Furthest the audio output rate is stretched to keep the ring buffer half full
+// https://github.com/libretro/docs/blob/master/archive/ratecontrol.pdf
+const MAX_RATE_DELTA: f64 = 0.005;
+// Give up on catching up when this far behind (e.g. after being paused)
+const MAX_FRAMES_BEHIND: u32 = 4;
+// How often battery-backed RAM gets written out (if it changed)
+const SAVE_INTERVAL_FRAMES: u64 = 5 * 60;
+// How often a stopped debugger checks on the GDB client
+const GDB_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+pub enum EmuCommand {
+    Pause,
+    Resume,
+    Quit,
+    // Save states, read from/written to the given file
+    SaveState(PathBuf),
+    LoadState(PathBuf),
+    // Debugger REPL command line
+    Debug(String),
+}
+
+// Things the emulator thread reports back
+pub enum EmuEvent {
+    GdbConnected,
+    GdbDisconnected,
+}
+
+// Everything the emulator thread owns while it runs
+pub struct EmuContext {
+    pub cpu: Cpu,
+    // Audio goes out through `audio` at `sample_rate`
+    pub audio: AudioRing,
+    pub sample_rate: f64,
+    pub save: Option<SaveFile>,
+    pub debugger: Option<Debugger>,
+    pub gdb: Option<GdbServer>,
+    // Build the pattern table/nametable views for the debug window every frame
+    pub debug_views: bool,
+}
+
+// Battery save (.sav) file for the cartridge's non-volatile RAM
+pub struct SaveFile {
+    path: PathBuf,
+    // What the file currently holds
+    written: Option<Vec<u8>>,
+}
+
+impl SaveFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path, written: None }
+    }
+
+    // Loads the save into the cartridge, a missing file just means there's no save yet
+    pub fn load(&mut self, cpu: &mut Cpu) -> Result<()> {
+        match fs::read(&self.path) {
+            Ok(data) => {
+                cpu.load_nvram(&data);
+                self.written = Some(data);
+                Ok(())
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Writes the cartridge's RAM out if it changed since the last flush
+    pub fn flush(&mut self, cpu: &Cpu) -> Result<()> {
+        if let Some(ram) = cpu.nvram() {
+            if self.written.as_ref() != Some(&ram) {
+                fs::write(&self.path, &ram)?;
+                self.written = Some(ram);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Handle to the emulator running on its own thread
+pub struct EmuThread {
+    commands: Sender<EmuCommand>,
+    frames: Receiver<(Frame, Option<CpuInfo>)>,
+    events: Receiver<EmuEvent>,
+    // Box<dyn Error> isn't Send, errors come back as strings
+    handle: Option<JoinHandle<std::result::Result<(), String>>>,
+}
+
+impl EmuThread {
+    // Runs the context's CPU one frame at a time
+    pub fn spawn(ctx: EmuContext) -> Self {
+        let (commands, cmd_rcv) = channel();
+        let (frame_send, frames) = channel();
+        let (event_send, events) = channel();
+        let handle = thread::spawn(move || {
+            run(ctx, cmd_rcv, frame_send, event_send).map_err(|e| e.to_string())
+        });
+        Self {
+            commands,
+            frames,
+            events,
+            handle: Some(handle),
+        }
+    }
+
+    pub fn send(&self, cmd: EmuCommand) {
+        // If the thread is gone the error surfaces in join()
+        let _ = self.commands.send(cmd);
+    }
+
+    // For sending commands from other threads
+    pub fn command_sender(&self) -> Sender<EmuCommand> {
+        self.commands.clone()
+    }
+
+    // Latest frame produced since the last call, waits at most `timeout` for one
+    pub fn latest_frame(&self, timeout: Duration) -> Option<(Frame, Option<CpuInfo>)> {
+        let mut latest = self.frames.recv_timeout(timeout).ok()?;
+        while let Ok(frame) = self.frames.try_recv() {
+            latest = frame;
+        }
+        Some(latest)
+    }
+
+    // Next event the thread reported, if any
+    pub fn poll_event(&self) -> Option<EmuEvent> {
+        self.events.try_recv().ok()
+    }
+
+    pub fn join(mut self) -> Result<()> {
+        self.send(EmuCommand::Quit);
+        match self.handle.take().map(|h| h.join()) {
+            Some(Ok(res)) => res.map_err(|e| e.into()),
+            Some(Err(_)) => Err("emulator thread panicked".into()),
+            None => Ok(()),
+        }
+    }
+}
+
+// Output rate which nudges the ring buffer's fill level back towards half full
+fn adjusted_rate(sample_rate: f64, fill: f64) -> f64 {
+    sample_rate * (1.0 + MAX_RATE_DELTA * (1.0 - 2.0 * fill.clamp(0.0, 1.0)))
+}
+
+fn run(
+    mut ctx: EmuContext,
+    commands: Receiver<EmuCommand>,
+    frames: Sender<(Frame, Option<CpuInfo>)>,
+    events: Sender<EmuEvent>,
+) -> Result<()> {
+    let res = run_frames(&mut ctx, &commands, &frames, &events);
+    // Battery RAM written since the last flush is kept even if the emulator failed
+    let saved = match ctx.save.as_mut() {
+        Some(s) => s.flush(&ctx.cpu),
+        None => Ok(()),
+    };
+    res.and(saved)
+}
+
+fn run_frames(
+    ctx: &mut EmuContext,
+    commands: &Receiver<EmuCommand>,
+    frames: &Sender<(Frame, Option<CpuInfo>)>,
+    events: &Sender<EmuEvent>,
+) -> Result<()> {
+    let EmuContext {
+        cpu,
+        audio,
+        sample_rate,
+        save,
+        debugger,
+        gdb,
+        debug_views,
+    } = ctx;
+    let frame_time = Duration::from_secs_f64(1.0 / FRAME_RATE);
+    let mut next_frame = Instant::now();
+    let mut paused = false;
+    let mut frame_count: u64 = 0;
+
+    loop {
+        if let (Some(server), Some(dbg)) = (gdb.as_mut(), debugger.as_mut()) {
+            let was_stopped = dbg.is_stopped();
+            let was_connected = server.is_connected();
+            server.poll(dbg, cpu)?;
+            if was_connected != server.is_connected() {
+                let event = if was_connected {
+                    EmuEvent::GdbDisconnected
+                } else {
+                    EmuEvent::GdbConnected
+                };
+                // Nobody listening is fine, it's only a status message
+                let _ = events.send(event);
+            }
+            if was_stopped && !dbg.is_stopped() {
+                next_frame = Instant::now();
+            }
+        }
+        let stopped = debugger.as_ref().is_some_and(|d| d.is_stopped());
+        let cmd = if stopped && gdb.is_some() {
+            // Can't block, the GDB client may resume the game
+            match commands.recv_timeout(GDB_POLL_INTERVAL) {
+                Ok(cmd) => Some(cmd),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => Some(EmuCommand::Quit),
+            }
+        } else if paused || stopped {
+            commands.recv().ok().or(Some(EmuCommand::Quit))
+        } else {
+            match commands.try_recv() {
+                Ok(cmd) => Some(cmd),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => Some(EmuCommand::Quit),
+            }
+        };
+        match cmd {
+            Some(EmuCommand::Pause) => {
+                paused = true;
+                continue;
+            }
+            Some(EmuCommand::Resume) => {
+                paused = false;
+                next_frame = Instant::now();
+            }
+            Some(EmuCommand::Quit) => break,
+            Some(EmuCommand::SaveState(path)) => {
+                match fs::write(&path, cpu.save_state()) {
+                    Ok(()) => println!("Saved state to {}", path.display()),
+                    Err(e) => eprintln!("Failed to save state to {}: {e}", path.display()),
+                }
+            }
+            Some(EmuCommand::LoadState(path)) => {
+                let res = fs::read(&path).map_err(|e| e.into()).and_then(|data| cpu.load_state(&data));
+                match res {
+                    Ok(()) => println!("Loaded state from {}", path.display()),
+                    Err(e) => eprintln!("Failed to load state from {}: {e}", path.display()),
+                }
+            }
+            Some(EmuCommand::Debug(line)) => {
+                if let Some(dbg) = debugger.as_mut() {
+                    match run_command(dbg, cpu, &line) {
+                        Ok(out) if out.is_empty() => (),
+                        Ok(out) => println!("{}", out.trim_end()),
+                        Err(e) => println!("{e}"),
+                    }
+                    if stopped && !dbg.is_stopped() {
+                        next_frame = Instant::now();
+                    }
+                }
+            }
+            None => (),
+        }
+        if paused {
+            continue;
+        }
+
+        let frame = match debugger.as_mut() {
+            Some(dbg) => {
+                let (frame, stop) = dbg.run_frame(cpu)?;
+                if let Some(reason) = stop {
+                    println!("{}", describe_stop(dbg, cpu, &reason));
+                    if let Some(server) = gdb.as_mut() {
+                        server.notify_stop(&reason)?;
+                    }
+                }
+                match frame {
+                    Some(frame) => frame,
+                    // Stopped partway through the frame
+                    None => continue,
+                }
+            }
+            None => cpu.next_frame()?,
+        };
+        cpu.flush_audio(audio);
+        cpu.set_audio_rate(adjusted_rate(*sample_rate, audio.fill()));
+        let info = match *debug_views {
+            true => Some(CpuInfo::new(cpu)?),
+            false => None,
+        };
+        if frames.send((frame, info)).is_err() {
+            // Nobody is listening anymore
+            break;
+        }
+
+        frame_count += 1;
+        if frame_count.is_multiple_of(SAVE_INTERVAL_FRAMES) {
+            if let Some(Err(e)) = save.as_mut().map(|s| s.flush(cpu)) {
+                eprintln!("Failed to write save file: {e}");
+            }
+        }
+
+        next_frame += frame_time;
+        let now = Instant::now();
+        if next_frame > now {
+            thread::sleep(next_frame - now);
+        } else if now - next_frame > frame_time * MAX_FRAMES_BEHIND {
+            next_frame = now;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod emu_tests {
+    use super::{adjusted_rate, MAX_RATE_DELTA};
+
+    #[test]
+    fn rate_control() {
+        let rate = 48_000.0;
+        assert_eq!(adjusted_rate(rate, 0.5), rate);
+        // Running dry -> produce more samples, filling up -> produce fewer
+        assert_eq!(adjusted_rate(rate, 0.0), rate * (1.0 + MAX_RATE_DELTA));
+        assert_eq!(adjusted_rate(rate, 1.0), rate * (1.0 - MAX_RATE_DELTA));
+        assert!(adjusted_rate(rate, 0.25) > rate && adjusted_rate(rate, 0.75) < rate);
+    }
+}
@@ -1,3 +1,8 @@
+pub mod colors;
+pub mod info;
+#[cfg(feature = "sdl")]
 pub mod debug;
+#[cfg(feature = "sdl")]
 pub mod simple;
+#[cfg(feature = "sdl")]
 pub mod graphics;
\ No newline at end of file
@@ -0,0 +1,80 @@
+use std::{fs, path::Path};
+
+use crate::error::Result;
+
+const NUM_COLORS: usize = 0x40;
+// Every palette index with every combination of the 3 emphasis bits
+const NUM_EMPHASIS_COLORS: usize = NUM_COLORS * 8;
+// How much emphasis dims the other two channels (approximation of the NTSC PPU)
+const EMPHASIS_ATTENUATION: f32 = 0.816;
+
+static DEFAULT_PALETTE: &[u8] = include_bytes!("../../palette/ntsc.pal");
+
+// Maps the pixels of a ppu::ppu::Frame to RGB
+pub struct ColorMap {
+    colors: Box<[[u8; 3]; NUM_EMPHASIS_COLORS]>,
+}
+
+impl ColorMap {
+    pub fn rgb(&self, pixel: u16) -> [u8; 3] {
+        self.colors[pixel as usize % NUM_EMPHASIS_COLORS]
+    }
+}
+
+impl Default for ColorMap {
+    fn default() -> Self {
+        load_color_map(None).unwrap()
+    }
+}
+
+// .pal files either have the 64 base colors, the emphasis variants are derived from those,
+// or all 512 colors
+pub fn load_color_map(pal_file: Option<&str>) -> Result<ColorMap> {
+    let bytes = match pal_file {
+        Some(f) => fs::read(Path::new(f))?,
+        None => DEFAULT_PALETTE.to_vec()
+    };
+
+    let num_colors = bytes.len() / 3;
+    if bytes.len() != NUM_COLORS * 3 && bytes.len() != NUM_EMPHASIS_COLORS * 3 {
+        return Err("Invalid .pal file".into());
+    }
+
+    let mut colors = Box::new([[0; 3]; NUM_EMPHASIS_COLORS]);
+    for (pixel, color) in colors.iter_mut().enumerate() {
+        let rgb = &bytes[(pixel % num_colors) * 3..][..3];
+        *color = [rgb[0], rgb[1], rgb[2]];
+        if num_colors == NUM_COLORS {
+            // Emphasis bits are red, green, blue and each one dims the other channels,
+            // with all three set everything is dimmed
+            let emphasis = pixel / NUM_COLORS;
+            for (channel, c) in color.iter_mut().enumerate() {
+                if emphasis != 0 && (emphasis & (1 << channel) == 0 || emphasis == 0b111) {
+                    *c = (*c as f32 * EMPHASIS_ATTENUATION) as u8;
+                }
+            }
+        }
+    }
+    Ok(ColorMap { colors })
+}
+
+#[cfg(test)]
+mod colors_tests {
+    use super::{load_color_map, DEFAULT_PALETTE};
+
+    #[test]
+    fn emphasis() {
+        let colors = load_color_map(None).unwrap();
+        assert_eq!(colors.rgb(0x20), [DEFAULT_PALETTE[0x60], DEFAULT_PALETTE[0x61], DEFAULT_PALETTE[0x62]]);
+
+        // Red emphasis leaves red alone and dims green and blue
+        let [r, g, b] = colors.rgb(0x20);
+        let [er, eg, eb] = colors.rgb(0x20 | (1 << 6));
+        assert_eq!(er, r);
+        assert!(eg < g && eb < b);
+
+        // All three dim everything
+        let [er, eg, eb] = colors.rgb(0x20 | (0b111 << 6));
+        assert!(er < r && eg < g && eb < b);
+    }
+}
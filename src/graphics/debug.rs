@@ -2,23 +2,29 @@ use std::collections::VecDeque;
 use std::time::Instant;
 
 use crate::error::Result;
-use crate::ppu::ppu::Frame;
+use crate::ppu::ppu::{Frame, NametableView};
 use sdl2::keyboard::Keycode;
-use sdl2::pixels::Color;
+use sdl2::pixels::{Color, PixelFormatEnum};
 use sdl2::rect::{Point, Rect};
 use sdl2::render::{Canvas, Texture};
 use sdl2::surface::Surface;
 use sdl2::video::Window;
 use sdl2::VideoSubsystem;
 
-use super::graphics::{CpuInfo, NesGraphics};
+use super::colors::ColorMap;
+use super::graphics::{sdl_color, NesGraphics};
+use super::info::CpuInfo;
 
 pub struct DebugGraphics {
     canvas: Canvas<Window>,
+    colors: ColorMap,
     font_texture: Texture,
     character_rects: [Rect; 256],
     show_nametable_boundaries: bool,
     show_oam: bool,
+    // The four nametables instead of the screen
+    show_nametables: bool,
+    nametable_texture: Texture,
     iscale: u32,
     frame_times: VecDeque<Instant>,
     curr_palette: u8,
@@ -28,24 +34,29 @@ pub struct DebugGraphics {
 const FPS_SAMPLE_SIZE: usize = 60;
 
 impl NesGraphics for DebugGraphics {
-    fn render_frame(&mut self, frame: Frame, info: CpuInfo) -> Result<()> {
+    fn render_frame(&mut self, frame: Frame, info: Option<CpuInfo>) -> Result<()> {
+        let info = info.ok_or("The debug window needs the emulator's debug views")?;
         self.canvas.set_draw_color(Color::BLACK);
         self.canvas.clear();
 
-        // Draw NES graphics
-        for r in 0..frame.len() {
-            for c in 0..frame[r].len() {
-                self.canvas.set_draw_color(frame[r][c]);
-                self.canvas.fill_rect(Rect::new(
-                    (c * (self.iscale as usize)).try_into().unwrap(),
-                    (r * (self.iscale as usize)).try_into().unwrap(),
-                    self.iscale,
-                    self.iscale,
-                ))?;
+        if self.show_nametables {
+            self.draw_nametables(&info.nametables, info.scroll)?;
+        } else {
+            // Draw NES graphics
+            for r in 0..frame.len() {
+                for c in 0..frame[r].len() {
+                    self.canvas.set_draw_color(sdl_color(&self.colors, frame[r][c]));
+                    self.canvas.fill_rect(Rect::new(
+                        (c * (self.iscale as usize)).try_into().unwrap(),
+                        (r * (self.iscale as usize)).try_into().unwrap(),
+                        self.iscale,
+                        self.iscale,
+                    ))?;
+                }
             }
         }
 
-        if self.show_nametable_boundaries {
+        if self.show_nametable_boundaries && !self.show_nametables {
             self.canvas.set_draw_color(Color::RED);
             let s = self.iscale as i32;
             let w = Self::NES_WIDTH as i32;
@@ -161,6 +172,7 @@ impl NesGraphics for DebugGraphics {
                     P => self.curr_palette = (self.curr_palette + 1) % 8,
                     O => self.show_oam = !self.show_oam,
                     N => self.show_nametable_boundaries = !self.show_nametable_boundaries,
+                    M => self.show_nametables = !self.show_nametables,
                     _ => (),
                 },
                 _ => (),
@@ -179,7 +191,7 @@ impl DebugGraphics {
     const SPRITES_PER_ROW: i32 = 16;
     const SPRITES_PER_COL: i32 = 16;
 
-    pub fn new(iscale: u32, video: VideoSubsystem) -> Self {
+    pub fn new(iscale: u32, colors: ColorMap, video: VideoSubsystem) -> Self {
         let window = video
             .window(
                 Self::TITLE,
@@ -199,6 +211,9 @@ impl DebugGraphics {
         let font_texture = texture_creator
             .create_texture_from_surface(font_surface)
             .unwrap();
+        let nametable_texture = texture_creator
+            .create_texture_streaming(PixelFormatEnum::RGB24, 512, 480)
+            .unwrap();
 
         let mut character_rects =
             [Rect::new(0, 0, Self::CHAR_WIDTH as u32, Self::CHAR_HEIGHT as u32); 256];
@@ -213,11 +228,14 @@ impl DebugGraphics {
 
         Self {
             canvas,
+            colors,
             font_texture,
             character_rects,
             curr_palette: 0,
             show_nametable_boundaries: false,
             show_oam: true,
+            show_nametables: false,
+            nametable_texture,
             frame_times: VecDeque::new(),
             iscale,
         }
@@ -234,14 +252,15 @@ impl DebugGraphics {
     fn draw_pattern_table(
         &mut self,
         pixels: &[[u8; 128]; 128],
-        palette: &Vec<Color>,
+        palette: &[u8],
         scale: u32,
         x: i32,
         y: i32,
     ) -> Result<()> {
         for r in 0..pixels.len() {
             for c in 0..pixels[r].len() {
-                self.canvas.set_draw_color(palette[pixels[r][c] as usize]);
+                let color = palette[pixels[r][c] as usize];
+                self.canvas.set_draw_color(sdl_color(&self.colors, color as u16));
                 self.canvas.fill_rect(Rect::new(
                     x + (c * scale as usize) as i32,
                     y + (r * scale as usize) as i32,
@@ -253,7 +272,46 @@ impl DebugGraphics {
         Ok(())
     }
 
-    fn draw_palettes(&mut self, palettes: &Vec<Vec<Color>>) -> Result<()> {
+    // All four nametables at half scale where the screen goes, with the part the scroll
+    // registers point at outlined
+    fn draw_nametables(&mut self, nametables: &NametableView, scroll: (u16, u16)) -> Result<()> {
+        let colors = &self.colors;
+        self.nametable_texture.with_lock(None, |pixels, pitch| {
+            for (r, row) in nametables.iter().enumerate() {
+                for (c, pixel) in row.iter().enumerate() {
+                    let i = r * pitch + c * 3;
+                    pixels[i..i + 3].copy_from_slice(&colors.rgb(*pixel));
+                }
+            }
+        })?;
+        let s = self.iscale as f32 / 2.0;
+        let scaled = |x: i32| (x as f32 * s) as i32;
+        let view = Rect::new(0, 0, scaled(512) as u32, scaled(480) as u32);
+        self.canvas.copy(&self.nametable_texture, None, view)?;
+
+        self.canvas.set_draw_color(Color::GRAY);
+        self.canvas
+            .draw_line(Point::new(scaled(256), 0), Point::new(scaled(256), scaled(480)))?;
+        self.canvas
+            .draw_line(Point::new(0, scaled(240)), Point::new(scaled(512), scaled(240)))?;
+
+        // The viewport wraps around to the other side
+        self.canvas.set_clip_rect(view);
+        self.canvas.set_draw_color(Color::RED);
+        let (x, y) = (scroll.0 as i32, scroll.1 as i32);
+        for (dx, dy) in [(0, 0), (-512, 0), (0, -480), (-512, -480)] {
+            self.canvas.draw_rect(Rect::new(
+                scaled(x + dx),
+                scaled(y + dy),
+                scaled(256) as u32,
+                scaled(240) as u32,
+            ))?;
+        }
+        self.canvas.set_clip_rect(None);
+        Ok(())
+    }
+
+    fn draw_palettes(&mut self, palettes: &[Vec<u8>]) -> Result<()> {
         const COLOR_HEIGHT: u32 = 8;
         const COLOR_WIDTH: u32 = 16;
         const BORDER_SCALE: u32 = 2;
@@ -273,8 +331,8 @@ impl DebugGraphics {
                         .draw_rect(Rect::new(x, y, palettes_width, palette_height))?;
                 }
                 for color in 0..4 {
-                    self.canvas
-                        .set_draw_color(palettes[palette_num as usize][color]);
+                    let c = palettes[palette_num as usize][color] as u16;
+                    self.canvas.set_draw_color(sdl_color(&self.colors, c));
                     // self.canvas.set_draw_color(Color::WHITE);
                     self.canvas.fill_rect(Rect::new(
                         x + BORDER_SCALE as i32 + (color as i32 * COLOR_WIDTH as i32),
@@ -1,26 +1,22 @@
-use crate::cpu::isa::Instr;
-use crate::cpu::reg::Registers;
 use crate::error::Result;
-use crate::ppu::ppu::{PatternTable, OamSprite, Frame};
+use crate::ppu::ppu::Frame;
 use sdl2::VideoSubsystem;
 use sdl2::event::Event;
 use sdl2::pixels::Color;
 
+use super::colors::ColorMap;
 use super::debug::DebugGraphics;
+use super::info::CpuInfo;
 use super::simple::SimpleGraphics;
 
-pub struct CpuInfo {
-    pub sprites: Vec<OamSprite>,
-    pub palettes: Vec<Vec<Color>>,
-    // These pattern tables have to have a hard-wired palette, we could also just store the index
-    // and have the graphics window index into the palettes
-    pub pattern_tables: (PatternTable, PatternTable),
-    pub instructions: Vec<(u16, Instr)>,
-    pub registers: Registers
+pub fn sdl_color(colors: &ColorMap, pixel: u16) -> Color {
+    let [r, g, b] = colors.rgb(pixel);
+    Color::RGB(r, g, b)
 }
 
 pub trait NesGraphics {
-    fn render_frame(&mut self, frame: Frame, info: CpuInfo) -> Result<()>;
+    // `info` is only there when the emulator was asked for debug views
+    fn render_frame(&mut self, frame: Frame, info: Option<CpuInfo>) -> Result<()>;
     fn process_events(&mut self, events: &Vec<Event>);
 }
 
@@ -28,17 +24,22 @@ pub trait NesGraphics {
 pub struct GraphicsBuilder {
     iscale: u32,
     debug: bool,
+    colors: ColorMap,
     video: VideoSubsystem
 }
 
 impl GraphicsBuilder {
     pub fn new(video: VideoSubsystem) -> Self {
-        GraphicsBuilder { iscale: 3, debug: false, video }
+        GraphicsBuilder { iscale: 3, debug: false, colors: ColorMap::default(), video }
     }
     pub fn debug(mut self, debug: bool) -> Self {
         self.debug = debug;
         self
     }
+    pub fn colors(mut self, colors: ColorMap) -> Self {
+        self.colors = colors;
+        self
+    }
     pub fn scale(mut self, s: Option<u32>) -> Self {
         if let Some(s) = s {
             self.iscale = s;
@@ -48,8 +49,8 @@ impl GraphicsBuilder {
 
     pub fn build(self) -> Box<dyn NesGraphics> {
         match self.debug {
-            true => Box::new(DebugGraphics::new(self.iscale, self.video)),
-            false => Box::new(SimpleGraphics::new(self.iscale, self.video))
+            true => Box::new(DebugGraphics::new(self.iscale, self.colors, self.video)),
+            false => Box::new(SimpleGraphics::new(self.iscale, self.colors, self.video))
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,44 @@
+use crate::cpu::cpu::Cpu;
+use crate::error::Result;
+use crate::cpu::isa::Instr;
+use crate::cpu::reg::Registers;
+use crate::ppu::ppu::{NametableView, OamSprite, PatternTable};
+
+// Snapshot of the machine for the debug view
+pub struct CpuInfo {
+    pub sprites: Vec<OamSprite>,
+    // System palette indices, map them with a ColorMap
+    pub palettes: Vec<Vec<u8>>,
+    // These pattern tables have to have a hard-wired palette, we could also just store the index
+    // and have the graphics window index into the palettes
+    pub pattern_tables: (PatternTable, PatternTable),
+    pub nametables: NametableView,
+    // Top left corner of the screen in the nametables
+    pub scroll: (u16, u16),
+    pub instructions: Vec<(u16, Instr)>,
+    pub registers: Registers
+}
+
+impl CpuInfo {
+    pub fn new(cpu: &Cpu) -> Result<Self> {
+        const NUM_INSTR: u16 = 10;
+        let mut instr = vec![];
+        for off in 0..NUM_INSTR {
+            match cpu.peek_next_instr(off) {
+                Ok((addr, i)) => instr.push((addr, i)),
+                Err(_) => {
+                    break;
+                }
+            }
+        }
+        Ok(CpuInfo {
+            sprites: cpu.debug_oam(),
+            palettes: cpu.debug_palettes(),
+            pattern_tables: cpu.debug_pattern_tables()?,
+            nametables: cpu.debug_nametables()?,
+            scroll: cpu.debug_scroll(),
+            instructions: instr,
+            registers: cpu.reg.clone(),
+        })
+    }
+}
@@ -4,18 +4,21 @@ use sdl2::render::Canvas;
 use sdl2::video::Window;
 use sdl2::VideoSubsystem;
 
-use super::graphics::{CpuInfo, NesGraphics};
+use super::colors::ColorMap;
+use super::graphics::{sdl_color, NesGraphics};
+use super::info::CpuInfo;
 
 pub struct SimpleGraphics {
     canvas: Canvas<Window>,
+    colors: ColorMap,
     iscale: u32,
 }
 
 impl NesGraphics for SimpleGraphics {
-    fn render_frame(&mut self, frame: Frame, _info: CpuInfo) -> Result<()> {
+    fn render_frame(&mut self, frame: Frame, _info: Option<CpuInfo>) -> Result<()> {
         for r in 0..frame.len() {
             for c in 0..frame[r].len() {
-                self.canvas.set_draw_color(frame[r][c]);
+                self.canvas.set_draw_color(sdl_color(&self.colors, frame[r][c]));
                 self.canvas.fill_rect(Rect::new(
                     (c * (self.iscale as usize)).try_into().unwrap(),
                     (r * (self.iscale as usize)).try_into().unwrap(),
@@ -38,7 +41,7 @@ impl SimpleGraphics {
     const HEIGHT: u32 = 240;
     const TITLE: &'static str = "nes-emu";
 
-    pub fn new(iscale: u32, video: VideoSubsystem) -> Self {
+    pub fn new(iscale: u32, colors: ColorMap, video: VideoSubsystem) -> Self {
         let canvas = video
             .window(Self::TITLE, Self::WIDTH * iscale, Self::HEIGHT * iscale)
             .position_centered()
@@ -48,6 +51,6 @@ impl SimpleGraphics {
             .build()
             .unwrap();
 
-        Self { canvas, iscale }
+        Self { canvas, colors, iscale }
     }
 }
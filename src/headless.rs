@@ -0,0 +1,231 @@
+// Runs a ROM without a window or audio device, for batch runs and CI smoke tests.
+//
+//   nes-headless rom.nes --frames 600 --input script.txt --screenshot out.ppm --ram-dump ram.bin
+//   nes-headless rom.nes --frames 10 --trace trace.log --trace-format banked --trace-start frame:5
+//   nes-headless rom.nes --frames 3600 --input script.txt --cdl rom.cdl
+//
+// Input scripts have one "<frame> <buttons>" entry per line, the buttons are held from that
+// frame on until the next entry. Buttons are separated by '+', '-' releases everything:
+//
+//   # Press start for a few frames, then hold right and A
+//   60 START
+//   65 -
+//   120 RIGHT+A
+
+use clap::Parser;
+use nes_emu::cart::builder::build_cartridge;
+use nes_emu::controller::{make_controller, Inputs};
+use nes_emu::cpu::cpu::Cpu;
+use nes_emu::cpu::trace::{TraceCondition, TraceFormat, TraceLogger};
+use nes_emu::graphics::colors::{load_color_map, ColorMap};
+use nes_emu::ines::parse::INesFile;
+use nes_emu::mem::cdl::CodeDataLog;
+use nes_emu::ppu::ppu::Frame;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::process::exit;
+use std::sync::{Arc, Mutex};
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+#[derive(Parser)]
+struct CliArgs {
+    rom_path: PathBuf,
+    /// Number of frames to run
+    #[arg(short, long, default_value_t = 60)]
+    frames: u64,
+    /// Controller 1 input script
+    #[arg(short, long)]
+    input: Option<PathBuf>,
+    /// Write the last frame as a PPM image
+    #[arg(short, long)]
+    screenshot: Option<PathBuf>,
+    /// .pal file used for the screenshot
+    #[arg(long)]
+    palette: Option<String>,
+    /// Write the 2K of CPU RAM at the end of the run
+    #[arg(short, long)]
+    ram_dump: Option<PathBuf>,
+    /// Exit with an error unless the last frame has this hash
+    #[arg(long)]
+    expect_hash: Option<String>,
+    /// Log every instruction run to this file
+    #[arg(long)]
+    trace: Option<PathBuf>,
+    /// Trace log layout: nestest, counters or banked
+    #[arg(long, default_value = "counters")]
+    trace_format: TraceFormat,
+    /// Start tracing at an address ($C000) or frame (frame:120)
+    #[arg(long)]
+    trace_start: Option<TraceCondition>,
+    /// Stop tracing at an address or frame
+    #[arg(long)]
+    trace_stop: Option<TraceCondition>,
+    /// Code/data log (FCEUX .cdl) to add to, written at the end of the run
+    #[arg(long)]
+    cdl: Option<PathBuf>,
+}
+
+// (frame, buttons held from that frame on), sorted by frame
+fn parse_input_script(script: &str) -> Result<Vec<(u64, Inputs)>> {
+    let mut entries = vec![];
+    for (line_num, line) in script.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let err = |msg: &str| format!("Input script line {}: {msg}", line_num + 1);
+        let (frame, buttons) = line.split_once(char::is_whitespace).ok_or_else(|| err("expected <frame> <buttons>"))?;
+        let frame: u64 = frame.parse().map_err(|_| err("invalid frame number"))?;
+
+        let mut inputs = Inputs::empty();
+        if buttons.trim() != "-" {
+            for button in buttons.split('+') {
+                inputs |= match button.trim().to_ascii_uppercase().as_str() {
+                    "A" => Inputs::A,
+                    "B" => Inputs::B,
+                    "SELECT" => Inputs::SELECT,
+                    "START" => Inputs::START,
+                    "UP" => Inputs::UP,
+                    "DOWN" => Inputs::DOWN,
+                    "LEFT" => Inputs::LEFT,
+                    "RIGHT" => Inputs::RIGHT,
+                    b => return Err(err(&format!("unknown button {b}")).into()),
+                };
+            }
+        }
+        entries.push((frame, inputs));
+    }
+    entries.sort_by_key(|(frame, _)| *frame);
+    Ok(entries)
+}
+
+// 64-bit FNV-1a of the frame's pixels, so it doesn't depend on the palette
+fn frame_hash(frame: &Frame) -> u64 {
+    frame.iter().flatten().flat_map(|p| p.to_le_bytes()).fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn write_ppm(path: &PathBuf, frame: &Frame, colors: &ColorMap) -> Result<()> {
+    let mut ppm = format!("P6\n{} {}\n255\n", frame[0].len(), frame.len()).into_bytes();
+    ppm.extend(frame.iter().flatten().flat_map(|p| colors.rgb(*p)));
+    fs::write(path, ppm)?;
+    Ok(())
+}
+
+fn run(args: &CliArgs) -> Result<u64> {
+    let rom = fs::read(&args.rom_path)?;
+    let ines_rom = INesFile::try_from(&rom)?;
+    let cart = build_cartridge(&ines_rom)?;
+
+    let script = match &args.input {
+        Some(path) => parse_input_script(&fs::read_to_string(path)?)?,
+        None => vec![],
+    };
+    let mut script = script.into_iter().peekable();
+
+    let controller = make_controller();
+    let mut cpu = Cpu::new(cart, 44100.0, Some(controller.clone()), None);
+    cpu.reset()?;
+    if let Some(path) = &args.trace {
+        let tracer = TraceLogger::create(path, args.trace_format)?
+            .start_at(args.trace_start)
+            .stop_at(args.trace_stop);
+        cpu.set_tracer(Some(tracer));
+    }
+    let cdl = match &args.cdl {
+        Some(path) => {
+            let log = CodeDataLog::load(path, ines_rom.prg_rom.len(), ines_rom.chr_rom.len())?;
+            let log = Arc::new(Mutex::new(log));
+            cpu.set_cdl(Some(log.clone()));
+            Some((path, log))
+        }
+        None => None,
+    };
+
+    let mut last_frame = None;
+    let mut samples: Vec<f32> = vec![];
+    for frame_num in 0..args.frames {
+        while let Some((_, inputs)) = script.next_if(|(frame, _)| *frame <= frame_num) {
+            let mut c = controller.lock().unwrap();
+            c.clear();
+            c.input(inputs);
+        }
+        last_frame = Some(cpu.next_frame()?);
+        // Nobody is listening
+        cpu.flush_audio(&mut samples);
+        samples.clear();
+    }
+    let frame = last_frame.ok_or("No frames were run")?;
+
+    if let Some(path) = &args.screenshot {
+        write_ppm(path, &frame, &load_color_map(args.palette.as_deref())?)?;
+    }
+    if let Some(path) = &args.ram_dump {
+        let ram = (0..0x800).map(|addr| cpu.peek(addr)).collect::<Result<Vec<u8>>>()?;
+        fs::write(path, ram)?;
+    }
+    if let Some((path, log)) = cdl {
+        log.lock().unwrap().save(path)?;
+    }
+    Ok(frame_hash(&frame))
+}
+
+fn main() {
+    let args = CliArgs::parse();
+    match run(&args) {
+        Ok(hash) => {
+            let hash = format!("{hash:016x}");
+            println!("{hash}");
+            if let Some(expected) = &args.expect_hash {
+                if !expected.eq_ignore_ascii_case(&hash) {
+                    eprintln!("Frame hash mismatch, expected {expected}");
+                    exit(1);
+                }
+            }
+        }
+        Err(e) => {
+            eprintln!("Error: {e}");
+            exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod headless_tests {
+    use super::{frame_hash, parse_input_script};
+    use nes_emu::controller::Inputs;
+
+    #[test]
+    fn input_script() {
+        let script = "
+            # comment
+            120 right+A
+            60 START   # trailing comment
+            65 -
+        ";
+        let entries = parse_input_script(script).unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                (60, Inputs::START),
+                (65, Inputs::empty()),
+                (120, Inputs::RIGHT | Inputs::A),
+            ]
+        );
+
+        assert!(parse_input_script("10 TURBO").is_err());
+        assert!(parse_input_script("START").is_err());
+        assert!(parse_input_script("x START").is_err());
+    }
+
+    #[test]
+    fn hash() {
+        let mut frame = Box::new([[0; 256]; 240]);
+        let black = frame_hash(&frame);
+        frame[100][100] = 1;
+        assert_ne!(frame_hash(&frame), black);
+    }
+}
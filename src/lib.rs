@@ -1,8 +1,13 @@
 pub mod mem;
 pub mod error;
 pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod apu;
 pub mod ppu;
 pub mod cart;
 pub mod ines;
 pub mod controller;
-pub mod graphics;
\ No newline at end of file
+pub mod graphics;
+pub mod emu;
+pub mod state;
\ No newline at end of file
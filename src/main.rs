@@ -1,30 +1,43 @@
+mod apu;
 mod cart;
 mod controller;
 mod cpu;
+mod debugger;
+mod disasm;
+mod emu;
 mod error;
 mod graphics;
 pub mod ines;
 mod mem;
 mod ppu;
+mod state;
 
-use graphics::graphics::{NesGraphics, CpuInfo};
 use ines::parse::INesFile;
-use ppu::ppu::Frame;
-use sdl2::audio::{AudioSpecDesired, AudioCallback, AudioSpec};
+use sdl2::audio::{AudioSpecDesired, AudioCallback};
 
+use crate::apu::ring::AudioRing;
 use crate::cart::builder::build_cartridge;
 use crate::controller::make_controller;
+use crate::graphics::colors::load_color_map;
 use crate::graphics::graphics::GraphicsBuilder;
 use cpu::cpu::Cpu;
-use std::sync::mpsc::{channel, Sender, TryRecvError};
-use std::{error::Error, fs, path::Path};
+use cpu::trace::{TraceCondition, TraceFormat, TraceLogger};
+use mem::cdl::CodeDataLog;
+use debugger::debugger::Debugger;
+use debugger::gdb::GdbServer;
+use disasm::symbols::SymbolTable;
+use emu::{EmuCommand, EmuContext, EmuEvent, EmuThread, SaveFile};
+use std::{error::Error, fs, io, path::{Path, PathBuf}, sync::{Arc, Mutex}, thread};
 
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
-use std::time::Instant;
+use std::time::Duration;
 
 use clap::Parser;
 
+// Latency of the audio ring buffer
+const AUDIO_BUFFER_MS: usize = 100;
+
 #[derive(Parser)]
 struct CliArgs {
     rom_path: String,
@@ -32,6 +45,49 @@ struct CliArgs {
     scale: Option<u32>,
     #[arg(short, long)]
     debug: bool,
+    /// .pal file with the system palette (64 or 512 colors)
+    #[arg(long)]
+    palette: Option<String>,
+    /// Directory for battery saves (defaults to the ROM's directory)
+    #[arg(long)]
+    save_dir: Option<PathBuf>,
+    /// Listen for a GDB remote debugger on this port (localhost only)
+    #[arg(long)]
+    gdb: Option<u16>,
+    /// Log every instruction run to this file
+    #[arg(long)]
+    trace: Option<PathBuf>,
+    /// Trace log layout: nestest, counters or banked
+    #[arg(long, default_value = "counters")]
+    trace_format: TraceFormat,
+    /// Start tracing at an address ($C000) or frame (frame:120)
+    #[arg(long)]
+    trace_start: Option<TraceCondition>,
+    /// Stop tracing at an address or frame
+    #[arg(long)]
+    trace_stop: Option<TraceCondition>,
+    /// Code/data log (FCEUX .cdl) to add to, written on exit
+    #[arg(long)]
+    cdl: Option<PathBuf>,
+}
+
+// <rom name>.<extension>, next to the ROM unless a save directory was given
+fn save_path(rom_path: &Path, save_dir: Option<&Path>, extension: &str) -> PathBuf {
+    let file_name = rom_path.with_extension(extension);
+    let file_name = file_name.file_name().unwrap_or_default();
+    match save_dir.or(rom_path.parent()) {
+        Some(dir) => dir.join(file_name),
+        None => PathBuf::from(file_name),
+    }
+}
+
+// Number keys select the slot used by F5 (save state) and F7 (load state)
+fn map_state_slot(keycode: &Keycode) -> Option<u8> {
+    use Keycode::*;
+    [Num0, Num1, Num2, Num3, Num4, Num5, Num6, Num7, Num8, Num9]
+        .iter()
+        .position(|k| k == keycode)
+        .map(|slot| slot as u8)
 }
 
 fn map_inputs(keycode: &Keycode) -> Option<controller::Inputs> {
@@ -49,32 +105,25 @@ fn map_inputs(keycode: &Keycode) -> Option<controller::Inputs> {
     }
 }
 
-struct EmuMain {
-    cpu: Cpu,
-    frame_send: Sender<(Frame, CpuInfo)>,
-    audio_spec: AudioSpec,
-    // time_step: f64,
-    // global_time: f64,
+// Audio device callback, only ever drains what the emulator thread has produced
+struct AudioOut {
+    ring: AudioRing,
+    num_channels: usize,
+    mono: Vec<f32>,
 }
 
-impl AudioCallback for EmuMain {
+impl AudioCallback for AudioOut {
     type Channel = f32;
 
-    fn callback(&mut self, channels: &mut [Self::Channel]) {
-        loop {
-            let (frame, audio_sample) = self.cpu.system_tick(None).unwrap();
-            if let Some(frame) = frame {
-                self.frame_send.send((frame, self.cpu.get_info())).unwrap();
-            }
-            if let Some(audio_sample) = audio_sample {
-                channels[0] = audio_sample as f32;
-                break;
-            }
+    fn callback(&mut self, out: &mut [Self::Channel]) {
+        self.mono.resize(out.len() / self.num_channels, 0.0);
+        self.ring.pop_into(&mut self.mono);
+        // Same (mono) signal on every channel
+        for (frame, sample) in out.chunks_mut(self.num_channels).zip(self.mono.iter()) {
+            frame.fill(*sample);
         }
-        // self.global_time += self.time_step;
     }
 }
- 
 
 fn main() -> Result<(), Box<dyn Error>> {
     let args = CliArgs::parse();
@@ -99,6 +148,7 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let mut graphics = GraphicsBuilder::new(video_subsystem)
         .debug(args.debug)
+        .colors(load_color_map(args.palette.as_deref())?)
         .scale(args.scale)
         .build();
     
@@ -106,27 +156,92 @@ fn main() -> Result<(), Box<dyn Error>> {
     let desired = AudioSpecDesired {
         freq: Some(44100),
         channels: Some(1),
-        samples: Some(128)
+        samples: Some(512)
     };
 
-    let c = controller.clone();
-    let (send, rcv) = channel();
-
-    let device = audio.open_playback(None, &desired, move |spec| {
-        let mut cpu = Cpu::new(
-            cart,
-            spec.freq as f64 / spec.samples as f64,
-            Some(c),
-            None,
-        );
-        cpu.reset().unwrap();
+    let mut ring = None;
+    let device = audio.open_playback(None, &desired, |spec| {
+        // Enough room for a few frames, the emulator aims to keep it half full
+        let r = AudioRing::new(spec.freq as usize * AUDIO_BUFFER_MS / 1000);
+        ring = Some(r.clone());
+        AudioOut { ring: r, num_channels: spec.channels.max(1) as usize, mono: vec![] }
+    })?;
+    let spec = *device.spec();
+    if args.debug {
         println!("{spec:?}");
-        EmuMain { cpu, frame_send: send, audio_spec: spec } 
-    }).unwrap();
-    
+    }
+
+    let mut cpu = Cpu::new(cart, spec.freq as f64, Some(controller.clone()), None);
+    cpu.reset()?;
+    if let Some(path) = &args.trace {
+        let tracer = TraceLogger::create(path, args.trace_format)?
+            .start_at(args.trace_start)
+            .stop_at(args.trace_stop);
+        cpu.set_tracer(Some(tracer));
+    }
+    let cdl = match &args.cdl {
+        Some(path) => {
+            let log = CodeDataLog::load(path, ines_rom.prg_rom.len(), ines_rom.chr_rom.len())?;
+            let log = Arc::new(Mutex::new(log));
+            cpu.set_cdl(Some(log.clone()));
+            Some((path, log))
+        }
+        None => None,
+    };
+
+    let save = if cpu.nvram().is_some() {
+        let path = save_path(Path::new(&args.rom_path), args.save_dir.as_deref(), "sav");
+        let mut save = SaveFile::new(path);
+        save.load(&mut cpu)?;
+        Some(save)
+    } else {
+        None
+    };
+    let gdb = match args.gdb {
+        Some(port) => {
+            let server = GdbServer::bind(("127.0.0.1", port))?;
+            println!("Waiting for GDB on port {}", server.port()?);
+            Some(server)
+        }
+        None => None,
+    };
+    let debugger = if args.debug || gdb.is_some() {
+        let mut dbg = Debugger::new();
+        // Labels from FCEUX/ca65 symbol files next to the ROM
+        dbg.symbols = SymbolTable::for_rom(Path::new(&args.rom_path))?;
+        Some(dbg)
+    } else {
+        None
+    };
+    let emu = EmuThread::spawn(EmuContext {
+        cpu,
+        audio: ring.unwrap(),
+        sample_rate: spec.freq as f64,
+        save,
+        debugger,
+        gdb,
+        debug_views: args.debug,
+    });
+    if args.debug {
+        // The debugger REPL reads commands from the terminal
+        let commands = emu.command_sender();
+        thread::spawn(move || {
+            println!("Debugger ready, type help for a list of commands");
+            for line in io::stdin().lines().map_while(|l| l.ok()) {
+                if commands.send(EmuCommand::Debug(line)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
     let mut running = true;
 
     let mut paused = false;
+    let mut state_slot = 0;
+    let state_path = |slot: u8| {
+        save_path(Path::new(&args.rom_path), args.save_dir.as_deref(), &format!("state{slot}"))
+    };
     device.resume();
 
     // Main loop
@@ -143,14 +258,23 @@ fn main() -> Result<(), Box<dyn Error>> {
                 #[rustfmt::skip]
                 Event::KeyDown {keycode: Some(Keycode::Space) ,..} => {
                     paused = !paused;
-                    if paused {
-                        device.pause();
-                    } else {
-                        device.resume();
-                    }
+                    emu.send(if paused { EmuCommand::Pause } else { EmuCommand::Resume });
                     controller.lock().unwrap().clear();
                 }
                 #[rustfmt::skip]
+                Event::KeyDown { keycode: Some(Keycode::F5), .. } => {
+                    emu.send(EmuCommand::SaveState(state_path(state_slot)));
+                }
+                #[rustfmt::skip]
+                Event::KeyDown { keycode: Some(Keycode::F7), .. } => {
+                    emu.send(EmuCommand::LoadState(state_path(state_slot)));
+                }
+                #[rustfmt::skip]
+                Event::KeyDown {  keycode: Some(keycode), ..} if map_state_slot(keycode).is_some() => {
+                    state_slot = map_state_slot(keycode).unwrap();
+                    println!("Save state slot {state_slot}");
+                }
+                #[rustfmt::skip]
                 Event::KeyDown {  keycode: Some(keycode), ..} => {
                     if let Some(input) = map_inputs(keycode) {
                         controller.lock().unwrap().input(input);
@@ -166,14 +290,27 @@ fn main() -> Result<(), Box<dyn Error>> {
             }
         }
         graphics.process_events(&events);
-        // println!("waiting on frame...");
-        match rcv.try_recv() {
-            Ok((frame, info)) => graphics.render_frame(frame, info),
-            Err(TryRecvError::Empty) => Ok(()),
-            Err(e) => Err(e.into())
-        }?;
+        while let Some(event) = emu.poll_event() {
+            match event {
+                EmuEvent::GdbConnected => println!("GDB client connected"),
+                EmuEvent::GdbDisconnected => println!("GDB client disconnected"),
+            }
+        }
+        // The emulator thread sets the pace, just show whatever it produced last
+        if let Some((frame, info)) = emu.latest_frame(Duration::from_millis(2)) {
+            graphics.render_frame(frame, info)?;
+        }
     }
 
+    // The code/data log is still worth keeping if the emulator failed
+    let joined = emu.join();
+    if let Some((path, log)) = cdl {
+        let log = log.lock().unwrap();
+        log.save(path)?;
+        let (code, data, unused) = log.prg_summary();
+        println!("Code/data log: {code} bytes of code, {data} of data, {unused} not accessed");
+    }
+    joined?;
     device.close_and_get_callback();
     Ok(())
 }
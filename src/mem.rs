@@ -1,3 +1,5 @@
+pub mod access;
+pub mod cdl;
 pub mod bus;
 pub mod ram;
 pub mod error;
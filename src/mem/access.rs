@@ -0,0 +1,43 @@
+// Memory accesses recorded for the debugger's watchpoints, nothing is kept unless enabled
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    Cpu,
+    Ppu,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemAccess {
+    pub kind: AccessKind,
+    pub addr: u16,
+    pub value: u8,
+}
+
+#[derive(Debug, Default)]
+pub struct AccessLog {
+    enabled: bool,
+    accesses: Vec<MemAccess>,
+}
+
+impl AccessLog {
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.accesses.clear();
+    }
+
+    pub fn record(&mut self, kind: AccessKind, addr: u16, value: u8) {
+        if self.enabled {
+            self.accesses.push(MemAccess { kind, addr, value });
+        }
+    }
+
+    pub fn drain(&mut self) -> impl Iterator<Item = MemAccess> + '_ {
+        self.accesses.drain(..)
+    }
+}
@@ -2,28 +2,34 @@ use std::cell::RefCell;
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 
+use crate::apu::apu::Apu;
 use crate::cart::mock::mock_cart;
-use crate::controller::ControllerRef;
+use crate::controller::{make_controller, ControllerRef};
 use crate::error::Result;
 use crate::ppu::ppu::{Ppu, PpuBuilder};
+use crate::state::{StateReader, StateWriter};
 
-use super::error::inv_addr;
+use super::access::{AccessKind, AccessLog};
 use super::ram::Ram;
 use crate::cart::cart::Cartridge;
 
 pub struct MemoryBus {
     ram: Ram,
     pub ppu: Ppu,
+    pub apu: Apu,
     pub cart: Arc<Mutex<Cartridge>>,
     p1: Option<ControllerRef>,
-    p2: Option<ControllerRef>
+    p2: Option<ControllerRef>,
+    // CPU reads and writes, for the debugger
+    pub accesses: AccessLog,
 }
 
 pub struct MemoryBusBuilder {
     ram: Option<Ram>,
     cart: Option<Cartridge>,
     p1: Option<ControllerRef>,
-    p2: Option<ControllerRef>
+    p2: Option<ControllerRef>,
+    sample_rate: Option<f64>
 }
 
 impl MemoryBusBuilder {
@@ -32,7 +38,8 @@ impl MemoryBusBuilder {
             ram: None,
             cart: None,
             p1: None,
-            p2: None
+            p2: None,
+            sample_rate: None
         }
     }
 
@@ -52,24 +59,38 @@ impl MemoryBusBuilder {
         self
     }
 
+    pub fn with_sample_rate(mut self, sample_rate: f64) -> Self {
+        self.sample_rate = Some(sample_rate);
+        self
+    }
+
     pub fn build(self) -> MemoryBus {
         let cart = Arc::new(Mutex::new(self.cart.unwrap_or_else(|| mock_cart())));
         MemoryBus {
             ram: self.ram.unwrap_or_default(),
             ppu: PpuBuilder::new(cart.clone()).build().unwrap(),
+            apu: self.sample_rate.map(Apu::new).unwrap_or_default(),
             cart,
             p1: self.p1,
-            p2: self.p2
+            p2: self.p2,
+            accesses: AccessLog::default(),
         }
     }
 }
 
 impl MemoryBus {
     pub fn read(&mut self, addr: u16) -> Result<u8> {
+        let byte = self.read_mapped(addr)?;
+        self.accesses.record(AccessKind::Read, addr, byte);
+        Ok(byte)
+    }
+
+    fn read_mapped(&mut self, addr: u16) -> Result<u8> {
         match addr {
             0x0000..=0x1FFF => self.ram.read(addr),
             0x2000..=0x3FFF => self.ppu.read(addr),
-            0x4000..=0x4015 => Ok(0), 
+            0x4015 => Ok(self.apu.read_status()),
+            0x4000..=0x4014 => Ok(0),
             0x4016 => {
                 if let Some(p1) = self.p1.as_ref() {
                     Ok(p1.lock().unwrap().read())
@@ -85,32 +106,80 @@ impl MemoryBus {
                     Ok(0)
                 }
             }
+            // CPU test mode registers, disabled on retail consoles (dummy reads can land here)
+            0x4018..=0x401F => Ok(0),
             0x4020..=0xFFFF => {
                 self.cart.lock().unwrap().read(addr)
             },
-            _ => Err(inv_addr(addr)),
+        }
+    }
+
+    // What read() would return, without side effects and without being recorded
+    pub fn peek(&self, addr: u16) -> Result<u8> {
+        match addr {
+            0x0000..=0x1FFF => self.ram.read(addr),
+            0x2000..=0x3FFF => self.ppu.peek(addr),
+            0x4015 => Ok(self.apu.peek_status()),
+            0x4000..=0x4014 => Ok(0),
+            0x4016 => Ok(self.p1.as_ref().map_or(0, |p1| p1.lock().unwrap().peek())),
+            0x4017 => Ok(self.p2.as_ref().map_or(0, |p2| p2.lock().unwrap().peek())),
+            0x4018..=0x401F => Ok(0),
+            0x4020..=0xFFFF => self.cart.lock().unwrap().peek(addr),
         }
     }
 
     pub fn write(&mut self, addr: u16, byte: u8) -> Result<()> {
+        self.accesses.record(AccessKind::Write, addr, byte);
         match addr {
             0x0000..=0x1FFF => self.ram.write(addr, byte),
-            0x2000..=0x3FFF => self.ppu.write(addr, byte),
+            0x2000..=0x3FFF => {
+                self.cart.lock().unwrap().ppu_register_write(0x2000 | (addr & 0x7), byte);
+                self.ppu.write(addr, byte)
+            }
             0x4016 => {
+                // The strobe is shared by both controller ports
                 if let Some(p1) = self.p1.as_ref() {
                     p1.lock().unwrap().write(byte);
                 }
-                Ok(())
-            },
-            0x4017 => {
                 if let Some(p2) = self.p2.as_ref() {
                     p2.lock().unwrap().write(byte);
                 }
                 Ok(())
+            },
+            0x4000..=0x4015 | 0x4017 => {
+                self.apu.write(addr, byte);
+                Ok(())
             }
-            0x4000..=0x4015 => Ok(()),
+            0x4018..=0x401F => Ok(()),
             0x4020..=0xFFFF => self.cart.lock().unwrap().write(addr, byte),
-            _ => Err(inv_addr(addr)),
         }
     }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        self.ram.save_state(w);
+        self.ppu.save_state(w);
+        self.apu.save_state(w);
+        self.cart.lock().unwrap().save_state(w);
+        for port in [&self.p1, &self.p2] {
+            w.bool(port.is_some());
+            if let Some(c) = port {
+                c.lock().unwrap().save_state(w);
+            }
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        self.ram.load_state(r)?;
+        self.ppu.load_state(r)?;
+        self.apu.load_state(r)?;
+        self.cart.lock().unwrap().load_state(r)?;
+        for port in [&self.p1, &self.p2] {
+            if r.bool()? {
+                // Still has to be read if nothing is plugged in now
+                let c = port.clone().unwrap_or_else(make_controller);
+                c.lock().unwrap().load_state(r)?;
+            }
+        }
+        Ok(())
+    }
 }
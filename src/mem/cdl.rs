@@ -0,0 +1,147 @@
+use bitflags::bitflags;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::error::Result;
+
+// Code/data log in the FCEUX .cdl layout: one byte per PRG ROM byte followed by one per CHR ROM
+// byte, each a set of flags for how it has been accessed so far. Logs from FCEUX can be loaded
+// and keep getting added to.
+// https://fceux.com/web/help/CodeDataLogger.html
+
+bitflags! {
+    pub struct PrgFlags: u8 {
+        const CODE          = 0b00000001;
+        const DATA          = 0b00000010;
+        // Bits 2-3 are which 8K of $8000-$FFFF the byte was mapped to, see mark_prg()
+        const INDIRECT_CODE = 0b00010000;
+        const INDIRECT_DATA = 0b00100000;
+        const PCM           = 0b01000000;
+    }
+}
+
+bitflags! {
+    pub struct ChrFlags: u8 {
+        const DRAWN = 0b00000001;
+        const READ  = 0b00000010;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeDataLog {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+}
+
+// Shared by the CPU and PPU side of the bus
+pub type CdlRef = Arc<Mutex<CodeDataLog>>;
+
+impl CodeDataLog {
+    // `chr_rom_size` is 0 for CHR RAM carts, there's nothing to log for those
+    pub fn new(prg_rom_size: usize, chr_rom_size: usize) -> Self {
+        Self {
+            prg: vec![0; prg_rom_size],
+            chr: vec![0; chr_rom_size],
+        }
+    }
+
+    // Parses a .cdl file for a ROM of the given size
+    pub fn from_bytes(data: &[u8], prg_rom_size: usize, chr_rom_size: usize) -> Result<Self> {
+        if data.len() != prg_rom_size + chr_rom_size {
+            return Err(format!(
+                "Code/data log is {} bytes, expected {} for this ROM",
+                data.len(),
+                prg_rom_size + chr_rom_size
+            )
+            .into());
+        }
+        let (prg, chr) = data.split_at(prg_rom_size);
+        Ok(Self {
+            prg: prg.to_vec(),
+            chr: chr.to_vec(),
+        })
+    }
+
+    // Picks up where an existing file left off, a missing file starts an empty log
+    pub fn load(path: &Path, prg_rom_size: usize, chr_rom_size: usize) -> Result<Self> {
+        match fs::read(path) {
+            Ok(data) => Self::from_bytes(&data, prg_rom_size, chr_rom_size)
+                .map_err(|e| format!("{}: {e}", path.display()).into()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::new(prg_rom_size, chr_rom_size)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [self.prg.as_slice(), self.chr.as_slice()].concat()
+    }
+
+    // Byte at `offset` in PRG ROM was accessed through CPU address `addr`
+    pub fn mark_prg(&mut self, offset: usize, addr: u16, flags: PrgFlags) {
+        if let Some(byte) = self.prg.get_mut(offset) {
+            *byte |= flags.bits() | ((addr >> 11) & 0x0C) as u8;
+        }
+    }
+
+    pub fn mark_chr(&mut self, offset: usize, flags: ChrFlags) {
+        if let Some(byte) = self.chr.get_mut(offset) {
+            *byte |= flags.bits();
+        }
+    }
+
+    #[allow(dead_code)] // Used by tests
+    pub fn prg_flags(&self, offset: usize) -> Option<PrgFlags> {
+        self.prg.get(offset).map(|b| PrgFlags::from_bits_truncate(*b))
+    }
+
+    #[allow(dead_code)] // Used by tests
+    pub fn chr_flags(&self, offset: usize) -> Option<ChrFlags> {
+        self.chr.get(offset).map(|b| ChrFlags::from_bits_truncate(*b))
+    }
+
+    // (code, data, unaccessed) PRG ROM bytes, code that was also read as data counts as code
+    pub fn prg_summary(&self) -> (usize, usize, usize) {
+        let code = self.prg.iter().filter(|b| *b & PrgFlags::CODE.bits() != 0).count();
+        let accessed = self.prg.iter().filter(|b| *b & PrgFlags::all().bits() != 0).count();
+        (code, accessed - code, self.prg.len() - accessed)
+    }
+}
+
+#[cfg(test)]
+mod cdl_tests {
+    use super::{ChrFlags, CodeDataLog, PrgFlags};
+
+    #[test]
+    fn flags() {
+        let mut cdl = CodeDataLog::new(0x8000, 0x2000);
+        cdl.mark_prg(0x0010, 0x8010, PrgFlags::CODE);
+        cdl.mark_prg(0x0010, 0x8010, PrgFlags::DATA);
+        cdl.mark_prg(0x7FFC, 0xFFFC, PrgFlags::DATA);
+        cdl.mark_prg(0x4000, 0xC000, PrgFlags::DATA | PrgFlags::INDIRECT_DATA);
+        cdl.mark_chr(0x1000, ChrFlags::DRAWN);
+        // Out of range offsets are ignored
+        cdl.mark_prg(0x8000, 0x8000, PrgFlags::CODE);
+
+        let data = cdl.to_bytes();
+        assert_eq!(data.len(), 0xA000);
+        assert_eq!(data[0x0010], 0x03);
+        // Bank bits for $E000-$FFFF
+        assert_eq!(data[0x7FFC], 0x0E);
+        assert_eq!(data[0x4000], 0x2A);
+        assert_eq!(data[0x8000 + 0x1000], 0x01);
+        assert_eq!(cdl.prg_summary(), (1, 2, 0x8000 - 3));
+
+        let loaded = CodeDataLog::from_bytes(&data, 0x8000, 0x2000).unwrap();
+        assert_eq!(loaded, cdl);
+        assert_eq!(loaded.prg_flags(0x4000), Some(PrgFlags::DATA | PrgFlags::INDIRECT_DATA));
+        assert_eq!(loaded.chr_flags(0x1000), Some(ChrFlags::DRAWN));
+        assert!(CodeDataLog::from_bytes(&data, 0x4000, 0x2000).is_err());
+    }
+}
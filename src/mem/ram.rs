@@ -1,5 +1,6 @@
 use super::error::{MemoryError};
 use crate::error::Result;
+use crate::state::{StateReader, StateWriter};
 
 const RAM_SIZE: u16 = 0x800;
 
@@ -31,6 +32,14 @@ impl Ram {
         }
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.mem);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        r.bytes_into(&mut self.mem)
+    }
+
     pub fn from(bytes: &[u8]) -> Self {
         if bytes.len() > RAM_SIZE as usize{
             panic!("RAM size is smaller than bytes specified.")
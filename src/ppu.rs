@@ -1,2 +1 @@
-pub mod ppu;
-pub mod colors;
\ No newline at end of file
+pub mod ppu;
\ No newline at end of file
@@ -4,21 +4,36 @@ use std::sync::{Arc, Mutex};
 
 use bitfield::bitfield;
 
-use crate::cart::cart::Cartridge;
-use crate::cpu::cpu::Interrupt;
+use crate::cart::cart::{Cartridge, PpuFetch};
 use crate::error::Result;
+use crate::mem::access::{AccessKind, AccessLog};
+use crate::mem::cdl::{CdlRef, ChrFlags};
 use crate::mem::error::{inv_addr, rd_only, wr_only};
-use sdl2::pixels::Color;
+use crate::state::{StateReader, StateWriter};
 
-use super::colors::{load_color_map, ColorMap};
-
-pub type Frame = Box<[[Color; 256]; 240]>;
+// Each pixel is a 6-bit palette index with the PPUMASK emphasis bits (red, green, blue) in
+// bits 6-8, turning that into RGB is up to the frontend (see graphics::colors)
+pub type Frame = Box<[[u16; 256]; 240]>;
 pub type PatternTable = Box<[[u8; 128]; 128]>;
+// The four nametables side by side (2x2), pixels like in a Frame
+pub type NametableView = Box<[[u16; 512]; 480]>;
 
 fn set_low_byte(x: &mut u16, lsb: u8) {
     *x = (*x & 0xFF00) | (lsb as u16)
 }
 
+// Palette RAM address of a pixel (index into the palette) in one of the 4 bg/fg palettes
+fn color_addr(mut palette_idx: u8, mut pixel: u8, bg: bool) -> u16 {
+    palette_idx &= 0b11;
+    pixel &= 0b11;
+    if pixel == 0 {
+        palette_idx = 0;
+    }
+    let bg_select = !bg as u8;
+    let idx = (bg_select << 4) | (palette_idx << 2) | pixel;
+    PALETTES_OFFSET | (idx as u16)
+}
+
 bitfield! {
     #[derive(Debug, Default)]
     struct PpuControl(u8);
@@ -108,7 +123,27 @@ pub struct PendingSprite {
     sprite: OamSprite,
     row_offset: u8,
     col_offset: u8,
-    is_sprite_zero: bool
+    is_sprite_zero: bool,
+    // Pattern bytes, fetched during cycles 257-320 of the previous scanline
+    pattern_lo: u8,
+    pattern_hi: u8
+}
+
+impl PendingSprite {
+    // Pixel in column `col_offset` of the fetched row
+    fn pixel(&self) -> u8 {
+        use bit::BitIndex;
+
+        debug_assert!(self.col_offset < 8);
+        // Because of the way we're indexing into the byte, we subtract from 7 when
+        // _not_ flipped. (If flipped then we keep c as is)
+        let c = if self.sprite.attributes.get_flip_horizontal() {
+            self.col_offset
+        } else {
+            7 - self.col_offset
+        } as usize;
+        ((self.pattern_hi.bit(c) as u8) << 1) | (self.pattern_lo.bit(c) as u8)
+    }
 }
 
 #[derive(Default, Debug)]
@@ -138,26 +173,16 @@ pub struct OamSprite {
 // impl PpuReg {}
 
 pub struct PpuBuilder {
-    palette_file: Option<String>,
     cart: Arc<Mutex<Cartridge>>,
 }
 
 impl PpuBuilder {
     pub fn new(cart: Arc<Mutex<Cartridge>>) -> Self {
-        PpuBuilder {
-            palette_file: None,
-            cart,
-        }
+        PpuBuilder { cart }
     }
 
-    // pub fn with_palette(mut self, pal_file: String) -> Self {
-    //     self.palette_file = Some(pal_file);
-    //     self
-    // }
-
     pub fn build(self) -> Result<Ppu> {
         Ok(Ppu {
-            color_map: load_color_map(self.palette_file.as_deref())?,
             cart: self.cart,
             vram: [0u8; 1024 * 2],
             oam: [0u8; 256],
@@ -166,10 +191,11 @@ impl PpuBuilder {
             odd_frame: false,
             cycle: 0,
             scanline: 0,
-            buffer: Box::new([[Color::BLACK; 256]; 240]),
+            buffer: Box::new([[0; 256]; 240]),
             bg: BackgroundState::default(),
-            fg: ForegroundState::default()
-            // buffer: [[Color::BLACK; 256]; 240]
+            fg: ForegroundState::default(),
+            accesses: AccessLog::default(),
+            cdl: None,
         })
     }
 }
@@ -178,7 +204,6 @@ impl PpuBuilder {
 pub struct Ppu {
     pub buffer: Frame,
     cart: Arc<Mutex<Cartridge>>,
-    color_map: ColorMap,
     vram: [u8; 1024 * 2],
     oam: [u8; 256],
     palettes: [u8; 256],
@@ -189,7 +214,11 @@ pub struct Ppu {
     pub scanline: i32,
     // Background rendering intermediates
    bg: BackgroundState,
-   fg: ForegroundState
+   fg: ForegroundState,
+   // VRAM reads and writes (through $2007 and rendering), for the debugger
+   pub accesses: AccessLog,
+   // CHR ROM fetches for the code/data log, if one is being kept
+   pub cdl: Option<CdlRef>,
 }
 
 impl Ppu {
@@ -215,7 +244,10 @@ impl Ppu {
                 6 => Err(wr_only(addr)),
                 7 => {
                     let mut data = self.reg.ppu_data_buffer;
+                    self.notify_bus(self.reg.v_addr.0);
                     self.reg.ppu_data_buffer = self.ppu_read(self.reg.v_addr.0)?;
+                    self.log_chr(&self.cart.try_lock().unwrap(), self.reg.v_addr.0, ChrFlags::READ);
+                    self.accesses.record(AccessKind::Read, self.reg.v_addr.0, self.reg.ppu_data_buffer);
                     if self.reg.v_addr.0 > 0x3F00 {
                         data = self.reg.ppu_data_buffer;
                     }
@@ -231,6 +263,21 @@ impl Ppu {
         }
     }
 
+    // What read() would return, without clearing vblank, the latch or moving the VRAM address
+    pub fn peek(&self, addr: u16) -> Result<u8> {
+        match addr {
+            0x2000..=0x3FFF => match addr & 0x7 {
+                2 => Ok((self.reg.status.0 & 0xE0) | (self.reg.ppu_data_buffer & 0x1F)),
+                4 => Ok(self.oam[self.reg.oam_addr as usize]),
+                // Palette reads aren't buffered
+                7 if self.reg.v_addr.0 > 0x3F00 => self.ppu_peek(self.reg.v_addr.0),
+                7 => Ok(self.reg.ppu_data_buffer),
+                _ => Err(wr_only(addr)),
+            },
+            _ => Err(inv_addr(addr)),
+        }
+    }
+
     pub fn write(&mut self, addr: u16, byte: u8) -> Result<()> {
         if addr < 0x2000 || addr > 0x3FFF {
             return Err(inv_addr(addr));
@@ -278,6 +325,8 @@ impl Ppu {
                     Ok(())
                 }
                 7 => {
+                    self.notify_bus(self.reg.v_addr.0);
+                    self.accesses.record(AccessKind::Write, self.reg.v_addr.0, byte);
                     let ret = self.ppu_write(self.reg.v_addr.0, byte);
                     if self.reg.control.get_vram_inc() {
                         self.reg.v_addr.0 += 32
@@ -338,6 +387,41 @@ impl Ppu {
         }
     }
 
+    // PPU address space as the debug views see it, mappers don't notice these reads
+    pub fn ppu_peek(&self, addr: u16) -> Result<u8> {
+        match addr {
+            0x0000..=0x3EFF => {
+                self.cart.try_lock().unwrap().ppu_peek(addr, &self.vram)
+            },
+            0x3F00..=0x3FFF => Ok(self.palettes[self.map_palette_addr(addr)]),
+            _ => Err(inv_addr(addr)),
+        }
+    }
+
+    // Reads done by the rendering pipeline, these show up on the cartridge's view of the bus
+    fn fetch(&mut self, addr: u16, kind: PpuFetch) -> Result<u8> {
+        let mut cart = self.cart.try_lock().unwrap();
+        cart.ppu_bus(addr);
+        let byte = cart.ppu_fetch(addr, kind, &self.vram)?;
+        self.log_chr(&cart, addr, ChrFlags::DRAWN);
+        self.accesses.record(AccessKind::Read, addr, byte);
+        Ok(byte)
+    }
+
+    fn log_chr(&self, cart: &Cartridge, addr: u16, flags: ChrFlags) {
+        if let Some(cdl) = &self.cdl {
+            if let Some(offset) = cart.chr_rom_offset(addr) {
+                cdl.lock().unwrap().mark_chr(offset, flags);
+            }
+        }
+    }
+
+    fn notify_bus(&self, addr: u16) {
+        if addr < PALETTES_OFFSET {
+            self.cart.try_lock().unwrap().ppu_bus(addr);
+        }
+    }
+
     fn ppu_write(&mut self, addr: u16, byte: u8) -> Result<()> {
         match addr {
             0x0000..=0x3EFF => self.cart.try_lock().unwrap().ppu_write(addr, byte, &mut self.vram),
@@ -371,9 +455,13 @@ impl Ppu {
         Ok(())
     }
 
-    pub fn tick(&mut self) -> Result<(Option<Frame>, Option<Interrupt>)> {
+    // Level of the NMI output, the CPU triggers on its rising edge
+    pub fn nmi(&self) -> bool {
+        self.reg.status.get_vblank_start() && self.reg.control.get_nmi_toggle()
+    }
+
+    pub fn tick(&mut self) -> Result<Option<Frame>> {
         let mut ret_frame = None;
-        let mut ret_int = None;
 
         if self.scanline >= -1 && self.scanline < 240 {
             // if self.scanline == 0 && self.cycle == 0 {
@@ -386,7 +474,8 @@ impl Ppu {
                 self.reg.status.set_sprite_zero_hit(false);
                 self.fg.sprite_zero_hit = false;
             }
-            if (2..258).contains(&self.cycle) || (321..338).contains(&self.cycle) {
+            let fetching = self.rendering_enabled();
+            if fetching && ((2..258).contains(&self.cycle) || (321..338).contains(&self.cycle)) {
                 self.update_bg_shift();
                 self.load_bg()?;
             }
@@ -405,19 +494,25 @@ impl Ppu {
                     if self.scanline >= 0 {
                         // Find sprites for the next scanline
                         self.find_sprites_for_scanline()
+                    } else {
+                        // Nothing was evaluated for the first scanline
+                        self.fg.scanline_sprites.clear();
                     }
                 }
                 280..=304 if self.scanline == -1 => {
                     self.copy_y();
                 }
-                338 | 340 => {
+                338 | 340 if fetching => {
                     self.bg.tile_id = self
-                        .ppu_read(NAMETABLE_OFFSET | self.reg.v_addr.get_nametable_lookup_addr())?;
+                        .fetch(
+                            NAMETABLE_OFFSET | self.reg.v_addr.get_nametable_lookup_addr(),
+                            PpuFetch::Nametable,
+                        )?;
                 }
                 _ => (),
             }
-            if self.scanline >= 0 && self.cycle == 340 {
-                self.find_sprites_for_scanline()
+            if fetching && (257..321).contains(&self.cycle) {
+                self.load_sprite_patterns()?;
             }
         }
 
@@ -425,9 +520,6 @@ impl Ppu {
         // Start of vblank period
         if self.scanline == 241 && self.cycle == 1 {
             self.reg.status.set_vblank_start(true);
-            if self.reg.control.get_nmi_toggle() {
-                ret_int = Some(Interrupt::NonMaskable);
-            }
         }
         // }
 
@@ -453,10 +545,10 @@ impl Ppu {
             let mut sprite_palette = 0;
             let mut bg_priority = false;
             let mut sprite_zero = false;
-            for idx in 0..self.fg.scanline_sprites.len() {
-                let PendingSprite { sprite, row_offset, col_offset , is_sprite_zero} = self.fg.scanline_sprites[idx];
+            for ps in self.fg.scanline_sprites.iter() {
+                let PendingSprite { sprite, col_offset, is_sprite_zero, .. } = *ps;
                 if sprite.x == 0 && col_offset < 8 {
-                    sprite_pixel = self.get_sprite_pixel(&sprite, row_offset, col_offset)?;
+                    sprite_pixel = ps.pixel();
                     sprite_palette = sprite.attributes.get_palette();
                     bg_priority = sprite.attributes.get_priority();
                     sprite_zero = is_sprite_zero;
@@ -505,7 +597,8 @@ impl Ppu {
             let row = self.scanline as usize;
             let col = (self.cycle - 1) as usize;
             if row < self.buffer.len() && col < self.buffer[row].len() {
-                self.buffer[row][col] = self.get_color(palette, pixel, bg)?;
+                let emphasis = (self.reg.mask.0 >> 5) as u16;
+                self.buffer[row][col] = (emphasis << 6) | self.get_color(palette, pixel, bg)? as u16;
             }
         }
 
@@ -521,7 +614,7 @@ impl Ppu {
             }
         }
 
-        Ok((ret_frame, ret_int))
+        Ok(ret_frame)
     }
 
     fn rendering_enabled(&self) -> bool {
@@ -619,7 +712,10 @@ impl Ppu {
             0 => {
                 self.load_bg_shift();
                 // load nametable entry
-                self.bg.tile_id = self.ppu_read(self.reg.v_addr.get_nametable_lookup_addr() | NAMETABLE_OFFSET)?;
+                self.bg.tile_id = self.fetch(
+                    self.reg.v_addr.get_nametable_lookup_addr() | NAMETABLE_OFFSET,
+                    PpuFetch::Nametable,
+                )?;
             }
             2 => {
                 // Attribute Address:
@@ -627,11 +723,12 @@ impl Ppu {
                 // X, Y are the top 3 bits of the coarse_x and coarse_y V addr registers
 
                 // load attribute for tile
-                self.bg.tile_attribute = self.ppu_read(
+                self.bg.tile_attribute = self.fetch(
                     ATTRIBUTE_TABLE_OFFSET |
                     (self.reg.v_addr.0 & 0xC00) | // Get nametable select
                     (self.reg.v_addr.get_coarse_x() >> 2) |
-                    ((self.reg.v_addr.get_coarse_y() >> 2) << 3)
+                    ((self.reg.v_addr.get_coarse_y() >> 2) << 3),
+                    PpuFetch::Attribute,
                 )?;
                 // Byte from attribute table consists of four pairs of two bits
                 if self.reg.v_addr.get_coarse_y() & 0b10 != 0 {
@@ -647,19 +744,21 @@ impl Ppu {
             }
             4 => {
                 // load lsb of tile data
-                self.bg.tile_lsb = self.ppu_read(
+                self.bg.tile_lsb = self.fetch(
                     pattern_table_addr
                     + ((self.bg.tile_id as u16) << 4)
-                    + self.reg.v_addr.get_fine_y()
+                    + self.reg.v_addr.get_fine_y(),
+                    PpuFetch::Background,
                 )?;
             }
             6 => {
                 // load msb of tile data
-                self.bg.tile_msb = self.ppu_read(
+                self.bg.tile_msb = self.fetch(
                     pattern_table_addr
                         + ((self.bg.tile_id as u16) << 4)
                         + self.reg.v_addr.get_fine_y()
                         + 8,
+                    PpuFetch::Background,
                 )?;
             }
             7 => {
@@ -672,21 +771,9 @@ impl Ppu {
         Ok(())
     }
 
-    fn get_color(&mut self, mut palette_idx: u8, mut pixel: u8, bg: bool) -> Result<Color> {
-        // (pixel is an index into the palette)
-        palette_idx &= 0b11;
-        pixel &= 0b11;
-        if pixel == 0 {
-            palette_idx = 0;
-        }
-        let bg_select = !bg as u8;
-        let idx = (bg_select << 4) | (palette_idx << 2) | pixel;
-        let addr = PALETTES_OFFSET | (idx as u16);
-        let color = self.ppu_read(addr)?;
-        match self.color_map.get(&color) {
-            Some(c) => Ok(*c),
-            None => Err("Invalid color".into()),
-        }
+    // Index into the system palette
+    fn get_color(&mut self, palette_idx: u8, pixel: u8, bg: bool) -> Result<u8> {
+        Ok(self.ppu_read(color_addr(palette_idx, pixel, bg))? & 0x3F)
     }
 
     fn find_sprites_for_scanline(&mut self) {
@@ -708,7 +795,14 @@ impl Ppu {
             let diff = (self.scanline as i16) - (sprite.y as i16);
             if diff >= 0 && diff < sprite_height {
                 if self.fg.scanline_sprites.len() < 8 {
-                    let ps = PendingSprite { sprite, row_offset: diff as u8, col_offset: 0, is_sprite_zero: idx == 0 } ;
+                    let ps = PendingSprite {
+                        sprite,
+                        row_offset: diff as u8,
+                        col_offset: 0,
+                        is_sprite_zero: idx == 0,
+                        pattern_lo: 0,
+                        pattern_hi: 0
+                    };
                     self.fg.scanline_sprites.push(ps);
                 } else {
                     self.reg.status.set_sprite_overflow(true);
@@ -721,7 +815,7 @@ impl Ppu {
 
     fn update_sprites(&mut self) {
         if self.reg.mask.get_show_sprites() {
-            for PendingSprite { sprite, row_offset: _, col_offset , is_sprite_zero: _} in self.fg.scanline_sprites.iter_mut() {
+            for PendingSprite { sprite, col_offset, .. } in self.fg.scanline_sprites.iter_mut() {
                 if sprite.x > 0 {
                     sprite.x -= 1;
                 } else if *col_offset < 8 {
@@ -731,19 +825,40 @@ impl Ppu {
         }
     }
 
-    fn get_sprite_pixel(&mut self, sprite: &OamSprite, mut r: u8, mut c: u8) -> Result<u8> {
-        use bit::BitIndex;
-
-        debug_assert!(c < 8);
+    // Each of the 8 sprite slots gets 8 cycles, the pattern bytes are fetched on the last 4.
+    // Empty slots still fetch (tile $FF), which mappers watching A12 rely on.
+    fn load_sprite_patterns(&mut self) -> Result<()> {
+        let slot = ((self.cycle - 257) / 8) as usize;
+        let step = (self.cycle - 257) % 8;
+        if step != 4 && step != 6 {
+            return Ok(());
+        }
+        let addr = match self.fg.scanline_sprites.get(slot) {
+            Some(ps) => self.sprite_pattern_addr(&ps.sprite, ps.row_offset),
+            None => {
+                let dummy = OamSprite { y: 0xFF, id: 0xFF, attributes: SpriteAttributes(0), x: 0xFF };
+                self.sprite_pattern_addr(&dummy, 0)
+            }
+        };
+        if step == 4 {
+            let lo = self.fetch(addr, PpuFetch::Sprite)?;
+            if let Some(ps) = self.fg.scanline_sprites.get_mut(slot) {
+                ps.pattern_lo = lo;
+            }
+        } else {
+            let hi = self.fetch(addr + 8, PpuFetch::Sprite)?;
+            if let Some(ps) = self.fg.scanline_sprites.get_mut(slot) {
+                ps.pattern_hi = hi;
+            }
+        }
+        Ok(())
+    }
 
+    // Address of the low bitplane of row `r` of the sprite
+    fn sprite_pattern_addr(&self, sprite: &OamSprite, mut r: u8) -> u16 {
         if self.reg.control.get_sprite_size() {
             // 8x16
             debug_assert!(r < 16);
-            if !sprite.attributes.get_flip_horizontal() {
-                // Because of the way we're indexing into the byte, we subtract from 7 when
-                // _not_ flipped. (If flipped then we keep c as is)
-                c = 7 - c;
-            }
             let mut id = (sprite.id & 0xFE) as u16;
             if sprite.attributes.get_flip_vertical() != (r > 7)  {
                 // If EITHER flipped or in second half of rows, then we go to the next tile
@@ -758,64 +873,196 @@ impl Ppu {
 
             let pattern_base = ((sprite.id & 1) as u16) << 12;
 
-            let tile_addr_lo = pattern_base 
-                                | (id * 16)      // Each pattern is 16 bytes
-                                | (r as u16);   // Row offset
-
-            let tile_addr_hi = tile_addr_lo + 8;
-            let lo = self.ppu_read(tile_addr_lo)?;
-            let hi = self.ppu_read(tile_addr_hi)?;
-            let pixel = ((hi.bit(c as usize) as u8) << 1) | (lo.bit(c as usize) as u8);
-            Ok(pixel) 
+            pattern_base 
+                | (id * 16)      // Each pattern is 16 bytes
+                | (r as u16)     // Row offset
         } else {
             // 8x8
-            assert!(r < 8);
+            debug_assert!(r < 8);
             let pattern_base = (self.reg.control.get_sprite_table_addr() as u16) << 12;
 
-            if !sprite.attributes.get_flip_horizontal() {
-                c = 7 - c;
-            } 
             if sprite.attributes.get_flip_vertical() {
                 r = 7 - r;
             }
-            let tile_addr_lo = pattern_base 
-                                + (sprite.id as u16 * 16) // Each pattern is 16 bytes
-                                + (r as u16);           // Row offset
-            let tile_addr_hi = tile_addr_lo + 8;
-            let lo = self.ppu_read(tile_addr_lo)?;
-            let hi = self.ppu_read(tile_addr_hi)?;
-            let pixel = ((hi.bit(c as usize) as u8) << 1) | (lo.bit(c as usize) as u8);
-            Ok(pixel)
-        }
-    }
-
-    // returns the 4 background and 4 foreground palettes
-    pub fn debug_palettes(&mut self) -> Vec<Vec<Color>> {
-        let mut background: Vec<Vec<Color>> = (0..4)
-            .map(|palette| {
-                (0..4)
-                    .map(|pixel| self.get_color(palette, pixel, true).unwrap_or(Color::BLACK))
-                    .collect()
-            })
+            pattern_base 
+                + (sprite.id as u16 * 16) // Each pattern is 16 bytes
+                + (r as u16)              // Row offset
+        }
+    }
+
+    // The frame currently being drawn isn't included, it gets redrawn soon enough
+    pub fn save_state(&self, w: &mut StateWriter) {
+        let reg = &self.reg;
+        w.u8(reg.control.0);
+        w.u8(reg.mask.0);
+        w.u8(reg.status.0);
+        w.u8(reg.oam_addr);
+        w.bool(reg.ppu_addr_latch);
+        w.u8(reg.ppu_data);
+        w.u8(reg.ppu_data_buffer);
+        w.u16(reg.t_addr.0);
+        w.u16(reg.v_addr.0);
+        w.u8(reg.fine_x);
+
+        w.bytes(&self.vram);
+        w.bytes(&self.oam);
+        w.bytes(&self.palettes);
+        w.bool(self.odd_frame);
+        w.u64(self.cycle);
+        w.i32(self.scanline);
+
+        let bg = &self.bg;
+        w.u8(bg.tile_id);
+        w.u8(bg.tile_attribute);
+        w.u8(bg.tile_lsb);
+        w.u8(bg.tile_msb);
+        w.u16(bg.shift_pattern_lsb);
+        w.u16(bg.shift_pattern_msb);
+        w.u16(bg.shift_attribute_lsb);
+        w.u16(bg.shift_attribute_msb);
+
+        w.u8(self.fg.scanline_sprites.len() as u8);
+        for ps in self.fg.scanline_sprites.iter() {
+            w.u8(ps.sprite.y);
+            w.u8(ps.sprite.id);
+            w.u8(ps.sprite.attributes.0);
+            w.u8(ps.sprite.x);
+            w.u8(ps.row_offset);
+            w.u8(ps.col_offset);
+            w.bool(ps.is_sprite_zero);
+            w.u8(ps.pattern_lo);
+            w.u8(ps.pattern_hi);
+        }
+        w.bool(self.fg.sprite_zero_hit);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<()> {
+        let reg = &mut self.reg;
+        reg.control = PpuControl(r.u8()?);
+        reg.mask = PpuMask(r.u8()?);
+        reg.status = PpuStatus(r.u8()?);
+        reg.oam_addr = r.u8()?;
+        reg.ppu_addr_latch = r.bool()?;
+        reg.ppu_data = r.u8()?;
+        reg.ppu_data_buffer = r.u8()?;
+        reg.t_addr = PpuAddress(r.u16()?);
+        reg.v_addr = PpuAddress(r.u16()?);
+        reg.fine_x = r.u8()? & 0x7;
+
+        r.bytes_into(&mut self.vram)?;
+        r.bytes_into(&mut self.oam)?;
+        r.bytes_into(&mut self.palettes)?;
+        self.odd_frame = r.bool()?;
+        self.cycle = r.u64()?;
+        self.scanline = r.i32()?;
+        if self.cycle >= 341 || !(-1..261).contains(&self.scanline) {
+            return Err("Invalid PPU position in save state".into());
+        }
+
+        let bg = &mut self.bg;
+        bg.tile_id = r.u8()?;
+        bg.tile_attribute = r.u8()?;
+        bg.tile_lsb = r.u8()?;
+        bg.tile_msb = r.u8()?;
+        bg.shift_pattern_lsb = r.u16()?;
+        bg.shift_pattern_msb = r.u16()?;
+        bg.shift_attribute_lsb = r.u16()?;
+        bg.shift_attribute_msb = r.u16()?;
+
+        let num_sprites = r.u8()?;
+        if num_sprites > 8 {
+            return Err("Too many sprites in save state".into());
+        }
+        self.fg.scanline_sprites.clear();
+        for _ in 0..num_sprites {
+            let sprite = OamSprite {
+                y: r.u8()?,
+                id: r.u8()?,
+                attributes: SpriteAttributes(r.u8()?),
+                x: r.u8()?,
+            };
+            self.fg.scanline_sprites.push(PendingSprite {
+                sprite,
+                row_offset: r.u8()?,
+                col_offset: r.u8()?.min(8),
+                is_sprite_zero: r.bool()?,
+                pattern_lo: r.u8()?,
+                pattern_hi: r.u8()?,
+            });
+        }
+        self.fg.sprite_zero_hit = r.bool()?;
+        Ok(())
+    }
+
+    // returns the 4 background and 4 foreground palettes (as system palette indices)
+    pub fn debug_palettes(&self) -> Vec<Vec<u8>> {
+        let color = |palette, pixel, bg| {
+            self.ppu_peek(color_addr(palette, pixel, bg)).unwrap_or_default() & 0x3F
+        };
+        let mut background: Vec<Vec<u8>> = (0..4)
+            .map(|palette| (0..4).map(|pixel| color(palette, pixel, true)).collect())
             .collect();
 
-        let foreground: Vec<Vec<Color>> = (0..4)
-            .map(|palette| {
-                (0..4)
-                    .map(|pixel| {
-                        self.get_color(palette, pixel, false)
-                            .unwrap_or(Color::BLACK)
-                    })
-                    .collect()
-            })
+        let foreground: Vec<Vec<u8>> = (0..4)
+            .map(|palette| (0..4).map(|pixel| color(palette, pixel, false)).collect())
             .collect();
 
         background.extend(foreground.into_iter());
         background
     }
 
+    // All four nametables as the background would draw them, $2000 top left and $2C00 bottom
+    // right, through the cartridge's mirroring and with the attribute palettes applied
+    pub fn debug_nametables(&self) -> Result<NametableView> {
+        let mut view: NametableView = Box::new([[0; 512]; 480]);
+        let palettes = self.debug_palettes();
+        let pattern_table_addr = if self.reg.control.get_bg_table_addr() {
+            0x1000
+        } else {
+            0
+        };
+
+        for nametable in 0..4u16 {
+            let base = NAMETABLE_OFFSET + nametable * 0x400;
+            let x = (nametable % 2) as usize * 256;
+            let y = (nametable / 2) as usize * 240;
+            for tile_row in 0..30u16 {
+                for tile_col in 0..32u16 {
+                    let tile_id = self.ppu_peek(base + tile_row * 32 + tile_col)? as u16;
+                    // Each attribute byte covers 4x4 tiles, 2 bits per 2x2 quadrant
+                    let attr_addr = base + (ATTRIBUTE_TABLE_OFFSET - NAMETABLE_OFFSET)
+                        + (tile_row / 4) * 8
+                        + tile_col / 4;
+                    let shift = ((tile_row & 2) << 1) | (tile_col & 2);
+                    let palette = &palettes[((self.ppu_peek(attr_addr)? >> shift) & 3) as usize];
+
+                    for row in 0..8 {
+                        let lo = self.ppu_peek(pattern_table_addr + tile_id * 16 + row)?;
+                        let hi = self.ppu_peek(pattern_table_addr + tile_id * 16 + row + 8)?;
+                        let line = &mut view[y + (tile_row * 8 + row) as usize];
+                        for col in 0..8 {
+                            let pixel = (((hi >> (7 - col)) & 1) << 1) | ((lo >> (7 - col)) & 1);
+                            line[x + tile_col as usize * 8 + col] = palette[pixel as usize] as u16;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(view)
+    }
+
+    // Where the top left corner of the screen is in debug_nametables(), going by the scroll
+    // the game last set (t and fine x, before mid-frame changes take effect)
+    pub fn debug_scroll(&self) -> (u16, u16) {
+        let t = &self.reg.t_addr;
+        let x = t.get_nametable_x() as u16 * 256 + t.get_coarse_x() * 8 + self.reg.fine_x as u16;
+        let y = t.get_nametable_y() as u16 * 240 + t.get_coarse_y() * 8 + t.get_fine_y();
+        // Coarse Y 30 and 31 point into the attribute table, they wrap around just the same
+        (x, y % 480)
+    }
+
     pub fn debug_pattern_tables(
-        &mut self,
+        &self,
     ) -> Result<(PatternTable, PatternTable)> {
         use bit::BitIndex;
         let mut pat0: PatternTable = Box::new([[0; 128]; 128]);
@@ -831,10 +1078,10 @@ impl Ppu {
             let tile_y = (tile / 16) * 8;
 
             for row in 0..8 {
-                let lo0 = self.ppu_read(lo_plane0_addr + row)?;
-                let hi0 = self.ppu_read(hi_plane0_addr + row)?;
-                let lo1 = self.ppu_read(lo_plane1_addr + row)?;
-                let hi1 = self.ppu_read(hi_plane1_addr + row)?;
+                let lo0 = self.ppu_peek(lo_plane0_addr + row)?;
+                let hi0 = self.ppu_peek(hi_plane0_addr + row)?;
+                let lo1 = self.ppu_peek(lo_plane1_addr + row)?;
+                let hi1 = self.ppu_peek(hi_plane1_addr + row)?;
                 for col in 0..8u16 {
                     let pixel0 =
                         ((hi0.bit(7 - col as usize) as u8) << 1) | lo0.bit(7 - col as usize) as u8;
@@ -0,0 +1,160 @@
+use crate::error::Result;
+
+// Save state layout: magic, format version, then every component writing its fields in a
+// fixed order (see Cpu::save_state). Bump the version whenever that order changes.
+pub const STATE_MAGIC: &[u8; 4] = b"NESS";
+pub const STATE_VERSION: u16 = 6;
+
+#[derive(Default)]
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    pub fn bool(&mut self, v: bool) {
+        self.u8(v as u8);
+    }
+
+    pub fn u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, v: i32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    // Length prefixed
+    pub fn bytes(&mut self, v: &[u8]) {
+        self.u32(v.len() as u32);
+        self.buf.extend_from_slice(v);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.data.len() - self.pos < n {
+            return Err("Save state is truncated".into());
+        }
+        let slice = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(slice)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    pub fn i32(&mut self) -> Result<i32> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    // Reads a length prefixed block into `out`, which has to be exactly the right size
+    pub fn bytes_into(&mut self, out: &mut [u8]) -> Result<()> {
+        let bytes = self.bytes()?;
+        if bytes.len() != out.len() {
+            return Err(format!(
+                "Save state has {} bytes where {} were expected",
+                bytes.len(),
+                out.len()
+            )
+            .into());
+        }
+        out.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+}
+
+#[cfg(test)]
+mod state_tests {
+    use super::{StateReader, StateWriter};
+
+    #[test]
+    fn round_trip() {
+        let mut w = StateWriter::new();
+        w.u8(0x12);
+        w.bool(true);
+        w.u16(0x3456);
+        w.u32(0x789ABCDE);
+        w.u64(u64::MAX - 1);
+        w.i32(-1);
+        w.bytes(&[1, 2, 3]);
+        let data = w.into_bytes();
+
+        let mut r = StateReader::new(&data);
+        assert_eq!(r.u8().unwrap(), 0x12);
+        assert!(r.bool().unwrap());
+        assert_eq!(r.u16().unwrap(), 0x3456);
+        assert_eq!(r.u32().unwrap(), 0x789ABCDE);
+        assert_eq!(r.u64().unwrap(), u64::MAX - 1);
+        assert_eq!(r.i32().unwrap(), -1);
+        let mut out = [0; 3];
+        r.bytes_into(&mut out).unwrap();
+        assert_eq!(out, [1, 2, 3]);
+        assert!(r.is_empty());
+        assert!(r.u8().is_err());
+    }
+
+    #[test]
+    fn size_mismatch() {
+        let mut w = StateWriter::new();
+        w.bytes(&[0; 4]);
+        let data = w.into_bytes();
+        let mut out = [0; 8];
+        assert!(StateReader::new(&data).bytes_into(&mut out).is_err());
+        assert!(StateReader::new(&data[..6]).bytes().is_err());
+    }
+}